@@ -0,0 +1,186 @@
+//! Load test for oppgave
+//!
+//! Floods a queue with jobs from several producers while several workers consume them,
+//! then reports throughput and latency percentiles.
+//!
+//! Run it with `cargo run --release --example oppgave-bench -- --jobs 100000 --payload 1024`.
+//! All options: `--url`, `--queue`, `--jobs`, `--payload` (bytes), `--producers`, `--consumers`.
+
+#[macro_use]
+extern crate serde_derive;
+extern crate redis;
+extern crate oppgave;
+
+use oppgave::Queue;
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Serialize)]
+struct Job {
+    id: usize,
+    sent_at: u64,
+    payload: String,
+}
+
+struct Options {
+    url: String,
+    queue: String,
+    jobs: usize,
+    payload: usize,
+    producers: usize,
+    consumers: usize,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: oppgave-bench [--url URL] [--queue NAME] [--jobs N] [--payload BYTES] \
+         [--producers N] [--consumers N]"
+    );
+    process::exit(1);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        url: "redis://127.0.0.1/".into(),
+        queue: "bench".into(),
+        jobs: 100_000,
+        payload: 128,
+        producers: 4,
+        consumers: 4,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        let number = || value.parse::<usize>().unwrap_or_else(|_| usage());
+        match &flag[..] {
+            "--url" => options.url = value.clone(),
+            "--queue" => options.queue = value.clone(),
+            "--jobs" => options.jobs = number(),
+            "--payload" => options.payload = number(),
+            "--producers" => options.producers = number().max(1),
+            "--consumers" => options.consumers = number().max(1),
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+fn now_micros() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1_000_000 + u64::from(now.subsec_micros())
+}
+
+/// Get the given percentile of the sorted latencies, in microseconds
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+fn report(name: &str, count: usize, elapsed: Duration, mut latencies: Vec<u64>) {
+    latencies.sort();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+    println!(
+        "{}: {} jobs in {:.2}s, {:.0} jobs/sec",
+        name,
+        count,
+        secs,
+        count as f64 / secs
+    );
+    println!(
+        "  latency (µs): p50 {}, p90 {}, p99 {}, max {}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        latencies.last().cloned().unwrap_or(0)
+    );
+}
+
+fn main() {
+    let options = parse_options();
+    let client = redis::Client::open(&options.url[..]).unwrap();
+    let con = client.get_connection().unwrap();
+    let queue_name = format!("oppgave:{}", options.queue);
+    let _: () = redis::cmd("DEL").arg(&queue_name[..]).query(&con).unwrap();
+
+    println!(
+        "Pushing {} jobs of {} bytes with {} producers, consuming with {} workers",
+        options.jobs,
+        options.payload,
+        options.producers,
+        options.consumers
+    );
+
+    let consumed = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+
+    let consumers: Vec<_> = (0..options.consumers)
+        .map(|_| {
+            let client = client.clone();
+            let name = options.queue.clone();
+            let consumed = consumed.clone();
+            let total = options.jobs;
+            thread::spawn(move || {
+                let worker = Queue::new(name, client);
+                let mut latencies = vec![];
+                while consumed.load(Ordering::SeqCst) < total {
+                    let task = match worker.next::<Job>(1) {
+                        Some(Ok(task)) => task,
+                        // Timed out waiting for a job
+                        _ => continue,
+                    };
+                    latencies.push(now_micros().saturating_sub(task.sent_at));
+                    consumed.fetch_add(1, Ordering::SeqCst);
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    let producers: Vec<_> = (0..options.producers)
+        .map(|producer| {
+            let client = client.clone();
+            let name = options.queue.clone();
+            let payload = "x".repeat(options.payload);
+            let jobs = (producer..options.jobs).step_by(options.producers).collect::<Vec<_>>();
+            thread::spawn(move || {
+                let queue = Queue::new(name, client);
+                let mut latencies = Vec::with_capacity(jobs.len());
+                for id in jobs {
+                    let sent = Instant::now();
+                    queue
+                        .push(Job {
+                            id: id,
+                            sent_at: now_micros(),
+                            payload: payload.clone(),
+                        })
+                        .unwrap();
+                    let elapsed = sent.elapsed();
+                    latencies.push(elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros()));
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    let push_latencies = producers
+        .into_iter()
+        .flat_map(|producer| producer.join().unwrap())
+        .collect::<Vec<_>>();
+    report("push", push_latencies.len(), start.elapsed(), push_latencies);
+
+    let latencies = consumers
+        .into_iter()
+        .flat_map(|consumer| consumer.join().unwrap())
+        .collect::<Vec<_>>();
+    report("consume (end to end)", latencies.len(), start.elapsed(), latencies);
+}
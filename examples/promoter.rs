@@ -0,0 +1,13 @@
+extern crate redis;
+extern crate oppgave;
+
+use oppgave::Promoter;
+
+fn main() {
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let promoter = Promoter::new(client).queue("default");
+
+    println!("Promoting delayed jobs of queue `default`");
+
+    promoter.run().unwrap();
+}
@@ -0,0 +1,129 @@
+//! Archive of completed jobs, kept for a limited time.
+
+use std::time::Duration;
+use serde_json;
+use redis::{self, Pipeline, RedisResult, ErrorKind};
+use functions;
+
+/// Adds a job to the archive and drops all jobs past the retention window or over capacity.
+///
+/// KEYS[1]: the archive index, scored by completion time
+/// KEYS[2]: the archived jobs, by id
+/// ARGV[1]: id of the job
+/// ARGV[2]: the archived job
+/// ARGV[3]: completion time in milliseconds
+/// ARGV[4]: oldest completion time to keep in milliseconds
+/// ARGV[5]: maximum number of jobs to keep
+pub(crate) const ARCHIVE: &'static str = r"
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
+local old = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', '(' .. ARGV[4])
+local excess = redis.call('ZCARD', KEYS[1]) - #old - tonumber(ARGV[5])
+if excess > 0 then
+  for _, jid in ipairs(redis.call('ZRANGE', KEYS[1], #old, #old + excess - 1)) do
+    table.insert(old, jid)
+  end
+end
+for _, jid in ipairs(old) do
+  redis.call('ZREM', KEYS[1], jid)
+  redis.call('HDEL', KEYS[2], jid)
+end
+return #old
+";
+
+/// How long and how many completed jobs are kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retention {
+    /// Completed jobs older than this are dropped
+    pub max_age: Duration,
+    /// Only this many of the latest completed jobs are kept
+    pub max_jobs: usize,
+}
+
+/// A completed job kept in the archive.
+///
+/// All times are in milliseconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedJob {
+    /// Id of the job
+    pub jid: String,
+    /// The encoded task
+    pub task: String,
+    /// Time the job was pushed, if known
+    pub enqueued_at: Option<u64>,
+    /// Time a worker started processing the job
+    pub started_at: u64,
+    /// Time the job completed
+    pub finished_at: u64,
+    /// The worker which processed the job
+    pub worker: String,
+}
+
+/// Get the key of the archive index of `queue`.
+fn index_key(queue: &str) -> String {
+    format!("{}:archive", queue)
+}
+
+/// Get the key of the archived jobs of `queue`.
+fn jobs_key(queue: &str) -> String {
+    format!("{}:archive:jobs", queue)
+}
+
+/// Add the commands archiving `job` to the pipeline.
+pub(crate) fn archive(pipe: &mut Pipeline, queue: &str, retention: &Retention, job: &ArchivedJob) {
+    let oldest = job.finished_at.saturating_sub(::duration_millis(retention.max_age));
+    let record = serde_json::to_string(job).expect("Encoding an archived job can't fail");
+
+    functions::eval(pipe, ARCHIVE, 2)
+        .arg(index_key(queue))
+        .arg(jobs_key(queue))
+        .arg(&job.jid[..])
+        .arg(record)
+        .arg(job.finished_at)
+        .arg(oldest)
+        .arg(retention.max_jobs)
+        .ignore();
+}
+
+fn decode(record: &str) -> RedisResult<ArchivedJob> {
+    serde_json::from_str(record).map_err(|_| {
+        From::from((ErrorKind::TypeError, "Invalid archived job"))
+    })
+}
+
+/// Look up the job `jid` in the archive of `queue`.
+pub(crate) fn find<C: redis::ConnectionLike>(con: &C, queue: &str, jid: &str) -> RedisResult<Option<ArchivedJob>> {
+    let record: Option<String> = redis::cmd("HGET").arg(jobs_key(queue)).arg(jid).query(con)?;
+    match record {
+        Some(record) => decode(&record).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// List archived jobs of `queue`, latest first.
+pub(crate) fn list<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    offset: usize,
+    count: usize,
+) -> RedisResult<Vec<ArchivedJob>> {
+    if count == 0 {
+        return Ok(vec![]);
+    }
+
+    let jids: Vec<String> = redis::cmd("ZREVRANGE")
+        .arg(index_key(queue))
+        .arg(offset)
+        .arg(offset + count - 1)
+        .query(con)?;
+    if jids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let records: Vec<Option<String>> = redis::cmd("HMGET").arg(jobs_key(queue)).arg(jids).query(con)?;
+    records
+        .into_iter()
+        .flatten()
+        .map(|record| decode(&record))
+        .collect()
+}
@@ -0,0 +1,218 @@
+//! Batches of tasks with callbacks once all of them finished.
+
+use redis::{self, Pipeline, RedisResult};
+use functions;
+use envelope::Envelope;
+use TaskEncodable;
+
+/// How long batch state is kept in Redis if a batch never finishes, in seconds.
+const BATCH_TTL: usize = 30 * 24 * 60 * 60;
+
+/// Marks one member of a batch as finished and enqueues the callbacks after the last one.
+///
+/// KEYS[1]: the batch key
+/// KEYS[2]: the dead letter queue of the member
+/// ARGV[1]: "1" if the member failed
+/// ARGV[2]: id of the member to only finish it if it's dead, empty to finish it right away
+pub(crate) const FINISH_MEMBER: &'static str = r"
+local key = KEYS[1]
+if ARGV[2] ~= '' and not redis.call('ZSCORE', KEYS[2], ARGV[2]) then
+  return 0
+end
+if redis.call('EXISTS', key) == 0 then
+  return 0
+end
+if ARGV[1] == '1' then
+  redis.call('HINCRBY', key, 'failed', 1)
+end
+if redis.call('HINCRBY', key, 'pending', -1) > 0 then
+  return 0
+end
+local failed = tonumber(redis.call('HGET', key, 'failed'))
+local cb = redis.call('HMGET', key, 'complete_queue', 'complete_job', 'success_queue', 'success_job')
+if cb[1] then
+  redis.call('LPUSH', cb[1], cb[2])
+end
+if failed == 0 and cb[3] then
+  redis.call('LPUSH', cb[3], cb[4])
+end
+redis.call('DEL', key)
+return 1
+";
+
+/// Get the key the state of a batch is stored in.
+fn batch_key(bid: &str) -> String {
+    format!("oppgave:batch:{}", bid)
+}
+
+/// Add the commands marking a member of the batch `bid` as finished to the pipeline.
+///
+/// With `if_dead`, the dead letter queue and id of the member, it only counts as finished if
+/// it's dead by then.
+pub(crate) fn finish_member(pipe: &mut Pipeline, bid: &str, failed: bool, if_dead: Option<(&str, &str)>) {
+    let key = batch_key(bid);
+    let (dead, jid) = if_dead.unwrap_or((&key, ""));
+    functions::eval(pipe, FINISH_MEMBER, 2)
+        .arg(&key[..])
+        .arg(dead)
+        .arg(if failed { "1" } else { "0" })
+        .arg(jid)
+        .ignore();
+}
+
+/// A group of tasks, tracked together to run callbacks once all of them finished.
+///
+/// All tasks are pushed at once with `Queue::push_batch`.
+/// The number of remaining tasks is tracked in Redis and decremented whenever a member
+/// finishes, no matter which worker processed it.
+///
+/// Two callback tasks can be registered:
+///
+/// * `on_success` is enqueued once all members completed successfully.
+/// * `on_complete` is enqueued once all members finished, even if some of them failed.
+///
+/// Tasks in a batch need to be encoded as JSON, which is the default for all `Serialize` types.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let batch = Batch::new()
+///     .push(Resize { id: 1 })
+///     .push(Resize { id: 2 })
+///     .on_success("reports", Report { gallery: 7 });
+///
+/// let bid = images.push_batch(batch).unwrap();
+/// ```
+pub struct Batch {
+    bid: String,
+    tasks: Vec<RedisResult<Vec<u8>>>,
+    on_success: Option<(String, RedisResult<Vec<u8>>)>,
+    on_complete: Option<(String, RedisResult<Vec<u8>>)>,
+}
+
+/// The progress of a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchStatus {
+    /// Number of tasks in the batch
+    pub total: u64,
+    /// Number of tasks not finished yet
+    pub pending: u64,
+    /// Number of tasks that failed
+    pub failed: u64,
+}
+
+impl Batch {
+    /// Create a new, empty batch
+    pub fn new() -> Batch {
+        Batch {
+            bid: ::envelope::new_jid(),
+            tasks: vec![],
+            on_success: None,
+            on_complete: None,
+        }
+    }
+
+    /// Get the id of this batch
+    pub fn id(&self) -> &str {
+        &self.bid
+    }
+
+    /// Add a task to the batch
+    pub fn push<T: TaskEncodable>(mut self, task: T) -> Batch {
+        self.tasks.push(task.try_encode_task());
+        self
+    }
+
+    /// Push `task` to the queue `name` once all tasks of the batch completed successfully
+    pub fn on_success<T: TaskEncodable>(mut self, name: &str, task: T) -> Batch {
+        self.on_success = Some((format!("oppgave:{}", name), task.try_encode_task()));
+        self
+    }
+
+    /// Push `task` to the queue `name` once all tasks of the batch finished, even if some failed
+    pub fn on_complete<T: TaskEncodable>(mut self, name: &str, task: T) -> Batch {
+        self.on_complete = Some((format!("oppgave:{}", name), task.try_encode_task()));
+        self
+    }
+
+    /// Build a pipeline atomically storing the batch state and pushing all tasks to `queue`.
+    pub(crate) fn into_pipeline(self, queue: &str) -> RedisResult<(String, Pipeline)> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        // Tasks which failed to encode fail the whole batch
+        let encoded = |callback: Option<(String, RedisResult<Vec<u8>>)>| {
+            callback.map(|(queue, task)| task.map(|task| (queue, task))).transpose()
+        };
+        let callbacks = [("success", encoded(self.on_success)?), ("complete", encoded(self.on_complete)?)];
+
+        if self.tasks.is_empty() {
+            // Nothing to wait for, the batch is finished right away
+            for callback in callbacks.iter().filter_map(|c| c.1.as_ref()) {
+                let job = Envelope::new(callback.1.clone())?;
+                pipe.cmd("LPUSH").arg(&callback.0[..]).arg(job.encode()).ignore();
+            }
+            return Ok((self.bid, pipe));
+        }
+
+        let key = batch_key(&self.bid);
+        pipe.cmd("HMSET")
+            .arg(&key[..])
+            .arg("total")
+            .arg(self.tasks.len())
+            .arg("pending")
+            .arg(self.tasks.len())
+            .arg("failed")
+            .arg(0)
+            .ignore();
+
+        for &(name, ref callback) in &callbacks {
+            if let Some((ref queue, ref task)) = *callback {
+                let job = Envelope::new(task.clone())?;
+                pipe.cmd("HMSET")
+                    .arg(&key[..])
+                    .arg(format!("{}_queue", name))
+                    .arg(&queue[..])
+                    .arg(format!("{}_job", name))
+                    .arg(job.encode())
+                    .ignore();
+            }
+        }
+        pipe.cmd("EXPIRE").arg(&key[..]).arg(BATCH_TTL).ignore();
+
+        for task in self.tasks {
+            let mut job = Envelope::new(task?)?;
+            job.batch = Some(self.bid.clone());
+            pipe.cmd("LPUSH").arg(queue).arg(job.encode()).ignore();
+        }
+
+        Ok((self.bid, pipe))
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Batch {
+        Batch::new()
+    }
+}
+
+/// Get the progress of the batch `bid`.
+///
+/// Returns `None` if the batch is unknown or already finished.
+pub(crate) fn status<C: redis::ConnectionLike>(con: &C, bid: &str) -> RedisResult<Option<BatchStatus>> {
+    let (total, pending, failed): (Option<u64>, Option<u64>, Option<u64>) = redis::cmd("HMGET")
+        .arg(batch_key(bid))
+        .arg("total")
+        .arg("pending")
+        .arg("failed")
+        .query(con)?;
+
+    Ok(match (total, pending, failed) {
+        (Some(total), Some(pending), Some(failed)) => Some(BatchStatus {
+            total: total,
+            pending: pending,
+            failed: failed,
+        }),
+        _ => None,
+    })
+}
@@ -0,0 +1,502 @@
+//! Supervisor running a pool of worker processes
+//!
+//! Starts the configured number of worker processes, restarts crashed ones with an exponential
+//! backoff and forwards signals to them, so a fleet of workers can be deployed as one unit.
+//!
+//! Run it with `oppgave-worker CONFIG`, where `CONFIG` is a JSON file like
+//!
+//! ```json
+//! {
+//!     "command": "/usr/local/bin/mail-worker",
+//!     "args": ["--queue", "emails"],
+//!     "env": {"REDIS_URL": "redis://127.0.0.1/"},
+//!     "processes": 4,
+//!     "restart_backoff_secs": 1,
+//!     "max_restart_backoff_secs": 60,
+//!     "shutdown_timeout_secs": 25
+//! }
+//! ```
+//!
+//! Only `command` is required. Every process gets its index in `OPPGAVE_WORKER_INDEX`.
+//!
+//! Signals:
+//!
+//! * `SIGTERM` and `SIGINT` stop all processes with `SIGTERM`, processes still running after
+//!   the shutdown timeout are killed.
+//! * `SIGHUP` reloads the config and restarts the processes one after another, e.g. to roll out a
+//!   new build without stopping all workers at once. The other processes are supervised as usual
+//!   in the meantime.
+//! * `SIGUSR1` and `SIGUSR2` are forwarded to all processes.
+//! * `SIGTSTP` is forwarded to all processes, then the supervisor stops itself. Once it's
+//!   continued, it continues the processes as well.
+//!
+//! Worker processes should finish their current task and exit on `SIGTERM`, e.g. by calling
+//! `Worker::stop` from a signal handler.
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate libc;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::process::{self, Child, Command};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::{cmp, env, thread};
+
+/// A process which ran at least this long gets the shortest restart backoff again.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Signals received but not handled yet, one bit per signal number.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Signals passed on to all processes as they are.
+const FORWARDED: [libc::c_int; 2] = [libc::SIGUSR1, libc::SIGUSR2];
+
+#[derive(Clone, Debug, Deserialize)]
+struct Config {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default = "default_processes")]
+    processes: usize,
+    #[serde(default = "default_restart_backoff")]
+    restart_backoff_secs: u64,
+    #[serde(default = "default_max_restart_backoff")]
+    max_restart_backoff_secs: u64,
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout_secs: u64,
+}
+
+fn default_processes() -> usize {
+    1
+}
+
+fn default_restart_backoff() -> u64 {
+    1
+}
+
+fn default_max_restart_backoff() -> u64 {
+    60
+}
+
+fn default_shutdown_timeout() -> u64 {
+    25
+}
+
+impl Config {
+    fn load(path: &str) -> Result<Config, String> {
+        let file = File::open(path).map_err(|e| format!("Can't open {}: {}", path, e))?;
+        let config: Config = serde_json::from_reader(file).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        if config.processes == 0 {
+            return Err(format!("Invalid config {}: at least one process is needed", path));
+        }
+        Ok(config)
+    }
+
+    fn restart_backoff(&self) -> Duration {
+        Duration::from_secs(self.restart_backoff_secs)
+    }
+
+    fn max_restart_backoff(&self) -> Duration {
+        Duration::from_secs(cmp::max(self.restart_backoff_secs, self.max_restart_backoff_secs))
+    }
+
+    fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+/// One supervised worker process.
+struct Slot {
+    index: usize,
+    child: Option<Child>,
+    started_at: Instant,
+    backoff: Duration,
+    restart_at: Instant,
+    /// When the process is killed if it didn't exit after being asked to stop
+    stop_deadline: Option<Instant>,
+}
+
+impl Slot {
+    fn new(index: usize, config: &Config) -> Slot {
+        Slot {
+            index: index,
+            child: None,
+            started_at: Instant::now(),
+            backoff: config.restart_backoff(),
+            restart_at: Instant::now(),
+            stop_deadline: None,
+        }
+    }
+
+    /// Start the process if it's due, or note that it exited.
+    fn supervise(&mut self, config: &Config) {
+        let exited = match self.child {
+            Some(ref mut child) => match child.try_wait() {
+                Ok(Some(status)) => Some(format!("process {} (pid {}) exited with {}", self.index, child.id(), status)),
+                Ok(None) => None,
+                Err(e) => Some(format!("process {} (pid {}) can't be waited for: {}", self.index, child.id(), e)),
+            },
+            None => {
+                if Instant::now() >= self.restart_at {
+                    self.start(config);
+                }
+                return;
+            }
+        };
+
+        if let Some(reason) = exited {
+            self.child = None;
+            if self.started_at.elapsed() >= STABLE_AFTER {
+                self.backoff = config.restart_backoff();
+            }
+            log(&format!("{}, restarting in {} s", reason, self.backoff.as_secs()));
+            self.schedule_restart(config);
+        }
+    }
+
+    fn start(&mut self, config: &Config) {
+        let spawned = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .env("OPPGAVE_WORKER_INDEX", self.index.to_string())
+            .spawn();
+
+        match spawned {
+            Ok(child) => {
+                log(&format!("started process {} (pid {})", self.index, child.id()));
+                self.child = Some(child);
+                self.started_at = Instant::now();
+            }
+            Err(e) => {
+                log(&format!("can't start process {}: {}, retrying in {} s", self.index, e, self.backoff.as_secs()));
+                self.schedule_restart(config);
+            }
+        }
+    }
+
+    fn schedule_restart(&mut self, config: &Config) {
+        self.restart_at = Instant::now() + self.backoff;
+        self.backoff = cmp::min(self.backoff * 2, config.max_restart_backoff());
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        if let Some(ref child) = self.child {
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, signal);
+            }
+        }
+    }
+
+    /// Wait until the process exited or `deadline` passed, then kill it.
+    fn reap(&mut self, deadline: Instant) -> bool {
+        let child = match self.child {
+            Some(ref mut child) => child,
+            None => return true,
+        };
+        match child.try_wait() {
+            Ok(None) if Instant::now() < deadline => return false,
+            Ok(None) => {
+                log(&format!("killing process {} (pid {})", self.index, child.id()));
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            _ => {}
+        }
+        self.child = None;
+        true
+    }
+
+    /// Ask the process to stop, it's killed if it still runs after the shutdown timeout.
+    ///
+    /// Stopping processes are not restarted by `supervise`, see `stopped`.
+    fn terminate(&mut self, config: &Config) {
+        if self.stop_deadline.is_none() {
+            self.signal(libc::SIGTERM);
+            self.stop_deadline = Some(Instant::now() + config.shutdown_timeout());
+        }
+    }
+
+    /// Check if a process asked to stop is gone, killing it once its deadline passed.
+    fn stopped(&mut self) -> bool {
+        let deadline = self.stop_deadline.unwrap_or_else(Instant::now);
+        if !self.reap(deadline) {
+            return false;
+        }
+        self.stop_deadline = None;
+        true
+    }
+}
+
+/// A rolling restart in progress, see `roll`.
+struct Roll {
+    /// The slot being restarted
+    index: usize,
+    /// Slots left over from a config with more processes, stopping in the background
+    retiring: Vec<Slot>,
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Check if `signal` was received since the last check.
+fn take(signal: libc::c_int) -> bool {
+    PENDING.fetch_and(!(1 << signal), Ordering::SeqCst) & (1 << signal) != 0
+}
+
+fn log(message: &str) {
+    eprintln!("oppgave-worker[{}]: {}", process::id(), message);
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: oppgave-worker CONFIG");
+    process::exit(1);
+}
+
+/// Stop all processes at once, waiting at most the shutdown timeout for them.
+fn shutdown(slots: &mut [Slot], config: &Config) {
+    log("shutting down");
+    for slot in slots.iter() {
+        slot.signal(libc::SIGTERM);
+    }
+    let deadline = Instant::now() + config.shutdown_timeout();
+    loop {
+        let running = slots.iter_mut().map(|slot| !slot.reap(deadline)).filter(|&running| running).count();
+        if running == 0 {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Start replacing the processes one after another with ones started from `config`.
+///
+/// Surplus processes are stopped and missing ones started right away, `roll` restarts the others.
+fn start_roll(slots: &mut Vec<Slot>, config: &Config) -> Roll {
+    log(&format!("restarting {} processes", config.processes));
+    let mut retiring = slots.split_off(cmp::min(slots.len(), config.processes));
+    for slot in &mut retiring {
+        slot.terminate(config);
+    }
+    let existing = slots.len();
+    slots.extend((existing..config.processes).map(|index| Slot::new(index, config)));
+    Roll {
+        index: 0,
+        retiring: retiring,
+    }
+}
+
+/// Advance the rolling restart without waiting, returning `None` once it's done.
+///
+/// The slot being restarted is asked to stop and started again once it's gone, so the other
+/// slots keep being supervised in the meantime.
+fn roll(slots: &mut [Slot], mut roll: Roll, config: &Config) -> Option<Roll> {
+    for slot in &mut roll.retiring {
+        slot.stopped();
+    }
+    roll.retiring.retain(|slot| slot.child.is_some());
+    while roll.index < slots.len() {
+        let slot = &mut slots[roll.index];
+        slot.terminate(config);
+        if !slot.stopped() {
+            return Some(roll);
+        }
+        slot.backoff = config.restart_backoff();
+        slot.start(config);
+        roll.index += 1;
+    }
+    if roll.retiring.is_empty() {
+        None
+    } else {
+        Some(roll)
+    }
+}
+
+/// Stop the supervisor on `SIGTSTP`, continuing the processes once it's continued itself.
+fn suspend(slots: &[Slot]) {
+    for slot in slots {
+        slot.signal(libc::SIGTSTP);
+    }
+    unsafe {
+        libc::signal(libc::SIGTSTP, libc::SIG_DFL);
+        libc::raise(libc::SIGTSTP);
+        libc::signal(libc::SIGTSTP, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    for slot in slots {
+        slot.signal(libc::SIGCONT);
+    }
+}
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| usage());
+    let mut config = Config::load(&path).unwrap_or_else(|e| {
+        log(&e);
+        process::exit(1);
+    });
+
+    for &signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGTSTP].iter().chain(FORWARDED.iter()) {
+        unsafe {
+            libc::signal(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+
+    let mut slots: Vec<Slot> = (0..config.processes).map(|index| Slot::new(index, &config)).collect();
+    let mut rolling: Option<Roll> = None;
+    loop {
+        if take(libc::SIGTERM) | take(libc::SIGINT) {
+            if let Some(mut roll) = rolling.take() {
+                slots.append(&mut roll.retiring);
+            }
+            shutdown(&mut slots, &config);
+            return;
+        }
+        if take(libc::SIGHUP) {
+            match Config::load(&path) {
+                Ok(reloaded) => config = reloaded,
+                Err(e) => log(&format!("{}, keeping the current config", e)),
+            }
+            let mut restarted = start_roll(&mut slots, &config);
+            if let Some(mut previous) = rolling.take() {
+                restarted.retiring.append(&mut previous.retiring);
+            }
+            rolling = Some(restarted);
+        }
+        for &signal in &FORWARDED {
+            if take(signal) {
+                for slot in &slots {
+                    slot.signal(signal);
+                }
+            }
+        }
+        if take(libc::SIGTSTP) {
+            suspend(&slots);
+        }
+
+        if let Some(restarted) = rolling.take() {
+            rolling = roll(&mut slots, restarted, &config);
+        }
+        for slot in &mut slots {
+            if slot.stop_deadline.is_none() {
+                slot.supervise(&config);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::{roll, start_roll, Config, Slot};
+
+    /// Write `json` to a config file of its own and load it.
+    fn load(name: &str, json: &str) -> Result<Config, String> {
+        let path = env::temp_dir().join(format!("oppgave-worker-{}-{}.json", process::id(), name));
+        fs::write(&path, json).unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    fn config(command: &str, args: &[&str]) -> Config {
+        let mut config = load("base", "{\"command\": \"true\", \"shutdown_timeout_secs\": 1}").unwrap();
+        config.command = command.into();
+        config.args = args.iter().map(|&arg| arg.to_string()).collect();
+        config
+    }
+
+    /// Supervise `slots` like the main loop does, until `done` holds or 5 seconds passed.
+    fn supervise_until<F: Fn(&[Slot]) -> bool>(slots: &mut [Slot], config: &Config, done: F) {
+        let started = Instant::now();
+        while !done(slots) {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            for slot in slots.iter_mut() {
+                slot.supervise(config);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn loads_configs() {
+        let config = load("defaults", "{\"command\": \"/usr/local/bin/mail-worker\"}").unwrap();
+        assert_eq!("/usr/local/bin/mail-worker", config.command);
+        assert!(config.args.is_empty());
+        assert_eq!(1, config.processes);
+        assert_eq!(Duration::from_secs(1), config.restart_backoff());
+        assert_eq!(Duration::from_secs(60), config.max_restart_backoff());
+        assert_eq!(Duration::from_secs(25), config.shutdown_timeout());
+
+        let config = load("backoff", "{\"command\": \"w\", \"restart_backoff_secs\": 90}").unwrap();
+        assert_eq!(Duration::from_secs(90), config.max_restart_backoff());
+
+        assert!(load("empty", "{\"command\": \"w\", \"processes\": 0}").unwrap_err().contains("at least one process"));
+        assert!(load("missing", "{\"processes\": 2}").unwrap_err().starts_with("Invalid config"));
+        assert!(Config::load("/nonexistent/oppgave-worker.json").unwrap_err().starts_with("Can't open"));
+    }
+
+    #[test]
+    fn backs_off_restarts() {
+        let mut config = config("true", &[]);
+        config.restart_backoff_secs = 1;
+        config.max_restart_backoff_secs = 5;
+        let mut slot = Slot::new(0, &config);
+
+        let backoffs: Vec<u64> = (0..5)
+            .map(|_| {
+                let before = Instant::now();
+                slot.schedule_restart(&config);
+                (slot.restart_at - before).as_secs()
+            })
+            .collect();
+        assert_eq!(vec![1, 2, 4, 5, 5], backoffs);
+    }
+
+    #[test]
+    fn restarts_exited_processes() {
+        let config = config("true", &[]);
+        let mut slots = vec![Slot::new(0, &config)];
+
+        slots[0].supervise(&config);
+        assert!(slots[0].child.is_some());
+        supervise_until(&mut slots, &config, |slots| slots[0].child.is_none());
+        // Restarted after the backoff, not right away
+        assert!(slots[0].restart_at > Instant::now());
+        assert_eq!(Duration::from_secs(2), slots[0].backoff);
+    }
+
+    #[test]
+    fn rolls_without_blocking() {
+        let mut config = config("sleep", &["10"]);
+        config.processes = 2;
+        let mut slots: Vec<Slot> = (0..2).map(|index| Slot::new(index, &config)).collect();
+        supervise_until(&mut slots, &config, |slots| slots.iter().all(|slot| slot.child.is_some()));
+        let pids: Vec<u32> = slots.iter().map(|slot| slot.child.as_ref().unwrap().id()).collect();
+
+        config.processes = 1;
+        let started = Instant::now();
+        let mut rolling = Some(start_roll(&mut slots, &config));
+        assert_eq!(1, slots.len());
+        while let Some(restarted) = rolling.take() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            rolling = roll(&mut slots, restarted, &config);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let pid = slots[0].child.as_ref().unwrap().id();
+        assert!(!pids.contains(&pid));
+        slots[0].terminate(&config);
+        while !slots[0].stopped() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Circuit breaker pausing consumption while too many tasks fail.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The state of a `CircuitBreaker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Tasks are fetched as usual.
+    Closed,
+    /// Too many tasks failed recently, no tasks are fetched.
+    Open,
+    /// The cooldown passed, a single task is fetched to probe if failures persist.
+    HalfOpen,
+}
+
+struct Inner {
+    state: BreakerState,
+    outcomes: VecDeque<bool>,
+    opened_at: Instant,
+    probing: bool,
+}
+
+/// Pauses consumption when the recent failure rate exceeds a threshold.
+///
+/// The breaker looks at the outcomes of the last `window` tasks.
+/// Once the share of failures among them reaches `threshold`, the breaker opens and no tasks are
+/// fetched for the `cooldown`.
+/// Afterwards a single task is fetched as a probe: if it succeeds, the breaker closes again,
+/// otherwise it opens for another cooldown.
+///
+/// Clones share their state.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let breaker = CircuitBreaker::new(0.5, 20)
+///     .cooldown(Duration::from_secs(30))
+///     .on_change(|state| println!("Circuit breaker is now {:?}", state));
+///
+/// Worker::new(queue).circuit_breaker(breaker).run(handler);
+/// ```
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    threshold: f64,
+    window: usize,
+    cooldown: Duration,
+    on_change: Option<Arc<dyn Fn(BreakerState) + Send + Sync>>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreaker {
+    /// Create a new breaker opening once `threshold` (0.0 to 1.0) of the last `window` tasks failed
+    pub fn new(threshold: f64, window: usize) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: threshold,
+            window: if window == 0 { 1 } else { window },
+            cooldown: Duration::from_secs(30),
+            on_change: None,
+            inner: Arc::new(Mutex::new(Inner {
+                state: BreakerState::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: Instant::now(),
+                probing: false,
+            })),
+        }
+    }
+
+    /// Set how long the breaker stays open before probing. Defaults to 30 seconds.
+    pub fn cooldown(mut self, cooldown: Duration) -> CircuitBreaker {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Call `callback` whenever the state of the breaker changes
+    pub fn on_change<F: Fn(BreakerState) + Send + Sync + 'static>(mut self, callback: F) -> CircuitBreaker {
+        self.on_change = Some(Arc::new(callback));
+        self
+    }
+
+    /// Get the current state of the breaker
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    fn transition(&self, inner: &mut Inner, state: BreakerState) {
+        inner.state = state;
+        if let Some(ref callback) = self.on_change {
+            callback(state);
+        }
+    }
+
+    /// Check if a task may be fetched.
+    pub(crate) fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => {
+                if inner.opened_at.elapsed() < self.cooldown {
+                    return false;
+                }
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                inner.probing = true;
+                true
+            }
+            BreakerState::HalfOpen => {
+                if inner.probing {
+                    return false;
+                }
+                inner.probing = true;
+                true
+            }
+        }
+    }
+
+    /// Give up a permission of `allow` without processing a task.
+    pub(crate) fn release(&self) {
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    /// Record the outcome of a processed task.
+    pub(crate) fn record(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => {
+                inner.outcomes.push_back(success);
+                if inner.outcomes.len() > self.window {
+                    inner.outcomes.pop_front();
+                }
+
+                let failures = inner.outcomes.iter().filter(|success| !**success).count();
+                if inner.outcomes.len() == self.window &&
+                    failures as f64 >= self.threshold * self.window as f64
+                {
+                    inner.opened_at = Instant::now();
+                    self.transition(&mut inner, BreakerState::Open);
+                }
+            }
+            BreakerState::HalfOpen => {
+                inner.probing = false;
+                inner.outcomes.clear();
+                if success {
+                    self.transition(&mut inner, BreakerState::Closed);
+                } else {
+                    inner.opened_at = Instant::now();
+                    self.transition(&mut inner, BreakerState::Open);
+                }
+            }
+            BreakerState::Open => {}
+        }
+    }
+}
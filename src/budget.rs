@@ -0,0 +1,61 @@
+//! Limits on the rate of retries, against retry storms after mass failures.
+
+use std::time::Duration;
+
+/// Caps how many failed jobs are retried per time window.
+///
+/// Retries beyond the budget of the current window are delayed by as many windows as needed
+/// to spread them out, so an outage failing thousands of jobs at once doesn't hit the
+/// recovering downstream with all of them again.
+/// The budget applies to jobs retried because of `JobOptions::max_attempts`, on top of their
+/// backoff. The delayed retries need a running `Promoter`.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// // At most 100 retries per second across all queues sharing the budget
+/// let budget = RetryBudget::new(100, Duration::from_secs(1)).global();
+/// let queue = Queue::new("webhooks".into(), client).with_retry_budget(budget);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryBudget {
+    max: u64,
+    per: Duration,
+    global: bool,
+}
+
+impl RetryBudget {
+    /// Allow at most `max` retries of the queue `per` window
+    pub fn new(max: u64, per: Duration) -> RetryBudget {
+        RetryBudget {
+            max: ::cmp::max(1, max),
+            per: per,
+            global: false,
+        }
+    }
+
+    /// Share the budget with all other queues using a global budget
+    pub fn global(mut self) -> RetryBudget {
+        self.global = true;
+        self
+    }
+
+    /// Get the maximum number of retries per window
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Get the length of a window
+    pub fn per(&self) -> Duration {
+        self.per
+    }
+
+    /// Get the prefix of the counters of retries of `queue`, one per window.
+    pub(crate) fn key(&self, queue: &str) -> String {
+        if self.global {
+            "oppgave:retries".into()
+        } else {
+            format!("{}:retries", queue)
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Cooperative cancellation of running handlers.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Signals a running handler to stop.
+///
+/// A `Worker` hands a token to every handler. It is cancelled when the worker is stopped, the
+/// handler runs over its timeout or the job is cancelled with `JobHandle::cancel`.
+/// Handlers doing long work should check `is_cancelled` regularly and return early.
+///
+/// Clones share their state, cancelling one cancels all of them.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a new token, not cancelled yet
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancel the token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the token was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
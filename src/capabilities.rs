@@ -0,0 +1,69 @@
+//! Detection of the commands a Redis server supports.
+
+use redis::{self, ErrorKind, RedisResult};
+
+/// The version of a Redis server and the commands it supports, see `Queue::capabilities`.
+///
+/// Queues detect the server on first use and prefer newer commands where available, falling
+/// back to older ones otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    version: (u32, u32, u32),
+}
+
+impl Capabilities {
+    /// Get the capabilities of the server with the given `(major, minor, patch)` version
+    pub fn new(version: (u32, u32, u32)) -> Capabilities {
+        Capabilities { version: version }
+    }
+
+    /// Get the version of the server
+    ///
+    /// Servers not reporting their version or refusing `INFO` are taken as `(0, 0, 0)`, so
+    /// only long-standing commands are used.
+    pub fn version(&self) -> (u32, u32, u32) {
+        self.version
+    }
+
+    /// Check if the server has `LMOVE` and `BLMOVE`, added in Redis 6.2
+    ///
+    /// They replace `RPOPLPUSH` and `BRPOPLPUSH`, which are deprecated since.
+    pub fn has_lmove(&self) -> bool {
+        self.version >= (6, 2, 0)
+    }
+
+    /// Check if the server has `LMPOP`, added in Redis 7.0, to pop from several lists at once
+    pub fn has_lmpop(&self) -> bool {
+        self.version >= (7, 0, 0)
+    }
+
+    /// Check if the server has Redis Functions, added in Redis 7.0, see `install_functions`
+    pub fn has_functions(&self) -> bool {
+        self.version >= (7, 0, 0)
+    }
+}
+
+/// Parse the version from the output of `INFO server`.
+fn parse(info: &str) -> (u32, u32, u32) {
+    let version = info.lines().find_map(|line| line.strip_prefix("redis_version:")).unwrap_or("");
+    let mut parts = version.trim().split('.').map(|part| part.parse().unwrap_or(0));
+    (
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    )
+}
+
+/// Detect the capabilities of the server behind `con`.
+///
+/// Servers refusing `INFO`, e.g. because ACLs deny the `@dangerous` category to the user, are
+/// taken as `(0, 0, 0)` as well. Only failures to reach the server are returned.
+pub(crate) fn detect<C: redis::ConnectionLike>(con: &C) -> RedisResult<Capabilities> {
+    match redis::cmd("INFO").arg("server").query::<String>(con) {
+        Ok(info) => Ok(Capabilities::new(parse(&info))),
+        Err(e) => match e.kind() {
+            ErrorKind::IoError | ErrorKind::BusyLoadingError => Err(e),
+            _ => Ok(Capabilities::new((0, 0, 0))),
+        },
+    }
+}
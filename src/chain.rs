@@ -0,0 +1,60 @@
+//! Chains of tasks, where each task is only enqueued once its predecessor succeeded.
+
+use redis::RedisResult;
+use envelope::{Envelope, Followup};
+use TaskEncodable;
+
+/// A sequence of tasks, each one enqueued only after the previous one completed successfully.
+///
+/// The follow-up tasks are stored alongside the first task and pushed by the worker that
+/// successfully processed their predecessor.
+/// If a task fails, none of its follow-ups are enqueued.
+///
+/// Tasks in a chain need to be encoded as JSON, which is the default for all `Serialize` types.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let chain = Chain::new(Upload { id: 1 })
+///     .then("thumbnails", Thumbnail { id: 1 })
+///     .then("notifications", Notify { id: 1 });
+///
+/// uploads.push_chain(chain).unwrap();
+/// ```
+pub struct Chain {
+    first: RedisResult<Vec<u8>>,
+    steps: Vec<(String, RedisResult<Vec<u8>>)>,
+}
+
+impl Chain {
+    /// Start a new chain with the task pushed to the queue the chain is pushed to
+    pub fn new<T: TaskEncodable>(task: T) -> Chain {
+        Chain {
+            first: task.try_encode_task(),
+            steps: vec![],
+        }
+    }
+
+    /// Add a task to push to the queue `name` once all previous tasks completed
+    pub fn then<T: TaskEncodable>(mut self, name: &str, task: T) -> Chain {
+        self.steps.push((format!("oppgave:{}", name), task.try_encode_task()));
+        self
+    }
+
+    /// Build the job for the first task, with all follow-ups nested inside.
+    pub(crate) fn into_envelope(self) -> RedisResult<Envelope> {
+        let mut next = None;
+        for (queue, task) in self.steps.into_iter().rev() {
+            let mut job = Envelope::new(task?)?;
+            job.then = next;
+            next = Some(Followup {
+                queue: queue,
+                job: Box::new(job),
+            });
+        }
+
+        let mut job = Envelope::new(self.first?)?;
+        job.then = next;
+        Ok(job)
+    }
+}
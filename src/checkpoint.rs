@@ -0,0 +1,44 @@
+//! Intermediate progress of jobs, kept across retries.
+
+use redis::{self, Pipeline, RedisResult, Value};
+use {TaskDecodable, TaskEncodable};
+
+/// How long a checkpoint is kept after it was last written, in seconds.
+const CHECKPOINT_TTL: usize = 7 * 24 * 60 * 60;
+
+/// Get the key the checkpoint of `jid` in `queue` is stored in.
+fn checkpoint_key(queue: &str, jid: &str) -> String {
+    format!("{}:checkpoint:{}", queue, jid)
+}
+
+/// Store `state` as the checkpoint of `jid`, replacing any previous one.
+pub(crate) fn save<C: redis::ConnectionLike, S: TaskEncodable>(
+    con: &C,
+    queue: &str,
+    jid: &str,
+    state: S,
+) -> RedisResult<()> {
+    redis::cmd("SETEX")
+        .arg(checkpoint_key(queue, jid))
+        .arg(CHECKPOINT_TTL)
+        .arg(state.try_encode_task()?)
+        .query(con)
+}
+
+/// Load the last checkpoint of `jid`.
+pub(crate) fn load<C: redis::ConnectionLike, S: TaskDecodable>(
+    con: &C,
+    queue: &str,
+    jid: &str,
+) -> RedisResult<Option<S>> {
+    let state: Value = redis::cmd("GET").arg(checkpoint_key(queue, jid)).query(con)?;
+    match state {
+        Value::Nil => Ok(None),
+        state => S::decode_task(&state).map(Some),
+    }
+}
+
+/// Add the command dropping the checkpoint of `jid` to the pipeline.
+pub(crate) fn clear(pipe: &mut Pipeline, queue: &str, jid: &str) {
+    pipe.cmd("DEL").arg(checkpoint_key(queue, jid)).ignore();
+}
@@ -0,0 +1,104 @@
+//! Fan-out of large amounts of items into jobs of a few items each.
+
+use redis::{self, RedisResult, Value};
+use batch::Batch;
+use serde::ser::Serialize;
+use TaskDecodable;
+
+/// How long partial results are kept in Redis if they are never reduced, in seconds.
+const PARTIAL_TTL: usize = 30 * 24 * 60 * 60;
+
+/// A job processing a slice of a large set of items, see `Queue::push_chunked`.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// queue.push_chunked(users.iter().map(|user| user.id), 500)?;
+///
+/// Worker::new(queue).run(|chunk: ChunkTask<u64>, _token| -> Result<(), String> {
+///     for id in chunk.items {
+///         reindex(id)?;
+///     }
+///     Ok(())
+/// });
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkTask<I> {
+    /// The items of the chunk, in the order they were pushed
+    pub items: Vec<I>,
+    /// Key to store the partial result of the chunk in, if it's the map step of a map-reduce.
+    /// See `Queue::store_partial`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial: Option<String>,
+}
+
+/// The reduce step of a map-reduce, enqueued once all chunks finished.
+///
+/// See `Queue::push_map_reduce`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReduceTask {
+    /// Id of the batch of chunks
+    pub batch: String,
+    /// Keys of the partial results of all chunks, in the order of the chunks
+    pub partials: Vec<String>,
+}
+
+/// Get the key the partial result of chunk `index` of the batch `bid` is stored in.
+fn partial_key(bid: &str, index: usize) -> String {
+    format!("oppgave:mapreduce:{}:{}", bid, index)
+}
+
+/// Build a batch of chunks of `items`, with the reduce step pushed to the queue `reduce`.
+pub(crate) fn map_batch<I, It>(items: It, chunk_size: usize, reduce: &str) -> Batch
+where
+    I: Serialize,
+    It: IntoIterator<Item = I>,
+{
+    let mut batch = Batch::new();
+    let bid = batch.id().to_string();
+    let mut items = items.into_iter().peekable();
+    let mut partials = vec![];
+
+    while items.peek().is_some() {
+        let key = partial_key(&bid, partials.len());
+        batch = batch.push(ChunkTask {
+            items: items.by_ref().take(chunk_size).collect(),
+            partial: Some(key.clone()),
+        });
+        partials.push(key);
+    }
+
+    batch.on_complete(reduce, ReduceTask { batch: bid, partials: partials })
+}
+
+/// Store the encoded partial result at `key`.
+pub(crate) fn store<C: redis::ConnectionLike>(con: &C, key: &str, result: &[u8]) -> RedisResult<()> {
+    redis::cmd("SET").arg(key).arg(result).arg("EX").arg(PARTIAL_TTL).query(con)
+}
+
+/// Load the partial results stored at `keys`, `None` for chunks without a result.
+pub(crate) fn load<C, R>(con: &C, keys: &[String]) -> RedisResult<Vec<Option<R>>>
+where
+    C: redis::ConnectionLike,
+    R: TaskDecodable,
+{
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let values: Vec<Value> = redis::cmd("MGET").arg(keys).query(con)?;
+    values.iter()
+        .map(|value| match *value {
+            Value::Nil => Ok(None),
+            ref value => R::decode_task(value).map(Some),
+        })
+        .collect()
+}
+
+/// Delete the partial results stored at `keys`.
+pub(crate) fn delete<C: redis::ConnectionLike>(con: &C, keys: &[String]) -> RedisResult<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    redis::cmd("DEL").arg(keys).query(con)
+}
@@ -0,0 +1,299 @@
+//! A task type running external commands, turning a queue into a distributed command runner.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use libc;
+use {CancellationToken, Classify, TaskOutcome};
+
+/// How much of the output of a command is kept, in bytes per stream.
+///
+/// Longer output is cut at the front, keeping its end.
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// A command to run as a subprocess, see `Worker::run_commands`.
+///
+/// The program is looked up in the `PATH` of the worker. It inherits the environment of the
+/// worker, extended by `env`.
+///
+/// The command runs in its own process group. If it runs longer than its `timeout`, the whole
+/// group is killed, including processes the command started itself. Processes it left running
+/// in the background are killed once it exits, as they would keep its output open.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let backup = CommandTask::new("pg_dump")
+///     .with_args(&["--format=custom", "--file=/backups/shop.dump", "shop"])
+///     .with_env("PGHOST", "db.internal");
+/// commands.push_tracked(backup)?;
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandTask {
+    /// The program to run
+    pub program: String,
+    /// Arguments passed to the program
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment variables set for the program
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// How long the command may run before it's killed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+}
+
+impl CommandTask {
+    /// Create a command running `program` without arguments
+    pub fn new(program: &str) -> CommandTask {
+        CommandTask {
+            program: program.into(),
+            args: vec![],
+            env: BTreeMap::new(),
+            timeout: None,
+        }
+    }
+
+    /// Add an argument
+    pub fn with_arg(mut self, arg: &str) -> CommandTask {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add several arguments
+    pub fn with_args(mut self, args: &[&str]) -> CommandTask {
+        self.args.extend(args.iter().map(|&arg| arg.to_string()));
+        self
+    }
+
+    /// Set the environment variable `name` to `value`
+    pub fn with_env(mut self, name: &str, value: &str) -> CommandTask {
+        self.env.insert(name.into(), value.into());
+        self
+    }
+
+    /// Kill the command if it runs longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> CommandTask {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// How a command ended, stored as the result of its task.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandOutput {
+    /// The exit code, `None` if the command was killed by a signal
+    pub status: Option<i32>,
+    /// The signal which killed the command
+    pub signal: Option<i32>,
+    /// The end of the standard output, decoded lossily as UTF-8
+    pub stdout: String,
+    /// The end of the standard error, decoded lossily as UTF-8
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// Check if the command exited with status 0
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+/// Why a command failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    /// The command couldn't be started or waited for, e.g. because the program doesn't exist
+    Io(String),
+    /// The command exited with a non-zero status or was killed
+    Failed(CommandOutput),
+    /// The command ran longer than its timeout and was killed
+    TimedOut {
+        /// The timeout of the command
+        timeout: Duration,
+        /// The output up to the timeout
+        output: CommandOutput,
+    },
+    /// The command was killed because its task was cancelled
+    Cancelled,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Io(ref e) => write!(f, "Failed to run command: {}", e),
+            CommandError::Failed(ref output) => {
+                match (output.status, output.signal) {
+                    (Some(status), _) => write!(f, "Command exited with status {}", status)?,
+                    (None, Some(signal)) => write!(f, "Command was killed by signal {}", signal)?,
+                    (None, None) => write!(f, "Command failed")?,
+                }
+                match output.stderr.trim().lines().last() {
+                    Some(line) => write!(f, ": {}", line),
+                    None => Ok(()),
+                }
+            }
+            CommandError::TimedOut { timeout, .. } => {
+                write!(f, "Command timed out after {} ms", ::duration_millis(timeout))
+            }
+            CommandError::Cancelled => write!(f, "Command was cancelled"),
+        }
+    }
+}
+
+impl CommandError {
+    /// Get the output the command produced before it failed, if it ran at all
+    pub fn output(&self) -> Option<&CommandOutput> {
+        match *self {
+            CommandError::Failed(ref output) | CommandError::TimedOut { ref output, .. } => Some(output),
+            CommandError::Io(_) | CommandError::Cancelled => None,
+        }
+    }
+}
+
+/// Commands which can't be started are dead-lettered, all other failures are retried.
+impl Classify for CommandError {
+    fn classify(&self) -> TaskOutcome {
+        match *self {
+            CommandError::Io(_) => TaskOutcome::Fail,
+            CommandError::Failed(_) | CommandError::TimedOut { .. } | CommandError::Cancelled => TaskOutcome::Retry,
+        }
+    }
+}
+
+/// A failed command as reported by `Worker::run_commands`, together with its whole output.
+pub(crate) struct Failure(pub CommandError);
+
+impl Failure {
+    pub(crate) fn classify(&self) -> TaskOutcome {
+        self.0.classify()
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        if let Some(output) = self.0.output() {
+            for &(name, stream) in &[("stdout", &output.stdout), ("stderr", &output.stderr)] {
+                if !stream.is_empty() {
+                    write!(f, "\n--- {} ---\n{}", name, stream.trim_end())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the command of `task` to completion, capturing its output.
+///
+/// The command is killed once `token` is cancelled or its timeout passed.
+/// This is the handler used by `Worker::run_commands`, it can be called from other handlers
+/// as well.
+pub fn execute(task: CommandTask, token: CancellationToken) -> Result<CommandOutput, CommandError> {
+    let mut child = Command::new(&task.program)
+        .args(&task.args)
+        .envs(&task.env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| CommandError::Io(e.to_string()))?;
+
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+    let started = Instant::now();
+    let (status, timed_out) = wait(&mut child, &token, task.timeout.map(|timeout| started + timeout))?;
+
+    let output = CommandOutput {
+        status: status.code(),
+        signal: status.signal(),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+
+    if timed_out {
+        Err(CommandError::TimedOut {
+            timeout: task.timeout.unwrap_or_default(),
+            output: output,
+        })
+    } else if output.success() {
+        Ok(output)
+    } else {
+        Err(CommandError::Failed(output))
+    }
+}
+
+/// Wait for `child` to exit, killing its process group once `token` is cancelled or `deadline`
+/// passed.
+///
+/// Returns the exit status and whether the deadline passed.
+fn wait(child: &mut Child, token: &CancellationToken, deadline: Option<Instant>) -> Result<(ExitStatus, bool), CommandError> {
+    loop {
+        if token.is_cancelled() {
+            let _ = kill_group(child);
+            return Err(CommandError::Cancelled);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let status = kill_group(child).map_err(|e| CommandError::Io(e.to_string()))?;
+            return Ok((status, true));
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                signal_group(child);
+                return Ok((status, false));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => return Err(CommandError::Io(e.to_string())),
+        }
+    }
+}
+
+/// Kill the process group led by `child` and reap it.
+///
+/// Processes started by the command hold on to its output, so they have to go as well.
+fn kill_group(child: &mut Child) -> io::Result<ExitStatus> {
+    signal_group(child);
+    child.wait()
+}
+
+/// Kill all processes of the group led by `child`, which may have exited already.
+///
+/// The id of the group stays taken while any of its processes is alive, so it can't hit
+/// another group.
+fn signal_group(child: &Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+/// Read `stream` to its end in the background, keeping the last `OUTPUT_LIMIT` bytes.
+fn capture<R: Read + Send + 'static>(stream: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => return String::new(),
+        };
+        let mut kept = vec![];
+        let mut buf = [0u8; 8192];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    kept.extend_from_slice(&buf[..n]);
+                    if kept.len() > 2 * OUTPUT_LIMIT {
+                        let cut = kept.len() - OUTPUT_LIMIT;
+                        kept.drain(..cut);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        let cut = kept.len().saturating_sub(OUTPUT_LIMIT);
+        String::from_utf8_lossy(&kept[cut..]).into_owned()
+    })
+}
@@ -0,0 +1,137 @@
+//! Settings of a queue stored in Redis, applied by all of its workers.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use redis::{self, RedisResult};
+use functions;
+use Retention;
+
+/// Checks if another job may be fetched in the current second.
+///
+/// KEYS[1]: prefix of the per-second counters
+/// ARGV[1]: maximum number of jobs per second
+pub(crate) const RATE_ALLOWS: &'static str = r"
+local second = redis.call('TIME')[1]
+if tonumber(redis.call('GET', KEYS[1] .. ':' .. second) or '0') < tonumber(ARGV[1]) then
+  return 1
+end
+return 0
+";
+
+/// Counts a fetched job in the counter of the current second.
+///
+/// KEYS[1]: prefix of the per-second counters
+pub(crate) const RATE_COUNT: &'static str = r"
+local key = KEYS[1] .. ':' .. redis.call('TIME')[1]
+redis.call('INCR', key)
+redis.call('EXPIRE', key, 2)
+";
+
+/// Get the key the settings of `queue` are stored in.
+fn config_key(queue: &str) -> String {
+    format!("{}:config", queue)
+}
+
+/// Get the prefix of the per-second counters of `queue`.
+fn rate_key(queue: &str) -> String {
+    format!("{}:rate", queue)
+}
+
+/// Settings of a queue, changed at runtime without redeploying its workers.
+///
+/// The settings are stored in Redis, see `Queue::set_config`, and are picked up by every
+/// `Worker` of the queue within a second, see `Worker::config_reload`.
+/// Unset settings leave the behavior configured in code in place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Stop fetching jobs. Running jobs finish, new jobs wait in the queue.
+    pub paused: bool,
+    /// Maximum number of jobs fetched per second, by all workers of the queue together
+    ///
+    /// Workers check the limit before fetching, so it can be exceeded by one job per worker.
+    pub rate_limit: Option<u32>,
+    /// Maximum number of jobs processed at once, by all workers of the queue together
+    ///
+    /// Jobs are counted by their reservations, so this only applies to queues delivering
+    /// `AtLeastOnce`. Like the rate limit, it can be exceeded by one job per worker.
+    pub concurrency: Option<u32>,
+    /// Number of attempts of jobs without `JobOptions::max_attempts`
+    pub max_attempts: Option<u32>,
+    /// How long completed jobs are archived, replacing the one of `Queue::with_archive`
+    pub retention: Option<Retention>,
+}
+
+impl QueueConfig {
+    /// Encode the settings into the fields of the config hash.
+    fn fields(&self) -> Vec<(&'static str, u64)> {
+        let mut fields = vec![("paused", self.paused as u64)];
+        if let Some(rate_limit) = self.rate_limit {
+            fields.push(("rate_limit", rate_limit.into()));
+        }
+        if let Some(concurrency) = self.concurrency {
+            fields.push(("concurrency", concurrency.into()));
+        }
+        if let Some(max_attempts) = self.max_attempts {
+            fields.push(("max_attempts", max_attempts.into()));
+        }
+        if let Some(retention) = self.retention {
+            fields.push(("retention_max_age", ::duration_millis(retention.max_age)));
+            fields.push(("retention_max_jobs", retention.max_jobs as u64));
+        }
+        fields
+    }
+
+    /// Decode the settings from the fields of the config hash, skipping invalid ones.
+    fn from_fields(fields: &HashMap<String, String>) -> QueueConfig {
+        let number = |name: &str| fields.get(name).and_then(|value| value.parse::<u64>().ok());
+        QueueConfig {
+            paused: number("paused").is_some_and(|paused| paused != 0),
+            rate_limit: number("rate_limit").map(|limit| limit as u32),
+            concurrency: number("concurrency").map(|concurrency| concurrency as u32),
+            max_attempts: number("max_attempts").map(|max| max as u32),
+            retention: match (number("retention_max_age"), number("retention_max_jobs")) {
+                (Some(max_age), Some(max_jobs)) => Some(Retention {
+                    max_age: Duration::from_millis(max_age),
+                    max_jobs: max_jobs as usize,
+                }),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Get the settings of `queue`, the defaults if none are stored.
+pub(crate) fn load<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<QueueConfig> {
+    let fields: HashMap<String, String> = redis::cmd("HGETALL").arg(config_key(queue)).query(con)?;
+    Ok(QueueConfig::from_fields(&fields))
+}
+
+/// Replace the settings of `queue`.
+pub(crate) fn store<C: redis::ConnectionLike>(con: &C, queue: &str, config: &QueueConfig) -> RedisResult<()> {
+    let key = config_key(queue);
+    redis::pipe()
+        .atomic()
+        .cmd("DEL")
+        .arg(&key[..])
+        .ignore()
+        .cmd("HMSET")
+        .arg(&key[..])
+        .arg(config.fields())
+        .ignore()
+        .query(con)
+}
+
+/// Set the single setting `field` of `queue`, leaving the others in place.
+pub(crate) fn set<C: redis::ConnectionLike>(con: &C, queue: &str, field: &str, value: u64) -> RedisResult<()> {
+    redis::cmd("HSET").arg(config_key(queue)).arg(field).arg(value).query(con)
+}
+
+/// Check if a job of `queue` may be fetched without exceeding `limit` jobs per second.
+pub(crate) fn rate_allows<C: redis::ConnectionLike>(con: &C, queue: &str, limit: u32) -> RedisResult<bool> {
+    functions::script(RATE_ALLOWS).key(rate_key(queue)).arg(limit).invoke(con)
+}
+
+/// Count a job fetched from `queue` against its rate limit.
+pub(crate) fn count<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<()> {
+    functions::script(RATE_COUNT).key(rate_key(queue)).invoke(con)
+}
@@ -0,0 +1,112 @@
+//! Commands sent to running workers through Redis.
+
+use redis::{self, RedisResult};
+use serde_json;
+use registry::{self, WorkerInfo};
+
+/// How long a status report is kept, in seconds.
+const DUMP_TTL: usize = 60;
+
+/// How long commands are kept for a worker which doesn't pick them up, in seconds.
+const CONTROL_TTL: usize = 5 * 60;
+
+/// Get the key of the list of pending commands of worker `id`.
+fn control_key(id: &str) -> String {
+    format!("oppgave:worker:{}:control", id)
+}
+
+/// Get the key the status report of worker `id` is stored in.
+fn dump_key(id: &str) -> String {
+    format!("oppgave:worker:{}:dump", id)
+}
+
+/// A command for a running `Worker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    /// Stop fetching new tasks, but keep running
+    Quiet,
+    /// Finish the current task and exit
+    Terminate,
+    /// Report the current status, see `worker_dump`
+    Dump,
+}
+
+impl Control {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Control::Quiet => "quiet",
+            Control::Terminate => "terminate",
+            Control::Dump => "dump",
+        }
+    }
+
+    fn parse(command: &str) -> Option<Control> {
+        match command {
+            "quiet" => Some(Control::Quiet),
+            "terminate" => Some(Control::Terminate),
+            "dump" => Some(Control::Dump),
+            _ => None,
+        }
+    }
+}
+
+/// Send `command` to the worker `worker`, or all live workers if `None`
+///
+/// Workers pick up commands with their next heartbeat.
+/// Returns the number of workers the command was sent to.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+///
+/// // Drain the fleet before a deploy
+/// send_control(&client, Control::Quiet, None).unwrap();
+/// ```
+pub fn send_control(client: &redis::Client, command: Control, worker: Option<&str>) -> RedisResult<usize> {
+    let ids = match worker {
+        Some(id) => vec![id.to_string()],
+        None => registry::list_workers(client)?.into_iter().map(|worker| worker.id).collect(),
+    };
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut pipe = redis::pipe();
+    for id in &ids {
+        pipe.cmd("LPUSH").arg(control_key(id)).arg(command.as_str()).ignore();
+        pipe.cmd("EXPIRE").arg(control_key(id)).arg(CONTROL_TTL).ignore();
+    }
+    pipe.query::<()>(&client.get_connection()?)?;
+
+    Ok(ids.len())
+}
+
+/// Get the last status reported by worker `id` after a `Control::Dump`
+///
+/// Reports are kept for a minute.
+pub fn worker_dump(client: &redis::Client, id: &str) -> RedisResult<Option<WorkerInfo>> {
+    let record: Option<String> = redis::cmd("GET").arg(dump_key(id)).query(&client.get_connection()?)?;
+    Ok(record.and_then(|record| serde_json::from_str(&record).ok()))
+}
+
+/// Take all pending commands of worker `id`, oldest first.
+pub(crate) fn take<C: redis::ConnectionLike>(con: &C, id: &str) -> RedisResult<Vec<Control>> {
+    let (commands, _): (Vec<String>, ()) = redis::pipe()
+        .atomic()
+        .cmd("LRANGE")
+        .arg(control_key(id))
+        .arg(0)
+        .arg(-1)
+        .cmd("DEL")
+        .arg(control_key(id))
+        .query(con)?;
+
+    Ok(commands.iter().rev().filter_map(|command| Control::parse(command)).collect())
+}
+
+/// Store the status report of `worker`.
+pub(crate) fn dump<C: redis::ConnectionLike>(con: &C, worker: &WorkerInfo) -> RedisResult<()> {
+    let record = serde_json::to_string(worker).expect("Encoding a worker can't fail");
+    redis::cmd("SETEX").arg(dump_key(&worker.id)).arg(DUMP_TTL).arg(record).query(con)
+}
@@ -0,0 +1,284 @@
+//! Discovery of all queues stored in a Redis instance.
+
+use std::collections::BTreeMap;
+use redis::{self, Commands, RedisResult};
+use {manage, registry, shard, tenant};
+
+/// Prefix of all keys written by oppgave
+const PREFIX: &'static str = "oppgave:";
+
+/// Key spaces which don't belong to a single queue
+const SHARED: &'static [&'static str] = &[
+    "oppgave:job:",
+    "oppgave:batch:",
+    "oppgave:workflow:",
+    "oppgave:worker:",
+    "oppgave:lock:",
+    "oppgave:ordered:",
+    "oppgave:workers",
+];
+
+/// A queue found in Redis, together with the number of jobs in each state.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueInfo {
+    /// Name of the queue within its tenant
+    pub name: String,
+    /// The tenant the queue is scoped to, if any
+    pub tenant: Option<String>,
+    /// Number of jobs waiting in the queue, summed over all shards
+    pub pending: u64,
+    /// Number of jobs reserved by workers, summed over all backup queues
+    pub in_progress: u64,
+    /// Number of delayed jobs not yet promoted
+    pub delayed: u64,
+    /// Number of jobs in the dead letter queue
+    pub dead: u64,
+    /// Whether the queue is registered with `create_queue`
+    ///
+    /// Queues found only by their keys may be leftovers of queues no longer in use.
+    pub registered: bool,
+}
+
+/// The sizes of all queues of a tenant, summed up.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TenantInfo {
+    /// Id of the tenant
+    pub tenant: String,
+    /// Number of queues of the tenant
+    pub queues: usize,
+    /// Number of jobs waiting in the queues
+    pub pending: u64,
+    /// Number of jobs reserved by workers
+    pub in_progress: u64,
+    /// Number of delayed jobs not yet promoted
+    pub delayed: u64,
+    /// Number of jobs in the dead letter queues
+    pub dead: u64,
+}
+
+/// A snapshot of all queues and workers of a Redis instance.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GlobalStats {
+    /// All queues, sorted by name
+    pub queues: Vec<QueueInfo>,
+    /// Number of jobs waiting in all queues
+    pub pending: u64,
+    /// Number of jobs reserved by workers
+    pub in_progress: u64,
+    /// Number of delayed jobs not yet promoted
+    pub delayed: u64,
+    /// Number of jobs in all dead letter queues
+    pub dead: u64,
+    /// Number of live workers
+    pub workers: usize,
+    /// Number of workers currently processing a job
+    pub busy_workers: usize,
+    /// Number of workers which stopped fetching new jobs
+    pub quiet_workers: usize,
+}
+
+/// The role of a key within a queue.
+enum Role {
+    Pending,
+    Backup,
+    Delayed,
+    Dead,
+}
+
+/// Find the queue a key belongs to and its role, based on the name and type of the key.
+fn classify<'a>(key: &'a str, kind: &str) -> Option<(&'a str, Role)> {
+    match kind {
+        "zset" => {
+            if let Some(queue) = key.strip_suffix(":delayed") {
+                Some((queue, Role::Delayed))
+            } else if let Some(queue) = key.strip_suffix(":dead") {
+                Some((queue, Role::Dead))
+            } else {
+                None
+            }
+        }
+        "list" => {
+            if let Some(queue) = shard::logical_queue(key) {
+                return Some((queue, Role::Pending));
+            }
+
+            // Backup queues are named `<queue>:<pid>:<thread>`
+            let mut parts = key.rsplitn(3, ':');
+            let _thread = parts.next();
+            let pid = parts.next().unwrap_or("");
+            match parts.next() {
+                Some(queue) if queue.len() > PREFIX.len() && !pid.is_empty() &&
+                                pid.chars().all(|c| c.is_ascii_digit()) => Some((queue, Role::Backup)),
+                _ => Some((key, Role::Pending)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Find all queues in the Redis instance of `client`
+///
+/// Keys are found with `SCAN`, so this does not block the server, but queues created while
+/// scanning may be missed.
+/// Queues registered with `create_queue` are listed even while they have no jobs.
+/// Queues are sorted by name.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+///
+/// for queue in discover(&client).unwrap() {
+///     println!("{}: {} pending, {} dead", queue.name, queue.pending, queue.dead);
+/// }
+/// ```
+pub fn discover(client: &redis::Client) -> RedisResult<Vec<QueueInfo>> {
+    scan(client, &format!("{}*", PREFIX))
+}
+
+/// Find all queues of `tenant` in the Redis instance of `client`
+///
+/// See `discover`.
+pub fn discover_tenant(client: &redis::Client, tenant: &str) -> RedisResult<Vec<QueueInfo>> {
+    scan(client, &format!("{}{}*", PREFIX, tenant::tenant_queue(tenant, "")))
+}
+
+/// Sum up the sizes of all queues per tenant in the Redis instance of `client`
+///
+/// Queues not scoped to a tenant are left out.
+/// Tenants are sorted by id.
+pub fn tenants(client: &redis::Client) -> RedisResult<Vec<TenantInfo>> {
+    let mut tenants = BTreeMap::new();
+    for queue in discover(client)? {
+        if let Some(id) = queue.tenant {
+            let info = tenants.entry(id.clone()).or_insert_with(|| TenantInfo {
+                tenant: id,
+                ..TenantInfo::default()
+            });
+            info.queues += 1;
+            info.pending += queue.pending;
+            info.in_progress += queue.in_progress;
+            info.delayed += queue.delayed;
+            info.dead += queue.dead;
+        }
+    }
+
+    Ok(tenants.into_values().collect())
+}
+
+/// Sum up the lengths of the backup queues of all workers of `queue`.
+pub(crate) fn in_progress<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<u64> {
+    // Backup queues are named `<queue>:<pid>:<thread>`
+    let keys: Vec<String> = {
+        let iter = redis::cmd("SCAN").cursor_arg(0).arg("MATCH").arg(format!("{}:[0-9]*", queue)).iter(con)?;
+        iter.filter(|key: &String| {
+            let rest = &key[queue.len() + 1..];
+            rest.find(':').is_some_and(|end| rest[..end].chars().all(|c| c.is_ascii_digit()))
+        }).collect()
+    };
+    if keys.is_empty() {
+        return Ok(0);
+    }
+
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.cmd("TYPE").arg(&key[..]);
+    }
+    let kinds: Vec<String> = pipe.query(con)?;
+
+    let mut pipe = redis::pipe();
+    for (key, kind) in keys.iter().zip(kinds) {
+        if kind == "list" {
+            pipe.cmd("LLEN").arg(&key[..]);
+        }
+    }
+    let sizes: Vec<u64> = pipe.query(con)?;
+    Ok(sizes.into_iter().sum())
+}
+
+/// Get the sizes of all queues and the number of workers in the Redis instance of `client`
+///
+/// Combines `discover` and `list_workers`, e.g. for a health endpoint.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let stats = global_stats(&client)?;
+/// println!("{} pending, {} dead, {} workers", stats.pending, stats.dead, stats.workers);
+/// ```
+pub fn global_stats(client: &redis::Client) -> RedisResult<GlobalStats> {
+    let queues = discover(client)?;
+    let workers = registry::list_workers(client)?;
+
+    Ok(GlobalStats {
+        pending: queues.iter().map(|queue| queue.pending).sum(),
+        in_progress: queues.iter().map(|queue| queue.in_progress).sum(),
+        delayed: queues.iter().map(|queue| queue.delayed).sum(),
+        dead: queues.iter().map(|queue| queue.dead).sum(),
+        workers: workers.len(),
+        busy_workers: workers.iter().filter(|worker| !worker.current.is_empty()).count(),
+        quiet_workers: workers.iter().filter(|worker| worker.quiet).count(),
+        queues: queues,
+    })
+}
+
+/// Find all queues with keys matching `pattern`.
+fn scan(client: &redis::Client, pattern: &str) -> RedisResult<Vec<QueueInfo>> {
+    let con = client.get_connection()?;
+    let keys: Vec<String> = {
+        let iter = con.scan_match::<_, String>(pattern)?;
+        iter.filter(|key| !SHARED.iter().any(|shared| key.starts_with(shared)))
+            .collect()
+    };
+
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.cmd("TYPE").arg(&key[..]);
+    }
+    let kinds: Vec<String> = if keys.is_empty() { vec![] } else { pipe.query(&con)? };
+
+    let mut members = vec![];
+    let mut pipe = redis::pipe();
+    for (key, kind) in keys.iter().zip(kinds.iter()) {
+        if let Some((queue, role)) = classify(key, kind) {
+            match role {
+                Role::Pending | Role::Backup => pipe.cmd("LLEN").arg(&key[..]),
+                Role::Delayed | Role::Dead => pipe.cmd("ZCARD").arg(&key[..]),
+            };
+            members.push((queue, role));
+        }
+    }
+    let sizes: Vec<u64> = if members.is_empty() { vec![] } else { pipe.query(&con)? };
+
+    let mut queues = BTreeMap::new();
+    for ((queue, role), size) in members.into_iter().zip(sizes) {
+        let info = queues.entry(queue.to_string()).or_insert_with(|| info(queue));
+        match role {
+            Role::Pending => info.pending += size,
+            Role::Backup => info.in_progress += size,
+            Role::Delayed => info.delayed += size,
+            Role::Dead => info.dead += size,
+        }
+    }
+
+    let scope = pattern.trim_end_matches('*');
+    for name in manage::registered(&con)? {
+        let queue = format!("{}{}", PREFIX, name);
+        if queue.starts_with(scope) {
+            let empty = info(&queue);
+            queues.entry(queue).or_insert(empty).registered = true;
+        }
+    }
+
+    Ok(queues.into_values().collect())
+}
+
+/// Create the empty info of the queue with the full name `queue`.
+fn info(queue: &str) -> QueueInfo {
+    let (tenant, name) = tenant::split(&queue[PREFIX.len()..]);
+    QueueInfo {
+        name: name.into(),
+        tenant: tenant.map(|tenant| tenant.into()),
+        ..QueueInfo::default()
+    }
+}
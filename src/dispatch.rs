@@ -0,0 +1,105 @@
+//! Dispatch of tasks of several types sharing a queue to a handler per type.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use CancellationToken;
+
+type Handler = Arc<dyn Fn(Value, CancellationToken) -> Result<(), String> + Send + Sync>;
+
+/// Routes tasks to handlers by their `type` field.
+///
+/// Queues carrying several task types are best modelled with an internally tagged enum, which
+/// stores the name of the variant in the `type` field:
+///
+/// ```rust,ignore
+/// #[derive(Deserialize, Serialize)]
+/// #[serde(tag = "type")]
+/// enum Task {
+///     SendEmail(SendEmail),
+///     Transcode(Transcode),
+/// }
+///
+/// queue.push(Task::SendEmail(SendEmail { to: "jane@example.com".into() }))?;
+/// ```
+///
+/// Workers can decode the enum and `match` on it. A dispatcher instead maps every variant to a
+/// handler taking the variant's payload, so handlers can live in their own modules:
+///
+/// ```rust,ignore
+/// let dispatcher = Dispatcher::new()
+///     .on("SendEmail", |email: SendEmail, _token| send(email))
+///     .on("Transcode", |video: Transcode, token| transcode(video, token));
+///
+/// Worker::new(queue).run(move |task, token| dispatcher.handle(task, token));
+/// ```
+///
+/// Variants need to wrap a struct (or be a struct variant) for the payload to decode from the
+/// same object as the tag. Tasks of a type without a handler fail, unless a `fallback` is set.
+/// The `type` field also lets workers pick types with `Worker::only_kinds`.
+///
+/// Clones share their handlers.
+#[derive(Clone, Default)]
+pub struct Dispatcher {
+    handlers: HashMap<String, Handler>,
+    fallback: Option<Handler>,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher without any handlers
+    pub fn new() -> Dispatcher {
+        Dispatcher::default()
+    }
+
+    /// Handle tasks tagged with `kind` by decoding their payload as `T` and calling `handler`
+    pub fn on<T, F, E>(mut self, kind: &str, handler: F) -> Dispatcher
+    where
+        T: DeserializeOwned,
+        F: Fn(T, CancellationToken) -> Result<(), E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        let kind_name = kind.to_string();
+        self.handlers.insert(kind.into(), Arc::new(move |task, token| {
+            let task = serde_json::from_value(task).map_err(|e| format!("Invalid {} task: {}", kind_name, e))?;
+            handler(task, token).map_err(|e| e.to_string())
+        }));
+        self
+    }
+
+    /// Handle tasks of all types without a handler with `handler`, getting the task as it is
+    pub fn fallback<F, E>(mut self, handler: F) -> Dispatcher
+    where
+        F: Fn(Value, CancellationToken) -> Result<(), E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        self.fallback = Some(Arc::new(move |task, token| handler(task, token).map_err(|e| e.to_string())));
+        self
+    }
+
+    /// Get the types with a handler
+    pub fn kinds(&self) -> Vec<&str> {
+        let mut kinds: Vec<&str> = self.handlers.keys().map(|kind| &kind[..]).collect();
+        kinds.sort();
+        kinds
+    }
+
+    /// Run the handler for the type of `task`
+    ///
+    /// Meant to be called from the handler of a `Worker`.
+    pub fn handle(&self, task: Value, token: CancellationToken) -> Result<(), String> {
+        let handler = task.get("type")
+            .and_then(|kind| kind.as_str())
+            .and_then(|kind| self.handlers.get(kind))
+            .or(self.fallback.as_ref());
+
+        match handler {
+            Some(handler) => handler(task, token),
+            None => {
+                let kind = task.get("type").and_then(|kind| kind.as_str()).unwrap_or("<none>");
+                Err(format!("No handler for task type {}", kind))
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! The format jobs are stored in Redis.
+//!
+//! A job wraps the encoded task together with the metadata oppgave needs to process it.
+//! Entries which are not a job (e.g. pushed by other producers) are handed to the task decoder
+//! unchanged.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{self, value::RawValue};
+use redis::{Value, RedisResult, ErrorKind, Pipeline};
+use {batch, workflow, job, TaskDecodable};
+use job::JobStatus;
+use options::JobOptions;
+
+static JID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Generate a new job id.
+///
+/// Combines the current time, the PID and a per-process counter, which is unique enough to
+/// identify a job without an additional round trip to Redis.
+pub fn new_jid() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{:x}{:08x}{:x}{:x}",
+        now.as_secs(),
+        now.subsec_nanos(),
+        super::getpid(),
+        JID_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+/// Decode the task stored in `data`, whether it is wrapped in a job or not.
+pub fn decode<T: TaskDecodable>(data: Vec<u8>) -> RedisResult<T> {
+    match Envelope::parse(&data) {
+        Some(job) => T::decode_task(&job.task_value()),
+        None => T::decode_task(&Value::Data(data)),
+    }
+}
+
+/// Check if `data` is a job tagged with `tag`.
+pub fn has_tag(data: &[u8], tag: &str) -> bool {
+    Envelope::parse(data).is_some_and(|job| job.tags().iter().any(|t| t == tag))
+}
+
+/// Get the type of the task stored in `data`, see `JobOptions::kind`.
+pub fn kind(job: Option<&Envelope>, data: &[u8]) -> Option<String> {
+    if let Some(kind) = job.and_then(|job| job.options.as_ref()).and_then(|options| options.kind.clone()) {
+        return Some(kind);
+    }
+
+    let task = match job {
+        Some(job) => job.task.get().as_bytes(),
+        None => data,
+    };
+    let task: serde_json::Value = serde_json::from_slice(task).ok()?;
+    task.get("type").and_then(|kind| kind.as_str()).map(String::from)
+}
+
+/// A job as stored in Redis.
+#[derive(Serialize, Deserialize)]
+pub struct Envelope {
+    /// Unique id of this job.
+    pub jid: String,
+    /// The encoded task.
+    pub task: Box<RawValue>,
+    /// Time the job was pushed, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enqueued_at: Option<u64>,
+    /// Id of the job whose handler enqueued this job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Id of the batch this job belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    /// The workflow this job is a node of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workflow: Option<WorkflowNode>,
+    /// Job to enqueue once this one completed successfully.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub then: Option<Followup>,
+    /// Settings overriding the defaults of the queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<JobOptions>,
+    /// Version of the task type at the time the job was pushed, see `Migrations`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+}
+
+/// The position of a job within a workflow.
+#[derive(Serialize, Deserialize)]
+pub struct WorkflowNode {
+    /// Id of the workflow.
+    pub id: String,
+    /// Index of the node within the workflow.
+    pub node: usize,
+}
+
+/// A job waiting for its predecessor to complete.
+#[derive(Serialize, Deserialize)]
+pub struct Followup {
+    /// Full name of the queue the job is pushed to.
+    pub queue: String,
+    /// The job itself.
+    pub job: Box<Envelope>,
+}
+
+impl Envelope {
+    /// Wrap an encoded task into a new job.
+    ///
+    /// Fails if the task is not encoded as JSON.
+    pub fn new(task: Vec<u8>) -> RedisResult<Envelope> {
+        Envelope::wrap(task).map_err(|_| From::from((ErrorKind::TypeError, "Task is not JSON encoded")))
+    }
+
+    /// Wrap an encoded task into a new job.
+    ///
+    /// Hands back the task if it is not encoded as JSON.
+    pub fn wrap(task: Vec<u8>) -> Result<Envelope, Vec<u8>> {
+        let raw = match serde_json::from_slice::<Box<RawValue>>(&task) {
+            Ok(raw) => raw,
+            Err(_) => return Err(task),
+        };
+
+        Ok(Envelope {
+            jid: new_jid(),
+            task: raw,
+            enqueued_at: Some(::now_millis()),
+            parent: None,
+            batch: None,
+            workflow: None,
+            then: None,
+            options: None,
+            version: None,
+        })
+    }
+
+    /// Parse a job from the data stored in Redis.
+    ///
+    /// Returns `None` if the data is not a job, but a plain task.
+    pub fn parse(data: &[u8]) -> Option<Envelope> {
+        serde_json::from_slice(data).ok()
+    }
+
+    /// The tags the job was pushed with.
+    pub fn tags(&self) -> &[String] {
+        self.options.as_ref().map_or(&[], |options| &options.tags[..])
+    }
+
+    /// Encode the job for storing it in Redis.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Encoding a job can't fail")
+    }
+
+    /// The encoded task as a Redis value, ready to be decoded.
+    pub fn task_value(&self) -> Value {
+        Value::Data(self.task.get().as_bytes().to_vec())
+    }
+
+    /// Add the commands to run once the job finished to the pipeline.
+    ///
+    /// A failed job that may still be retried only finishes if it's dead by the time the
+    /// commands run. Pass `if_dead`, its dead letter queue and its id there, for such jobs.
+    pub fn finish(&self, pipe: &mut Pipeline, failed: bool, if_dead: Option<(&str, &str)>) {
+        if !failed {
+            if let Some(ref next) = self.then {
+                // Enqueue the next task of the chain
+                pipe.cmd("LPUSH").arg(&next.queue[..]).arg(next.job.encode()).ignore();
+            }
+        }
+
+        if let Some(ref bid) = self.batch {
+            batch::finish_member(pipe, bid, failed, if_dead);
+        }
+
+        if self.parent.is_some() {
+            match if_dead {
+                Some((dead, rid)) => job::fail_if_dead(pipe, &self.jid, dead, rid),
+                None => {
+                    let status = if failed { JobStatus::Failed } else { JobStatus::Completed };
+                    job::set_status(pipe, &self.jid, status);
+                }
+            }
+        }
+
+        if let Some(ref node) = self.workflow {
+            workflow::finish_node(pipe, &node.id, node.node, failed, if_dead);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Errors of pushing tasks.
+
+use std::{error, fmt};
+use redis::RedisError;
+
+/// The reason pushing a task failed.
+///
+/// Encoding errors are caused by the task itself and fail again on retry, while Redis errors
+/// may be temporary.
+#[derive(Debug)]
+pub enum PushError {
+    /// The task could not be encoded, e.g. a map with non-string keys
+    Encode(RedisError),
+    /// Writing the task to Redis failed
+    Redis(RedisError),
+    /// The queue holds its maximum number of tasks, see `Queue::with_max_size`
+    Full,
+    /// Another job with the same unique key is around, see `Unique`
+    Duplicate,
+}
+
+impl PushError {
+    /// Check if the task could not be encoded
+    pub fn is_encode(&self) -> bool {
+        match *self {
+            PushError::Encode(_) => true,
+            PushError::Redis(_) | PushError::Full | PushError::Duplicate => false,
+        }
+    }
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PushError::Encode(ref e) => write!(f, "Encoding the task failed: {}", e),
+            PushError::Redis(ref e) => write!(f, "Pushing the task failed: {}", e),
+            PushError::Full => write!(f, "The queue is full"),
+            PushError::Duplicate => write!(f, "A job with the same unique key exists"),
+        }
+    }
+}
+
+impl error::Error for PushError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PushError::Encode(ref e) | PushError::Redis(ref e) => Some(e),
+            PushError::Full | PushError::Duplicate => None,
+        }
+    }
+}
+
+impl From<RedisError> for PushError {
+    fn from(e: RedisError) -> PushError {
+        PushError::Redis(e)
+    }
+}
@@ -0,0 +1,152 @@
+//! Live events of running jobs, streamed over Redis pub/sub.
+
+use std::time::Duration;
+use redis::{self, ErrorKind, Pipeline, RedisError, RedisResult};
+use serde_json;
+use StalledJob;
+
+/// Something that happened while a job was processed.
+///
+/// Events are published on the channel `oppgave:job:<jid>:events` as JSON, tagged by the
+/// `event` field, so front-ends can subscribe to it without this crate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JobEvent {
+    /// The handler reported its progress, see `TaskGuard::progress`.
+    Progress {
+        /// Number of finished steps
+        current: u64,
+        /// Total number of steps
+        total: u64,
+        /// Description of the current step
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// The handler logged a line, see `TaskGuard::log`.
+    Log {
+        /// The logged text
+        line: String,
+    },
+    /// The job was moved back into the queue after stalling, see `Queue::reap_stuck`.
+    Stalled {
+        /// The worker which held the job
+        worker: String,
+        /// How long the worker held the job, in milliseconds
+        held_for: u64,
+    },
+    /// Processing of the job ended.
+    Finished {
+        /// Whether the job failed
+        failed: bool,
+    },
+}
+
+/// Get the pub/sub channel the events of `jid` are published on.
+pub(crate) fn channel(jid: &str) -> String {
+    format!("oppgave:job:{}:events", jid)
+}
+
+/// Get the pub/sub channel stalled jobs of `queue` are announced on.
+pub(crate) fn stalled_channel(queue: &str) -> String {
+    format!("{}:stalled", queue)
+}
+
+/// Add the commands announcing `job` on the channels of its queue and of the job to the pipeline.
+pub(crate) fn publish_stalled(pipe: &mut Pipeline, job: &StalledJob) {
+    let payload = serde_json::to_string(job).expect("Encoding a stalled job can't fail");
+    pipe.cmd("PUBLISH").arg(stalled_channel(&job.queue)).arg(payload).ignore();
+    publish(pipe, &job.jid, &JobEvent::Stalled {
+        worker: job.worker.clone(),
+        held_for: job.held_for,
+    });
+}
+
+/// Add the command publishing `event` of `jid` to the pipeline.
+pub(crate) fn publish(pipe: &mut Pipeline, jid: &str, event: &JobEvent) {
+    let payload = serde_json::to_string(event).expect("Encoding an event can't fail");
+    pipe.cmd("PUBLISH").arg(channel(jid)).arg(payload).ignore();
+}
+
+/// A subscription to the events of a job, see `Queue::subscribe`.
+///
+/// Iterating yields events as they are published, until the job finished.
+/// Pub/sub doesn't buffer, events published before subscribing are missed.
+pub struct JobEvents {
+    pubsub: redis::PubSub,
+    finished: bool,
+}
+
+impl JobEvents {
+    /// Subscribe to the events of `jid`.
+    pub(crate) fn subscribe(client: &redis::Client, jid: &str) -> RedisResult<JobEvents> {
+        let mut pubsub = client.get_pubsub()?;
+        pubsub.subscribe(channel(jid))?;
+        Ok(JobEvents {
+            pubsub: pubsub,
+            finished: false,
+        })
+    }
+
+    /// Wait at most `timeout` for each event, `None` to wait forever. Defaults to forever.
+    ///
+    /// Iterating returns an error once the timeout passes.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> RedisResult<()> {
+        self.pubsub.set_read_timeout(timeout)
+    }
+}
+
+impl Iterator for JobEvents {
+    type Item = RedisResult<JobEvent>;
+
+    fn next(&mut self) -> Option<RedisResult<JobEvent>> {
+        if self.finished {
+            return None;
+        }
+
+        let event = self.pubsub.get_message().and_then(|msg| {
+            serde_json::from_slice(msg.get_payload_bytes())
+                .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid job event")))
+        });
+        // Errors end the subscription as well, the connection can't be trusted anymore
+        self.finished = match event {
+            Ok(JobEvent::Finished { .. }) | Err(_) => true,
+            Ok(_) => false,
+        };
+        Some(event)
+    }
+}
+
+/// A subscription to the stalled jobs of a queue, see `Queue::subscribe_stalled`.
+///
+/// Iterating yields jobs as they are moved back into the queue, by any process.
+/// Pub/sub doesn't buffer, jobs reaped before subscribing are missed.
+pub struct StalledJobs {
+    pubsub: redis::PubSub,
+}
+
+impl StalledJobs {
+    /// Subscribe to the stalled jobs of `queue`.
+    pub(crate) fn subscribe(client: &redis::Client, queue: &str) -> RedisResult<StalledJobs> {
+        let mut pubsub = client.get_pubsub()?;
+        pubsub.subscribe(stalled_channel(queue))?;
+        Ok(StalledJobs { pubsub: pubsub })
+    }
+
+    /// Wait at most `timeout` for each job, `None` to wait forever. Defaults to forever.
+    ///
+    /// Iterating returns an error once the timeout passes.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> RedisResult<()> {
+        self.pubsub.set_read_timeout(timeout)
+    }
+}
+
+impl Iterator for StalledJobs {
+    type Item = RedisResult<StalledJob>;
+
+    fn next(&mut self) -> Option<RedisResult<StalledJob>> {
+        Some(self.pubsub.get_message().and_then(|msg| {
+            serde_json::from_slice(msg.get_payload_bytes())
+                .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid stalled job")))
+        }))
+    }
+}
@@ -0,0 +1,366 @@
+//! Structured records of failed and dead-lettered jobs.
+
+use std::collections::HashMap;
+use redis::{self, Pipeline, RedisResult};
+use functions;
+use envelope;
+use TaskDecodable;
+
+/// Moves dead jobs back into the queue, dropping their failure records.
+///
+/// KEYS[1]: the dead letter queue
+/// KEYS[2]: the queue
+/// ARGV[1]: the maximum number of jobs to move, oldest first
+/// ARGV[2]: optional id of the only job to move
+pub(crate) const RETRY: &'static str = r"
+local jids = {ARGV[2]}
+if not ARGV[2] then
+  jids = redis.call('ZRANGE', KEYS[1], 0, tonumber(ARGV[1]) - 1)
+end
+local count = 0
+for _, jid in ipairs(jids) do
+  if redis.call('ZREM', KEYS[1], jid) == 1 then
+    local key = KEYS[2] .. ':failure:' .. jid
+    local job = redis.call('HGET', key, 'job')
+    if job then
+      redis.call('LPUSH', KEYS[2], job)
+      count = count + 1
+    end
+    redis.call('DEL', key, key .. ':workers')
+  end
+end
+return count
+";
+
+/// Moves a job which failed on too many different workers to the dead letter queue.
+///
+/// KEYS[1]: the workers the job failed on
+/// KEYS[2]: the failure record
+/// KEYS[3]: the dead letter queue
+/// KEYS[4]: the backup queue
+/// ARGV[1]: the worker
+/// ARGV[2]: the number of workers after which the job is quarantined
+/// ARGV[3]: the current time in milliseconds
+/// ARGV[4]: id of the job
+/// ARGV[5]: the stored job
+pub(crate) const QUARANTINE: &'static str = r"
+redis.call('SADD', KEYS[1], ARGV[1])
+if redis.call('SCARD', KEYS[1]) < tonumber(ARGV[2]) then
+  return 0
+end
+redis.call('LREM', KEYS[4], -1, ARGV[5])
+redis.call('ZADD', KEYS[3], ARGV[3], ARGV[4])
+redis.call('HSET', KEYS[2], 'poison', 1)
+return 1
+";
+
+/// Get the key the failure record of `jid` in `queue` is stored in.
+pub(crate) fn failure_key(queue: &str, jid: &str) -> String {
+    format!("{}:failure:{}", queue, jid)
+}
+
+/// Get the key of the set of workers `jid` in `queue` failed on.
+fn workers_key(queue: &str, jid: &str) -> String {
+    format!("{}:failure:{}:workers", queue, jid)
+}
+
+/// Get the key of the dead letter queue of `queue`, scored by the time the job died.
+pub(crate) fn dead_key(queue: &str) -> String {
+    format!("{}:dead", queue)
+}
+
+/// A failure of a job, as reported by a worker.
+pub(crate) struct Failure<'a> {
+    pub jid: &'a str,
+    pub job: &'a [u8],
+    pub task: &'a [u8],
+    pub error: Option<&'a str>,
+    pub worker: &'a str,
+    pub at: u64,
+}
+
+/// A job that failed at least once, together with the details of its failures.
+///
+/// All times are in milliseconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedJob {
+    /// Id of the job
+    pub jid: String,
+    /// The encoded task
+    pub task: String,
+    /// The error of the last failure, if one was given
+    pub error: Option<String>,
+    /// Number of times the job failed
+    pub attempts: u64,
+    /// Time of the first failure
+    pub first_failed_at: u64,
+    /// Time of the last failure
+    pub last_failed_at: u64,
+    /// The worker the job failed on last
+    pub worker: String,
+    /// Whether the job was quarantined for failing on too many workers
+    pub poison: bool,
+}
+
+/// Hook called whenever a task fails, e.g. to report failures to an alerting system.
+///
+/// Set it with `Queue::with_failure_handler`. It's called after the failure was recorded, on
+/// the thread that finished the task, so it should not block for long.
+/// Closures taking the same arguments implement it as well.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let queue = Queue::new("emails".into(), client).with_failure_handler(
+///     |queue: &str, job: &FailedJob, dead: bool| {
+///         if dead {
+///             alert(format!("{} gave up on {}: {:?}", queue, job.jid, job.error));
+///         }
+///     },
+/// );
+/// ```
+pub trait FailureHandler: Send + Sync {
+    /// Handle the failure of `job` in `queue`
+    ///
+    /// The job holds the error and the number of attempts so far. `dead` tells if the job was
+    /// moved to the dead letter queue, instead of waiting for a retry.
+    fn on_failure(&self, queue: &str, job: &FailedJob, dead: bool);
+}
+
+impl<F: Fn(&str, &FailedJob, bool) + Send + Sync> FailureHandler for F {
+    fn on_failure(&self, queue: &str, job: &FailedJob, dead: bool) {
+        self(queue, job, dead)
+    }
+}
+
+/// A decoded job from the dead letter queue.
+#[derive(Clone, Debug)]
+pub struct DeadJob<T> {
+    /// The decoded task
+    pub task: T,
+    /// Time the job was moved to the dead letter queue, in milliseconds since the Unix epoch
+    pub died_at: u64,
+    /// Details of the failures of the job
+    pub failure: FailedJob,
+}
+
+/// Add the commands recording `failure` to the pipeline.
+///
+/// Failures of the same job are aggregated into one record.
+pub(crate) fn record(pipe: &mut Pipeline, queue: &str, failure: &Failure) {
+    let key = failure_key(queue, failure.jid);
+
+    pipe.cmd("HMSET")
+        .arg(&key[..])
+        .arg("job")
+        .arg(failure.job)
+        .arg("task")
+        .arg(failure.task)
+        .arg("error")
+        .arg(failure.error.unwrap_or(""))
+        .arg("last_failed_at")
+        .arg(failure.at)
+        .arg("worker")
+        .arg(failure.worker)
+        .ignore();
+    pipe.cmd("HSETNX").arg(&key[..]).arg("first_failed_at").arg(failure.at).ignore();
+    pipe.cmd("HINCRBY").arg(&key[..]).arg("attempts").arg(1).ignore();
+}
+
+/// Add the commands moving the failed job `jid` to the dead letter queue to the pipeline.
+pub(crate) fn bury(pipe: &mut Pipeline, queue: &str, jid: &str, at: u64) {
+    pipe.cmd("ZADD").arg(dead_key(queue)).arg(at).arg(jid).ignore();
+}
+
+/// Add the command quarantining the failed job `jid` to the pipeline.
+///
+/// Once the job failed on `threshold` different workers, it is moved from the backup queue
+/// to the dead letter queue and flagged as poison.
+pub(crate) fn quarantine(pipe: &mut Pipeline, queue: &str, backup: &str, failure: &Failure, threshold: usize) {
+    functions::eval(pipe, QUARANTINE, 4)
+        .arg(workers_key(queue, failure.jid))
+        .arg(failure_key(queue, failure.jid))
+        .arg(dead_key(queue))
+        .arg(backup)
+        .arg(failure.worker)
+        .arg(threshold)
+        .arg(failure.at)
+        .arg(failure.jid)
+        .arg(failure.job)
+        .ignore();
+}
+
+/// Add the command dropping the failure record of `jid` to the pipeline.
+pub(crate) fn clear(pipe: &mut Pipeline, queue: &str, jid: &str) {
+    pipe.cmd("DEL").arg(failure_key(queue, jid)).arg(workers_key(queue, jid)).ignore();
+}
+
+/// Build a failed job from the fields of its record.
+pub(crate) fn from_fields(jid: &str, mut fields: HashMap<String, String>) -> Option<FailedJob> {
+    let task = fields.remove("task")?;
+    let number = |fields: &HashMap<String, String>, name: &str| {
+        fields.get(name).and_then(|v| v.parse().ok()).unwrap_or(0)
+    };
+
+    Some(FailedJob {
+        jid: jid.into(),
+        error: fields.remove("error").and_then(|e| if e.is_empty() { None } else { Some(e) }),
+        attempts: number(&fields, "attempts"),
+        first_failed_at: number(&fields, "first_failed_at"),
+        last_failed_at: number(&fields, "last_failed_at"),
+        worker: fields.remove("worker").unwrap_or_default(),
+        poison: fields.get("poison").is_some_and(|poison| poison == "1"),
+        task: task,
+    })
+}
+
+/// Look up the failure record of `jid` in `queue`.
+pub(crate) fn find<C: redis::ConnectionLike>(con: &C, queue: &str, jid: &str) -> RedisResult<Option<FailedJob>> {
+    let fields: HashMap<String, String> = redis::cmd("HGETALL").arg(failure_key(queue, jid)).query(con)?;
+    Ok(from_fields(jid, fields))
+}
+
+/// Look up the failure record of `jid` in `queue`, together with whether the job is dead.
+pub(crate) fn find_with_state<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    jid: &str,
+) -> RedisResult<Option<(FailedJob, bool)>> {
+    let (fields, died_at): (HashMap<String, String>, Option<u64>) = redis::pipe()
+        .cmd("HGETALL")
+        .arg(failure_key(queue, jid))
+        .cmd("ZSCORE")
+        .arg(dead_key(queue))
+        .arg(jid)
+        .query(con)?;
+    Ok(from_fields(jid, fields).map(|failure| (failure, died_at.is_some())))
+}
+
+/// Move the dead job `jid` back into `queue`.
+///
+/// Returns `true` if the job was dead.
+pub(crate) fn retry<C: redis::ConnectionLike>(con: &C, queue: &str, jid: &str) -> RedisResult<bool> {
+    let count: usize = functions::script(RETRY)
+        .key(dead_key(queue))
+        .key(queue)
+        .arg(1)
+        .arg(jid)
+        .invoke(con)?;
+    Ok(count > 0)
+}
+
+/// Move up to `limit` dead jobs back into `queue`, oldest first.
+///
+/// Returns the number of moved jobs.
+pub(crate) fn retry_all<C: redis::ConnectionLike>(con: &C, queue: &str, limit: usize) -> RedisResult<usize> {
+    if limit == 0 {
+        return Ok(0);
+    }
+
+    functions::script(RETRY)
+        .key(dead_key(queue))
+        .key(queue)
+        .arg(limit)
+        .invoke(con)
+}
+
+/// List up to `count` dead jobs of `queue`, latest first.
+pub(crate) fn list_dead<C: redis::ConnectionLike, T: TaskDecodable>(
+    con: &C,
+    queue: &str,
+    offset: usize,
+    count: usize,
+) -> RedisResult<Vec<DeadJob<T>>> {
+    if count == 0 {
+        return Ok(vec![]);
+    }
+
+    let dead: Vec<(String, u64)> = redis::cmd("ZREVRANGE")
+        .arg(dead_key(queue))
+        .arg(offset)
+        .arg(offset + count - 1)
+        .arg("WITHSCORES")
+        .query(con)?;
+    load_dead(con, queue, dead)
+}
+
+/// Find up to `limit` dead jobs of `queue` whose stored job matches `filter`, latest first.
+///
+/// Returns the ids of the jobs together with the time they died.
+pub(crate) fn scan_dead<C, F>(con: &C, queue: &str, limit: usize, filter: F) -> RedisResult<Vec<(String, u64)>>
+where
+    C: redis::ConnectionLike,
+    F: Fn(&[u8]) -> bool,
+{
+    const CHUNK: usize = 500;
+    let mut found = vec![];
+    let mut offset = 0;
+
+    while found.len() < limit {
+        let dead: Vec<(String, u64)> = redis::cmd("ZREVRANGE")
+            .arg(dead_key(queue))
+            .arg(offset)
+            .arg(offset + CHUNK - 1)
+            .arg("WITHSCORES")
+            .query(con)?;
+        if dead.is_empty() {
+            break;
+        }
+        let done = dead.len() < CHUNK;
+
+        let mut pipe = redis::pipe();
+        for entry in &dead {
+            pipe.cmd("HGET").arg(failure_key(queue, &entry.0)).arg("job");
+        }
+        let jobs: Vec<Option<Vec<u8>>> = pipe.query(con)?;
+        for (entry, job) in dead.into_iter().zip(jobs) {
+            if found.len() < limit && job.is_some_and(|job| filter(&job)) {
+                found.push(entry);
+            }
+        }
+
+        if done {
+            break;
+        }
+        offset += CHUNK;
+    }
+
+    Ok(found)
+}
+
+/// Decode the given dead jobs of `queue` together with their failure records.
+pub(crate) fn load_dead<C: redis::ConnectionLike, T: TaskDecodable>(
+    con: &C,
+    queue: &str,
+    dead: Vec<(String, u64)>,
+) -> RedisResult<Vec<DeadJob<T>>> {
+    if dead.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for entry in &dead {
+        pipe.cmd("HGETALL").arg(failure_key(queue, &entry.0));
+    }
+    let records: Vec<HashMap<String, String>> = pipe.query(con)?;
+
+    let mut jobs = vec![];
+    for ((jid, died_at), mut fields) in dead.into_iter().zip(records) {
+        let data = match fields.remove("job") {
+            Some(job) => job.into_bytes(),
+            // The record is gone, e.g. while the job is retried
+            None => continue,
+        };
+        let task = envelope::decode(data)?;
+
+        if let Some(failure) = from_fields(&jid, fields) {
+            jobs.push(DeadJob {
+                task: task,
+                died_at: died_at,
+                failure: failure,
+            });
+        }
+    }
+
+    Ok(jobs)
+}
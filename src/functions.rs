@@ -0,0 +1,161 @@
+//! The Lua scripts of oppgave, run with `EVAL` or installed as Redis Functions.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use redis::{self, FromRedisValue, Pipeline, RedisResult, ToRedisArgs};
+
+/// Version of the installed library, bumped whenever a script changes.
+const VERSION: u32 = 8;
+
+/// Whether scripts are called as functions, see `install_functions`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// All scripts with the name of their function and whether they only read.
+const SCRIPTS: &'static [(&'static str, &'static str, bool)] = &[
+    ("archive", ::archive::ARCHIVE, false),
+    ("finish_member", ::batch::FINISH_MEMBER, false),
+    ("retry_dead", ::failure::RETRY, false),
+    ("quarantine", ::failure::QUARANTINE, false),
+    ("replace", ::inspect::REPLACE, false),
+    ("position", ::inspect::POSITION, true),
+    ("lock_release", ::lock::RELEASE, false),
+    ("lock_extend", ::lock::EXTEND, false),
+    ("retry_or_bury", ::options::RETRY_OR_BURY, false),
+    ("overlap", ::options::OVERLAP, false),
+    ("release_if_dead", ::options::RELEASE_IF_DEAD, false),
+    ("ordering_enqueue", ::ordering::ENQUEUE, false),
+    ("ordering_release", ::ordering::RELEASE, false),
+    ("reserve", ::processing::RESERVE, false),
+    ("reap", ::processing::REAP, false),
+    ("promote", ::promoter::PROMOTE, false),
+    ("register_recurring", ::recurring::REGISTER, false),
+    ("enqueue_due", ::recurring::ENQUEUE_DUE, false),
+    ("store_result", ::results::STORE, false),
+    ("record_throughput", ::throughput::RECORD, false),
+    ("rate", ::throughput::RATE, true),
+    ("finish_node", ::workflow::FINISH_NODE, false),
+    ("relay", ::outbox::RELAY, false),
+    ("rate_allows", ::config::RATE_ALLOWS, true),
+    ("rate_count", ::config::RATE_COUNT, false),
+    ("fail_if_dead", ::job::FAIL_IF_DEAD, false),
+];
+
+/// Get the name of the function running `code`, if functions are used.
+fn function(code: &str) -> Option<String> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    SCRIPTS.iter()
+        .find(|&&(_, script, _)| script == code)
+        .map(|&(name, _, _)| format!("oppgave_{}", name))
+}
+
+/// Build the source of the library holding all scripts.
+fn library() -> String {
+    let mut library = format!(
+        "#!lua name=oppgave\nredis.register_function{{function_name='oppgave_version', callback=function() return {} end, flags={{'no-writes'}}}}\n",
+        VERSION
+    );
+    for &(name, code, read_only) in SCRIPTS {
+        library.push_str(&format!(
+            "redis.register_function{{function_name='oppgave_{}', callback=function(KEYS, ARGV)\n{}\nend{}}}\n",
+            name,
+            code,
+            if read_only { ", flags={'no-writes'}" } else { "" }
+        ));
+    }
+    library
+}
+
+/// Install the scripts of oppgave as Redis Functions and call them with `FCALL` from now on
+///
+/// Requires Redis 7 or later. The functions form the library `oppgave`, which replaces any
+/// older version of it, so they show up by name in `FUNCTION LIST`, `SLOWLOG` and the like.
+///
+/// Functions are used by the whole process, so install them on every Redis server the process
+/// talks to, or switch back with `use_functions(false)`.
+/// Processes using an older version of oppgave keep working, they still run their scripts with
+/// `EVAL`.
+pub fn install_functions(client: &redis::Client) -> RedisResult<()> {
+    let con = client.get_connection()?;
+    redis::cmd("FUNCTION").arg("LOAD").arg("REPLACE").arg(library()).query::<String>(&con)?;
+    use_functions(true);
+    Ok(())
+}
+
+/// Get the version of the installed library of functions, `None` if it's not installed
+pub fn functions_version(client: &redis::Client) -> RedisResult<Option<u32>> {
+    let con = client.get_connection()?;
+    match redis::cmd("FCALL_RO").arg("oppgave_version").arg(0).query(&con) {
+        Ok(version) => Ok(Some(version)),
+        // Older servers and servers without the library reject the call alike
+        Err(_) => Ok(None),
+    }
+}
+
+/// Call scripts as functions installed with `install_functions`, or as plain `EVAL` scripts
+pub fn use_functions(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Add the command running `code` with `keys` keys to the pipeline, followed by its arguments.
+pub(crate) fn eval<'a>(pipe: &'a mut Pipeline, code: &str, keys: usize) -> &'a mut Pipeline {
+    match function(code) {
+        Some(name) => pipe.cmd("FCALL").arg(name).arg(keys),
+        None => pipe.cmd("EVAL").arg(code).arg(keys),
+    }
+}
+
+/// Prepare running `code` on a connection, see `Invocation`.
+pub(crate) fn script(code: &'static str) -> Invocation {
+    Invocation {
+        code: code,
+        keys: vec![],
+        args: vec![],
+    }
+}
+
+/// A script with its keys and arguments, run as function or with `EVALSHA`.
+pub(crate) struct Invocation {
+    code: &'static str,
+    keys: Vec<Vec<u8>>,
+    args: Vec<Vec<u8>>,
+}
+
+impl Invocation {
+    /// Add a key.
+    pub(crate) fn key<T: ToRedisArgs>(&mut self, key: T) -> &mut Invocation {
+        self.keys.extend(key.to_redis_args());
+        self
+    }
+
+    /// Add an argument.
+    pub(crate) fn arg<T: ToRedisArgs>(&mut self, arg: T) -> &mut Invocation {
+        self.args.extend(arg.to_redis_args());
+        self
+    }
+
+    /// Run the script.
+    pub(crate) fn invoke<T: FromRedisValue, C: redis::ConnectionLike>(&self, con: &C) -> RedisResult<T> {
+        if let Some(name) = function(self.code) {
+            let mut cmd = redis::cmd("FCALL");
+            cmd.arg(name).arg(self.keys.len());
+            for key in &self.keys {
+                cmd.arg(&key[..]);
+            }
+            for arg in &self.args {
+                cmd.arg(&arg[..]);
+            }
+            return cmd.query(con);
+        }
+
+        let script = redis::Script::new(self.code);
+        let mut invocation = script.prepare_invoke();
+        for key in &self.keys {
+            invocation.key(&key[..]);
+        }
+        for arg in &self.args {
+            invocation.arg(&arg[..]);
+        }
+        invocation.invoke(con)
+    }
+}
@@ -0,0 +1,131 @@
+//! Handles to follow a pushed job.
+
+use std::time::{Duration, Instant};
+use redis::{self, RedisResult};
+use job::JobStatus;
+use envelope::Envelope;
+use {failure, inspect, job, processing, results, JobResult, Queue, TaskDecodable};
+
+/// What `JobHandle::cancel` did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cancellation {
+    /// The job was still pending and is removed from the queue
+    Removed,
+    /// The job is running, its handler is asked to stop
+    ///
+    /// Handlers run by a `Worker` see the request through their `CancellationToken`, others
+    /// need to check `TaskGuard::is_cancel_requested`. The job ends like any other, e.g. as
+    /// failed if the handler returns an error.
+    Requested,
+    /// The job is neither pending nor running, e.g. because it finished already
+    NotFound,
+}
+
+/// A job pushed to a queue, see `Queue::push_tracked`.
+///
+/// The handle only holds the id of the job, so it can be dropped or recreated with
+/// `Queue::handle` at any time.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let job = queue.push_tracked(Export { org: 42 })?;
+/// if job.status()? == Some(JobStatus::Pending) {
+///     job.cancel()?;
+/// }
+/// ```
+#[derive(Clone)]
+pub struct JobHandle {
+    queue: Queue,
+    jid: String,
+}
+
+impl JobHandle {
+    /// Create a handle of the job `jid` of `queue`.
+    pub(crate) fn new(queue: Queue, jid: String) -> JobHandle {
+        JobHandle {
+            queue: queue,
+            jid: jid,
+        }
+    }
+
+    /// Get the id of the job
+    pub fn id(&self) -> &str {
+        &self.jid
+    }
+
+    /// Get the queue the job was pushed to
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    /// Get the processing state of the job
+    ///
+    /// Jobs waiting for a retry count as pending, dead and discarded jobs as failed.
+    /// Returns `None` if the job is unknown, e.g. because it's delayed or it finished on a
+    /// queue without `with_results`. Running jobs are only seen with `Delivery::AtLeastOnce`.
+    pub fn status(&self) -> RedisResult<Option<JobStatus>> {
+        let con = self.queue.read_connection()?;
+        if let Some(stored) = results::peek(&con, &self.jid)? {
+            return Ok(Some(stored.status()));
+        }
+        let failure = failure::find_with_state(&con, self.queue.queue(), &self.jid)?;
+        if failure.as_ref().is_some_and(|&(_, dead)| dead) {
+            return Ok(Some(JobStatus::Failed));
+        }
+        if processing::is_reserved(&con, self.queue.queue(), &self.jid)? {
+            return Ok(Some(JobStatus::Running));
+        }
+        if self.queue.position(&self.jid)?.is_some() || failure.is_some() {
+            return Ok(Some(JobStatus::Pending));
+        }
+        Ok(None)
+    }
+
+    /// Wait until the job finished and get its outcome, see `Queue::await_result`
+    pub fn await_result<R: TaskDecodable>(&self, timeout: Duration) -> RedisResult<JobResult<R>> {
+        let con = self.queue.connection()?;
+        let lmove = self.queue.detect(&con)?.has_lmove();
+        results::await_result(&con, &self.jid, Instant::now() + timeout, lmove)
+    }
+
+    /// Cancel the job
+    ///
+    /// A pending job is removed from the queue and counts as failed for its batch, workflow or
+    /// parent. A running job is asked to stop, which its
+    /// handler needs to honor. See `Cancellation`.
+    /// Running jobs are only seen with `Delivery::AtLeastOnce`, delayed jobs are not cancelled.
+    pub fn cancel(&self) -> RedisResult<Cancellation> {
+        let con = self.queue.connection()?;
+        for source in self.queue.sources() {
+            // Watching the list makes sure the job is only finished if it's removed
+            let removed = redis::transaction(&con, &[&source[..]], |pipe| {
+                let found = inspect::scan_list(&con, &source, self.queue.order, 1, |data| {
+                    Envelope::parse(data).is_some_and(|job| job.jid == self.jid)
+                })?;
+                let data = match found.into_iter().next() {
+                    Some(data) => data,
+                    None => return Ok(Some(false)),
+                };
+                pipe.cmd("LREM").arg(&source[..]).arg(1).arg(&data[..]).ignore();
+                if let Some(job) = Envelope::parse(&data) {
+                    job.finish(pipe, true, None);
+                    if let Some(unique) = job.options.as_ref().and_then(|options| options.unique.as_ref()) {
+                        unique.release(pipe, &job.jid, None);
+                    }
+                }
+                pipe.query::<Option<()>>(&con).map(|done| done.map(|_| true))
+            })?;
+            if removed {
+                return Ok(Cancellation::Removed);
+            }
+        }
+
+        // The job may have been fetched while searching the queue
+        if processing::is_reserved(&con, self.queue.queue(), &self.jid)? {
+            job::request_cancel(&con, &self.jid)?;
+            return Ok(Cancellation::Requested);
+        }
+        Ok(Cancellation::NotFound)
+    }
+}
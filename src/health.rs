@@ -0,0 +1,57 @@
+//! Health checks of the Redis server and the keys of a queue.
+
+use std::time::Instant;
+use redis::{self, RedisResult};
+
+/// The result of `Queue::health`, ready to be served from a health endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Health {
+    /// Whether Redis answered the `PING`
+    pub reachable: bool,
+    /// Round-trip time of the `PING` in milliseconds
+    pub latency_ms: Option<u64>,
+    /// Keys of the queue holding a different type than expected, with the type they hold
+    ///
+    /// Such keys break pushing or fetching, e.g. when another application uses the same name.
+    pub wrong_types: Vec<(String, String)>,
+    /// The error which made the check fail, if any
+    pub error: Option<String>,
+}
+
+impl Health {
+    /// Check if Redis is reachable and all keys have the expected type
+    pub fn is_healthy(&self) -> bool {
+        self.reachable && self.wrong_types.is_empty() && self.error.is_none()
+    }
+}
+
+/// Ping the server and compare the types of `keys` to the expected ones.
+///
+/// Keys which don't exist are fine.
+pub(crate) fn check(client: &redis::Client, keys: &[(String, &str)]) -> Health {
+    let mut health = Health::default();
+    if let Err(e) = probe(client, keys, &mut health) {
+        health.error = Some(e.to_string());
+    }
+    health
+}
+
+fn probe(client: &redis::Client, keys: &[(String, &str)], health: &mut Health) -> RedisResult<()> {
+    let con = client.get_connection()?;
+    let start = Instant::now();
+    redis::cmd("PING").query::<String>(&con)?;
+    health.latency_ms = Some(::duration_millis(start.elapsed()));
+    health.reachable = true;
+
+    let mut pipe = redis::pipe();
+    for (key, _) in keys {
+        pipe.cmd("TYPE").arg(&key[..]);
+    }
+    let kinds: Vec<String> = pipe.query(&con)?;
+    for (&(ref key, expected), kind) in keys.iter().zip(kinds) {
+        if kind != "none" && kind != expected {
+            health.wrong_types.push((key.clone(), kind));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Strategies for waiting while polling an empty queue.
+
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait after polling an empty queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleStrategy {
+    /// Poll again right away
+    Immediate,
+    /// Wait the same time after every empty poll
+    Fixed(Duration),
+    /// Start with `initial` and double the wait after every empty poll, up to `max`
+    Exponential {
+        /// Wait after the first empty poll
+        initial: Duration,
+        /// Longest wait
+        max: Duration,
+    },
+}
+
+/// Tracks empty polls and waits according to an `IdleStrategy`.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let mut idle = Idle::new(IdleStrategy::Exponential {
+///     initial: Duration::from_millis(10),
+///     max: Duration::from_secs(1),
+/// });
+///
+/// loop {
+///     match queue.try_next::<Job>() {
+///         Ok(Some(task)) => {
+///             idle.reset();
+///             // ...
+///         }
+///         _ => idle.idle(),
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Idle {
+    strategy: IdleStrategy,
+    misses: u32,
+}
+
+impl Idle {
+    /// Create a new tracker waiting according to `strategy`
+    pub fn new(strategy: IdleStrategy) -> Idle {
+        Idle {
+            strategy: strategy,
+            misses: 0,
+        }
+    }
+
+    /// Get the time the next call to `idle` waits
+    pub fn delay(&self) -> Duration {
+        match self.strategy {
+            IdleStrategy::Immediate => Duration::from_millis(0),
+            IdleStrategy::Fixed(delay) => delay,
+            IdleStrategy::Exponential { initial, max } => {
+                let factor = 1u32 << cmp::min(self.misses, 16);
+                cmp::min(initial * factor, max)
+            }
+        }
+    }
+
+    /// Record an empty poll and wait before polling again
+    pub fn idle(&mut self) {
+        let delay = self.delay();
+        self.misses = self.misses.saturating_add(1);
+        if delay > Duration::from_millis(0) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Record a successful poll, so the next empty poll starts over with the shortest wait
+    pub fn reset(&mut self) {
+        self.misses = 0;
+    }
+}
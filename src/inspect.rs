@@ -0,0 +1,111 @@
+//! Finding and editing stored jobs by their contents.
+
+use serde_json;
+use redis::{self, RedisResult};
+use functions;
+use envelope::Envelope;
+use Order;
+
+/// Replaces an entry of a list, if it is still there.
+///
+/// KEYS[1]: the list
+/// ARGV[1]: the current entry
+/// ARGV[2]: the new entry
+pub(crate) const REPLACE: &'static str = r"
+local entries = redis.call('LRANGE', KEYS[1], 0, -1)
+for i, entry in ipairs(entries) do
+  if entry == ARGV[1] then
+    redis.call('LSET', KEYS[1], i - 1, ARGV[2])
+    return 1
+  end
+end
+return 0
+";
+
+/// Finds the number of entries ahead of a job in a list.
+///
+/// KEYS[1]: the list
+/// ARGV[1]: start of the stored job, up to and including its id
+/// ARGV[2]: `1` if the list is consumed from the tail
+pub(crate) const POSITION: &'static str = r#"
+local entries = redis.call('LRANGE', KEYS[1], 0, -1)
+for i, entry in ipairs(entries) do
+  if string.sub(entry, 1, #ARGV[1]) == ARGV[1] then
+    if ARGV[2] == '1' then
+      return #entries - i
+    end
+    return i - 1
+  end
+end
+return -1
+"#;
+
+/// Number of entries fetched per round trip while scanning.
+const CHUNK: usize = 500;
+
+/// Find up to `limit` entries of the list `key` matching `filter`, next entry first.
+///
+/// The list is read in chunks, so entries consumed or pushed while scanning may be missed or
+/// seen twice.
+pub(crate) fn scan_list<C, F>(con: &C, key: &str, order: Order, limit: usize, filter: F) -> RedisResult<Vec<Vec<u8>>>
+where
+    C: redis::ConnectionLike,
+    F: Fn(&[u8]) -> bool,
+{
+    let mut found = vec![];
+    let mut offset = 0;
+
+    while found.len() < limit {
+        // Tasks are pushed to the head, so the next task is at the tail unless consumed newest-first
+        let (start, stop) = match order {
+            Order::Fifo => (-((offset + CHUNK) as isize), -(offset as isize) - 1),
+            Order::Lifo => (offset as isize, (offset + CHUNK) as isize - 1),
+        };
+        let mut chunk: Vec<Vec<u8>> = redis::cmd("LRANGE").arg(key).arg(start).arg(stop).query(con)?;
+        let done = chunk.len() < CHUNK;
+        if order == Order::Fifo {
+            chunk.reverse();
+        }
+
+        found.extend(chunk.into_iter().filter(|data| filter(data)).take(limit - found.len()));
+        if done {
+            break;
+        }
+        offset += CHUNK;
+    }
+
+    Ok(found)
+}
+
+/// Check if the task stored in `data` is JSON encoded and matches `predicate`.
+pub(crate) fn task_matches<F>(data: &[u8], predicate: &F) -> bool
+where
+    F: Fn(&serde_json::Value) -> bool,
+{
+    let task = match Envelope::parse(data) {
+        Some(job) => serde_json::from_str(job.task.get()),
+        None => serde_json::from_slice(data),
+    };
+    task.is_ok_and(|task| predicate(&task))
+}
+
+/// Replace the entry `old` of the list `key` with `new`.
+///
+/// Returns `false` if the entry is gone, e.g. because a worker fetched it.
+pub(crate) fn replace<C: redis::ConnectionLike>(con: &C, key: &str, old: &[u8], new: &[u8]) -> RedisResult<bool> {
+    functions::script(REPLACE).key(key).arg(old).arg(new).invoke(con)
+}
+
+/// Get the number of entries ahead of the job `jid` in the list `key`.
+///
+/// Returns `None` if the job is not in the list.
+pub(crate) fn position<C: redis::ConnectionLike>(con: &C, key: &str, order: Order, jid: &str) -> RedisResult<Option<usize>> {
+    // The id is the first field of every stored job
+    let start = format!("{{\"jid\":{}", serde_json::to_string(jid).expect("Encoding a string can't fail"));
+    let position: i64 = functions::script(POSITION)
+        .key(key)
+        .arg(start)
+        .arg(if order == Order::Fifo { 1 } else { 0 })
+        .invoke(con)?;
+    Ok(if position < 0 { None } else { Some(position as usize) })
+}
@@ -0,0 +1,128 @@
+//! Timing and error counts of the Redis commands run by oppgave.
+//!
+//! With the `metrics` feature, every command is recorded through the `metrics` facade:
+//!
+//! * `oppgave_redis_command_seconds`, a histogram of the latency per command
+//! * `oppgave_redis_errors_total`, a counter of failed commands per command
+//! * `oppgave_redis_retries_total`, a counter of retried commands per command
+//!
+//! Scripts run as Redis Functions are labelled with the name of their function, pipelines with
+//! `PIPELINE`. Blocking commands like `BRPOPLPUSH` mostly wait for tasks to arrive, so their
+//! latency is not recorded, only their errors. Without the feature, or without a recorder
+//! installed, nothing is recorded.
+
+use std::str;
+use std::time::{Duration, Instant};
+use redis::{self, RedisResult, Value};
+
+/// A connection recording the latency and errors of all commands sent over it.
+///
+/// Queues wrap all their connections, so Redis time can be told apart from the time spent in
+/// handlers. Applications can wrap their own connections the same way.
+pub struct Instrumented<C> {
+    inner: C,
+}
+
+impl<C: redis::ConnectionLike> Instrumented<C> {
+    /// Wrap the connection `inner`
+    pub fn new(inner: C) -> Instrumented<C> {
+        Instrumented { inner: inner }
+    }
+
+    /// Get the wrapped connection
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Unwrap the connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: redis::ConnectionLike> redis::ConnectionLike for Instrumented<C> {
+    fn req_packed_command(&self, cmd: &[u8]) -> RedisResult<Value> {
+        let start = Instant::now();
+        let result = self.inner.req_packed_command(cmd);
+        record(&label(cmd), start.elapsed(), result.is_err());
+        result
+    }
+
+    fn req_packed_commands(&self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let start = Instant::now();
+        let result = self.inner.req_packed_commands(cmd, offset, count);
+        record("PIPELINE", start.elapsed(), result.is_err());
+        result
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+/// Split the first `count` arguments off a command packed in the Redis protocol.
+fn arguments(packed: &[u8], count: usize) -> Vec<&[u8]> {
+    let mut args = vec![];
+    // Every argument is sent as `$<length>\r\n<data>\r\n`, after the header `*<count>\r\n`
+    let mut rest = match packed.iter().position(|&b| b == b'\n') {
+        Some(end) => &packed[end + 1..],
+        None => return args,
+    };
+    while args.len() < count && rest.first() == Some(&b'$') {
+        let end = match rest.iter().position(|&b| b == b'\r') {
+            Some(end) => end,
+            None => break,
+        };
+        let len = match str::from_utf8(&rest[1..end]).ok().and_then(|len| len.parse::<usize>().ok()) {
+            Some(len) => len,
+            None => break,
+        };
+        let start = end + 2;
+        if rest.len() < start + len + 2 {
+            break;
+        }
+        args.push(&rest[start..start + len]);
+        rest = &rest[start + len + 2..];
+    }
+    args
+}
+
+/// Get the label of the packed command `cmd`, its name or the name of the called function.
+fn label(cmd: &[u8]) -> String {
+    let args = arguments(cmd, 2);
+    let name = args.first().map_or_else(String::new, |name| String::from_utf8_lossy(name).to_uppercase());
+    match (&name[..], args.get(1)) {
+        ("FCALL", Some(function)) | ("FCALL_RO", Some(function)) => String::from_utf8_lossy(function).into_owned(),
+        _ => name,
+    }
+}
+
+/// Commands waiting on the server until data arrives or they time out.
+#[cfg(feature = "metrics")]
+const BLOCKING: [&'static str; 8] = ["BLPOP", "BRPOP", "BRPOPLPUSH", "BLMOVE", "BLMPOP", "BZPOPMIN", "BZPOPMAX", "BZMPOP"];
+
+/// Record a command which took `elapsed`.
+#[cfg(feature = "metrics")]
+fn record(command: &str, elapsed: Duration, failed: bool) {
+    if !BLOCKING.contains(&command) {
+        ::metrics::histogram!("oppgave_redis_command_seconds", "command" => command.to_string())
+            .record(elapsed.as_secs_f64());
+    }
+    if failed {
+        ::metrics::counter!("oppgave_redis_errors_total", "command" => command.to_string()).increment(1);
+    }
+}
+
+/// Record a command which took `elapsed`.
+#[cfg(not(feature = "metrics"))]
+fn record(_command: &str, _elapsed: Duration, _failed: bool) {}
+
+/// Record a retry of the command `command`.
+#[cfg(feature = "metrics")]
+pub(crate) fn record_retry(command: &str) {
+    ::metrics::counter!("oppgave_redis_retries_total", "command" => command.to_string()).increment(1);
+}
+
+/// Record a retry of the command `command`.
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_retry(_command: &str) {}
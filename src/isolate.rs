@@ -0,0 +1,164 @@
+//! Running handlers in child processes of their own, see `Worker::isolated`.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+use libc;
+use serde::Serialize;
+use serde_json::{self, value::RawValue};
+use {CancellationToken, TaskOutcome};
+
+/// How the handler in the child process ended, sent to the parent through a pipe.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Report {
+    /// The handler succeeded with this encoded result
+    Completed(Box<RawValue>),
+    /// The handler succeeded, but its result couldn't be encoded
+    InvalidResult(String),
+    /// The handler failed
+    Failed {
+        /// What happens to the task, as displayed by `TaskOutcome`
+        outcome: String,
+        /// The error
+        message: String,
+    },
+}
+
+impl Report {
+    /// A failure to retry, e.g. a crash of the child process.
+    fn retry(message: String) -> Report {
+        Report::Failed {
+            outcome: TaskOutcome::Retry.to_string(),
+            message: message,
+        }
+    }
+}
+
+/// Get the outcome displayed as `name`.
+pub(crate) fn outcome(name: &str) -> TaskOutcome {
+    match name {
+        "fail" => TaskOutcome::Fail,
+        "discard" => TaskOutcome::Discard,
+        _ => TaskOutcome::Retry,
+    }
+}
+
+/// Run `handler` for `task` in a forked child process and report how it ended.
+///
+/// The child is terminated with `SIGTERM` once `token` is cancelled and killed once `timeout`
+/// passed. A crash of the child only fails the task.
+pub(crate) fn run<T, F, R, E>(
+    handler: &F,
+    classify: fn(&E) -> TaskOutcome,
+    task: T,
+    token: &CancellationToken,
+    timeout: Option<Duration>,
+) -> Report
+where
+    F: Fn(T, CancellationToken) -> Result<R, E>,
+    R: Serialize,
+    E: fmt::Display,
+{
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Report::retry(format!("Can't create pipe: {}", io::Error::last_os_error()));
+    }
+    // Programs the handler runs must not keep the pipe open
+    for &fd in &fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Report::retry(format!("Can't fork: {}", io::Error::last_os_error())),
+        0 => {
+            drop(read);
+            child(handler, classify, task, token.clone(), write)
+        }
+        pid => {
+            drop(write);
+            parent(pid, read, token, timeout)
+        }
+    }
+}
+
+/// Run the handler and send its report to the parent, then exit without running destructors.
+fn child<T, F, R, E>(handler: &F, classify: fn(&E) -> TaskOutcome, task: T, token: CancellationToken, mut pipe: File) -> !
+where
+    F: Fn(T, CancellationToken) -> Result<R, E>,
+    R: Serialize,
+    E: fmt::Display,
+{
+    let report = match panic::catch_unwind(AssertUnwindSafe(|| handler(task, token))) {
+        Ok(Ok(result)) => match serde_json::value::to_raw_value(&result) {
+            Ok(result) => Report::Completed(result),
+            Err(e) => Report::InvalidResult(e.to_string()),
+        },
+        Ok(Err(e)) => Report::Failed {
+            outcome: classify(&e).to_string(),
+            message: e.to_string(),
+        },
+        Err(_) => unsafe { libc::_exit(101) },
+    };
+
+    let status = if serde_json::to_writer(&mut pipe, &report).is_ok() { 0 } else { 1 };
+    unsafe { libc::_exit(status) }
+}
+
+/// Wait for the child `pid` to exit and read its report from `pipe`.
+fn parent(pid: libc::pid_t, mut pipe: File, token: &CancellationToken, timeout: Option<Duration>) -> Report {
+    let reader = thread::spawn(move || {
+        let mut report = vec![];
+        let _ = pipe.read_to_end(&mut report);
+        report
+    });
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut terminated = false;
+    let mut timed_out = false;
+    let mut status = 0;
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+            0 => {}
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Report::retry(format!("Can't wait for child process: {}", io::Error::last_os_error())),
+            _ => break,
+        }
+        if token.is_cancelled() && !terminated {
+            unsafe {
+                libc::kill(pid, libc::SIGTERM);
+            }
+            terminated = true;
+        }
+        if !timed_out && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+            timed_out = true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let report = reader.join().unwrap_or_default();
+    if timed_out {
+        let timeout = timeout.unwrap_or_default();
+        return Report::retry(format!("Timed out after {} ms", ::duration_millis(timeout)));
+    }
+    if let Ok(report) = serde_json::from_slice(&report) {
+        return report;
+    }
+
+    if libc::WIFSIGNALED(status) {
+        Report::retry(format!("Child process was killed by signal {}", libc::WTERMSIG(status)))
+    } else if libc::WEXITSTATUS(status) == 101 {
+        Report::retry("Handler panicked".into())
+    } else {
+        Report::retry(format!("Child process exited with status {} without a result", libc::WEXITSTATUS(status)))
+    }
+}
@@ -0,0 +1,203 @@
+//! State of individual jobs, stored in Redis next to the queues.
+
+use redis::{self, Pipeline, RedisResult};
+use serde_json;
+use functions;
+
+/// Marks a job as failed once it's dead.
+///
+/// KEYS[1]: the state of the job
+/// KEYS[2]: the dead letter queue
+/// ARGV[1]: id of the job in the dead letter queue
+pub(crate) const FAIL_IF_DEAD: &'static str = r"
+if redis.call('ZSCORE', KEYS[2], ARGV[1]) then
+  redis.call('HSET', KEYS[1], 'status', 'failed')
+end
+";
+
+/// How long the state of a job is kept in Redis, in seconds.
+const JOB_TTL: usize = 7 * 24 * 60 * 60;
+
+/// Maximum number of log lines kept per job, older lines are dropped.
+const LOG_CAP: isize = 1000;
+
+/// Get the key the state of a job is stored in.
+pub(crate) fn job_key(jid: &str) -> String {
+    format!("oppgave:job:{}", jid)
+}
+
+/// Get the key the children of a job are listed in.
+fn children_key(jid: &str) -> String {
+    format!("oppgave:job:{}:children", jid)
+}
+
+/// Get the key the log lines of a job are stored in.
+fn log_key(jid: &str) -> String {
+    format!("oppgave:job:{}:log", jid)
+}
+
+/// Get the key flagging a job to be cancelled.
+fn cancel_key(jid: &str) -> String {
+    format!("oppgave:job:{}:cancel", jid)
+}
+
+/// The processing state of a job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    /// The job waits in the queue.
+    Pending,
+    /// A worker is processing the job.
+    Running,
+    /// The job completed successfully.
+    Completed,
+    /// The job failed.
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Option<JobStatus> {
+        match s {
+            "pending" => Some(JobStatus::Pending),
+            "running" => Some(JobStatus::Running),
+            "completed" => Some(JobStatus::Completed),
+            "failed" => Some(JobStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A job enqueued by the handler of another job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Child {
+    /// Id of the job
+    pub jid: String,
+    /// Full name of the queue the job was pushed to
+    pub queue: String,
+    /// Current state of the job, `None` if it is not known (anymore)
+    pub status: Option<JobStatus>,
+}
+
+/// A line logged by the handler of a job, see `TaskGuard::log`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    /// Time the line was logged, in milliseconds since the Unix epoch
+    pub at: u64,
+    /// The logged text
+    pub line: String,
+}
+
+/// Add the commands recording `jid` as a child of `parent` to the pipeline.
+pub(crate) fn track_child(pipe: &mut Pipeline, parent: &str, jid: &str, queue: &str) {
+    let children = children_key(parent);
+    pipe.cmd("RPUSH").arg(&children[..]).arg(jid).ignore();
+    pipe.cmd("EXPIRE").arg(&children[..]).arg(JOB_TTL).ignore();
+
+    let key = job_key(jid);
+    pipe.cmd("HMSET")
+        .arg(&key[..])
+        .arg("queue")
+        .arg(queue)
+        .arg("status")
+        .arg(JobStatus::Pending.as_str())
+        .ignore();
+    pipe.cmd("EXPIRE").arg(&key[..]).arg(JOB_TTL).ignore();
+}
+
+/// Add the command updating the status of `jid` to the pipeline.
+pub(crate) fn set_status(pipe: &mut Pipeline, jid: &str, status: JobStatus) {
+    pipe.cmd("HSET")
+        .arg(job_key(jid))
+        .arg("status")
+        .arg(status.as_str())
+        .ignore();
+}
+
+/// Add the command marking `jid` as failed if `rid` is in the dead letter queue `dead` to the
+/// pipeline.
+pub(crate) fn fail_if_dead(pipe: &mut Pipeline, jid: &str, dead: &str, rid: &str) {
+    functions::eval(pipe, FAIL_IF_DEAD, 2).arg(job_key(jid)).arg(dead).arg(rid).ignore();
+}
+
+/// List all children of the job `jid` in the order they were enqueued.
+pub(crate) fn children<C: redis::ConnectionLike>(con: &C, jid: &str) -> RedisResult<Vec<Child>> {
+    let jids: Vec<String> = redis::cmd("LRANGE")
+        .arg(children_key(jid))
+        .arg(0)
+        .arg(-1)
+        .query(con)?;
+    if jids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for jid in &jids {
+        pipe.cmd("HMGET").arg(job_key(jid)).arg("queue").arg("status");
+    }
+    let states: Vec<(Option<String>, Option<String>)> = pipe.query(con)?;
+
+    Ok(
+        jids.into_iter()
+            .zip(states)
+            .map(|(jid, (queue, status))| {
+                Child {
+                    jid: jid,
+                    queue: queue.unwrap_or_default(),
+                    status: status.as_ref().and_then(|s| JobStatus::parse(s)),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Append `line` to the log of `jid`, keeping only the latest lines.
+pub(crate) fn append_log<C: redis::ConnectionLike>(con: &C, jid: &str, line: &LogLine) -> RedisResult<()> {
+    let key = log_key(jid);
+    let record = serde_json::to_string(line).expect("Encoding a log line can't fail");
+
+    redis::pipe()
+        .atomic()
+        .cmd("RPUSH")
+        .arg(&key[..])
+        .arg(record)
+        .ignore()
+        .cmd("LTRIM")
+        .arg(&key[..])
+        .arg(-LOG_CAP)
+        .arg(-1)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&key[..])
+        .arg(JOB_TTL)
+        .ignore()
+        .query(con)
+}
+
+/// Get the log of `jid`, oldest line first.
+pub(crate) fn log<C: redis::ConnectionLike>(con: &C, jid: &str) -> RedisResult<Vec<LogLine>> {
+    let records: Vec<String> = redis::cmd("LRANGE").arg(log_key(jid)).arg(0).arg(-1).query(con)?;
+    Ok(records.iter().filter_map(|record| serde_json::from_str(record).ok()).collect())
+}
+
+/// Ask the handler of `jid` to stop, see `JobHandle::cancel`.
+pub(crate) fn request_cancel<C: redis::ConnectionLike>(con: &C, jid: &str) -> RedisResult<()> {
+    redis::cmd("SET").arg(cancel_key(jid)).arg(1).arg("EX").arg(JOB_TTL).query(con)
+}
+
+/// Check if the handler of `jid` was asked to stop.
+pub(crate) fn is_cancel_requested<C: redis::ConnectionLike>(con: &C, jid: &str) -> RedisResult<bool> {
+    redis::cmd("EXISTS").arg(cancel_key(jid)).query(con)
+}
+
+/// Add the command dropping the cancellation request of `jid` to the pipeline.
+pub(crate) fn clear_cancel(pipe: &mut Pipeline, jid: &str) {
+    pipe.cmd("DEL").arg(cancel_key(jid)).ignore();
+}
@@ -11,6 +11,8 @@
 //! by moving acquired tasks to a backup queue.
 //! If a task finished it is removed from this backup queue.
 //! If a task fails it remains in the backup queue for human processing later on.
+//! Tasks given up on are moved to a dead letter queue instead.
+//! Every failure is recorded with its error, the number of attempts and the worker.
 //!
 //! See [`Queue`](struct.Queue.html) for a detailed documentation how to use this.
 //!
@@ -46,29 +48,193 @@
 
 #![deny(missing_docs)]
 
-#[cfg(test)]
 #[macro_use]
 extern crate serde_derive;
 
 extern crate serde;
 extern crate serde_json;
+#[cfg(feature = "sentry")]
+extern crate sentry_core;
+#[cfg(feature = "webhook")]
+extern crate ureq;
+#[cfg(feature = "metrics")]
+extern crate metrics;
 extern crate redis;
 extern crate libc;
 
-use std::{str, thread};
-use std::cell::Cell;
-use std::ops::{Deref, Drop};
+use std::{cmp, fmt, io, str, thread};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::ops::{Deref, DerefMut, Drop};
 use std::convert::From;
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use redis::{Value, RedisResult, ErrorKind, Commands};
 
+mod envelope;
+mod chain;
+mod batch;
+mod workflow;
+mod job;
+mod router;
+mod promoter;
+mod recurring;
+mod archive;
+mod failure;
+mod discover;
+mod tenant;
+mod shard;
+mod worker;
+mod checkpoint;
+mod cancel;
+mod registry;
+mod control;
+mod processing;
+mod breaker;
+mod options;
+mod inspect;
+mod throughput;
+mod transfer;
+mod memory;
+mod error;
+mod idle;
+mod ordering;
+mod limits;
+mod migrate;
+mod dispatch;
+mod budget;
+mod outcome;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod monitor;
+mod health;
+mod probe;
+mod events;
+mod results;
+mod handle;
+mod functions;
+mod capabilities;
+mod instrument;
+mod transient;
+mod outbox;
+mod priority;
+mod manage;
+mod config;
+mod scaling;
+mod chunk;
+mod command;
+mod isolate;
+mod pressure;
+pub mod lock;
+
+pub use chain::Chain;
+pub use batch::{Batch, BatchStatus};
+pub use workflow::{Workflow, Node, WorkflowStatus};
+pub use job::{JobStatus, Child, LogLine};
+pub use router::{Route, Router};
+pub use promoter::Promoter;
+pub use recurring::RecurringJob;
+pub use archive::{Retention, ArchivedJob};
+pub use failure::{FailedJob, DeadJob, FailureHandler};
+pub use discover::{discover, discover_tenant, tenants, global_stats, QueueInfo, TenantInfo, GlobalStats};
+pub use tenant::tenant_queue;
+pub use worker::Worker;
+pub use cancel::CancellationToken;
+pub use registry::{list_workers, WorkerInfo};
+pub use control::{send_control, worker_dump, Control};
+pub use processing::{Reservation, StalledJob, StallHandler};
+pub use breaker::{CircuitBreaker, BreakerState};
+pub use options::{JobOptions, Singleton, Overlap, Deadline, Expiry, Unique, Uniqueness};
+pub use memory::MemoryUsage;
+pub use error::PushError;
+pub use idle::{Idle, IdleStrategy};
+pub use limits::ConcurrencyLimits;
+pub use migrate::Migrations;
+pub use dispatch::Dispatcher;
+pub use budget::RetryBudget;
+pub use outcome::{Classify, TaskOutcome};
+#[cfg(feature = "sentry")]
+pub use sentry::SentryReporter;
+pub use monitor::{Monitor, Alert};
+pub use health::Health;
+pub use probe::{WorkerProbe, WorkerState};
+pub use events::{JobEvent, JobEvents, StalledJobs};
+pub use results::JobResult;
+pub use handle::{JobHandle, Cancellation};
+pub use functions::{install_functions, functions_version, use_functions};
+pub use capabilities::Capabilities;
+pub use instrument::Instrumented;
+pub use outbox::{Outbox, OutboxEntry, Relay};
+pub use priority::{PriorityQueue, Lane};
+pub use manage::{create_queue, delete_queue, list_queues, queue_meta, QueueMeta};
+pub use config::QueueConfig;
+pub use scaling::ScalingHint;
+pub use chunk::{ChunkTask, ReduceTask};
+pub use command::{CommandTask, CommandOutput, CommandError, execute};
+pub use pressure::{ResourceGate, ResourceProbe, ResourceUsage, SystemProbe};
+use envelope::Envelope;
+use worker::KindFilter;
+
+/// A connection of a queue, recording the commands sent over it.
+type Connection = Instrumented<redis::Connection>;
+
 /// Return the PID of the calling process.
 /// TODO: Does this work on Windows?
 fn getpid() -> i32 {
     unsafe { libc::getpid() as i32 }
 }
 
+/// Return the name of the host the process runs on.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "localhost".into();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Return the name of the current thread, as used in backup queues and worker ids.
+///
+/// Colons separate the parts of both, so they're replaced, e.g. in `module::test`.
+fn thread_name() -> String {
+    thread::current().name().unwrap_or("default").replace(':', "_")
+}
+
+/// Check if a push failed for a transient reason and may be retried.
+fn is_transient_push(e: &PushError) -> bool {
+    match *e {
+        PushError::Redis(ref e) => transient::is_transient(e),
+        PushError::Encode(_) | PushError::Full | PushError::Duplicate => false,
+    }
+}
+
+/// Return the whole milliseconds of the given duration.
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+/// Return the milliseconds passed since the Unix epoch for the given time.
+fn unix_millis(time: SystemTime) -> u64 {
+    duration_millis(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+/// Return the current time in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    unix_millis(SystemTime::now())
+}
+
+/// Return the current time of the Redis server in milliseconds since the Unix epoch.
+///
+/// Scheduling decisions rely on the server time, so clock skew between hosts doesn't matter.
+fn server_millis<C: redis::ConnectionLike>(con: &C) -> RedisResult<u64> {
+    let (secs, micros): (u64, u64) = redis::cmd("TIME").query(con)?;
+    Ok(secs * 1000 + micros / 1000)
+}
+
 /// Task objects that can be reconstructed from the data stored in Redis
 ///
 /// Implemented for all `Deserialize` objects by default by relying on JSON encoding.
@@ -86,11 +252,20 @@ where
 /// Task objects that can be encoded to a string to be stored in Redis
 ///
 /// Implemented for all `Serialize` objects by default by encoding as JSON.
+///
+/// Implementations provide `try_encode_task`, the deprecated `encode_task` calls it.
 pub trait TaskEncodable {
     /// Encode the value into a Blob to insert into Redis
     ///
     /// It should encode the value into a string.
-    fn encode_task(&self) -> Vec<u8>;
+    /// Panics if the value can't be encoded.
+    #[deprecated(note = "implement and call `try_encode_task` instead")]
+    fn encode_task(&self) -> Vec<u8> {
+        self.try_encode_task().expect("Encoding the task failed")
+    }
+
+    /// Encode the value into a Blob to insert into Redis, failing if it can't be encoded
+    fn try_encode_task(&self) -> RedisResult<Vec<u8>>;
 }
 
 impl<T: DeserializeOwned> TaskDecodable for T {
@@ -107,58 +282,391 @@ impl<T: DeserializeOwned> TaskDecodable for T {
 }
 
 impl<T: Serialize> TaskEncodable for T {
-    fn encode_task(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+    fn try_encode_task(&self) -> RedisResult<Vec<u8>> {
+        serde_json::to_vec(self).map_err(|_| From::from((ErrorKind::TypeError, "JSON encode failed")))
     }
 }
 
+/// How the processing of a task ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Complete,
+    Fail,
+    Dead,
+    Discard,
+}
+
 /// A wrapper of the fetched task.
 ///
 /// If not marked otherwise, the contained task will be removed from the backup queue on `Drop`.
 /// Call `fail()` to mark the processing as failed. The task will remain in the backup queue.
+/// Call `dead_letter()` to give up on the task. It is moved to the dead letter queue.
+/// Call `discard()` to drop the task without retrying it.
+///
+/// Failures are recorded together with the error, the number of attempts and the worker,
+/// see `Queue::failure`.
 ///
 /// It derefs to the underlying task automatically for all other method calls.
 pub struct TaskGuard<'a, T: 'a> {
-    task: T,
+    // Only taken by `into_inner`
+    task: Option<T>,
     queue: &'a Queue,
-    failed: Cell<bool>,
+    outcome: Cell<Outcome>,
+    error: RefCell<Option<String>>,
+    result: RefCell<Option<Vec<u8>>>,
+    data: Vec<u8>,
+    rid: String,
+    job: Option<Envelope>,
+    started_at: u64,
 }
 
 impl<'a, T> TaskGuard<'a, T> {
     /// Fail the current task, in order to keep it in the backup queue.
     pub fn fail(&self) {
-        self.failed.set(true);
+        self.outcome.set(Outcome::Fail);
+    }
+
+    /// Fail the current task with the given error, in order to keep it in the backup queue.
+    ///
+    /// The error is stored in the failure record of the job.
+    pub fn fail_with<E: fmt::Display>(&self, error: E) {
+        *self.error.borrow_mut() = Some(error.to_string());
+        self.outcome.set(Outcome::Fail);
+    }
+
+    /// Give up on the current task, moving it to the dead letter queue.
+    ///
+    /// The error is stored in the failure record of the job.
+    pub fn dead_letter<E: fmt::Display>(&self, error: E) {
+        *self.error.borrow_mut() = Some(error.to_string());
+        self.outcome.set(Outcome::Dead);
+    }
+
+    /// Drop the current task without retrying it or moving it to the dead letter queue.
+    ///
+    /// Earlier failures of the job are forgotten. The job counts as failed for its batch,
+    /// workflow or parent.
+    pub fn discard(&self) {
+        self.outcome.set(Outcome::Discard);
+    }
+
+    /// Set the result of the current task, handed to producers waiting with `Queue::await_result`
+    ///
+    /// Results are only stored if the queue was set up with `with_results`.
+    pub fn set_result<R: TaskEncodable>(&self, result: &R) -> RedisResult<()> {
+        *self.result.borrow_mut() = Some(result.try_encode_task()?);
+        Ok(())
+    }
+
+    /// Append a line to the log of the job, e.g. to see later where it got stuck.
+    ///
+    /// The latest 1000 lines are kept for a week, see `Queue::job_log`.
+    /// Tasks without job metadata get a new id on every fetch, so their logs are not kept
+    /// across retries.
+    /// The line is published to subscribers as well, see `Queue::subscribe`.
+    pub fn log<S: AsRef<str>>(&self, line: S) -> RedisResult<()> {
+        let line = LogLine {
+            at: now_millis(),
+            line: line.as_ref().into(),
+        };
+        let con = self.queue.connection()?;
+        job::append_log(&con, &self.rid, &line)?;
+
+        let mut pipe = redis::pipe();
+        events::publish(&mut pipe, &self.rid, &JobEvent::Log { line: line.line });
+        pipe.query(&con)
+    }
+
+    /// Publish the progress of the job to subscribers, e.g. "step 3 of 7"
+    ///
+    /// Progress is not stored, only clients subscribed with `Queue::subscribe` get it.
+    pub fn progress(&self, current: u64, total: u64, message: Option<&str>) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        let event = JobEvent::Progress {
+            current: current,
+            total: total,
+            message: message.map(String::from),
+        };
+        events::publish(&mut pipe, &self.rid, &event);
+        pipe.query(&self.queue.connection()?)
+    }
+
+    /// Check if the job was asked to stop with `JobHandle::cancel`
+    ///
+    /// `Worker`s cancel the `CancellationToken` of the handler instead, see `Worker::heartbeat`.
+    pub fn is_cancel_requested(&self) -> RedisResult<bool> {
+        job::is_cancel_requested(&self.queue.read_connection()?, &self.rid)
+    }
+
+    /// Save the progress made on the current task.
+    ///
+    /// The checkpoint replaces any previous one and is kept while the job is retried, so
+    /// a handler can resume where it left off using `last_checkpoint`.
+    /// It is dropped once the job completes.
+    ///
+    /// Tasks without job metadata get a new id on every fetch, so their checkpoints are not
+    /// available on retries.
+    pub fn checkpoint<S: TaskEncodable>(&self, state: S) -> RedisResult<()> {
+        checkpoint::save(&self.queue.connection()?, self.queue.queue(), &self.rid, state)
+    }
+
+    /// Get the progress last saved with `checkpoint` for this job, if any.
+    pub fn last_checkpoint<S: TaskDecodable>(&self) -> RedisResult<Option<S>> {
+        checkpoint::load(&self.queue.connection()?, self.queue.queue(), &self.rid)
     }
 
     /// Get access to the underlying task.
     ///
     /// This should only be needed in very few cases, as this guard derefs automatically.
     pub fn inner(&self) -> &T {
-        &self.task
+        self.task.as_ref().expect("Task was taken")
+    }
+
+    /// Finish the task and take ownership of it.
+    ///
+    /// The task is finished like when dropping the guard: it's acknowledged, unless it was
+    /// marked as failed or dead before.
+    pub fn into_inner(mut self) -> T {
+        self.task.take().expect("Task was taken")
+    }
+
+    /// Get mutable access to the underlying task.
+    ///
+    /// Changes only affect the decoded task, the job stored in Redis stays as it was pushed.
+    pub fn inner_mut(&mut self) -> &mut T {
+        self.task.as_mut().expect("Task was taken")
     }
 
     /// Get access to the wrapper queue.
     pub fn queue(&self) -> &Queue {
         self.queue
     }
+
+    /// Get the options the job was pushed with, see `Queue::push_with_options`.
+    pub fn options(&self) -> Option<&JobOptions> {
+        self.job.as_ref().and_then(|job| job.options.as_ref())
+    }
+
+    /// Get the tags the job was pushed with
+    pub fn tags(&self) -> &[String] {
+        self.job.as_ref().map_or(&[], |job| job.tags())
+    }
+
+    /// Get the header `name` the job was pushed with, see `JobOptions::headers`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.options().and_then(|options| options.headers.get(name)).map(|value| &value[..])
+    }
+
+    /// Get the id of the job.
+    ///
+    /// Tasks pushed by other producers without job metadata have no id.
+    pub fn jid(&self) -> Option<&str> {
+        self.job.as_ref().map(|job| &job.jid[..])
+    }
+
+    /// Get the time the job was pushed, in milliseconds since the Unix epoch
+    pub fn enqueued_at(&self) -> Option<u64> {
+        self.job.as_ref().and_then(|job| job.enqueued_at)
+    }
+
+    /// Get the time the task was fetched, in milliseconds since the Unix epoch
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    /// Get how long the job waited in the queue before it was fetched
+    ///
+    /// The time the job was pushed is taken from the clock of the producer, so clock skew
+    /// between machines ends up in the result. Returns `None` if the push time is unknown.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// if task.wait_time().is_some_and(|wait| wait > Duration::from_secs(60)) {
+    ///     // Nobody waits for this preview anymore
+    ///     task.discard();
+    ///     return;
+    /// }
+    /// ```
+    pub fn wait_time(&self) -> Option<Duration> {
+        self.enqueued_at().map(|at| Duration::from_millis(self.started_at.saturating_sub(at)))
+    }
+
+    /// Get how long the task is processed so far
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(now_millis().saturating_sub(self.started_at))
+    }
+
+    /// Get the number of times the job failed before
+    ///
+    /// Looks up the failure record of the job, see `Queue::failure`.
+    pub fn attempts(&self) -> RedisResult<u64> {
+        let failure = failure::find(&self.queue.read_connection()?, self.queue.queue(), &self.rid)?;
+        Ok(failure.map_or(0, |failure| failure.attempts))
+    }
+
+    /// Get the data of the job exactly as it was stored in Redis
+    ///
+    /// For jobs pushed by oppgave, this is the job metadata with the encoded task inside.
+    pub fn raw(&self) -> &[u8] {
+        &self.data
+    }
 }
 
 impl<'a, T> Deref for TaskGuard<'a, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.task
+        self.inner()
+    }
+}
+
+impl<'a, T> DerefMut for TaskGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner_mut()
     }
 }
 
 impl<'a, T> Drop for TaskGuard<'a, T> {
     fn drop(&mut self) {
-        if !self.failed.get() {
-            // Pop job from backup queue
+        // A task dropped while unwinding from a panic didn't complete
+        if thread::panicking() && self.outcome.get() == Outcome::Complete {
+            self.outcome.set(Outcome::Fail);
+            let mut error = self.error.borrow_mut();
+            if error.is_none() {
+                *error = Some("Panicked while processing the task".into());
+            }
+        }
+        let outcome = self.outcome.get();
+        let failed = outcome != Outcome::Complete;
+        let config = self.queue.applied_config();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        // Tasks pushed from now on are no children of this task anymore
+        if self.jid().is_some() && self.queue.current.borrow().as_ref().map(|jid| &jid[..]) == self.jid() {
+            *self.queue.current.borrow_mut() = None;
+        }
+
+        if self.queue.delivery == Delivery::AtLeastOnce {
+            processing::release(&mut pipe, self.queue.queue(), &self.rid);
+        }
+
+        if outcome != Outcome::Fail && self.queue.delivery == Delivery::AtLeastOnce {
+            // Remove exactly this job from the backup queue, other tasks may have been fetched since
             let backup = &self.queue.backup_queue[..];
-            self.queue.client.lpop::<_, ()>(backup).expect(
-                "LPOP from backup queue failed",
-            );
+            pipe.cmd("LREM").arg(backup).arg(-1).arg(&self.data[..]).ignore();
+        }
+
+        if outcome == Outcome::Discard {
+            failure::clear(&mut pipe, self.queue.queue(), &self.rid);
+            checkpoint::clear(&mut pipe, self.queue.queue(), &self.rid);
+        } else if failed {
+            let now = now_millis();
+            let task = match self.job {
+                Some(ref job) => job.task.get().as_bytes(),
+                None => &self.data[..],
+            };
+            let error = self.error.borrow();
+            let failure = failure::Failure {
+                jid: &self.rid,
+                job: &self.data,
+                task: task,
+                error: error.as_ref().map(|e| &e[..]),
+                worker: &self.queue.worker_id,
+                at: now,
+            };
+            failure::record(&mut pipe, self.queue.queue(), &failure);
+            let limit = self.options().and_then(|options| options.max_attempts).or(config.max_attempts);
+            match (outcome, self.queue.quarantine) {
+                (Outcome::Dead, _) => failure::bury(&mut pipe, self.queue.queue(), &self.rid, now),
+                (_, Some(threshold)) => {
+                    failure::quarantine(&mut pipe, self.queue.queue(), self.queue.backup_queue(), &failure, threshold)
+                }
+                _ => {}
+            }
+            if let (Outcome::Fail, Some(max)) = (outcome, limit) {
+                options::retry_or_bury(
+                    &mut pipe,
+                    self.queue,
+                    &self.rid,
+                    &self.data,
+                    self.options().unwrap_or(&JobOptions::default()),
+                    max,
+                );
+            }
+        } else {
+            failure::clear(&mut pipe, self.queue.queue(), &self.rid);
+            checkpoint::clear(&mut pipe, self.queue.queue(), &self.rid);
+
+            if let (Some(ref retention), Some(job)) = (config.retention.or(self.queue.archive), self.job.as_ref()) {
+                let archived = ArchivedJob {
+                    jid: job.jid.clone(),
+                    task: job.task.get().into(),
+                    enqueued_at: job.enqueued_at,
+                    started_at: self.started_at,
+                    finished_at: now_millis(),
+                    worker: self.queue.worker_id.clone(),
+                };
+                archive::archive(&mut pipe, self.queue.queue(), retention, &archived);
+            }
+        }
+
+        // A failed job is only finished once it's buried, not while it's retried
+        let dead = failure::dead_key(self.queue.queue());
+        let if_dead = if outcome == Outcome::Fail { Some(&dead[..]) } else { None };
+        if let Some(ref job) = self.job {
+            job.finish(&mut pipe, failed, if_dead.map(|dead| (dead, &self.rid[..])));
+        }
+        for (name, _) in self.options().map(JobOptions::locks).unwrap_or_default() {
+            lock::unlock(&mut pipe, &name, &self.rid);
+        }
+        if let Some(unique) = self.options().and_then(|options| options.unique.as_ref()) {
+            unique.release(&mut pipe, &self.rid, if_dead);
+        }
+        if let Some(options) = self.options() {
+            if let Some(ref key) = options.ordering_key {
+                // A failed job is retried before the next one of its key, unless it's dead
+                let held = outcome == Outcome::Fail &&
+                    (self.queue.delivery == Delivery::AtLeastOnce || options.max_attempts.or(config.max_attempts).is_some());
+                let target = self.queue.target(Some(key));
+                ordering::release(&mut pipe, self.queue.queue(), key, &target, &self.rid, held);
+            }
+        }
+        if let Some(ttl) = self.queue.results {
+            let error = self.error.borrow();
+            let error = error.as_ref().map(|e| &e[..]);
+            let (stored, if_dead) = match outcome {
+                Outcome::Complete => (results::Stored::completed(self.result.borrow().as_ref().map(|r| &r[..])), false),
+                Outcome::Dead => (results::Stored::failed(error), false),
+                // Only final once the job was moved to the dead letter queue
+                Outcome::Fail => (results::Stored::failed(error), true),
+                Outcome::Discard => (results::Stored::discarded(), false),
+            };
+            results::store(&mut pipe, self.queue.queue(), &self.rid, &stored, ttl, if_dead);
+        }
+        job::clear_cancel(&mut pipe, &self.rid);
+        throughput::record(&mut pipe, self.queue.queue(), now_millis().saturating_sub(self.started_at));
+        events::publish(&mut pipe, &self.rid, &JobEvent::Finished { failed: failed });
+
+        // Only connecting is retried: once the commands were sent, they may have been applied even
+        // if the reply got lost, and applying them twice would finish batches early
+        let finished = transient::retry(self.queue.retries, "finish", transient::is_transient, || self.queue.connection())
+            .and_then(|con| pipe.query::<()>(&con));
+        // Panicking again while unwinding would abort, the task stays in the backup queue then
+        if !thread::panicking() {
+            finished.expect("Finishing task failed");
+        }
+
+        if let Some(ref handler) = self.queue.failure_handler {
+            if outcome == Outcome::Fail || outcome == Outcome::Dead {
+                // Reporting is best-effort, the failure is recorded already
+                let failure = self.queue.connection()
+                    .and_then(|con| failure::find_with_state(&con, self.queue.queue(), &self.rid));
+                if let Ok(Some((failure, dead))) = failure {
+                    handler.on_failure(self.queue.queue(), &failure, dead);
+                }
+            }
         }
     }
 }
@@ -227,214 +735,4897 @@ impl<'a, T> Drop for TaskGuard<'a, T> {
 /// } // Task failed, stays in backup queue
 /// ```
 ///
+/// ### Example: At most once
+///
+/// For tasks where occasional loss is acceptable, the backup queue can be skipped entirely:
+///
+/// ```rust,ignore
+/// let worker = Queue::new("metrics".into(), client).with_delivery(Delivery::AtMostOnce);
+/// ```
+///
+/// ### Example: Newest task first
+///
+/// By default tasks are handed out in the order they were pushed.
+/// For queues where a more recent task supersedes older ones (e.g. cache warming or preview
+/// rendering), the queue can be consumed newest-first instead:
+///
+/// ```rust,ignore
+/// let worker = Queue::new("previews".into(), client).with_order(Order::Lifo);
+/// ```
 ///
 #[derive(Clone)]
 pub struct Queue {
     queue_name: String,
     backup_queue: String,
     stopped: Cell<bool>,
+    order: Order,
+    delivery: Delivery,
+    current: RefCell<Option<String>>,
+    worker_id: String,
+    archive: Option<Retention>,
+    tenant: Option<String>,
+    quarantine: Option<usize>,
+    retry_budget: Option<RetryBudget>,
+    failure_handler: Option<Arc<dyn FailureHandler>>,
+    stall_handler: Option<Arc<dyn StallHandler>>,
+    results: Option<Duration>,
+    max_size: Option<u64>,
+    shards: usize,
+    partitions: Option<Vec<usize>>,
+    kinds: Option<KindFilter>,
+    migrations: Option<Migrations>,
+    next_shard: Cell<usize>,
+    size_cache: Option<Duration>,
+    cached_size: Cell<Option<(Instant, u64)>>,
+    cached_delayed_size: Cell<Option<(Instant, u64)>>,
+    cached_config: Cell<Option<(Instant, QueueConfig)>>,
+    capabilities: Cell<Option<Capabilities>>,
+    config: Cell<Option<(Instant, QueueConfig)>>,
+    retries: Option<transient::Retries>,
     client: redis::Client,
+    replica: Option<redis::Client>,
+}
+
+/// The order in which a `Queue` hands out its tasks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// Oldest task first (queue semantics). This is the default.
+    Fifo,
+    /// Newest task first (stack semantics).
+    ///
+    /// This relies on `BLMOVE` and therefore requires Redis 6.2 or later, see `Capabilities`.
+    Lifo,
+}
+
+/// The delivery guarantee of a `Queue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Fetched tasks are kept in a backup queue until they complete. This is the default.
+    ///
+    /// Tasks of crashed workers are never lost, but may be processed twice.
+    AtLeastOnce,
+    /// Fetched tasks are removed from Redis right away.
+    ///
+    /// Tasks of crashed workers are lost, but there is no bookkeeping of fetched tasks.
+    /// Failed tasks are not kept, apart from their failure record.
+    AtMostOnce,
 }
 
 impl Queue {
     /// Create a new Queue for the given name
     pub fn new(name: String, client: redis::Client) -> Queue {
         let qname = format!("oppgave:{}", name);
-        let backup_queue = format!(
-            "{}:{}:{}",
-            qname,
-            getpid(),
-            thread::current().name().unwrap_or("default".into())
-        );
+        let thread_name = thread_name();
+        let backup_queue = format!("{}:{}:{}", qname, getpid(), thread_name);
+        let worker_id = format!("{}:{}:{}", hostname(), getpid(), thread_name);
 
         Queue {
             queue_name: qname,
             backup_queue: backup_queue,
             client: client,
             stopped: Cell::new(false),
+            order: Order::Fifo,
+            delivery: Delivery::AtLeastOnce,
+            current: RefCell::new(None),
+            worker_id: worker_id,
+            archive: None,
+            tenant: None,
+            quarantine: None,
+            retry_budget: None,
+            failure_handler: None,
+            stall_handler: None,
+            results: None,
+            max_size: None,
+            shards: 1,
+            partitions: None,
+            kinds: None,
+            migrations: None,
+            next_shard: Cell::new(0),
+            size_cache: None,
+            cached_size: Cell::new(None),
+            cached_delayed_size: Cell::new(None),
+            cached_config: Cell::new(None),
+            capabilities: Cell::new(None),
+            config: Cell::new(None),
+            retries: None,
+            replica: None,
         }
     }
 
-    fn connection(&self) -> RedisResult<redis::Connection> {
-        self.client.get_connection()
+    /// Use a replica for read-only inspection
+    ///
+    /// Sizes, peeking and browsing of archived, dead and failed jobs, recurring jobs and
+    /// child, batch and workflow states are read from the replica, keeping this traffic off the
+    /// primary which handles pushing and fetching tasks.
+    /// Replication is asynchronous, so these reads may lag slightly behind.
+    ///
+    /// To discover queues on a replica, pass its client to `discover`.
+    pub fn with_read_replica(mut self, replica: redis::Client) -> Queue {
+        self.replica = Some(replica);
+        self
     }
 
-    /// Stop processing the queue
+    /// Split the queue into `shards` shards
     ///
-    /// On the next `.next()` call `None` will be returned.
-    pub fn stop(&self) {
-        self.stopped.set(true);
+    /// Very hot queues put a lot of pressure on a single Redis key.
+    /// Tasks pushed to a sharded queue are distributed over all shards round-robin, or by a key
+    /// with `push_keyed`. Workers consume all shards as well as the queue itself, which still
+    /// receives delayed, recurring and retried jobs.
+    ///
+    /// All producers and workers of a queue need to use the same number of shards.
+    /// Every worker waits for new tasks on one shard at a time, so tasks pushed to other shards
+    /// can be delayed by up to a second while the shards are idle.
+    pub fn with_shards(mut self, shards: usize) -> Queue {
+        self.shards = cmp::max(1, shards);
+        // Start at different shards, so workers don't all pile onto the first one
+        self.next_shard.set(getpid() as usize % self.shards);
+        self
     }
 
-    /// Check if queue processing is stopped
-    pub fn is_stopped(&self) -> bool {
-        self.stopped.get()
+    /// Only consume the given shards of the queue
+    ///
+    /// Together with `push_keyed` this makes consumption sticky: all tasks of a key end up in the
+    /// same shard, and are therefore processed by the workers which claimed that shard, e.g. to
+    /// make use of local caches. Every shard needs to be claimed by at least one worker.
+    /// Delayed, recurring and retried jobs are pushed to the queue itself, which is still
+    /// consumed by all workers.
+    ///
+    /// Shards out of range are ignored. Has no effect on queues without shards.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// // Worker 1 of 2 takes the even shards
+    /// let queue = Queue::new("accounts".into(), client)
+    ///     .with_shards(8)
+    ///     .with_partitions(vec![0, 2, 4, 6]);
+    /// ```
+    pub fn with_partitions(mut self, partitions: Vec<usize>) -> Queue {
+        self.partitions = Some(partitions);
+        self
     }
 
-    /// Get the full queue name
-    pub fn queue(&self) -> &str {
-        &self.queue_name
+    /// Get the shards the queue consumes, if restricted with `with_partitions`
+    pub fn partitions(&self) -> Option<&[usize]> {
+        self.partitions.as_ref().map(|partitions| &partitions[..])
     }
 
-    /// Get the full backup queue name
-    pub fn backup_queue(&self) -> &str {
-        &self.backup_queue
+    /// Upgrade tasks pushed with older versions of their type when fetching them
+    ///
+    /// Tasks pushed to the queue record the current version of their type.
+    /// Producers and workers should use the same migrations, see `Migrations`.
+    pub fn with_migrations(mut self, migrations: Migrations) -> Queue {
+        self.migrations = Some(migrations);
+        self
     }
 
-    /// Get the number of remaining tasks in the queue
-    pub fn size(&self) -> u64 {
-        self.connection().and_then(|con| con.llen(self.queue())).unwrap_or(0)
+    /// Get the number of shards of the queue
+    pub fn shards(&self) -> usize {
+        self.shards
     }
 
-    /// Push a new task to the queue
-    pub fn push<T: TaskEncodable>(&self, task: T) -> RedisResult<()> {
-        self.connection()?.lpush(self.queue(), task.encode_task())
+    /// Get the full name of the shard `index`
+    pub fn shard_queue(&self, index: usize) -> String {
+        shard::shard_key(self.queue(), index)
     }
 
-    /// Grab the next task from the queue
-    ///
-    /// This method blocks for `timeout` ms and waits until a new task is available.
-    /// timeout of 0 will block indefinitely
-    pub fn next<T: TaskDecodable>(&self, timeout: usize) -> Option<RedisResult<TaskGuard<T>>> {
-        if self.stopped.get() {
-            return None;
+    /// All lists tasks are fetched from.
+    fn sources(&self) -> Vec<String> {
+        let mut sources = vec![self.queue_name.clone()];
+        if self.shards > 1 {
+            sources.extend((0..self.shards).map(|index| self.shard_queue(index)));
         }
+        sources
+    }
 
-        let v;
-        {
-            let qname = &self.queue_name[..];
-            let backup = &self.backup_queue[..];
+    /// The lists tasks are fetched from by this queue, which leaves out unclaimed shards.
+    fn consumed(&self) -> Vec<String> {
+        let mut sources = vec![self.queue_name.clone()];
+        if self.shards > 1 {
+            sources.extend((0..self.shards).filter(|index| {
+                self.partitions.as_ref().is_none_or(|partitions| partitions.contains(index))
+            }).map(|index| self.shard_queue(index)));
+        }
+        sources
+    }
 
-            v = match self.connection().and_then(|con| con.brpoplpush(qname, backup, timeout)) {
-                Ok(v) => v,
-                Err(_) => {
-                    return Some(Err(From::from((ErrorKind::TypeError, "next failed"))));
-                }
-            };
+    /// Get the list to push a task to, by its key if given.
+    fn target(&self, key: Option<&str>) -> String {
+        if self.shards <= 1 {
+            return self.queue_name.clone();
         }
 
-        let v = match v {
-            v @ Value::Data(_) => v,
-            _ => {
-                return Some(Err(
-                    From::from((ErrorKind::TypeError, "Not a proper reply")),
-                ));
+        let index = match key {
+            Some(key) => shard::pick(key, self.shards),
+            None => {
+                let index = self.next_shard.get() % self.shards;
+                self.next_shard.set(index + 1);
+                index
             }
         };
-
-        match T::decode_task(&v) {
-            Err(e) => Some(Err(e)),
-            Ok(task) => Some(Ok(TaskGuard {
-                task: task,
-                queue: self,
-                failed: Cell::new(false),
-            })),
-        }
+        self.shard_queue(index)
     }
-}
 
+    /// Scope the queue to a tenant
+    ///
+    /// All keys of the queue are namespaced by the tenant, so queues of the same name of different
+    /// tenants are fully isolated.
+    /// Use `tenant_queue` to refer to the queue by name elsewhere.
+    /// Tenant ids must not contain a `:`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let queue = Queue::new("emails".into(), client).for_tenant("acme");
+    /// ```
+    pub fn for_tenant(mut self, tenant: &str) -> Queue {
+        let name = tenant::split(&self.queue_name["oppgave:".len()..]).1.to_string();
+        let suffix = self.backup_queue[self.queue_name.len()..].to_string();
 
-#[cfg(test)]
-mod test {
-    extern crate redis;
+        self.queue_name = format!("oppgave:{}", tenant_queue(tenant, &name));
+        self.backup_queue = format!("{}{}", self.queue_name, suffix);
+        self.tenant = Some(tenant.into());
+        self
+    }
 
-    use redis::Commands;
-    use super::{Queue, TaskGuard};
+    /// Get the tenant the queue is scoped to
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_ref().map(|tenant| &tenant[..])
+    }
 
-    #[derive(Deserialize, Serialize)]
-    struct Job {
-        id: u64,
+    /// Keep completed jobs in an archive
+    ///
+    /// Completed jobs are kept together with their timings and the worker that processed them,
+    /// up to the limits of the `retention`.
+    /// Tasks pushed by other producers without job metadata are not archived.
+    pub fn with_archive(mut self, retention: Retention) -> Queue {
+        self.archive = Some(retention);
+        self
     }
 
-    #[test]
-    fn decodes_job() {
-        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let con = client.get_connection().unwrap();
-        let worker = Queue::new("default".into(), client);
+    /// Quarantine jobs failing on `workers` different workers
+    ///
+    /// Such jobs are likely poison messages, which fail no matter where they run.
+    /// Instead of staying in the backup queue to be retried, they are moved to the dead letter
+    /// queue and flagged as poison in their failure record.
+    pub fn with_quarantine(mut self, workers: usize) -> Queue {
+        self.quarantine = Some(cmp::max(1, workers));
+        self
+    }
 
-        let _: () = con.rpush(worker.queue(), "{\"id\":42}").unwrap();
+    /// Limit the rate at which failed jobs are retried, see `RetryBudget`
+    pub fn with_retry_budget(mut self, budget: RetryBudget) -> Queue {
+        self.retry_budget = Some(budget);
+        self
+    }
 
-        let j = worker.next::<Job>(0).unwrap().unwrap();
-        assert_eq!(42, j.id);
+    /// Call `handler` whenever a task of the queue fails or is dead-lettered
+    ///
+    /// See `FailureHandler`. Discarded tasks are not reported.
+    pub fn with_failure_handler<H: FailureHandler + 'static>(mut self, handler: H) -> Queue {
+        self.failure_handler = Some(Arc::new(handler));
+        self
     }
 
-    #[test]
-    fn releases_job() {
-        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let con = client.get_connection().unwrap();
-        let worker = Queue::new("default".into(), client);
-        let bqueue = worker.backup_queue();
+    /// Call `handler` whenever `reap_stuck` moves a stuck task back into the queue
+    ///
+    /// See `StallHandler`.
+    pub fn with_stall_handler<H: StallHandler + 'static>(mut self, handler: H) -> Queue {
+        self.stall_handler = Some(Arc::new(handler));
+        self
+    }
 
-        let _: () = con.del(bqueue).unwrap();
-        let _: () = con.lpush(worker.queue(), "{\"id\":42}").unwrap();
+    /// Keep the outcome of finished tasks for `ttl`, see `Queue::await_result`
+    ///
+    /// This is a setting of the consuming side: only workers of queues set up with it store
+    /// results. Tasks without job metadata get a new id on every fetch, so nobody can wait for them.
+    pub fn with_results(mut self, ttl: Duration) -> Queue {
+        self.results = Some(ttl);
+        self
+    }
 
-        {
-            let j = worker.next::<Job>(0).unwrap().unwrap();
-            assert_eq!(42, j.id);
-            let in_backup: Vec<String> = con.lrange(bqueue, 0, -1).unwrap();
-            assert_eq!(1, in_backup.len());
-            assert_eq!("{\"id\":42}", in_backup[0]);
+    /// Cache the sizes and settings of the queue for `ttl`, e.g. for dashboards or producers polling them
+    ///
+    /// `size`, `delayed_size`, `config` and the check of `with_max_size` reuse a value read
+    /// within the last `ttl` instead of asking Redis again. This is a plain time-based cache:
+    /// pushes, `pause`, `resume` and `set_config` through this queue drop the cached values,
+    /// changes by other clients only show up once the cache expired.
+    ///
+    /// Server-assisted invalidation (RESP3 client tracking) needs a newer `redis` crate and
+    /// is not used.
+    pub fn with_size_cache(mut self, ttl: Duration) -> Queue {
+        self.size_cache = Some(ttl);
+        self
+    }
+
+    /// Get the value of `cell` if it's younger than the size cache, or refresh it with `read`.
+    fn cached<V: Copy, F>(&self, cell: &Cell<Option<(Instant, V)>>, read: F) -> RedisResult<V>
+    where
+        F: FnOnce() -> RedisResult<V>,
+    {
+        let ttl = match self.size_cache {
+            Some(ttl) => ttl,
+            None => return read(),
+        };
+        if let Some((at, value)) = cell.get() {
+            if at.elapsed() < ttl {
+                return Ok(value);
+            }
         }
+        let value = read()?;
+        cell.set(Some((Instant::now(), value)));
+        Ok(value)
+    }
 
-        let in_backup: u32 = con.llen(bqueue).unwrap();
-        assert_eq!(0, in_backup);
+    /// Get the number of pending tasks in all shards.
+    fn pending_size<C: redis::ConnectionLike>(&self, con: &C) -> RedisResult<u64> {
+        self.cached(&self.cached_size, || {
+            self.sources().iter().map(|source| con.llen::<_, u64>(&source[..])).sum()
+        })
     }
 
-    #[test]
-    fn can_be_stopped() {
-        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let con = client.get_connection().unwrap();
-        let worker = Queue::new("stopper".into(), client);
+    /// Retry pushing and finishing tasks up to `retries` times if Redis fails for a transient reason
+    ///
+    /// Transient failures are dropped connections, a server still loading its data and cluster
+    /// redirections or failovers. The first retry waits for `backoff`, every further retry twice
+    /// as long as the one before. Other errors are returned right away.
+    ///
+    /// A push whose reply got lost is pushed again, so the task may end up in the queue twice.
+    /// Finishing a task only retries connecting, as finishing it twice would run its callbacks twice.
+    pub fn with_transient_retries(mut self, retries: u32, backoff: Duration) -> Queue {
+        self.retries = Some(transient::Retries {
+            retries: retries,
+            backoff: backoff,
+        });
+        self
+    }
 
-        let _: () = con.del(worker.queue()).unwrap();
-        let _: () = con.lpush(worker.queue(), "{\"id\":1}").unwrap();
-        let _: () = con.lpush(worker.queue(), "{\"id\":2}").unwrap();
-        let _: () = con.lpush(worker.queue(), "{\"id\":3}").unwrap();
+    /// Limit the number of pending tasks
+    ///
+    /// Pushing to a full queue fails with `PushError::Full`, see `push_blocking` to wait instead.
+    /// The size is checked right before pushing, so concurrent producers may exceed the limit
+    /// slightly. Delayed tasks are not limited.
+    pub fn with_max_size(mut self, max_size: u64) -> Queue {
+        self.max_size = Some(max_size);
+        self
+    }
 
-        assert_eq!(3, worker.size());
+    /// Get the identity of this worker: the host name, PID and thread name
+    ///
+    /// The thread is the one which created the queue. A `Worker` uses the thread it runs on.
+    pub fn worker_id(&self) -> &str {
+        &self.worker_id
+    }
+
+    /// Get a copy of the queue with the worker id and backup queue of the current thread.
+    pub(crate) fn on_current_thread(&self) -> Queue {
+        let thread_name = thread_name();
+        let mut queue = self.clone();
+        queue.backup_queue = format!("{}:{}:{}", self.queue_name, getpid(), thread_name);
+        queue.worker_id = format!("{}:{}:{}", hostname(), getpid(), thread_name);
+        queue
+    }
+
+    /// Look up a completed job in the archive
+    ///
+    /// Returns `None` if the job did not complete (yet) or was dropped from the archive already.
+    pub fn archived_job(&self, jid: &str) -> RedisResult<Option<ArchivedJob>> {
+        archive::find(&self.read_connection()?, self.queue(), jid)
+    }
+
+    /// List up to `count` completed jobs from the archive, latest first
+    pub fn archived(&self, offset: usize, count: usize) -> RedisResult<Vec<ArchivedJob>> {
+        archive::list(&self.read_connection()?, self.queue(), offset, count)
+    }
+
+    /// Set the order in which tasks are fetched from the queue
+    ///
+    /// Producers are not affected by this setting, apart from jobs with a positive priority:
+    /// they are pushed to the end tasks are fetched from, see `JobOptions`.
+    pub fn with_order(mut self, order: Order) -> Queue {
+        self.order = order;
+        self
+    }
+
+    /// Get the order in which tasks are fetched from the queue
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    /// Set the delivery guarantee for fetched tasks
+    ///
+    /// Use `Delivery::AtMostOnce` for workloads where losing a task now and then is acceptable,
+    /// to save the bookkeeping of the backup queue.
+    pub fn with_delivery(mut self, delivery: Delivery) -> Queue {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Get the delivery guarantee for fetched tasks
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+
+    fn connection(&self) -> RedisResult<Connection> {
+        self.client.get_connection().map(Instrumented::new)
+    }
+
+    /// Get a connection for read-only commands, to the replica if configured.
+    fn read_connection(&self) -> RedisResult<Connection> {
+        self.replica.as_ref().unwrap_or(&self.client).get_connection().map(Instrumented::new)
+    }
+
+    /// Stop processing the queue
+    ///
+    /// On the next `.next()` call `None` will be returned.
+    pub fn stop(&self) {
+        self.stopped.set(true);
+    }
+
+    /// Check if queue processing is stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.get()
+    }
+
+    /// Get the full queue name
+    pub fn queue(&self) -> &str {
+        &self.queue_name
+    }
+
+    /// Get the full backup queue name
+    pub fn backup_queue(&self) -> &str {
+        &self.backup_queue
+    }
+
+    /// Get the full name of the set holding delayed tasks
+    pub fn delayed_queue(&self) -> String {
+        format!("{}:delayed", self.queue_name)
+    }
+
+    /// Get the full name of the dead letter queue
+    ///
+    /// It holds the ids of all dead-lettered jobs, scored by the time they died.
+    pub fn dead_queue(&self) -> String {
+        failure::dead_key(self.queue())
+    }
+
+    /// Get the number of jobs in the dead letter queue
+    pub fn dead_size(&self) -> u64 {
+        self.read_connection()
+            .and_then(|con| con.zcard(self.dead_queue()))
+            .unwrap_or(0)
+    }
+
+    /// Look up the failure record of a job
+    ///
+    /// Returns `None` if the job never failed or completed since.
+    pub fn failure(&self, jid: &str) -> RedisResult<Option<FailedJob>> {
+        failure::find(&self.read_connection()?, self.queue(), jid)
+    }
+
+    /// List up to `count` jobs from the dead letter queue, latest first
+    ///
+    /// Every job is decoded and comes with the details of its failures.
+    pub fn dead<T: TaskDecodable>(&self, offset: usize, count: usize) -> RedisResult<Vec<DeadJob<T>>> {
+        failure::list_dead(&self.read_connection()?, self.queue(), offset, count)
+    }
+
+    /// Move a job from the dead letter queue back into the queue
+    ///
+    /// The failure record of the job is dropped, so its attempts start over.
+    /// Returns `false` if the job is not in the dead letter queue.
+    pub fn retry_dead(&self, jid: &str) -> RedisResult<bool> {
+        failure::retry(&self.connection()?, self.queue(), jid)
+    }
+
+    /// Move up to `limit` jobs from the dead letter queue back into the queue, oldest first
+    ///
+    /// The failure records of the jobs are dropped, so their attempts start over.
+    /// Returns the number of moved jobs.
+    pub fn retry_all_dead(&self, limit: usize) -> RedisResult<usize> {
+        failure::retry_all(&self.connection()?, self.queue(), limit)
+    }
+
+    /// Move all jobs from the dead letter queue back into the queue, at most `rate` per second
+    ///
+    /// Jobs are moved oldest first in small batches spread over every second, so a large dead
+    /// letter queue doesn't hit the service which caused the failures all at once.
+    /// Blocks until the jobs dead at the start were moved or the queue is stopped. Jobs dying
+    /// while draining are left in the dead letter queue, so jobs failing right away again don't
+    /// keep it going forever.
+    /// Returns the number of moved jobs.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// // Drain 50k dead jobs in about 15 minutes
+    /// let retried = queue.retry_all_dead_throttled(50)?;
+    /// ```
+    pub fn retry_all_dead_throttled(&self, rate: u32) -> RedisResult<usize> {
+        let rate = cmp::max(1, rate) as usize;
+        let batch = rate.div_ceil(10);
+        let interval = Duration::from_millis((batch * 1000 / rate) as u64);
+        let con = self.connection()?;
+        let dead: usize = con.zcard(self.dead_queue())?;
+        let mut retried = 0;
+
+        while dead > 0 && !self.is_stopped() {
+            let started = Instant::now();
+            let limit = cmp::min(batch, dead - retried);
+            let moved = failure::retry_all(&con, self.queue(), limit)?;
+            retried += moved;
+            if moved < limit || retried == dead {
+                break;
+            }
+            if let Some(rest) = interval.checked_sub(started.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+
+        Ok(retried)
+    }
+
+    /// List up to `limit` jobs from the dead letter queue whose task matches `predicate`, latest first
+    ///
+    /// See `Queue::search`.
+    pub fn search_dead<T, F>(&self, predicate: F, limit: usize) -> RedisResult<Vec<DeadJob<T>>>
+    where
+        T: TaskDecodable,
+        F: Fn(&serde_json::Value) -> bool,
+    {
+        let con = self.read_connection()?;
+        let dead = failure::scan_dead(&con, self.queue(), limit, |data| inspect::task_matches(data, &predicate))?;
+        failure::load_dead(&con, self.queue(), dead)
+    }
+
+    /// List up to `limit` jobs from the dead letter queue tagged with `tag`, latest first
+    ///
+    /// See `JobOptions::tags`.
+    pub fn dead_tagged<T: TaskDecodable>(&self, tag: &str, limit: usize) -> RedisResult<Vec<DeadJob<T>>> {
+        let con = self.read_connection()?;
+        let dead = failure::scan_dead(&con, self.queue(), limit, |data| envelope::has_tag(data, tag))?;
+        failure::load_dead(&con, self.queue(), dead)
+    }
+
+    /// Move all jobs tagged with `tag` from the dead letter queue back into the queue
+    ///
+    /// Returns the number of moved jobs.
+    pub fn retry_dead_tagged(&self, tag: &str) -> RedisResult<usize> {
+        let con = self.connection()?;
+        let dead = failure::scan_dead(&con, self.queue(), usize::MAX, |data| envelope::has_tag(data, tag))?;
+
+        let mut count = 0;
+        for (jid, _) in dead {
+            if failure::retry(&con, self.queue(), &jid)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// List the tasks currently processed by workers, oldest first
+    ///
+    /// Every fetched task has its own entry until it finishes, independent of the worker.
+    /// Tasks of queues with `Delivery::AtMostOnce` are not tracked.
+    pub fn processing(&self) -> RedisResult<Vec<Reservation>> {
+        processing::list(&self.read_connection()?, self.queue())
+    }
+
+    /// Move tasks processed for longer than `max_age` back into the queue
+    ///
+    /// This recovers tasks of crashed workers. Tasks still processed by a slow worker are
+    /// processed twice, so `max_age` should be well above the longest processing time.
+    ///
+    /// Every moved task is announced with the worker which held it and for how long: to the
+    /// `StallHandler` of the queue, to subscribers of `subscribe_stalled` and as a
+    /// `JobEvent::Stalled` to subscribers of the job.
+    /// Returns the number of moved tasks.
+    pub fn reap_stuck(&self, max_age: Duration) -> RedisResult<usize> {
+        let con = self.connection()?;
+        let stalled = processing::reap(&con, self.queue(), duration_millis(max_age))?;
+        if stalled.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pipe = redis::pipe();
+        for job in &stalled {
+            events::publish_stalled(&mut pipe, job);
+        }
+        // Announcing is best-effort, the tasks are back in the queue already
+        let _ = pipe.query::<()>(&con);
+        if let Some(ref handler) = self.stall_handler {
+            for job in &stalled {
+                handler.on_stalled(job);
+            }
+        }
+
+        Ok(stalled.len())
+    }
+
+    /// Subscribe to the tasks `reap_stuck` moves back into the queue, by any process
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// for job in queue.subscribe_stalled()? {
+    ///     let job = job?;
+    ///     println!("{} stalled on {} for {} ms", job.jid, job.worker, job.held_for);
+    /// }
+    /// ```
+    pub fn subscribe_stalled(&self) -> RedisResult<StalledJobs> {
+        StalledJobs::subscribe(&self.client, self.queue())
+    }
+
+    /// Get the version of the Redis server and the commands it supports
+    ///
+    /// The server is asked once, on first use of the queue, e.g. when fetching the first task.
+    pub fn capabilities(&self) -> RedisResult<Capabilities> {
+        self.detect(&self.connection()?)
+    }
+
+    /// Get the capabilities of the server, detecting them on first use.
+    fn detect<C: redis::ConnectionLike>(&self, con: &C) -> RedisResult<Capabilities> {
+        if let Some(capabilities) = self.capabilities.get() {
+            return Ok(capabilities);
+        }
+        let capabilities = capabilities::detect(con)?;
+        self.capabilities.set(Some(capabilities));
+        Ok(capabilities)
+    }
+
+    /// Get the settings of the queue stored in Redis
+    ///
+    /// See `with_size_cache` to cache them.
+    pub fn config(&self) -> RedisResult<QueueConfig> {
+        self.cached(&self.cached_config, || self.load_config())
+    }
+
+    /// Load the settings from Redis, bypassing the cache.
+    fn load_config(&self) -> RedisResult<QueueConfig> {
+        config::load(&self.connection()?, self.queue())
+    }
+
+    /// Store the settings of the queue in Redis, replacing the previous ones
+    ///
+    /// Workers of the queue apply them within a second, see `QueueConfig`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let mut config = queue.config()?;
+    /// config.rate_limit = Some(50);
+    /// queue.set_config(&config)?;
+    /// ```
+    pub fn set_config(&self, config: &QueueConfig) -> RedisResult<()> {
+        config::store(&self.connection()?, self.queue(), config)?;
+        self.config.set(None);
+        self.cached_config.set(None);
+        Ok(())
+    }
+
+    /// Stop all workers of the queue from fetching jobs, until `resume` is called
+    pub fn pause(&self) -> RedisResult<()> {
+        config::set(&self.connection()?, self.queue(), "paused", 1)?;
+        self.config.set(None);
+        self.cached_config.set(None);
+        Ok(())
+    }
+
+    /// Let the workers of a paused queue fetch jobs again
+    pub fn resume(&self) -> RedisResult<()> {
+        config::set(&self.connection()?, self.queue(), "paused", 0)?;
+        self.config.set(None);
+        self.cached_config.set(None);
+        Ok(())
+    }
+
+    /// Get the settings applied to fetched tasks, as last loaded by `refresh_config`.
+    fn applied_config(&self) -> QueueConfig {
+        self.config.get().map(|(_, config)| config).unwrap_or_default()
+    }
+
+    /// Reload the settings if they weren't loaded within `every`.
+    ///
+    /// The last known settings are kept while Redis is unavailable.
+    fn refresh_config(&self, every: Duration) -> QueueConfig {
+        if let Some((at, config)) = self.config.get() {
+            if at.elapsed() < every {
+                return config;
+            }
+        }
+        match self.load_config() {
+            Ok(config) => {
+                self.config.set(Some((Instant::now(), config)));
+                config
+            }
+            Err(_) => self.applied_config(),
+        }
+    }
+
+    /// Get the number of remaining tasks in the queue
+    ///
+    /// See `with_size_cache` to cache it.
+    pub fn size(&self) -> u64 {
+        self.read_connection()
+            .and_then(|con| self.pending_size(&con))
+            .unwrap_or(0)
+    }
+
+    /// Get the number of delayed tasks not yet promoted to the queue
+    pub fn delayed_size(&self) -> u64 {
+        self.read_connection()
+            .and_then(|con| self.cached(&self.cached_delayed_size, || con.zcard(self.delayed_queue())))
+            .unwrap_or(0)
+    }
+
+    /// Push a new task to the queue
+    ///
+    /// If called while a task fetched from this queue is processed, the new job is recorded as a
+    /// child of that task. See `children`.
+    ///
+    /// Fails with `PushError::Encode` if the task can't be encoded and with `PushError::Redis` if
+    /// writing it to Redis failed.
+    pub fn push<T: TaskEncodable>(&self, task: T) -> Result<(), PushError> {
+        let target = self.target(None);
+        self.push_to(&target, task.try_encode_task().map_err(PushError::Encode)?, self.version::<T>(), None)
+            .map(|_| ())
+    }
+
+    /// Push a new task like `push`, getting a handle to follow the job
+    ///
+    /// See `JobHandle`. The task needs to be encoded as JSON.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let job = queue.push_tracked(Resize { width: 100 })?;
+    /// println!("Pushed {}", job.id());
+    /// let url = job.await_result::<String>(Duration::from_secs(30))?;
+    /// ```
+    pub fn push_tracked<T: TaskEncodable>(&self, task: T) -> Result<JobHandle, PushError> {
+        self.push_tracked_with_options(task, JobOptions::default())
+    }
+
+    /// Push a new task like `push_with_options`, getting a handle to follow the job
+    ///
+    /// See `JobHandle`.
+    pub fn push_tracked_with_options<T: TaskEncodable>(&self, task: T, options: JobOptions) -> Result<JobHandle, PushError> {
+        let target = self.target(options.ordering_key.as_ref().map(|key| &key[..]));
+        let task = task.try_encode_task().map_err(PushError::Encode)?;
+        let jid = self.push_to(&target, task, self.version::<T>(), Some(options))?;
+        Ok(self.handle(&jid.expect("Jobs with options have an id")))
+    }
+
+    /// Get a handle to follow the job `jid` of this queue, e.g. for an id stored elsewhere
+    pub fn handle(&self, jid: &str) -> JobHandle {
+        JobHandle::new(self.clone(), jid.into())
+    }
+
+    /// Add the commands pushing a new task to `pipe`, returning the id of the job
+    ///
+    /// The task is pushed once the pipeline runs, together with the other commands of the
+    /// application. With `pipe.atomic()`, or within `redis::transaction`, either all of them are
+    /// applied or none, so state can't be updated without its job being enqueued.
+    ///
+    /// The task needs to be encoded as JSON. Nothing is read from Redis, so `with_max_size`
+    /// is not enforced and failures show up when the pipeline runs.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let mut pipe = redis::pipe();
+    /// pipe.atomic().cmd("HSET").arg("order:42").arg("state").arg("paid").ignore();
+    /// queue.push_in_pipeline(&mut pipe, SendReceipt { order: 42 })?;
+    /// pipe.query::<()>(&con)?;
+    /// ```
+    pub fn push_in_pipeline<T: TaskEncodable>(&self, pipe: &mut redis::Pipeline, task: T) -> RedisResult<String> {
+        let mut job = Envelope::new(task.try_encode_task()?)?;
+        job.version = self.version::<T>();
+        let parent = self.current.borrow().clone();
+        if let Some(parent) = parent {
+            job::track_child(pipe, &parent, &job.jid, self.queue());
+            job.parent = Some(parent);
+        }
+
+        let target = self.target(None);
+        pipe.cmd("LPUSH").arg(target).arg(job.encode()).ignore();
+        self.cached_size.set(None);
+        Ok(job.jid)
+    }
+
+    /// Push data as it is, without encoding it or adding job metadata
+    ///
+    /// Useful to bridge with producers and consumers not using oppgave.
+    /// The data can be fetched with `next_raw`, or with `next` if it decodes as a task.
+    pub fn push_raw(&self, data: &[u8]) -> RedisResult<()> {
+        let target = self.target(None);
+        self.connection()?.lpush(target, data)
+    }
+
+    /// Push a new task with options overriding the defaults of the queue and worker
+    ///
+    /// See `JobOptions`. The task needs to be encoded as JSON.
+    pub fn push_with_options<T: TaskEncodable>(&self, task: T, options: JobOptions) -> Result<(), PushError> {
+        let target = self.target(options.ordering_key.as_ref().map(|key| &key[..]));
+        self.push_to(&target, task.try_encode_task().map_err(PushError::Encode)?, self.version::<T>(), Some(options))
+            .map(|_| ())
+    }
+
+    /// Push a new task to the shard picked by `key`
+    ///
+    /// Tasks with the same key always end up in the same shard.
+    /// For queues without shards this is the same as `push`.
+    pub fn push_keyed<T: TaskEncodable>(&self, task: T, key: &str) -> Result<(), PushError> {
+        let target = self.target(Some(key));
+        self.push_to(&target, task.try_encode_task().map_err(PushError::Encode)?, self.version::<T>(), None)
+            .map(|_| ())
+    }
+
+    /// Push a new task, waiting for up to `timeout` while the queue is full
+    ///
+    /// Gives producers backpressure for queues with a `with_max_size` limit.
+    /// The queue size is polled with growing intervals of up to half a second.
+    /// Fails with `PushError::Full` if the queue is still full after `timeout`.
+    pub fn push_blocking<T: TaskEncodable>(&self, task: T, timeout: Duration) -> Result<(), PushError> {
+        let task = task.try_encode_task().map_err(PushError::Encode)?;
+        let target = self.target(None);
+        let deadline = Instant::now() + timeout;
+        let mut idle = Idle::new(IdleStrategy::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(500),
+        });
+
+        loop {
+            match self.push_to(&target, task.clone(), self.version::<T>(), None) {
+                Err(PushError::Full) if Instant::now() + idle.delay() < deadline => idle.idle(),
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+
+    /// Get the current version of the task type `T`, if the queue has migrations for it.
+    fn version<T>(&self) -> Option<u32> {
+        self.migrations.as_ref().and_then(|migrations| migrations.current::<T>())
+    }
+
+    /// Push the encoded task to `target`, returning the id of the job.
+    ///
+    /// Tasks not encoded as JSON are stored as they are, without an id.
+    /// Transient failures are retried, see `with_transient_retries`.
+    fn push_to(
+        &self,
+        target: &str,
+        task: Vec<u8>,
+        version: Option<u32>,
+        options: Option<JobOptions>,
+    ) -> Result<Option<String>, PushError> {
+        if self.retries.is_none() {
+            return self.push_once(target, task, version, options);
+        }
+        transient::retry(self.retries, "push", is_transient_push, || {
+            self.push_once(target, task.clone(), version, options.clone())
+        })
+    }
+
+    /// Push the encoded task to `target` once, see `push_to`.
+    fn push_once(
+        &self,
+        target: &str,
+        task: Vec<u8>,
+        version: Option<u32>,
+        options: Option<JobOptions>,
+    ) -> Result<Option<String>, PushError> {
+        let delay = options.as_ref().and_then(|options| options.delay);
+        let ordering_key = options.as_ref().and_then(|options| options.ordering_key.clone());
+        if ordering_key.is_some() && delay.is_some() {
+            return Err(PushError::Encode(From::from((ErrorKind::TypeError, "Ordered jobs can't be delayed"))));
+        }
+        if let (Some(max_size), None) = (self.max_size, delay) {
+            if self.pending_size(&self.connection()?)? >= max_size {
+                return Err(PushError::Full);
+            }
+        }
+        self.cached_size.set(None);
+        self.cached_delayed_size.set(None);
+
+        let mut job = match (Envelope::wrap(task), options.is_some()) {
+            (Ok(job), _) => job,
+            (Err(_), true) => {
+                return Err(PushError::Encode(From::from((ErrorKind::TypeError, "Task is not JSON encoded"))))
+            }
+            // Tasks not encoded as JSON are stored as they are
+            (Err(task), false) => {
+                self.connection()?.lpush::<_, _, ()>(target, task)?;
+                return Ok(None);
+            }
+        };
+
+        // Jobs are consumed from the tail, unless the queue is consumed newest-first
+        let urgent = options.as_ref().is_some_and(|options| options.priority > 0);
+        let push = match (urgent, self.order) {
+            (true, Order::Fifo) => "RPUSH",
+            _ => "LPUSH",
+        };
+        let unique = options.as_ref().and_then(|options| options.unique.clone());
+        job.options = options;
+        job.version = version;
+
+        let con = self.connection()?;
+        if let Some(ref unique) = unique {
+            if !lock::try_lock(&con, &unique.lock_name(), &job.jid, unique.lock_ttl())? {
+                return Err(PushError::Duplicate);
+            }
+        }
+        let pushed = self.enqueue(&con, &mut job, target, push, delay, ordering_key);
+        if let (&Err(_), Some(unique)) = (&pushed, unique) {
+            // Let the push be retried, the job is not in the queue
+            let mut pipe = redis::pipe();
+            lock::unlock(&mut pipe, &unique.lock_name(), &job.jid);
+            let _ = pipe.query::<()>(&con);
+        }
+        pushed?;
+        Ok(Some(job.jid))
+    }
+
+    /// Write `job` to `target`, the delayed set or its ordering key, see `push_once`.
+    fn enqueue(
+        &self,
+        con: &Connection,
+        job: &mut Envelope,
+        target: &str,
+        push: &str,
+        delay: Option<Duration>,
+        ordering_key: Option<String>,
+    ) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let parent = self.current.borrow().clone();
+        if let Some(parent) = parent {
+            job::track_child(&mut pipe, &parent, &job.jid, self.queue());
+            job.parent = Some(parent);
+        }
+        if let Some(key) = ordering_key {
+            pipe.query::<()>(con)?;
+            ordering::enqueue(con, self.queue(), &key, target, push, &job.jid, &job.encode())?;
+            return Ok(());
+        }
+        match delay {
+            Some(delay) => {
+                let at = server_millis(con)? + duration_millis(delay);
+                pipe.cmd("ZADD").arg(self.delayed_queue()).arg(at).arg(job.encode()).ignore()
+            }
+            None => pipe.cmd(push).arg(target).arg(job.encode()).ignore(),
+        };
+        pipe.query(con)
+    }
+
+    /// Push a task to be processed after the given delay
+    ///
+    /// The delay is counted from the current time of the Redis server, so clock skew of the
+    /// producer doesn't matter.
+    /// The task waits in the set of delayed tasks until a `Promoter` moves it to the queue.
+    pub fn push_delayed<T: TaskEncodable>(&self, task: T, delay: Duration) -> Result<(), PushError> {
+        let con = self.connection()?;
+        let at = server_millis(&con)? + duration_millis(delay);
+        self.push_at_millis(&con, task, at)
+    }
+
+    /// Push a task to be processed at the given time
+    ///
+    /// The task waits in the set of delayed tasks until a `Promoter` moves it to the queue.
+    pub fn push_at<T: TaskEncodable>(&self, task: T, at: SystemTime) -> Result<(), PushError> {
+        self.push_at_millis(&self.connection()?, task, unix_millis(at))
+    }
+
+    fn push_at_millis<T: TaskEncodable>(&self, con: &Connection, task: T, at: u64) -> Result<(), PushError> {
+        let data = match Envelope::wrap(task.try_encode_task().map_err(PushError::Encode)?) {
+            Ok(job) => job.encode(),
+            Err(task) => task,
+        };
+
+        Ok(con.zadd(self.delayed_queue(), data, at)?)
+    }
+
+    /// Move all delayed tasks which are due into the queue
+    ///
+    /// Returns the number of promoted tasks.
+    /// See `Promoter` for a component doing this continuously for several queues.
+    pub fn promote_delayed(&self) -> RedisResult<usize> {
+        let con = self.connection()?;
+        promoter::promote_due(&con, self.queue(), self.order, server_millis(&con)?, 100)
+    }
+
+    /// Register a task to be pushed to this queue repeatedly
+    ///
+    /// Recurring jobs are identified by their name: registering a job with an existing name
+    /// replaces it instead of adding another one, so jobs can safely be registered on every start.
+    /// The next run is only rescheduled if the interval changed.
+    ///
+    /// The task is pushed by a `Promoter` once per `interval`, the first time after one interval.
+    /// The task needs to be encoded as JSON.
+    pub fn register_recurring<T: TaskEncodable>(&self, name: &str, interval: Duration, task: T) -> RedisResult<()> {
+        self.register_recurring_with_jitter(name, interval, Duration::from_millis(0), task)
+    }
+
+    /// Register a task to be pushed to this queue repeatedly, spreading runs over a window
+    ///
+    /// Every run is delayed by an offset of up to `jitter`, which differs between jobs and runs,
+    /// so jobs registered with the same interval don't all fire at the same instant.
+    /// Offsets don't add up, runs stay `interval` apart on average.
+    /// See `register_recurring`.
+    pub fn register_recurring_with_jitter<T: TaskEncodable>(
+        &self,
+        name: &str,
+        interval: Duration,
+        jitter: Duration,
+        task: T,
+    ) -> RedisResult<()> {
+        let task = task.try_encode_task()?;
+        recurring::register(&self.connection()?, self.queue(), name, interval, jitter, task)
+    }
+
+    /// Remove the recurring job `name`
+    ///
+    /// Returns `true` if such a job was registered.
+    pub fn unregister_recurring(&self, name: &str) -> RedisResult<bool> {
+        recurring::unregister(&self.connection()?, self.queue(), name)
+    }
+
+    /// List all recurring jobs of this queue, ordered by their next run
+    pub fn recurring(&self) -> RedisResult<Vec<RecurringJob>> {
+        recurring::list(&self.read_connection()?, self.queue())
+    }
+
+    /// Push one task to several queues at once
+    ///
+    /// `names` are the queue names as passed to `Queue::new`.
+    /// If this queue is scoped to a tenant, the queues are scoped to the same tenant.
+    /// Every queue receives its own job, so each consumer handles the task independently.
+    /// All jobs are pushed atomically in a single round trip.
+    pub fn broadcast<T: TaskEncodable>(&self, task: T, names: &[&str]) -> RedisResult<()> {
+        let task = task.try_encode_task()?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for name in names {
+            let queue = self.sibling(name);
+            match Envelope::wrap(task.clone()) {
+                Ok(job) => pipe.cmd("LPUSH").arg(queue).arg(job.encode()).ignore(),
+                Err(task) => pipe.cmd("LPUSH").arg(queue).arg(task).ignore(),
+            };
+        }
+
+        pipe.query(&self.connection()?)
+    }
+
+    /// Push a task to each of several queues at once, e.g. the jobs following from one event
+    ///
+    /// `tasks` pairs queue names, as passed to `Queue::new`, with the task for that queue.
+    /// If this queue is scoped to a tenant, the queues are scoped to the same tenant.
+    /// The queues are taken to be set up like this one: tasks are spread over the same number of
+    /// shards, versioned by the same migrations and the `with_max_size` limit applies to each.
+    ///
+    /// All tasks are encoded and all sizes checked before anything is pushed, then they are
+    /// pushed atomically in a single round trip. If one of the queues is full, nothing is pushed
+    /// and it fails with `PushError::Full`. Transient failures are retried, see
+    /// `with_transient_retries`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// queue.push_many(&[
+    ///     ("emails", Event::OrderPaid { order: 42 }),
+    ///     ("invoices", Event::OrderPaid { order: 42 }),
+    /// ])?;
+    /// ```
+    pub fn push_many<T: TaskEncodable>(&self, tasks: &[(&str, T)]) -> Result<(), PushError> {
+        let mut jobs = vec![];
+        for &(name, ref task) in tasks {
+            jobs.push((self.sibling_queue(name), task.try_encode_task().map_err(PushError::Encode)?));
+        }
+        let version = self.version::<T>();
+        transient::retry(self.retries, "push", is_transient_push, || self.push_many_once(&jobs, version))
+    }
+
+    /// Push the encoded tasks to their queues once, see `push_many`.
+    fn push_many_once(&self, jobs: &[(Queue, Vec<u8>)], version: Option<u32>) -> Result<(), PushError> {
+        let con = self.connection()?;
+        for (queue, _) in jobs {
+            if let Some(max_size) = queue.max_size {
+                if queue.pending_size(&con)? >= max_size {
+                    return Err(PushError::Full);
+                }
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (queue, task) in jobs {
+            // Jobs are spread over the shards by their id, tasks without one go round robin
+            match Envelope::wrap(task.clone()) {
+                Ok(mut job) => {
+                    job.version = version;
+                    pipe.cmd("LPUSH").arg(queue.target(Some(&job.jid))).arg(job.encode()).ignore()
+                }
+                Err(task) => pipe.cmd("LPUSH").arg(queue.target(None)).arg(task).ignore(),
+            };
+        }
+        pipe.query::<()>(&con)?;
+        self.cached_size.set(None);
+        Ok(())
+    }
+
+    /// Get the queue `name` set up like this one, see `sibling`.
+    fn sibling_queue(&self, name: &str) -> Queue {
+        let suffix = &self.backup_queue[self.queue_name.len()..];
+        let queue_name = self.sibling(name);
+        Queue {
+            backup_queue: format!("{}{}", queue_name, suffix),
+            queue_name: queue_name,
+            cached_size: Cell::new(None),
+            cached_delayed_size: Cell::new(None),
+            cached_config: Cell::new(None),
+            config: Cell::new(None),
+            ..self.clone()
+        }
+    }
+
+    /// Split `items` into jobs of `chunk_size` items each and push them
+    ///
+    /// Every job is a `ChunkTask` holding the items of its chunk, the last one may hold fewer.
+    /// Items are consumed lazily, so millions of records can be fanned out without loading them
+    /// all at once. Jobs are written in pipelines of 100 jobs. Chunks of a few hundred items keep
+    /// the number of jobs low while a failing chunk doesn't repeat too much work.
+    ///
+    /// Jobs pushed before an error stay in the queue. Returns the number of pushed jobs.
+    pub fn push_chunked<I, It>(&self, items: It, chunk_size: usize) -> RedisResult<usize>
+    where
+        I: Serialize,
+        It: IntoIterator<Item = I>,
+    {
+        const PIPELINE: usize = 100;
+        let chunk_size = cmp::max(1, chunk_size);
+        let con = self.connection()?;
+        let mut items = items.into_iter().peekable();
+        let mut pushed = 0;
+
+        while items.peek().is_some() {
+            let mut pipe = redis::pipe();
+            let mut chunks = 0;
+            while chunks < PIPELINE && items.peek().is_some() {
+                let chunk = ChunkTask {
+                    items: items.by_ref().take(chunk_size).collect(),
+                    partial: None,
+                };
+                self.push_in_pipeline(&mut pipe, chunk)?;
+                chunks += 1;
+            }
+            pipe.query::<()>(&con)?;
+            pushed += chunks;
+        }
+
+        Ok(pushed)
+    }
+
+    /// Split `items` into chunk jobs like `push_chunked` and reduce their results
+    ///
+    /// The chunks are pushed to this queue as a batch. Each chunk stores its partial result with
+    /// `store_partial`. Once the last chunk finished, a `ReduceTask` holding the keys of all
+    /// partial results is pushed to the queue `reduce`, even if some chunks failed. Its handler
+    /// loads them with `partials` and removes them with `delete_partials` when it's done.
+    /// Partial results which are never reduced expire after 30 days.
+    ///
+    /// A chunk counts as finished once it completed, was discarded or moved to the dead letter
+    /// queue. Chunks failing without `max_attempts` hold up the reduce step until they complete.
+    ///
+    /// Returns the id of the batch, see `batch_status`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// orders.push_map_reduce(order_ids, 500, "order-totals")?;
+    ///
+    /// // Map
+    /// Worker::new(orders.clone()).run(move |chunk: ChunkTask<u64>, _token| {
+    ///     let total: u64 = chunk.items.iter().map(|&id| amount(id)).sum();
+    ///     orders.store_partial(&chunk, &total)
+    /// });
+    ///
+    /// // Reduce
+    /// Worker::new(totals.clone()).run(move |reduce: ReduceTask, _token| {
+    ///     let total: u64 = totals.partials::<u64>(&reduce)?.into_iter().flatten().sum();
+    ///     report(total);
+    ///     totals.delete_partials(&reduce)
+    /// });
+    /// ```
+    pub fn push_map_reduce<I, It>(&self, items: It, chunk_size: usize, reduce: &str) -> RedisResult<String>
+    where
+        I: Serialize,
+        It: IntoIterator<Item = I>,
+    {
+        self.push_batch(chunk::map_batch(items, cmp::max(1, chunk_size), reduce))
+    }
+
+    /// Store the partial result of a chunk pushed with `push_map_reduce`
+    ///
+    /// Store it as the last step of the handler, a chunk must not fail to be retried after its
+    /// partial result was stored: the result would be kept even if the chunk ends up
+    /// dead-lettered.
+    /// Fails for chunks which are not part of a map-reduce.
+    pub fn store_partial<I, R: TaskEncodable>(&self, chunk: &ChunkTask<I>, result: &R) -> RedisResult<()> {
+        match chunk.partial {
+            Some(ref key) => chunk::store(&self.connection()?, key, &result.try_encode_task()?),
+            None => Err(From::from((ErrorKind::TypeError, "Chunk is not part of a map-reduce"))),
+        }
+    }
+
+    /// Load the partial results of all chunks of a map-reduce, in the order of the chunks
+    ///
+    /// Chunks which failed or didn't store a result are `None`.
+    pub fn partials<R: TaskDecodable>(&self, reduce: &ReduceTask) -> RedisResult<Vec<Option<R>>> {
+        chunk::load(&self.connection()?, &reduce.partials)
+    }
+
+    /// Delete the partial results of all chunks of a map-reduce
+    pub fn delete_partials(&self, reduce: &ReduceTask) -> RedisResult<()> {
+        chunk::delete(&self.connection()?, &reduce.partials)
+    }
+
+    /// Get the full name of the queue `name` of the same tenant as this queue.
+    fn sibling(&self, name: &str) -> String {
+        match self.tenant {
+            Some(ref tenant) => format!("oppgave:{}", tenant_queue(tenant, name)),
+            None => format!("oppgave:{}", name),
+        }
+    }
+
+    /// List the jobs enqueued while processing the job `jid`, together with their state
+    pub fn children(&self, jid: &str) -> RedisResult<Vec<Child>> {
+        job::children(&self.read_connection()?, jid)
+    }
+
+    /// Subscribe to the live events of the job `jid`, e.g. to show its progress to a user
+    ///
+    /// See `JobEvents`. Subscribe before the job starts to get all of its events.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// for event in queue.subscribe(&jid)? {
+    ///     if let JobEvent::Progress { current, total, .. } = event? {
+    ///         println!("Step {} of {}", current, total);
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe(&self, jid: &str) -> RedisResult<JobEvents> {
+        JobEvents::subscribe(&self.client, jid)
+    }
+
+    /// Wait until the job `jid` finished and get its outcome, or `JobResult::TimedOut` after `timeout`
+    ///
+    /// The workers of the queue need to store results, see `with_results`. Failed jobs are only
+    /// final once they are moved to the dead letter queue, e.g. after running out of
+    /// `max_attempts`. Results stay available for their whole TTL, so any number of producers
+    /// can wait for the same job.
+    ///
+    /// The wait blocks the current thread. From async code, wait on a blocking thread pool, e.g.
+    /// with `tokio::task::spawn_blocking`, see "Async runtimes" in the README.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// match queue.await_result::<String>(jid, Duration::from_secs(30))? {
+    ///     JobResult::Completed(url) => println!("Resized to {}", url),
+    ///     JobResult::Failed { error } => println!("Failed: {:?}", error),
+    ///     JobResult::Discarded | JobResult::TimedOut => {}
+    /// }
+    /// ```
+    pub fn await_result<R: TaskDecodable>(&self, jid: &str, timeout: Duration) -> RedisResult<JobResult<R>> {
+        let con = self.connection()?;
+        let lmove = self.detect(&con)?.has_lmove();
+        results::await_result(&con, jid, Instant::now() + timeout, lmove)
+    }
+
+    /// Wait until all jobs of `jids` finished and get their outcomes, in the order of `jids`
+    ///
+    /// Fans in jobs pushed independently, without setting up a `Batch` or `Workflow`.
+    /// The `timeout` applies to the whole set: jobs which didn't finish in time are
+    /// `JobResult::TimedOut`. See `await_result`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let results = queue.await_all::<u64, _>(&jids, Duration::from_secs(60))?;
+    /// let total: u64 = results.iter().filter_map(|result| match *result {
+    ///     JobResult::Completed(size) => Some(size),
+    ///     _ => None,
+    /// }).sum();
+    /// ```
+    pub fn await_all<R: TaskDecodable, S: AsRef<str>>(&self, jids: &[S], timeout: Duration) -> RedisResult<Vec<JobResult<R>>> {
+        let con = self.connection()?;
+        let lmove = self.detect(&con)?.has_lmove();
+        results::await_all(&con, jids, Instant::now() + timeout, lmove)
+    }
+
+    /// Get the lines logged by the handlers of the job `jid`, oldest first
+    ///
+    /// See `TaskGuard::log`.
+    pub fn job_log(&self, jid: &str) -> RedisResult<Vec<LogLine>> {
+        job::log(&self.read_connection()?, jid)
+    }
+
+    /// Push a chain of tasks
+    ///
+    /// The first task of the chain is pushed to this queue, all following tasks are enqueued
+    /// one after another as their predecessors complete successfully.
+    pub fn push_chain(&self, chain: Chain) -> RedisResult<()> {
+        let job = chain.into_envelope()?;
+        self.connection()?.lpush(self.queue(), job.encode())
+    }
+
+    /// Push a batch of tasks
+    ///
+    /// All tasks of the batch are pushed to this queue at once.
+    /// Returns the id of the batch, which can be used to check its progress.
+    pub fn push_batch(&self, batch: Batch) -> RedisResult<String> {
+        let (bid, pipe) = batch.into_pipeline(self.queue())?;
+        pipe.query::<()>(&self.connection()?)?;
+        Ok(bid)
+    }
+
+    /// Get the progress of the batch with the given id
+    ///
+    /// Returns `None` if the batch is unknown or already finished.
+    pub fn batch_status(&self, bid: &str) -> RedisResult<Option<BatchStatus>> {
+        batch::status(&self.read_connection()?, bid)
+    }
+
+    /// Push a workflow of tasks
+    ///
+    /// Tasks without dependencies are pushed right away, all others are pushed once their
+    /// dependencies completed.
+    /// Returns the id of the workflow, which can be used to check its progress.
+    pub fn push_workflow(&self, workflow: Workflow) -> RedisResult<String> {
+        let (wid, pipe) = workflow.into_pipeline(self.queue())?;
+        pipe.query::<()>(&self.connection()?)?;
+        Ok(wid)
+    }
+
+    /// Get the progress of the workflow with the given id
+    ///
+    /// Returns `None` if the workflow is unknown or already completed.
+    pub fn workflow_status(&self, wid: &str) -> RedisResult<Option<WorkflowStatus>> {
+        workflow::status(&self.read_connection()?, wid)
+    }
+
+    /// Decode the next task without reserving it
+    ///
+    /// Returns `None` if the queue is empty.
+    pub fn peek<T: TaskDecodable>(&self) -> RedisResult<Option<T>> {
+        self.peek_many(1).map(|mut tasks| tasks.pop())
+    }
+
+    /// Decode up to `n` of the next tasks without reserving them, next task first
+    ///
+    /// The tasks stay in the queue and may be fetched by a worker at any time.
+    /// Sharded queues are looked at one shard after another.
+    pub fn peek_many<T: TaskDecodable>(&self, n: usize) -> RedisResult<Vec<T>> {
+        let con = self.read_connection()?;
+        let mut tasks = vec![];
+
+        for source in self.sources() {
+            if tasks.len() >= n {
+                break;
+            }
+            let want = n - tasks.len();
+
+            // Tasks are pushed to the head, so the next task is at the tail unless consumed newest-first
+            let (start, stop) = match self.order {
+                Order::Fifo => (-(want as isize), -1),
+                Order::Lifo => (0, want as isize - 1),
+            };
+            let mut data: Vec<Vec<u8>> = con.lrange(&source[..], start, stop)?;
+            if self.order == Order::Fifo {
+                data.reverse();
+            }
+
+            for data in data {
+                tasks.push(envelope::decode(data)?);
+            }
+        }
+
+        Ok(tasks)
+    }
+
+    /// Check that Redis is reachable and the keys of the queue hold the expected types
+    ///
+    /// Pings the server, measuring the round-trip time, and checks the queue, its shards,
+    /// backup, delayed and dead keys. Never fails, problems are reported in the `Health`.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let health = queue.health();
+    /// let status = if health.is_healthy() { 200 } else { 503 };
+    /// respond(status, serde_json::to_string(&health)?);
+    /// ```
+    pub fn health(&self) -> Health {
+        let mut keys: Vec<(String, &str)> = self.sources().into_iter().map(|source| (source, "list")).collect();
+        keys.push((self.backup_queue.clone(), "list"));
+        keys.push((self.delayed_queue(), "zset"));
+        keys.push((self.dead_queue(), "zset"));
+        health::check(&self.client, &keys)
+    }
+
+    /// Get the approximate memory used by the pending, delayed and dead jobs of this queue
+    ///
+    /// Requires Redis 4.0 or later.
+    pub fn memory_usage(&self) -> RedisResult<MemoryUsage> {
+        memory::measure(&self.read_connection()?, self.queue(), &self.sources(), &self.delayed_queue())
+    }
+
+    /// Write all pending and delayed jobs to `writer` as newline-delimited JSON
+    ///
+    /// Pending jobs are written in the order they are stored, followed by the delayed jobs.
+    /// Entries which are not valid UTF-8 are written as an array of their bytes.
+    /// The jobs stay in the queue. Jobs fetched or pushed while exporting may be missed.
+    /// Returns the number of exported jobs.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let file = File::create("default.ndjson")?;
+    /// queue.export(&mut BufWriter::new(file))?;
+    /// ```
+    pub fn export<W: io::Write>(&self, writer: &mut W) -> RedisResult<usize> {
+        transfer::export(&self.read_connection()?, &self.sources(), &self.delayed_queue(), writer)
+    }
+
+    /// Add all jobs written by `export` to this queue
+    ///
+    /// Pending jobs are appended behind the jobs already in the queue, keeping their order.
+    /// Delayed jobs keep their due time. Jobs keep their ids, so importing twice duplicates them.
+    /// Returns the number of imported jobs.
+    pub fn import<R: io::BufRead>(&self, reader: R) -> RedisResult<usize> {
+        transfer::import(&self.connection()?, self.queue(), &self.delayed_queue(), reader)
+    }
+
+    /// Get the number of tasks fetched by workers and not finished yet
+    ///
+    /// Sums up the backup queues of all workers, so tasks failed and left in a backup queue
+    /// are counted as well.
+    /// Tasks of queues with `Delivery::AtMostOnce` are never counted.
+    /// See `size` for the number of pending tasks.
+    pub fn in_progress_count(&self) -> RedisResult<u64> {
+        discover::in_progress(&self.read_connection()?, self.queue())
+    }
+
+    /// Get the number of jobs ahead of the pending job `jid`
+    ///
+    /// Returns `Some(0)` if the job is fetched next, and `None` if the job is not pending.
+    /// For sharded queues, only the jobs in the same shard are counted.
+    pub fn position(&self, jid: &str) -> RedisResult<Option<usize>> {
+        let con = self.read_connection()?;
+        for source in self.sources() {
+            if let Some(position) = inspect::position(&con, &source, self.order, jid)? {
+                return Ok(Some(position));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the number of jobs finished per second, averaged over the last minutes
+    ///
+    /// All workers of the queue are counted, no matter if their jobs completed or failed.
+    pub fn processing_rate(&self) -> RedisResult<f64> {
+        throughput::rate(&self.read_connection()?, self.queue())
+    }
+
+    /// Suggest how many workers the queue needs
+    ///
+    /// Combines the number of pending jobs, how fast that number changed over the last minute,
+    /// the `processing_rate` and the average processing time of a job into the number of workers
+    /// needed to keep up with incoming jobs and to work off the backlog within `drain_within`.
+    /// Autoscalers can scale on `ScalingHint::workers` directly.
+    ///
+    /// Every call records the current number of pending jobs, the trend is based on the samples
+    /// of earlier calls. Call it regularly, e.g. every 15 seconds, for a meaningful trend.
+    /// While no jobs finished lately, one worker is suggested for a queue with pending jobs.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let hint = queue.scaling_hint(Duration::from_secs(120))?;
+    /// deployment.scale(cmp::min(hint.workers, 50));
+    /// ```
+    pub fn scaling_hint(&self, drain_within: Duration) -> RedisResult<ScalingHint> {
+        let con = self.connection()?;
+        let depth = self.pending_size(&con)?;
+        let trend = scaling::sample(&con, self.queue(), server_millis(&con)?, depth)?;
+        let (rate, processing_time) = throughput::stats(&con, self.queue())?;
+
+        Ok(ScalingHint::new(depth, trend, rate, processing_time, drain_within))
+    }
+
+    /// Estimate how long it takes until the pending job `jid` is finished
+    ///
+    /// The estimate is based on the position of the job and the current `processing_rate`.
+    /// Returns `None` if the job is not pending or no jobs finished lately.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// if let (Some(position), Some(eta)) = (queue.position(&jid)?, queue.eta(&jid)?) {
+    ///     println!("Your export is #{} in line, ~{} minutes", position + 1, eta.as_secs() / 60);
+    /// }
+    /// ```
+    pub fn eta(&self, jid: &str) -> RedisResult<Option<Duration>> {
+        let position = match self.position(jid)? {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let rate = self.processing_rate()?;
+        if rate <= 0.0 {
+            return Ok(None);
+        }
+
+        let millis = (position + 1) as f64 / rate * 1000.0;
+        Ok(Some(Duration::from_millis(millis as u64)))
+    }
+
+    /// Replace the task of the pending job `jid`, keeping its place in the queue
+    ///
+    /// The new task needs to be encoded as JSON.
+    /// Returns `false` if the job is not pending anymore, e.g. because a worker already fetched it.
+    /// Delayed jobs can't be updated.
+    pub fn update<T: TaskEncodable>(&self, jid: &str, task: T) -> RedisResult<bool> {
+        let task = Envelope::new(task.try_encode_task()?)?.task;
+        let con = self.connection()?;
+
+        for source in self.sources() {
+            let found = inspect::scan_list(&con, &source, self.order, 1, |data| {
+                Envelope::parse(data).is_some_and(|job| job.jid == jid)
+            })?;
+            if let Some(old) = found.into_iter().next() {
+                let mut job = Envelope::parse(&old).expect("Found job can't be invalid");
+                job.task = task;
+                return inspect::replace(&con, &source, &old, &job.encode());
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Decode up to `limit` pending tasks matching `predicate`, next task first
+    ///
+    /// `predicate` gets the task as JSON, tasks not encoded as JSON never match.
+    /// The queue is read in chunks without blocking workers, so tasks fetched or pushed while
+    /// searching may be missed.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let exports = queue.search::<Export, _>(|task| task["org"] == 42, 100)?;
+    /// ```
+    pub fn search<T, F>(&self, predicate: F, limit: usize) -> RedisResult<Vec<T>>
+    where
+        T: TaskDecodable,
+        F: Fn(&serde_json::Value) -> bool,
+    {
+        let con = self.read_connection()?;
+        let mut tasks = vec![];
+
+        for source in self.sources() {
+            let found = inspect::scan_list(&con, &source, self.order, limit - tasks.len(), |data| {
+                inspect::task_matches(data, &predicate)
+            })?;
+            for data in found {
+                tasks.push(envelope::decode(data)?);
+            }
+        }
+
+        Ok(tasks)
+    }
+
+    /// Decode up to `limit` pending tasks tagged with `tag`, next task first
+    ///
+    /// See `JobOptions::tags`. The whole queue is scanned, so this is slow for long queues.
+    pub fn tagged<T: TaskDecodable>(&self, tag: &str, limit: usize) -> RedisResult<Vec<T>> {
+        let con = self.read_connection()?;
+        let mut tasks = vec![];
+
+        for source in self.sources() {
+            let found = inspect::scan_list(&con, &source, self.order, limit - tasks.len(), |data| {
+                envelope::has_tag(data, tag)
+            })?;
+            for data in found {
+                tasks.push(envelope::decode(data)?);
+            }
+        }
+
+        Ok(tasks)
+    }
+
+    /// Move the next task of `source` into the backup queue, respecting the configured order.
+    ///
+    /// Blocks for up to `timeout` seconds if given, otherwise returns right away.
+    fn take(&self, con: &Connection, source: &str, timeout: Option<usize>) -> RedisResult<Value> {
+        let backup = &self.backup_queue[..];
+        let from = match self.order {
+            Order::Fifo => "RIGHT",
+            Order::Lifo => "LEFT",
+        };
+
+        // Older servers only move from the tail, which is all FIFO queues need
+        if self.order == Order::Fifo && !self.detect(con)?.has_lmove() {
+            return match timeout {
+                Some(timeout) => con.brpoplpush(source, backup, timeout),
+                None => con.rpoplpush(source, backup),
+            };
+        }
+        match timeout {
+            Some(timeout) => {
+                redis::cmd("BLMOVE")
+                    .arg(source)
+                    .arg(backup)
+                    .arg(from)
+                    .arg("LEFT")
+                    .arg(timeout)
+                    .query(con)
+            }
+            None => {
+                redis::cmd("LMOVE")
+                    .arg(source)
+                    .arg(backup)
+                    .arg(from)
+                    .arg("LEFT")
+                    .query(con)
+            }
+        }
+    }
+
+    /// Atomically move the next task into the backup queue, respecting the configured order.
+    ///
+    /// Blocks for up to `timeout` seconds if given, otherwise returns right away.
+    /// Returns the list the task was taken from together with the task.
+    fn reserve(&self, con: &Connection, timeout: Option<usize>) -> RedisResult<(String, Value)> {
+        if self.delivery == Delivery::AtMostOnce {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => {
+                    let sources = self.consumed();
+                    if self.detect(con)?.has_lmpop() {
+                        let end = match self.order {
+                            Order::Fifo => "RIGHT",
+                            Order::Lifo => "LEFT",
+                        };
+                        let popped: Option<(String, Vec<Vec<u8>>)> =
+                            redis::cmd("LMPOP").arg(sources.len()).arg(&sources[..]).arg(end).query(con)?;
+                        return Ok(match popped {
+                            Some((source, data)) => (source, data.into_iter().next().map_or(Value::Nil, Value::Data)),
+                            None => (String::new(), Value::Nil),
+                        });
+                    }
+
+                    let pop = match self.order {
+                        Order::Fifo => "RPOP",
+                        Order::Lifo => "LPOP",
+                    };
+                    for source in sources {
+                        let popped: Option<Vec<u8>> = redis::cmd(pop).arg(&source[..]).query(con)?;
+                        if let Some(data) = popped {
+                            return Ok((source, Value::Data(data)));
+                        }
+                    }
+                    return Ok((String::new(), Value::Nil));
+                }
+            };
+            let pop = match self.order {
+                Order::Fifo => "BRPOP",
+                Order::Lifo => "BLPOP",
+            };
+            let popped: Option<(String, Vec<u8>)> = redis::cmd(pop).arg(self.consumed()).arg(timeout).query(con)?;
+            return Ok(popped.map_or((String::new(), Value::Nil), |(source, data)| (source, Value::Data(data))));
+        }
+
+        if self.shards <= 1 {
+            return self.take(con, &self.queue_name, timeout).map(|v| (self.queue_name.clone(), v));
+        }
+
+        // Redis can't block on several lists while moving the task, so poll all shards and only
+        // block on one of them for a short while
+        let sources = self.consumed();
+        let mut waited = 0;
+        loop {
+            let start = self.next_shard.get();
+            self.next_shard.set(start.wrapping_add(1));
+            for i in 0..sources.len() {
+                let source = &sources[(start + i) % sources.len()];
+                match self.take(con, source, None)? {
+                    Value::Nil => {}
+                    v => return Ok((source.clone(), v)),
+                }
+            }
+
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return Ok((String::new(), Value::Nil)),
+            };
+            if timeout != 0 && waited >= timeout {
+                return Ok((String::new(), Value::Nil));
+            }
+            let source = &sources[start % sources.len()];
+            match self.take(con, source, Some(1))? {
+                Value::Nil => waited += 1,
+                v => return Ok((source.clone(), v)),
+            }
+        }
+    }
+
+    /// Grab the next task from the queue
+    ///
+    /// This method blocks for `timeout` ms and waits until a new task is available.
+    /// timeout of 0 will block indefinitely
+    pub fn next<T: TaskDecodable>(&self, timeout: usize) -> Option<RedisResult<TaskGuard<T>>> {
+        self.poll(Some(timeout)).map(Queue::require)
+    }
+
+    /// Grab the next task from the queue if one is available, without blocking
+    ///
+    /// Returns `Ok(None)` if the queue is empty or stopped.
+    /// Combine it with an `Idle` strategy to poll without hammering Redis.
+    pub fn try_next<T: TaskDecodable>(&self) -> RedisResult<Option<TaskGuard<T>>> {
+        self.poll(None).unwrap_or(Ok(None))
+    }
+
+    /// Reserve and decode the next task, blocking for up to `timeout` seconds if given.
+    ///
+    /// Returns `None` if the queue is stopped and `Ok(None)` if no task is available.
+    pub(crate) fn poll<T: TaskDecodable>(&self, timeout: Option<usize>) -> Option<RedisResult<Option<TaskGuard<T>>>> {
+        self.poll_as::<T, T>(timeout)
+    }
+
+    /// Like `poll`, but upgrade the task with the migrations of the task type `M`.
+    ///
+    /// Used by workers, which decode the task as JSON before decoding it as their task type.
+    pub(crate) fn poll_as<M, T: TaskDecodable>(&self, timeout: Option<usize>) -> Option<RedisResult<Option<TaskGuard<T>>>> {
+        self.fetch(timeout, |job, v| self.decode::<M, T>(job, v))
+    }
+
+    /// Decode a fetched task, upgrading it to the current version of `M` first.
+    fn decode<M, T: TaskDecodable>(&self, job: Option<&Envelope>, v: &Value) -> RedisResult<T> {
+        let migrated = match (&self.migrations, job) {
+            (Some(migrations), Some(job)) => migrations.migrate::<M>(job.version, job.task.get().as_bytes())?,
+            (Some(migrations), None) => match *v {
+                Value::Data(ref data) => migrations.migrate::<M>(None, data)?,
+                _ => None,
+            },
+            (None, _) => None,
+        };
+
+        match (migrated, job) {
+            (Some(task), _) => T::decode_task(&task),
+            (None, Some(job)) => T::decode_task(&job.task_value()),
+            (None, None) => T::decode_task(v),
+        }
+    }
+
+    /// Grab the next entry from the queue without decoding it
+    ///
+    /// The entry is handed out exactly as it was stored, e.g. for relaying it to another system.
+    /// Jobs pushed with `push` come with their metadata, see `TaskGuard::raw`.
+    /// Blocks like `next`.
+    pub fn next_raw(&self, timeout: usize) -> Option<RedisResult<TaskGuard<Vec<u8>>>> {
+        let next = self.fetch(Some(timeout), |_, v| match *v {
+            Value::Data(ref data) => Ok(data.clone()),
+            _ => Err(From::from((ErrorKind::TypeError, "Not a proper reply"))),
+        });
+        next.map(Queue::require)
+    }
+
+    /// Turn a missing task into an error, as returned by `next` after a timeout.
+    fn require<G>(fetched: RedisResult<Option<G>>) -> RedisResult<G> {
+        match fetched {
+            Ok(Some(guard)) => Ok(guard),
+            Ok(None) => Err(From::from((ErrorKind::TypeError, "Not a proper reply"))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Take all locks the reserved job `data` needs.
+    ///
+    /// If one is taken by another job, the locks taken so far are released and the job is
+    /// skipped or delayed. Returns `false` in that case. Skipped jobs are finished as failed.
+    fn lock_job(&self, con: &Connection, job: &Envelope, rid: &str, data: &[u8]) -> RedisResult<bool> {
+        let options = match job.options {
+            Some(ref options) => options,
+            None => return Ok(true),
+        };
+        let locks = options.locks();
+        for (i, &(ref name, overlap)) in locks.iter().enumerate() {
+            if lock::try_lock(con, name, rid, options.lock_ttl())? {
+                continue;
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for (name, _) in &locks[..i] {
+                lock::unlock(&mut pipe, name, rid);
+            }
+            options::resolve_overlap(&mut pipe, &self.backup_queue, &self.delayed_queue(), data, overlap);
+            if overlap == Overlap::Skip {
+                job.finish(&mut pipe, true, None);
+                if let Some(ref unique) = options.unique {
+                    unique.release(&mut pipe, rid, None);
+                }
+            }
+            pipe.query::<()>(con)?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Reserve the next entry and decode it with `decode`.
+    ///
+    /// Returns `None` if the queue is stopped and `Ok(None)` if no entry is available.
+    fn fetch<T, F>(&self, timeout: Option<usize>, decode: F) -> Option<RedisResult<Option<TaskGuard<T>>>>
+    where
+        F: Fn(Option<&Envelope>, &Value) -> RedisResult<T>,
+    {
+        if self.stopped.get() {
+            return None;
+        }
+
+        let con = match self.connection() {
+            Ok(con) => con,
+            Err(_) => {
+                return Some(Err(From::from((ErrorKind::TypeError, "next failed"))));
+            }
+        };
+
+        // Skipped jobs are finished, so the next one is fetched right away
+        loop {
+            let (source, v) = match self.reserve(&con, timeout) {
+                Ok(reserved) => reserved,
+                Err(_) => {
+                    return Some(Err(From::from((ErrorKind::TypeError, "next failed"))));
+                }
+            };
+
+            let started_at = now_millis();
+            let (job, data) = match v {
+                Value::Data(ref data) => (Envelope::parse(data), data.clone()),
+                Value::Nil => return Some(Ok(None)),
+                _ => {
+                    return Some(Err(
+                        From::from((ErrorKind::TypeError, "Not a proper reply")),
+                    ));
+                }
+            };
+
+            if let Some(ref kinds) = self.kinds {
+                if !kinds.accepts(envelope::kind(job.as_ref(), &data).as_ref().map(|kind| &kind[..])) {
+                    // Leave the task to other workers, at the end of its list fetched last
+                    let push = match self.order {
+                        Order::Fifo => "LPUSH",
+                        Order::Lifo => "RPUSH",
+                    };
+                    let mut pipe = redis::pipe();
+                    pipe.atomic()
+                        .cmd("LREM").arg(&self.backup_queue[..]).arg(-1).arg(&data[..]).ignore()
+                        .cmd(push).arg(&source[..]).arg(&data[..]).ignore();
+                    return match pipe.query::<()>(&con) {
+                        Ok(()) => Some(Ok(None)),
+                        Err(e) => Some(Err(e)),
+                    };
+                }
+            }
+
+            let task = match decode(job.as_ref(), &v) {
+                Ok(task) => task,
+                Err(e) => return Some(Err(e)),
+            };
+
+            // Tasks without job metadata are tracked under a fresh id
+            let rid = job.as_ref().map(|job| job.jid.clone()).unwrap_or_else(envelope::new_jid);
+
+            // Deadlines are due by the time of the server, like delayed jobs
+            let deadline = job.as_ref()
+                .and_then(|job| job.options.as_ref())
+                .and_then(|options| options.deadline);
+            let expired = match deadline {
+                Some(deadline) => match server_millis(&con) {
+                    Ok(now) => Some(deadline).filter(|deadline| deadline.at < now),
+                    Err(e) => return Some(Err(e)),
+                },
+                None => None,
+            };
+            if let Some(deadline) = expired {
+                let guard = self.guard(task, data, rid, job, started_at);
+                match deadline.expiry {
+                    Expiry::DeadLetter => guard.dead_letter("Deadline passed before the job started"),
+                    Expiry::Discard => guard.discard(),
+                }
+                drop(guard);
+                continue;
+            }
+
+            if let Some(ref envelope) = job {
+                match self.lock_job(&con, envelope, &rid, &data) {
+                    Ok(true) => {}
+                    // Another instance is running, the job was skipped or delayed
+                    Ok(false) => continue,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            // Bookkeeping is informational only, the task is reserved already
+            let mut pipe = redis::pipe();
+            if self.delivery == Delivery::AtLeastOnce {
+                processing::reserve(&mut pipe, self.queue(), &rid, &data, &self.worker_id, &self.backup_queue);
+            }
+            if let Some(ref job) = job {
+                if job.parent.is_some() {
+                    job::set_status(&mut pipe, &job.jid, JobStatus::Running);
+                }
+            }
+            let unique = job.as_ref()
+                .and_then(|job| job.options.as_ref())
+                .and_then(|options| options.unique.as_ref());
+            if let Some(unique) = unique.filter(|unique| unique.uniqueness == Uniqueness::UntilStarted) {
+                lock::unlock(&mut pipe, &unique.lock_name(), &rid);
+            }
+            let _ = pipe.query::<()>(&con);
+            *self.current.borrow_mut() = job.as_ref().map(|job| job.jid.clone());
+
+            return Some(Ok(Some(self.guard(task, data, rid, job, started_at))));
+        }
+    }
+
+    /// Wrap a reserved task, completing it once dropped.
+    fn guard<T>(&self, task: T, data: Vec<u8>, rid: String, job: Option<Envelope>, started_at: u64) -> TaskGuard<T> {
+        TaskGuard {
+            task: Some(task),
+            queue: self,
+            outcome: Cell::new(Outcome::Complete),
+            error: RefCell::new(None),
+            result: RefCell::new(None),
+            data: data,
+            rid: rid,
+            job: job,
+            started_at: started_at,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    extern crate redis;
+
+    use redis::Commands;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+    use super::{Queue, TaskGuard, Order, Delivery, Chain, Batch, Workflow, JobStatus, Route, Router, Promoter,
+                Retention, Worker, CancellationToken, CircuitBreaker, BreakerState, Control, list_workers, send_control, worker_dump,
+                discover, discover_tenant, tenants, tenant_queue, JobOptions, global_stats,
+                Idle, IdleStrategy, PushError, Singleton, lock, ConcurrencyLimits, Migrations, Dispatcher, RetryBudget, TaskOutcome, FailedJob, Monitor, Alert, WorkerState, JobEvent, JobResult, Cancellation, install_functions,
+                functions_version, use_functions, Capabilities,
+                Instrumented, Outbox, OutboxEntry, Relay,
+                PriorityQueue, Lane, create_queue, delete_queue, list_queues,
+                queue_meta, QueueMeta, QueueConfig, ScalingHint, StalledJob, Deadline, Unique,
+                ChunkTask, ReduceTask, CommandTask, CommandError, Classify, execute,
+                ResourceGate, ResourceUsage};
+    use envelope::Envelope;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    #[derive(Deserialize, Serialize)]
+    struct Job {
+        id: u64,
+    }
+
+    /// Get `queue` as used by a worker running on a thread spawned without a name.
+    fn on_spawned_thread(queue: &Queue) -> Queue {
+        let queue = queue.clone();
+        thread::spawn(move || queue.on_current_thread()).join().unwrap()
+    }
+
+    impl Route for Job {
+        const QUEUE: &'static str = "routed";
+    }
+
+    #[test]
+    fn decodes_job() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("default".into(), client);
+
+        let _: () = con.rpush(worker.queue(), "{\"id\":42}").unwrap();
+
+        let j = worker.next::<Job>(0).unwrap().unwrap();
+        assert_eq!(42, j.id);
+    }
+
+    #[test]
+    fn releases_job() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("default".into(), client);
+        let bqueue = worker.backup_queue();
+
+        let _: () = con.del(bqueue).unwrap();
+        let _: () = con.lpush(worker.queue(), "{\"id\":42}").unwrap();
+
+        {
+            let j = worker.next::<Job>(0).unwrap().unwrap();
+            assert_eq!(42, j.id);
+            let in_backup: Vec<String> = con.lrange(bqueue, 0, -1).unwrap();
+            assert_eq!(1, in_backup.len());
+            assert_eq!("{\"id\":42}", in_backup[0]);
+        }
+
+        let in_backup: u32 = con.llen(bqueue).unwrap();
+        assert_eq!(0, in_backup);
+    }
+
+    #[test]
+    fn can_be_stopped() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("stopper".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.lpush(worker.queue(), "{\"id\":1}").unwrap();
+        let _: () = con.lpush(worker.queue(), "{\"id\":2}").unwrap();
+        let _: () = con.lpush(worker.queue(), "{\"id\":3}").unwrap();
+
+        assert_eq!(3, worker.size());
 
         while let Some(task) = worker.next::<Job>(0) {
             let _task = task.unwrap();
             worker.stop();
         }
 
-        assert_eq!(2, worker.size());
+        assert_eq!(2, worker.size());
+    }
+
+    #[test]
+    fn can_enqueue() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+
+        let worker = Queue::new("enqueue".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+
+        assert_eq!(0, worker.size());
+
+        worker.push(Job { id: 53 }).unwrap();
+
+        assert_eq!(1, worker.size());
+
+        let j = worker.next::<Job>(0).unwrap().unwrap();
+        assert_eq!(53, j.id);
+    }
+
+    #[test]
+    fn does_not_drop_failed() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("failure".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        let _: () = con.lpush(worker.queue(), "{\"id\":1}").unwrap();
+
+        {
+            let task: TaskGuard<Job> = worker.next(0).unwrap().unwrap();
+            task.fail();
+        }
+
+        let len: u32 = con.llen(worker.backup_queue()).unwrap();
+        assert_eq!(1, len);
+    }
+
+    #[test]
+    fn consumes_newest_first() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("lifo".into(), client).with_order(Order::Lifo);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
+        worker.push(Job { id: 2 }).unwrap();
+        worker.push(Job { id: 3 }).unwrap();
+
+        let j = worker.next::<Job>(0).unwrap().unwrap();
+        assert_eq!(3, j.id);
+        drop(j);
+
+        let j = worker.next::<Job>(0).unwrap().unwrap();
+        assert_eq!(2, j.id);
+    }
+
+    #[test]
+    fn enqueues_chain_on_success() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let first = Queue::new("chain-first".into(), client.clone());
+        let second = Queue::new("chain-second".into(), client);
+
+        let _: () = con.del(first.queue()).unwrap();
+        let _: () = con.del(second.queue()).unwrap();
+
+        let chain = Chain::new(Job { id: 1 }).then("chain-second", Job { id: 2 });
+        first.push_chain(chain).unwrap();
+        assert_eq!(0, second.size());
+
+        {
+            let j = first.next::<Job>(0).unwrap().unwrap();
+            assert_eq!(1, j.id);
+            assert_eq!(0, second.size());
+        }
+
+        assert_eq!(1, second.size());
+        let j = second.next::<Job>(0).unwrap().unwrap();
+        assert_eq!(2, j.id);
+    }
+
+    #[test]
+    fn does_not_enqueue_chain_on_failure() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let first = Queue::new("chain-failing".into(), client.clone());
+        let second = Queue::new("chain-never".into(), client);
+
+        let _: () = con.del(first.queue()).unwrap();
+        let _: () = con.del(second.queue()).unwrap();
+
+        first.push_chain(Chain::new(Job { id: 1 }).then("chain-never", Job { id: 2 })).unwrap();
+
+        {
+            let j = first.next::<Job>(0).unwrap().unwrap();
+            j.fail();
+        }
+
+        assert_eq!(0, second.size());
+    }
+
+    #[test]
+    fn runs_batch_callbacks() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("batch-members".into(), client.clone());
+        let succeeded = Queue::new("batch-success".into(), client.clone());
+        let completed = Queue::new("batch-complete".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(succeeded.queue()).unwrap();
+        let _: () = con.del(completed.queue()).unwrap();
+        let _: () = con.del(format!("{}:dead", worker.queue())).unwrap();
+        worker.set_config(&QueueConfig { max_attempts: Some(2), ..QueueConfig::default() }).unwrap();
+        worker.refresh_config(Duration::from_secs(0));
+
+        let batch = Batch::new()
+            .push(Job { id: 1 })
+            .push(Job { id: 2 })
+            .on_success("batch-success", Job { id: 10 })
+            .on_complete("batch-complete", Job { id: 11 });
+        let bid = worker.push_batch(batch).unwrap();
+
+        let status = worker.batch_status(&bid).unwrap().unwrap();
+        assert_eq!(2, status.total);
+        assert_eq!(2, status.pending);
+
+        {
+            let _j = worker.next::<Job>(0).unwrap().unwrap();
+        }
+        assert_eq!(1, worker.batch_status(&bid).unwrap().unwrap().pending);
+        assert_eq!(0, completed.size());
+
+        {
+            let j = worker.next::<Job>(0).unwrap().unwrap();
+            j.fail();
+        }
+        // The member is retried, so the batch isn't finished yet
+        assert_eq!(1, worker.batch_status(&bid).unwrap().unwrap().pending);
+        assert_eq!(0, completed.size());
+
+        {
+            let j = worker.next::<Job>(0).unwrap().unwrap();
+            j.fail();
+        }
+
+        assert_eq!(None, worker.batch_status(&bid).unwrap());
+        assert_eq!(1, completed.size());
+        assert_eq!(0, succeeded.size());
+    }
+
+    #[test]
+    fn releases_workflow_nodes_after_parents() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("workflow".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+
+        let mut workflow = Workflow::new();
+        let a = workflow.add(Job { id: 1 }, &[]);
+        let b = workflow.add(Job { id: 2 }, &[]);
+        workflow.add(Job { id: 3 }, &[a, b]);
+        let wid = worker.push_workflow(workflow).unwrap();
+
+        assert_eq!(2, worker.size());
+        {
+            let _j = worker.next::<Job>(0).unwrap().unwrap();
+        }
+        assert_eq!(1, worker.size());
+        {
+            let _j = worker.next::<Job>(0).unwrap().unwrap();
+        }
+        assert_eq!(1, worker.size());
+        assert_eq!(1, worker.workflow_status(&wid).unwrap().unwrap().pending);
+
+        {
+            let j = worker.next::<Job>(0).unwrap().unwrap();
+            assert_eq!(3, j.id);
+        }
+        assert_eq!(None, worker.workflow_status(&wid).unwrap());
+    }
+
+    #[test]
+    fn tracks_children() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("children".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
+
+        let parent = {
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            task.queue().push(Job { id: 2 }).unwrap();
+            task.jid().unwrap().to_string()
+        };
+
+        let children = worker.children(&parent).unwrap();
+        assert_eq!(1, children.len());
+        assert_eq!(Some(JobStatus::Pending), children[0].status);
+
+        {
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            assert_eq!(2, task.id);
+            assert_eq!(Some(&children[0].jid[..]), task.jid());
+        }
+
+        let children = worker.children(&parent).unwrap();
+        assert_eq!(Some(JobStatus::Completed), children[0].status);
+    }
+
+    #[test]
+    fn broadcasts_to_all_queues() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let producer = Queue::new("broadcast".into(), client.clone());
+        let first = Queue::new("broadcast-a".into(), client.clone());
+        let second = Queue::new("broadcast-b".into(), client);
+
+        let _: () = con.del(producer.queue()).unwrap();
+        let _: () = con.del(first.queue()).unwrap();
+        let _: () = con.del(second.queue()).unwrap();
+
+        producer.broadcast(Job { id: 7 }, &["broadcast-a", "broadcast-b"]).unwrap();
+
+        assert_eq!(0, producer.size());
+        assert_eq!(7, first.next::<Job>(0).unwrap().unwrap().id);
+        assert_eq!(7, second.next::<Job>(0).unwrap().unwrap().id);
+    }
+
+    #[test]
+    fn routes_by_type() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let router = Router::new(client);
+        let worker = router.queue::<Job>();
+
+        let _: () = con.del(worker.queue()).unwrap();
+        assert_eq!("oppgave:routed", worker.queue());
+
+        router.push(Job { id: 5 }).unwrap();
+        assert_eq!(5, worker.next::<Job>(0).unwrap().unwrap().id);
+    }
+
+    #[test]
+    fn promotes_due_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("delayed".into(), client.clone());
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.delayed_queue()).unwrap();
+
+        worker.push_delayed(Job { id: 1 }, Duration::from_secs(0)).unwrap();
+        worker.push_delayed(Job { id: 2 }, Duration::from_secs(3600)).unwrap();
+        assert_eq!(0, worker.size());
+        assert_eq!(2, worker.delayed_size());
+
+        let promoter = Promoter::new(client).queue("delayed").batch_size(1);
+        assert_eq!(1, promoter.promote().unwrap());
+        assert_eq!(0, promoter.promote().unwrap());
+
+        assert_eq!(1, worker.size());
+        assert_eq!(1, worker.delayed_size());
+        assert_eq!(1, worker.next::<Job>(0).unwrap().unwrap().id);
+    }
+
+    #[test]
+    fn registers_recurring_jobs_once() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("recurring".into(), client);
+
+        let _: () = con.del(format!("{}:recurring", queue.queue())).unwrap();
+
+        queue.register_recurring("report", Duration::from_secs(60), Job { id: 1 }).unwrap();
+        queue.register_recurring("report", Duration::from_secs(120), Job { id: 2 }).unwrap();
+
+        let jobs = queue.recurring().unwrap();
+        assert_eq!(1, jobs.len());
+        assert_eq!("report", jobs[0].name);
+        assert_eq!(Duration::from_secs(120), jobs[0].interval);
+        assert_eq!("{\"id\":2}", jobs[0].task);
+
+        assert!(queue.unregister_recurring("report").unwrap());
+        assert!(queue.recurring().unwrap().is_empty());
+    }
+
+    #[test]
+    fn archives_completed_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let retention = Retention {
+            max_age: Duration::from_secs(3600),
+            max_jobs: 2,
+        };
+        let worker = Queue::new("archive".into(), client).with_archive(retention);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(format!("{}:archive", worker.queue())).unwrap();
+        let _: () = con.del(format!("{}:archive:jobs", worker.queue())).unwrap();
+
+        let mut jids = vec![];
+        for id in 0..3 {
+            worker.push(Job { id: id }).unwrap();
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            jids.push(task.jid().unwrap().to_string());
+        }
+
+        assert_eq!(None, worker.archived_job(&jids[0]).unwrap());
+        let job = worker.archived_job(&jids[2]).unwrap().unwrap();
+        assert_eq!("{\"id\":2}", job.task);
+        assert_eq!(worker.worker_id(), job.worker);
+
+        let archived = worker.archived(0, 10).unwrap();
+        assert_eq!(2, archived.len());
+        assert_eq!(jids[2], archived[0].jid);
+    }
+
+    #[test]
+    fn records_failures() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("failures".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
+
+        worker.push(Job { id: 7 }).unwrap();
+        let jid = {
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            task.fail_with("timeout");
+            task.jid().unwrap().to_string()
+        };
+        let _: () = con.rpoplpush(worker.backup_queue(), worker.queue()).unwrap();
+        {
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            task.dead_letter("invalid id");
+        }
+
+        let failure = worker.failure(&jid).unwrap().unwrap();
+        assert_eq!("{\"id\":7}", failure.task);
+        assert_eq!(Some("invalid id".to_string()), failure.error);
+        assert_eq!(2, failure.attempts);
+        assert!(failure.first_failed_at <= failure.last_failed_at);
+        assert_eq!(worker.worker_id(), failure.worker);
+
+        assert_eq!(1, worker.dead_size());
+        let len: u32 = con.llen(worker.backup_queue()).unwrap();
+        assert_eq!(0, len);
+    }
+
+    #[test]
+    fn retries_dead_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("retry-dead".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
+
+        let mut jids = vec![];
+        for id in 0..3 {
+            worker.push(Job { id: id }).unwrap();
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            task.dead_letter("broken");
+            jids.push(task.jid().unwrap().to_string());
+        }
+        assert_eq!(3, worker.dead_size());
+
+        assert!(worker.retry_dead(&jids[2]).unwrap());
+        assert!(!worker.retry_dead(&jids[2]).unwrap());
+        assert_eq!(None, worker.failure(&jids[2]).unwrap());
+        assert_eq!(2, worker.retry_all_dead(10).unwrap());
+        assert_eq!(0, worker.dead_size());
+
+        let task = worker.next::<Job>(0).unwrap().unwrap();
+        assert_eq!(2, task.id);
+        assert_eq!(Some(&jids[2][..]), task.jid());
+    }
+
+    #[test]
+    fn lists_dead_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("list-dead".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
+
+        for id in 0..3 {
+            worker.push(Job { id: id }).unwrap();
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            task.dead_letter(format!("job {} broken", id));
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        let dead = worker.dead::<Job>(1, 5).unwrap();
+        assert_eq!(2, dead.len());
+        assert_eq!(1, dead[0].task.id);
+        assert_eq!(Some("job 1 broken".to_string()), dead[0].failure.error);
+        assert_eq!(1, dead[0].failure.attempts);
+        assert_eq!(0, dead[1].task.id);
+    }
+
+    #[test]
+    fn peeks_without_consuming() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("peek".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        assert!(queue.peek::<Job>().unwrap().is_none());
+
+        for id in 0..3 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        assert_eq!(0, queue.peek::<Job>().unwrap().unwrap().id);
+        let ids: Vec<u64> = queue.peek_many::<Job>(2).unwrap().iter().map(|job| job.id).collect();
+        assert_eq!(vec![0, 1], ids);
+        assert_eq!(3, queue.size());
+    }
+
+    #[test]
+    fn discovers_queues() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("discover".into(), client.clone());
+        // The name of the test thread, `test::discovers_queues`, doesn't add parts
+        assert_eq!(4, worker.backup_queue().split(':').count());
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        let _: () = con.del(worker.delayed_queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
+
+        for id in 0..3 {
+            worker.push(Job { id: id }).unwrap();
+        }
+        worker.push_delayed(Job { id: 3 }, Duration::from_secs(60)).unwrap();
+        let _task = worker.next::<Job>(0).unwrap().unwrap();
+
+        let queues = discover(&client).unwrap();
+        let info = queues.iter().find(|queue| queue.name == "discover").unwrap();
+        assert_eq!(2, info.pending);
+        assert_eq!(1, info.in_progress);
+        assert_eq!(1, info.delayed);
+        assert_eq!(0, info.dead);
+    }
+
+    #[test]
+    fn isolates_tenants() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let acme = Queue::new("tenant-jobs".into(), client.clone()).for_tenant("acme");
+        let globex = Queue::new("tenant-jobs".into(), client.clone()).for_tenant("globex");
+
+        assert_eq!("oppgave:tenant:acme:tenant-jobs", acme.queue());
+        assert_eq!(Some("acme"), acme.tenant());
+        assert!(acme.backup_queue().starts_with("oppgave:tenant:acme:tenant-jobs:"));
+
+        let _: () = con.del(acme.queue()).unwrap();
+        let _: () = con.del(globex.queue()).unwrap();
+
+        acme.push(Job { id: 1 }).unwrap();
+        acme.broadcast(Job { id: 2 }, &["tenant-jobs"]).unwrap();
+        globex.push(Job { id: 3 }).unwrap();
+        assert_eq!(2, acme.size());
+        assert_eq!(1, globex.size());
+
+        let queues = discover_tenant(&client, "acme").unwrap();
+        let info = queues.iter().find(|queue| queue.name == "tenant-jobs").unwrap();
+        assert_eq!(Some("acme".to_string()), info.tenant);
+        assert_eq!(2, info.pending);
+        assert!(queues.iter().all(|queue| queue.tenant == Some("acme".to_string())));
+
+        let tenants = tenants(&client).unwrap();
+        let globex = tenants.iter().find(|tenant| tenant.tenant == "globex").unwrap();
+        assert!(globex.pending >= 1);
+        assert_eq!("tenant:acme:tenant-jobs", tenant_queue("acme", "tenant-jobs"));
+    }
+
+    #[test]
+    fn consumes_all_shards() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("sharded".into(), client).with_shards(4);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        for index in 0..4 {
+            let _: () = con.del(queue.shard_queue(index)).unwrap();
+        }
+
+        for id in 0..8 {
+            queue.push(Job { id: id }).unwrap();
+        }
+        queue.push_keyed(Job { id: 8 }, "customer-1").unwrap();
+        queue.push_keyed(Job { id: 9 }, "customer-1").unwrap();
+        queue.push_delayed(Job { id: 10 }, Duration::from_secs(0)).unwrap();
+        queue.promote_delayed().unwrap();
+
+        for index in 0..4 {
+            let len: u64 = con.llen(queue.shard_queue(index)).unwrap();
+            assert!(len >= 2);
+        }
+        assert_eq!(11, queue.size());
+
+        let mut ids = vec![];
+        for _ in 0..11 {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            ids.push(task.id);
+        }
+        ids.sort();
+        assert_eq!((0..11).collect::<Vec<u64>>(), ids);
+    }
+
+    #[test]
+    fn reads_from_replica() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let replica = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("replica".into(), client).with_read_replica(replica);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        queue.push(Job { id: 5 }).unwrap();
+
+        assert_eq!(1, queue.size());
+        assert_eq!(5, queue.peek::<Job>().unwrap().unwrap().id);
+    }
+
+    #[test]
+    fn fails_tasks_running_over_timeout() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("timeout".into(), client);
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(running.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push(Job { id: 2 }).unwrap();
+
+        let worker = Worker::new(queue.clone()).timeout(Duration::from_millis(200));
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run(|job: Job, _token| -> Result<(), String> {
+                if job.id == 1 {
+                    thread::sleep(Duration::from_secs(2));
+                }
+                Ok(())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(1500));
+        handle.stop();
+        runner.join().unwrap();
+
+        assert_eq!(0, queue.size());
+        let failed: Vec<String> = con.lrange(running.backup_queue(), 0, -1).unwrap();
+        assert_eq!(1, failed.len());
+        assert!(failed[0].contains("\"id\":1"));
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("checkpoint".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
+
+        {
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            assert_eq!(None, task.last_checkpoint::<u32>().unwrap());
+            task.checkpoint(3u32).unwrap();
+            task.fail();
+        }
+        let _: () = con.rpoplpush(worker.backup_queue(), worker.queue()).unwrap();
+
+        let jid = {
+            let task = worker.next::<Job>(0).unwrap().unwrap();
+            assert_eq!(Some(3), task.last_checkpoint::<u32>().unwrap());
+            task.jid().unwrap().to_string()
+        };
+
+        let exists: bool = con.exists(format!("{}:checkpoint:{}", worker.queue(), jid)).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn cancels_handlers_on_stop() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("cancel".into(), client);
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(running.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let worker = Worker::new(queue.clone());
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run(|_job: Job, token: CancellationToken| -> Result<(), String> {
+                while !token.is_cancelled() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err("cancelled".into())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        handle.stop();
+        runner.join().unwrap();
+
+        let failed: u64 = con.llen(running.backup_queue()).unwrap();
+        assert_eq!(1, failed);
+    }
+
+    #[test]
+    fn registers_running_workers() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("registry".into(), client.clone());
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(running.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let worker = Worker::new(queue.clone()).heartbeat(Duration::from_millis(100));
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run(|_job: Job, token: CancellationToken| -> Result<(), String> {
+                while !token.is_cancelled() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Ok(())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        let workers = list_workers(&client).unwrap();
+        let info = workers.iter().find(|info| info.id == running.worker_id()).unwrap();
+        // The worker is registered under the thread running it, not the one creating the queue
+        assert!(workers.iter().all(|info| info.id != queue.worker_id()));
+        assert_eq!(vec![queue.queue().to_string()], info.queues);
+        assert_eq!(1, info.current.len());
+        assert!(info.heartbeat_at >= info.started_at);
+
+        handle.stop();
+        runner.join().unwrap();
+        let workers = list_workers(&client).unwrap();
+        assert!(workers.iter().all(|info| info.id != running.worker_id()));
+    }
+
+    #[test]
+    fn follows_remote_commands() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("control".into(), client.clone());
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+
+        let worker = Worker::new(queue.clone()).heartbeat(Duration::from_millis(50));
+        let id = running.worker_id().to_string();
+        let runner = thread::spawn(move || {
+            worker.run(|_job: Job, _token| -> Result<(), String> { Ok(()) })
+        });
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(1, send_control(&client, Control::Quiet, Some(&id)).unwrap());
+        send_control(&client, Control::Dump, Some(&id)).unwrap();
+        thread::sleep(Duration::from_millis(1500));
+
+        queue.push(Job { id: 1 }).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(1, queue.size());
+        assert!(worker_dump(&client, &id).unwrap().unwrap().quiet);
+
+        send_control(&client, Control::Terminate, Some(&id)).unwrap();
+        runner.join().unwrap();
+    }
+
+    #[test]
+    fn skips_backup_queue_at_most_once() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("at-most-once".into(), client).with_delivery(Delivery::AtMostOnce);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
+        worker.push(Job { id: 2 }).unwrap();
+
+        {
+            let task = worker.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(1, task.id);
+            let len: u64 = con.llen(worker.backup_queue()).unwrap();
+            assert_eq!(0, len);
+            task.fail();
+        }
+        let task = worker.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(2, task.id);
+        assert_eq!(0, worker.size());
+    }
+
+    #[test]
+    fn acks_the_finished_task() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("ack".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
+        worker.push(Job { id: 2 }).unwrap();
+
+        let first = worker.next::<Job>(1).unwrap().unwrap();
+        let second = worker.next::<Job>(1).unwrap().unwrap();
+        second.fail();
+        drop(first);
+        drop(second);
+
+        let backup: Vec<String> = con.lrange(worker.backup_queue(), 0, -1).unwrap();
+        assert_eq!(1, backup.len());
+        assert!(backup[0].contains("\"id\":2"));
+    }
+
+    #[test]
+    fn tracks_and_reaps_reservations() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("processing".into(), client);
+
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        let _: () = con.del(format!("{}:processing", worker.queue())).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
+        worker.push(Job { id: 2 }).unwrap();
+
+        let jid = {
+            let task = worker.next::<Job>(1).unwrap().unwrap();
+            let processing = worker.processing().unwrap();
+            assert_eq!(1, processing.len());
+            assert_eq!(task.jid().unwrap(), processing[0].jid);
+            assert_eq!(worker.worker_id(), processing[0].worker);
+            task.jid().unwrap().to_string()
+        };
+        assert!(worker.processing().unwrap().iter().all(|entry| entry.jid != jid));
+
+        // Simulate a crashed worker by leaking the guard
+        ::std::mem::forget(worker.next::<Job>(1).unwrap().unwrap());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(1, worker.reap_stuck(Duration::from_millis(10)).unwrap());
+        assert_eq!(1, worker.size());
+        let backup: u64 = con.llen(worker.backup_queue()).unwrap();
+        assert_eq!(0, backup);
+        assert!(worker.processing().unwrap().is_empty());
+    }
+
+    #[test]
+    fn opens_circuit_breaker_on_failures() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("breaker".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        for id in 0..5 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let breaker = CircuitBreaker::new(1.0, 2).cooldown(Duration::from_secs(60));
+        let worker = Worker::new(queue.clone()).circuit_breaker(breaker.clone());
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run(|_job: Job, _token| -> Result<(), String> { Err("downstream is down".into()) })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(BreakerState::Open, breaker.state());
+        assert_eq!(3, queue.size());
+
+        handle.stop();
+        runner.join().unwrap();
+    }
+
+    #[test]
+    fn quarantines_poison_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("quarantine".into(), client.clone()).with_quarantine(2);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let jid = {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.fail_with("crash");
+            task.jid().unwrap().to_string()
+        };
+        assert_eq!(0, queue.dead_size());
+        let _: () = con.rpoplpush(queue.backup_queue(), queue.queue()).unwrap();
+
+        let other = thread::Builder::new()
+            .name("other-worker".into())
+            .spawn(move || {
+                let queue = Queue::new("quarantine".into(), client).with_quarantine(2);
+                let task = queue.next::<Job>(1).unwrap().unwrap();
+                task.fail_with("crash");
+                queue.backup_queue().to_string()
+            })
+            .unwrap();
+        let backup = other.join().unwrap();
+
+        assert_eq!(1, queue.dead_size());
+        let backup_len: u64 = con.llen(backup).unwrap();
+        assert_eq!(0, backup_len);
+        let failure = queue.failure(&jid).unwrap().unwrap();
+        assert!(failure.poison);
+        assert_eq!(2, failure.attempts);
+    }
+
+    #[test]
+    fn honors_job_options() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("job-options".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        let options = JobOptions {
+            max_attempts: Some(2),
+            priority: 1,
+            ..JobOptions::default()
+        };
+        queue.push_with_options(Job { id: 2 }, options.clone()).unwrap();
+
+        {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(2, task.id);
+            assert_eq!(Some(&options), task.options());
+            task.fail();
+        }
+        let backup_len: u64 = con.llen(queue.backup_queue()).unwrap();
+        assert_eq!(0, backup_len);
+        assert_eq!(2, queue.size());
+
+        {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(2, task.id);
+            task.fail();
+        }
+        assert_eq!(1, queue.dead_size());
+        assert_eq!(1, queue.size());
+    }
+
+    #[test]
+    fn filters_jobs_by_tag() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("tags".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        let tagged = |tag: &str| JobOptions {
+            tags: vec![tag.into()],
+            ..JobOptions::default()
+        };
+        queue.push_with_options(Job { id: 1 }, tagged("customer:1")).unwrap();
+        queue.push_with_options(Job { id: 2 }, tagged("customer:2")).unwrap();
+        queue.push_with_options(Job { id: 3 }, tagged("customer:1")).unwrap();
+
+        let pending = queue.tagged::<Job>("customer:1", 10).unwrap();
+        assert_eq!(vec![1, 3], pending.iter().map(|job| job.id).collect::<Vec<_>>());
+
+        for _ in 0..3 {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(1, task.tags().len());
+            task.dead_letter("gone");
+        }
+        let dead = queue.dead_tagged::<Job>("customer:2", 10).unwrap();
+        assert_eq!(1, dead.len());
+        assert_eq!(2, dead[0].task.id);
+
+        assert_eq!(2, queue.retry_dead_tagged("customer:1").unwrap());
+        assert_eq!(1, queue.dead_size());
+        assert_eq!(2, queue.size());
+    }
+
+    #[test]
+    fn searches_jobs_by_content() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("search".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        for id in 0..1200 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let found = queue.search::<Job, _>(|task| task["id"].as_u64().is_some_and(|id| id % 500 == 42), 10).unwrap();
+        assert_eq!(vec![42, 542, 1042], found.iter().map(|job| job.id).collect::<Vec<_>>());
+        let first = queue.search::<Job, _>(|task| task["id"].as_u64().is_some_and(|id| id >= 100), 1).unwrap();
+        assert_eq!(100, first[0].id);
+
+        queue.next::<Job>(1).unwrap().unwrap().dead_letter("gone");
+        let dead = queue.search_dead::<Job, _>(|task| task["id"] == 0, 10).unwrap();
+        assert_eq!(1, dead.len());
+        assert!(queue.search_dead::<Job, _>(|task| task["id"] == 1, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn updates_pending_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("update".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push(Job { id: 2 }).unwrap();
+
+        let jid = {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.jid().unwrap().to_string()
+        };
+        assert!(!queue.update(&jid, Job { id: 10 }).unwrap());
+
+        let data: Vec<u8> = con.lindex(queue.queue(), 0).unwrap();
+        let pending = Envelope::parse(&data).unwrap().jid;
+        assert!(queue.update(&pending, Job { id: 20 }).unwrap());
+        assert_eq!(1, queue.size());
+
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(20, task.id);
+        assert_eq!(Some(&pending[..]), task.jid());
+    }
+
+    #[test]
+    fn estimates_position_of_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("position".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        for id in 0..4 {
+            queue.push(Job { id: id }).unwrap();
+        }
+        let data: Vec<u8> = con.lindex(queue.queue(), 0).unwrap();
+        let last = Envelope::parse(&data).unwrap().jid;
+        assert_eq!(Some(3), queue.position(&last).unwrap());
+
+        queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(Some(2), queue.position(&last).unwrap());
+        assert_eq!(None, queue.position("unknown").unwrap());
+
+        assert!(queue.processing_rate().unwrap() > 0.0);
+        assert!(queue.eta(&last).unwrap().is_some());
+    }
+
+    #[test]
+    fn counts_tasks_in_progress() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("in-progress".into(), client.clone());
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        for id in 0..3 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let first = queue.next::<Job>(1).unwrap().unwrap();
+        let other = thread::Builder::new()
+            .name("in-progress-worker".into())
+            .spawn(move || {
+                let queue = Queue::new("in-progress".into(), client);
+                let task = queue.next::<Job>(1).unwrap().unwrap();
+                task.fail();
+                queue.backup_queue().to_string()
+            })
+            .unwrap();
+        let backup = other.join().unwrap();
+
+        assert_eq!(2, queue.in_progress_count().unwrap());
+        assert_eq!(1, queue.size());
+        drop(first);
+        assert_eq!(1, queue.in_progress_count().unwrap());
+        let _: () = con.del(backup).unwrap();
+    }
+
+    #[test]
+    fn reports_global_stats() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("global-stats".into(), client.clone());
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push_delayed(Job { id: 2 }, Duration::from_secs(60)).unwrap();
+
+        let stats = global_stats(&client).unwrap();
+        let info = stats.queues.iter().find(|info| info.name == "global-stats").unwrap();
+        assert_eq!(1, info.pending);
+        assert_eq!(1, info.delayed);
+        assert!(stats.pending >= 1);
+        assert!(stats.delayed >= 1);
+    }
+
+    #[test]
+    fn exports_and_imports_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let source = Queue::new("export".into(), client.clone());
+        let target = Queue::new("import".into(), client);
+
+        for queue in &[&source, &target] {
+            let _: () = con.del(queue.queue()).unwrap();
+            let _: () = con.del(queue.delayed_queue()).unwrap();
+        }
+        for id in 0..3 {
+            source.push(Job { id: id }).unwrap();
+        }
+        source.push_delayed(Job { id: 3 }, Duration::from_secs(60)).unwrap();
+        let _: () = con.zadd(source.delayed_queue(), vec![0xffu8, 0xfe], 1).unwrap();
+        target.push(Job { id: 9 }).unwrap();
+
+        let mut file = vec![];
+        assert_eq!(5, source.export(&mut file).unwrap());
+        assert_eq!(5, target.import(&file[..]).unwrap());
+
+        assert_eq!(3, source.size());
+        assert_eq!(4, target.size());
+        let delayed: u64 = con.zcard(target.delayed_queue()).unwrap();
+        assert_eq!(2, delayed);
+        let raw: Option<u64> = con.zscore(target.delayed_queue(), vec![0xffu8, 0xfe]).unwrap();
+        assert_eq!(Some(1), raw);
+        // Imported jobs run after the ones already in the queue
+        let tasks = target.peek_many::<Job>(4).unwrap();
+        assert_eq!(vec![9, 0, 1, 2], tasks.iter().map(|job| job.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reports_memory_usage() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("memory".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        assert_eq!(0, queue.memory_usage().unwrap().total());
+
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push_delayed(Job { id: 2 }, Duration::from_secs(60)).unwrap();
+        let usage = queue.memory_usage().unwrap();
+        assert!(usage.pending > 0);
+        assert!(usage.delayed > 0);
+        assert_eq!(0, usage.dead);
+    }
+
+    #[test]
+    fn fails_to_push_unencodable_tasks() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("unencodable".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let mut task = HashMap::new();
+        task.insert((1, 2), "non-string key");
+
+        assert!(queue.push(task.clone()).unwrap_err().is_encode());
+        assert!(queue.push_batch(Batch::new().push(Job { id: 1 }).push(task)).is_err());
+        assert_eq!(0, queue.size());
+    }
+
+    #[test]
+    fn mutates_fetched_tasks() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("mutate".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let mut task = queue.next::<Job>(1).unwrap().unwrap();
+        task.id += 1;
+        task.inner_mut().id *= 10;
+        assert_eq!(20, task.id);
+        drop(task);
+
+        let backup_len: u64 = con.llen(queue.backup_queue()).unwrap();
+        assert_eq!(0, backup_len);
+    }
+
+    #[test]
+    fn takes_ownership_of_finished_tasks() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("into-inner".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push(Job { id: 2 }).unwrap();
+
+        let job = queue.next::<Job>(1).unwrap().unwrap().into_inner();
+        assert_eq!(1, job.id);
+        let backup_len: u64 = con.llen(queue.backup_queue()).unwrap();
+        assert_eq!(0, backup_len);
+
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        task.fail();
+        assert_eq!(2, task.into_inner().id);
+        let backup_len: u64 = con.llen(queue.backup_queue()).unwrap();
+        assert_eq!(1, backup_len);
+    }
+
+    #[test]
+    fn exposes_job_metadata() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("metadata".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let mut options = JobOptions::default();
+        options.headers.insert("trace-id".into(), "abc".into());
+        queue.push_with_options(Job { id: 1 }, options).unwrap();
+
+        let jid = {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(Some("abc"), task.header("trace-id"));
+            assert_eq!(None, task.header("other"));
+            assert!(task.enqueued_at().is_some());
+            assert_eq!(0, task.attempts().unwrap());
+            assert_eq!(task.jid(), Envelope::parse(task.raw()).map(|job| job.jid).as_ref().map(|jid| &jid[..]));
+            task.fail();
+            task.jid().unwrap().to_string()
+        };
+
+        let _: () = con.rpoplpush(queue.backup_queue(), queue.queue()).unwrap();
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(Some(&jid[..]), task.jid());
+        assert_eq!(1, task.attempts().unwrap());
+    }
+
+    #[test]
+    fn relays_raw_entries() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("raw".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push_raw(b"opaque \xff bytes").unwrap();
+        queue.push_raw(br#"{"id":7}"#).unwrap();
+
+        {
+            let task = queue.next_raw(1).unwrap().unwrap();
+            assert_eq!(&b"opaque \xff bytes"[..], &task[..]);
+            assert_eq!(None, task.jid());
+        }
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(7, task.id);
+        drop(task);
+
+        let backup_len: u64 = con.llen(queue.backup_queue()).unwrap();
+        assert_eq!(0, backup_len);
+    }
+
+    #[test]
+    fn polls_without_blocking() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("try-next".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        assert!(queue.try_next::<Job>().unwrap().is_none());
+
+        queue.push(Job { id: 1 }).unwrap();
+        assert_eq!(1, queue.try_next::<Job>().unwrap().unwrap().id);
+
+        let mut idle = Idle::new(IdleStrategy::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(25),
+        });
+        assert_eq!(Duration::from_millis(10), idle.delay());
+        idle.idle();
+        assert_eq!(Duration::from_millis(20), idle.delay());
+        idle.idle();
+        assert_eq!(Duration::from_millis(25), idle.delay());
+        idle.reset();
+        assert_eq!(Duration::from_millis(10), idle.delay());
+    }
+
+    #[test]
+    fn waits_for_room_in_bounded_queues() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("bounded".into(), client.clone()).with_max_size(1);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        match queue.push(Job { id: 2 }) {
+            Err(PushError::Full) => {}
+            _ => panic!("Pushing to a full queue should fail"),
+        }
+        assert!(queue.push_blocking(Job { id: 2 }, Duration::from_millis(50)).is_err());
+
+        let consumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let queue = Queue::new("bounded".into(), client);
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.into_inner().id
+        });
+        queue.push_blocking(Job { id: 2 }, Duration::from_secs(5)).unwrap();
+        assert_eq!(1, consumer.join().unwrap());
+        assert_eq!(1, queue.size());
+    }
+
+    #[test]
+    fn promotes_urgent_jobs_to_the_front() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("delayed-priority".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        let urgent = JobOptions {
+            priority: 1,
+            delay: Some(Duration::from_millis(1)),
+            ..JobOptions::default()
+        };
+        queue.push_with_options(Job { id: 2 }, urgent).unwrap();
+        assert_eq!(1, queue.size());
+        assert_eq!(1, queue.delayed_size());
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(1, queue.promote_delayed().unwrap());
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(2, task.id);
+
+        let lifo = Queue::new("delayed-priority-lifo".into(), redis::Client::open("redis://127.0.0.1:6379/").unwrap())
+            .with_order(Order::Lifo);
+        let _: () = con.del(lifo.queue()).unwrap();
+        let _: () = con.del(lifo.delayed_queue()).unwrap();
+        let _: () = con.del(lifo.backup_queue()).unwrap();
+        lifo.push(Job { id: 3 }).unwrap();
+        let urgent = JobOptions {
+            priority: 1,
+            delay: Some(Duration::from_millis(1)),
+            ..JobOptions::default()
+        };
+        lifo.push_with_options(Job { id: 4 }, urgent).unwrap();
+
+        thread::sleep(Duration::from_millis(10));
+        let promoter = Promoter::new(redis::Client::open("redis://127.0.0.1:6379/").unwrap())
+            .queue_with_order("delayed-priority-lifo", Order::Lifo);
+        assert_eq!(1, promoter.promote().unwrap());
+        assert_eq!(4, lifo.next::<Job>(1).unwrap().unwrap().id);
+    }
+
+    #[test]
+    fn spreads_recurring_jobs_with_jitter() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("recurring-jitter".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(format!("{}:recurring", queue.queue())).unwrap();
+        let interval = Duration::from_secs(300);
+        let jitter = Duration::from_secs(60);
+        for i in 0..10 {
+            queue.register_recurring_with_jitter(&format!("job-{}", i), interval, jitter, Job { id: i }).unwrap();
+        }
+
+        let jobs = queue.recurring().unwrap();
+        assert_eq!(10, jobs.len());
+        assert!(jobs.iter().all(|job| job.jitter == jitter));
+        let first = jobs[0].next_run;
+        let last = jobs[9].next_run;
+        let spread = last.duration_since(first).unwrap();
+        assert!(spread > Duration::from_millis(0));
+        assert!(spread <= jitter + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn runs_singleton_jobs_once_at_a_time() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("singleton".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del("oppgave:lock:singleton:report").unwrap();
+        let skip = JobOptions {
+            singleton: Some(Singleton::skip("report")),
+            ..JobOptions::default()
+        };
+        let delay = JobOptions {
+            singleton: Some(Singleton::delay("report", Duration::from_secs(60))),
+            ..JobOptions::default()
+        };
+        queue.push_with_options(Job { id: 1 }, skip.clone()).unwrap();
+        queue.push_with_options(Job { id: 2 }, skip.clone()).unwrap();
+        queue.push_with_options(Job { id: 3 }, delay).unwrap();
+
+        {
+            let running = queue.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(1, running.id);
+            assert!(queue.try_next::<Job>().unwrap().is_none());
+            assert!(queue.try_next::<Job>().unwrap().is_none());
+            assert_eq!(0, queue.size());
+            assert_eq!(1, queue.delayed_size());
+            let backup_len: u64 = con.llen(queue.backup_queue()).unwrap();
+            assert_eq!(1, backup_len);
+        }
+
+        queue.push_with_options(Job { id: 4 }, skip.clone()).unwrap();
+        let running = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(4, running.id);
+
+        // Skipped jobs don't stand in the way of the next one
+        queue.push_with_options(Job { id: 5 }, skip).unwrap();
+        queue.push(Job { id: 6 }).unwrap();
+        assert_eq!(6, queue.next::<Job>(1).unwrap().unwrap().id);
+        drop(running);
+    }
+
+    #[test]
+    fn locks_across_clients() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let _: () = con.del("oppgave:lock:test-lock").unwrap();
+
+        let guard = lock::acquire(&client, "test-lock", Duration::from_secs(10)).unwrap().unwrap();
+        assert!(lock::acquire(&client, "test-lock", Duration::from_secs(10)).unwrap().is_none());
+        assert!(guard.extend(Duration::from_secs(20)).unwrap());
+
+        let waiter = {
+            let client = client.clone();
+            thread::spawn(move || {
+                lock::acquire_wait(&client, "test-lock", Duration::from_secs(10), Duration::from_secs(5))
+                    .unwrap()
+                    .is_some()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(guard.release().unwrap());
+        assert!(waiter.join().unwrap());
+
+        // The guard of the waiter released the lock when dropped
+        assert!(lock::acquire(&client, "test-lock", Duration::from_secs(10)).unwrap().is_some());
+    }
+
+    #[test]
+    fn serializes_jobs_per_resource() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("resources".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(&["oppgave:lock:resource:user:1", "oppgave:lock:resource:user:2"][..]).unwrap();
+        let resource = |key: &str| JobOptions {
+            resource: Some(key.into()),
+            ..JobOptions::default()
+        };
+        queue.push_with_options(Job { id: 1 }, resource("user:1")).unwrap();
+        queue.push_with_options(Job { id: 2 }, resource("user:1")).unwrap();
+        queue.push_with_options(Job { id: 3 }, resource("user:2")).unwrap();
+
+        let first = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(1, first.id);
+        assert!(queue.try_next::<Job>().unwrap().is_none());
+        assert_eq!(1, queue.delayed_size());
+        let other = queue.try_next::<Job>().unwrap().unwrap();
+        assert_eq!(3, other.id);
+    }
+
+    #[test]
+    fn consumes_claimed_partitions_only() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let producer = Queue::new("partitioned".into(), client.clone()).with_shards(4);
+
+        let _: () = con.del(producer.queue()).unwrap();
+        for index in 0..4 {
+            let _: () = con.del(producer.shard_queue(index)).unwrap();
+        }
+        let _: () = con.del(producer.backup_queue()).unwrap();
+        let (mine, other) = ("account:1", "account:2");
+        assert_ne!(::shard::pick(mine, 4), ::shard::pick(other, 4));
+        producer.push_keyed(Job { id: 2 }, other).unwrap();
+        producer.push_keyed(Job { id: 1 }, mine).unwrap();
+        producer.push_keyed(Job { id: 3 }, mine).unwrap();
+
+        let consumer = Queue::new("partitioned".into(), client)
+            .with_shards(4)
+            .with_partitions(vec![::shard::pick(mine, 4)]);
+        assert_eq!(Some(&[::shard::pick(mine, 4)][..]), consumer.partitions());
+        assert_eq!(1, consumer.try_next::<Job>().unwrap().unwrap().id);
+        assert_eq!(3, consumer.try_next::<Job>().unwrap().unwrap().id);
+        assert!(consumer.try_next::<Job>().unwrap().is_none());
+        assert_eq!(1, producer.size());
+    }
+
+    #[test]
+    fn runs_jobs_of_a_key_in_order() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("ordered".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(&["oppgave:ordered:ordered", "oppgave:ordered:ordered:a", "oppgave:ordered:ordered:b"][..])
+            .unwrap();
+        let ordered = |key: &str| JobOptions {
+            ordering_key: Some(key.into()),
+            ..JobOptions::default()
+        };
+        queue.push_with_options(Job { id: 1 }, ordered("a")).unwrap();
+        queue.push_with_options(Job { id: 2 }, ordered("a")).unwrap();
+        queue.push_with_options(Job { id: 3 }, ordered("b")).unwrap();
+        queue.push_with_options(Job { id: 4 }, ordered("a")).unwrap();
+        assert_eq!(2, queue.size());
+
+        let first = queue.try_next::<Job>().unwrap().unwrap();
+        assert_eq!(1, first.id);
+        assert_eq!(3, queue.try_next::<Job>().unwrap().unwrap().id);
+        assert!(queue.try_next::<Job>().unwrap().is_none());
+
+        drop(first);
+        assert_eq!(2, queue.try_next::<Job>().unwrap().unwrap().id);
+        assert_eq!(4, queue.try_next::<Job>().unwrap().unwrap().id);
+        assert!(queue.try_next::<Job>().unwrap().is_none());
+    }
+
+    #[test]
+    fn limits_concurrent_tasks_per_type() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("limited".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        for id in 0..4 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let limits = ConcurrencyLimits::new().limit::<Job>(1);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let workers: Vec<_> = (0..3).map(|_| Worker::new(queue.clone()).concurrency_limits(limits.clone())).collect();
+        let runners: Vec<_> = workers.iter().cloned().map(|worker| {
+            let (running, peak) = (running.clone(), peak.clone());
+            thread::spawn(move || {
+                worker.run(move |_job: Job, _token| -> Result<(), String> {
+                    let now = running.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                    peak.fetch_max(now, AtomicOrdering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, AtomicOrdering::SeqCst);
+                    Ok(())
+                })
+            })
+        }).collect();
+
+        thread::sleep(Duration::from_millis(1500));
+        for worker in &workers {
+            worker.stop();
+        }
+        for runner in runners {
+            runner.join().unwrap();
+        }
+
+        assert_eq!(0, queue.size());
+        assert_eq!(1, peak.load(AtomicOrdering::SeqCst));
+        assert_eq!(0, limits.running::<Job>());
+    }
+
+    #[test]
+    fn leaves_other_kinds_to_other_workers() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("kinds".into(), client);
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(running.backup_queue()).unwrap();
+        queue.push(serde_json::json!({ "type": "transcode", "id": 1 })).unwrap();
+        queue.push_with_options(Job { id: 2 }, JobOptions {
+            kind: Some("email".into()),
+            ..JobOptions::default()
+        }).unwrap();
+        queue.push(Job { id: 3 }).unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let worker = Worker::new(queue.clone()).skip_kinds(vec!["transcode"]);
+        let handle = worker.clone();
+        let handled = seen.clone();
+        let runner = thread::spawn(move || {
+            worker.run(move |job: serde_json::Value, _token| -> Result<(), String> {
+                handled.lock().unwrap().push(job["id"].as_u64().unwrap());
+                Ok(())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(1000));
+        handle.stop();
+        runner.join().unwrap();
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(vec![2, 3], seen);
+        assert_eq!(1, queue.size());
+        assert_eq!(0, con.llen::<_, u64>(running.backup_queue()).unwrap());
+    }
+
+    #[test]
+    fn migrates_tasks_of_older_versions() {
+        #[derive(Deserialize, Serialize)]
+        struct Email {
+            to: Vec<String>,
+        }
+
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let migrations = Migrations::new().register::<Email, _>(1, |mut task| {
+            let to = task["to"].take();
+            task["to"] = serde_json::json!([to]);
+            Ok(task)
+        });
+        let old = Queue::new("migrations".into(), client.clone());
+        let queue = Queue::new("migrations".into(), client).with_migrations(migrations);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        old.push(serde_json::json!({ "to": "jane@example.com" })).unwrap();
+        queue.push(Email { to: vec!["joe@example.com".into()] }).unwrap();
+
+        assert_eq!(vec!["jane@example.com".to_string()], queue.next::<Email>(1).unwrap().unwrap().to);
+        assert_eq!(vec!["joe@example.com".to_string()], queue.next::<Email>(1).unwrap().unwrap().to);
+
+        // Workers upgrade tasks as well
+        old.push(serde_json::json!({ "to": "jim@example.com" })).unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let worker = Worker::new(queue.clone());
+        let handle = worker.clone();
+        let handled = seen.clone();
+        let runner = thread::spawn(move || {
+            worker.run(move |email: Email, _token| -> Result<(), String> {
+                handled.lock().unwrap().extend(email.to);
+                Ok(())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        handle.stop();
+        runner.join().unwrap();
+
+        assert_eq!(vec!["jim@example.com".to_string()], *seen.lock().unwrap());
+        assert_eq!(0, queue.size());
+    }
+
+    #[test]
+    fn dispatches_enum_variants_to_handlers() {
+        #[derive(Deserialize, Serialize)]
+        struct Email {
+            to: String,
+        }
+
+        #[derive(Deserialize, Serialize)]
+        #[serde(tag = "type")]
+        enum Task {
+            Email(Email),
+            Resize(Job),
+            Cleanup(Job),
+        }
+
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("dispatch".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Task::Email(Email { to: "jane@example.com".into() })).unwrap();
+        queue.push(Task::Resize(Job { id: 7 })).unwrap();
+        queue.push(Task::Cleanup(Job { id: 8 })).unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let (emails, resized) = (seen.clone(), seen.clone());
+        let dispatcher = Dispatcher::new()
+            .on("Email", move |email: Email, _token| -> Result<(), String> {
+                emails.lock().unwrap().push(email.to);
+                Ok(())
+            })
+            .on("Resize", move |job: Job, _token| -> Result<(), String> {
+                resized.lock().unwrap().push(job.id.to_string());
+                Ok(())
+            });
+        assert_eq!(vec!["Email", "Resize"], dispatcher.kinds());
+
+        for _ in 0..2 {
+            let task = queue.next::<serde_json::Value>(1).unwrap().unwrap();
+            dispatcher.handle(task.into_inner(), CancellationToken::new()).unwrap();
+        }
+        let task = queue.next::<serde_json::Value>(1).unwrap().unwrap();
+        assert!(dispatcher.handle(task.into_inner(), CancellationToken::new()).is_err());
+        assert_eq!(vec!["jane@example.com".to_string(), "7".to_string()], *seen.lock().unwrap());
+    }
+
+    #[test]
+    fn spreads_retries_over_budget() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("budget".into(), client)
+            .with_retry_budget(RetryBudget::new(2, Duration::from_secs(60)));
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        let retries: Vec<String> = con.keys(format!("{}:retries:*", queue.queue())).unwrap();
+        for key in retries {
+            let _: () = con.del(key).unwrap();
+        }
+        for id in 0..3 {
+            queue.push_with_options(Job { id: id }, JobOptions {
+                max_attempts: Some(3),
+                ..JobOptions::default()
+            }).unwrap();
+        }
+
+        for _ in 0..3 {
+            queue.next::<Job>(1).unwrap().unwrap().fail();
+        }
+        assert_eq!(2, queue.size());
+        assert_eq!(1, queue.delayed_size());
+    }
+
+    #[test]
+    fn classifies_handler_errors() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("classified".into(), client);
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(running.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        for id in 0..3 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let worker = Worker::new(queue.clone());
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run_classified(|job: Job, _token| match job.id {
+                0 => Err(TaskOutcome::Retry),
+                1 => Err(TaskOutcome::Fail),
+                _ => Err(TaskOutcome::Discard),
+            })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        handle.stop();
+        runner.join().unwrap();
+
+        let kept: Vec<String> = con.lrange(running.backup_queue(), 0, -1).unwrap();
+        assert_eq!(1, kept.len());
+        assert!(kept[0].contains("\"id\":0"));
+        assert_eq!(1, queue.dead_size());
+    }
+
+    #[test]
+    fn reports_failures_to_handler() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reported = reports.clone();
+        let queue = Queue::new("reported".into(), client).with_failure_handler(
+            move |_queue: &str, job: &FailedJob, dead: bool| {
+                reported.lock().unwrap().push((job.error.clone(), job.attempts, dead));
+            },
+        );
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        queue.next::<Job>(1).unwrap().unwrap().fail_with("timeout");
+        let _: () = con.rpoplpush(queue.backup_queue(), queue.queue()).unwrap();
+        queue.next::<Job>(1).unwrap().unwrap().dead_letter("invalid");
+
+        assert_eq!(
+            vec![(Some("timeout".to_string()), 1, false), (Some("invalid".to_string()), 2, true)],
+            *reports.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn alerts_on_queue_health() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("monitored".into(), client.clone());
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let _: () = con.del(queue.dead_queue()).unwrap();
+        for id in 0..3 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let raised = Arc::new(Mutex::new(vec![]));
+        let sink = raised.clone();
+        let monitor = Monitor::new(client)
+            .queue("monitored")
+            .max_depth(2)
+            .on_alert(move |alert| sink.lock().unwrap().push(alert.clone()));
+
+        let depth = Alert::QueueDepth {
+            queue: "monitored".into(),
+            pending: 3,
+            threshold: 2,
+        };
+        assert_eq!(vec![depth.clone()], monitor.check().unwrap());
+        assert!(monitor.check().unwrap().is_empty());
+
+        queue.next::<Job>(1).unwrap().unwrap().dead_letter("broken");
+        let dead = Alert::DeadJobs {
+            queue: "monitored".into(),
+            dead: 1,
+            added: 1,
+        };
+        assert_eq!(vec![dead.clone()], monitor.check().unwrap());
+        assert_eq!(vec![depth, dead], *raised.lock().unwrap());
+    }
+
+    #[test]
+    fn checks_queue_health() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("health".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let health = queue.health();
+        assert!(health.is_healthy());
+        assert!(health.latency_ms.is_some());
+
+        let _: () = con.set(queue.delayed_queue(), "oops").unwrap();
+        let health = queue.health();
+        assert!(!health.is_healthy());
+        assert_eq!(vec![(queue.delayed_queue(), "string".to_string())], health.wrong_types);
+        let _: () = con.del(queue.delayed_queue()).unwrap();
+    }
+
+    #[test]
+    fn probes_running_workers() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("probed".into(), client);
+
+        queue.resume().unwrap();
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let worker = Worker::new(queue.clone()).idle_strategy(IdleStrategy::Fixed(Duration::from_millis(10)));
+        let probe = worker.probe();
+        assert_eq!(WorkerState::Starting, probe.state());
+        assert!(!probe.is_ready());
+
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run(|_job: Job, _token| -> Result<(), String> {
+                thread::sleep(Duration::from_millis(300));
+                Ok(())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(1, probe.in_flight());
+        assert!(probe.is_ready());
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(0, probe.in_flight());
+        assert!(probe.is_live(Duration::from_millis(100)));
+
+        // Workers of paused queues stay live
+        queue.pause().unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        assert!(probe.is_live(Duration::from_millis(300)));
+        queue.resume().unwrap();
+
+        handle.stop();
+        runner.join().unwrap();
+        assert_eq!(WorkerState::Stopped, probe.state());
+        assert!(!probe.is_live(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn keeps_job_logs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("logged".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let jid = {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.log("downloading").unwrap();
+            task.log("resizing").unwrap();
+            task.jid().unwrap().to_string()
+        };
+
+        let lines: Vec<String> = queue.job_log(&jid).unwrap().into_iter().map(|line| line.line).collect();
+        assert_eq!(vec!["downloading", "resizing"], lines);
+        assert!(queue.job_log("unknown").unwrap().is_empty());
+    }
+
+    #[test]
+    fn streams_job_events() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("streamed".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        let jid = {
+            let jobs: Vec<String> = con.lrange(queue.queue(), 0, 0).unwrap();
+            Envelope::parse(jobs[0].as_bytes()).unwrap().jid
+        };
+
+        let events = queue.subscribe(&jid).unwrap();
+        events.set_timeout(Some(Duration::from_secs(1))).unwrap();
+        {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.progress(1, 2, Some("resizing")).unwrap();
+            task.log("done").unwrap();
+        }
+
+        let events: Vec<JobEvent> = events.map(|event| event.unwrap()).collect();
+        assert_eq!(
+            vec![
+                JobEvent::Progress {
+                    current: 1,
+                    total: 2,
+                    message: Some("resizing".into()),
+                },
+                JobEvent::Log { line: "done".into() },
+                JobEvent::Finished { failed: false },
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn awaits_job_results() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("awaited".into(), client).with_results(Duration::from_secs(60));
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push(Job { id: 2 }).unwrap();
+
+        let completed = {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.set_result(&format!("done {}", task.id)).unwrap();
+            task.jid().unwrap().to_string()
+        };
+        let failed = {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.dead_letter("broken");
+            task.jid().unwrap().to_string()
+        };
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(JobResult::Completed("done 1".to_string()), queue.await_result(&completed, timeout).unwrap());
+        // Results stay available for further producers
+        assert_eq!(JobResult::Completed("done 1".to_string()), queue.await_result(&completed, timeout).unwrap());
+        assert_eq!(
+            JobResult::Failed { error: Some("broken".into()) },
+            queue.await_result::<String>(&failed, timeout).unwrap()
+        );
+        assert_eq!(JobResult::TimedOut, queue.await_result::<String>("unknown", timeout).unwrap());
+    }
+
+    #[test]
+    fn awaits_all_job_results() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("joined".into(), client).with_results(Duration::from_secs(60));
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        for id in 1..4 {
+            queue.push(Job { id: id }).unwrap();
+        }
+
+        let mut jids = vec![];
+        for _ in 0..2 {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.set_result(&(task.id * 10)).unwrap();
+            jids.push(task.jid().unwrap().to_string());
+        }
+        jids.push("unknown".into());
+
+        let results = queue.await_all::<u64, _>(&jids, Duration::from_secs(1)).unwrap();
+        assert_eq!(vec![JobResult::Completed(10), JobResult::Completed(20), JobResult::TimedOut], results);
+    }
+
+    #[test]
+    fn follows_jobs_through_handles() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("handled".into(), client).with_results(Duration::from_secs(60));
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let first = queue.push_tracked(Job { id: 1 }).unwrap();
+        let second = queue.push_tracked(Job { id: 2 }).unwrap();
+        assert_eq!(Some(JobStatus::Pending), first.status().unwrap());
+
+        {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            assert_eq!(Some(first.id()), task.jid());
+            assert_eq!(Some(JobStatus::Running), first.status().unwrap());
+            task.set_result(&42).unwrap();
+        }
+        assert_eq!(Some(JobStatus::Completed), first.status().unwrap());
+        assert_eq!(JobResult::Completed(42), first.await_result::<u64>(Duration::from_secs(1)).unwrap());
+
+        assert_eq!(Cancellation::Removed, second.cancel().unwrap());
+        assert_eq!(Cancellation::NotFound, second.cancel().unwrap());
+        assert_eq!(None, second.status().unwrap());
+        assert_eq!(0, queue.size());
+    }
+
+    #[test]
+    fn requests_cancellation_of_running_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("cancelled".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let job = queue.push_tracked(Job { id: 1 }).unwrap();
+
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        assert!(!task.is_cancel_requested().unwrap());
+        assert_eq!(Cancellation::Requested, job.cancel().unwrap());
+        assert!(task.is_cancel_requested().unwrap());
+        drop(task);
+        assert_eq!(Cancellation::NotFound, job.cancel().unwrap());
+    }
+
+    #[test]
+    fn runs_scripts_as_functions() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let info: String = redis::cmd("INFO").arg("server").query(&con).unwrap();
+        let major = info.lines()
+            .find_map(|line| line.strip_prefix("redis_version:"))
+            .and_then(|version| version.split('.').next())
+            .and_then(|major| major.parse::<u32>().ok());
+        if major.is_none_or(|major| major < 7) {
+            // Functions need Redis 7
+            return;
+        }
+
+        install_functions(&client).unwrap();
+        assert_eq!(Some(2), functions_version(&client).unwrap());
+
+        let queue = Queue::new("functions".into(), client).with_results(Duration::from_secs(60));
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+        let job = queue.push_tracked(Job { id: 1 }).unwrap();
+        assert_eq!(Some(0), queue.position(job.id()).unwrap());
+        {
+            let task = queue.next::<Job>(1).unwrap().unwrap();
+            task.set_result(&1).unwrap();
+        }
+        use_functions(false);
+
+        assert_eq!(JobResult::Completed(1), job.await_result::<u64>(Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn caches_sizes() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("cached-size".into(), client).with_size_cache(Duration::from_secs(60));
+
+        let _: () = con.del(queue.queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        assert_eq!(1, queue.size());
+
+        // Pushed by another client, not seen until the cache expires
+        let _: () = con.lpush(queue.queue(), "{}").unwrap();
+        assert_eq!(1, queue.size());
+
+        queue.push(Job { id: 2 }).unwrap();
+        assert_eq!(3, queue.size());
+
+        queue.resume().unwrap();
+        assert!(!queue.config().unwrap().paused);
+        let other = Queue::new("cached-size".into(), redis::Client::open("redis://127.0.0.1:6379/").unwrap());
+        other.pause().unwrap();
+        assert!(!queue.config().unwrap().paused);
+        queue.pause().unwrap();
+        assert!(queue.config().unwrap().paused);
+        queue.resume().unwrap();
+    }
+
+    #[test]
+    fn detects_capabilities() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let queue = Queue::new("capable".into(), client);
+
+        let capabilities = queue.capabilities().unwrap();
+        assert!(capabilities.version() > (2, 0, 0));
+        assert_eq!(capabilities, queue.capabilities().unwrap());
+
+        assert!(!Capabilities::new((6, 0, 16)).has_lmove());
+        assert!(Capabilities::new((6, 2, 0)).has_lmove());
+        assert!(!Capabilities::new((6, 2, 14)).has_lmpop());
+        assert!(Capabilities::new((7, 2, 4)).has_lmpop());
+    }
+
+    #[test]
+    fn instruments_connections() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = Instrumented::new(client.get_connection().unwrap());
+
+        let _: () = con.set("oppgave:instrumented", 42).unwrap();
+        assert_eq!(42, con.get::<_, u64>("oppgave:instrumented").unwrap());
+        let (a, b): (u64, u64) = redis::pipe()
+            .cmd("INCR").arg("oppgave:instrumented")
+            .cmd("GET").arg("oppgave:instrumented")
+            .query(&con)
+            .unwrap();
+        assert_eq!((43, 43), (a, b));
+        assert!(con.lpush::<_, _, u64>("oppgave:instrumented", 1).is_err());
+        let _: () = con.del("oppgave:instrumented").unwrap();
+    }
+
+    #[test]
+    fn retries_transient_push_failures() {
+        use std::io;
+        use std::time::Instant;
+        use super::transient::is_transient;
+
+        assert!(is_transient(&io::Error::new(io::ErrorKind::ConnectionReset, "reset").into()));
+        assert!(!is_transient(&redis::RedisError::from((redis::ErrorKind::TypeError, "Not a number"))));
+
+        // Nothing listens on this port
+        let client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let queue = Queue::new("unreachable".into(), client).with_transient_retries(2, Duration::from_millis(20));
+
+        let start = Instant::now();
+        match queue.push(Job { id: 1 }) {
+            Err(PushError::Redis(e)) => assert!(is_transient(&e)),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn pushes_in_pipelines() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("pipelined".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(queue.backup_queue()).unwrap();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("SET").arg("oppgave:pipelined-state").arg("paid").ignore();
+        let jid = queue.push_in_pipeline(&mut pipe, Job { id: 1 }).unwrap();
+        assert_eq!(0, queue.size());
+
+        pipe.query::<()>(&con).unwrap();
+        assert_eq!("paid", con.get::<_, String>("oppgave:pipelined-state").unwrap());
+        let task = queue.next::<Job>(1).unwrap().unwrap();
+        assert_eq!(Some(&jid[..]), task.jid());
+        let _: () = con.del("oppgave:pipelined-state").unwrap();
     }
 
     #[test]
-    fn can_enqueue() {
+    fn relays_outbox_entries() {
+        use std::io;
+
+        struct Memory(Mutex<Vec<OutboxEntry>>);
+
+        impl Outbox for Memory {
+            fn pending(&self, limit: usize) -> io::Result<Vec<OutboxEntry>> {
+                Ok(self.0.lock().unwrap().iter().take(limit).cloned().collect())
+            }
+
+            fn relayed(&self, jids: &[String]) -> io::Result<()> {
+                self.0.lock().unwrap().retain(|entry| !jids.contains(&entry.jid));
+                Ok(())
+            }
+        }
+
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
         let con = client.get_connection().unwrap();
+        let queue = Queue::new("outbox".into(), client.clone());
+        let _: () = con.del(queue.queue()).unwrap();
+
+        let entry = OutboxEntry::new(&queue, Job { id: 1 }).unwrap();
+        // Relayed before, but not marked in the outbox
+        let outbox = Memory(Mutex::new(vec![entry.clone(), entry, OutboxEntry::new(&queue, Job { id: 2 }).unwrap()]));
+        let relay = Relay::new(outbox, client).batch_size(10);
+
+        assert_eq!(3, relay.relay().unwrap());
+        // Clones share the outbox, which doesn't need to be `Clone`
+        assert_eq!(0, relay.clone().relay().unwrap());
+        assert_eq!(2, queue.size());
+    }
+
+    #[test]
+    fn pushes_to_many_queues() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let emails = Queue::new("many-emails".into(), client.clone());
+        let invoices = Queue::new("many-invoices".into(), client);
+        let _: () = con.del(emails.queue()).unwrap();
+        let _: () = con.del(invoices.queue()).unwrap();
+
+        emails.push_many(&[("many-emails", Job { id: 1 }), ("many-invoices", Job { id: 2 })]).unwrap();
+
+        assert_eq!(1, emails.next::<Job>(1).unwrap().unwrap().id);
+        assert_eq!(2, invoices.next::<Job>(1).unwrap().unwrap().id);
+
+        let limited = Queue::new("many-emails".into(), redis::Client::open("redis://127.0.0.1:6379/").unwrap())
+            .with_max_size(1);
+        limited.push(Job { id: 3 }).unwrap();
+        match limited.push_many(&[("many-invoices", Job { id: 4 }), ("many-emails", Job { id: 5 })]) {
+            Err(PushError::Full) => {}
+            _ => panic!("Expected the queue to be full"),
+        }
+        assert_eq!(0, invoices.size());
+        assert_eq!(1, limited.size());
+    }
+
+    #[test]
+    fn drains_priority_lanes_by_weight() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = PriorityQueue::new("lanes", client);
+        for &lane in &[Lane::High, Lane::Default, Lane::Low] {
+            let _: () = con.del(queue.lane(lane).queue()).unwrap();
+            let _: () = con.del(queue.lane(lane).backup_queue()).unwrap();
+        }
+
+        queue.push_low(Job { id: 3 }).unwrap();
+        queue.push_default(Job { id: 2 }).unwrap();
+        queue.push_high(Job { id: 1 }).unwrap();
+        queue.push_high(Job { id: 1 }).unwrap();
+        assert_eq!(4, queue.size());
+
+        let queue = queue.with_weights(1, 1, 1);
+        let ids: Vec<u64> = (0..4)
+            .map(|_| queue.next::<Job>(Duration::from_secs(1)).unwrap().unwrap().id)
+            .collect();
+        assert_eq!(vec![1, 2, 3, 1], ids);
+        assert!(queue.next::<Job>(Duration::from_millis(100)).unwrap().is_none());
+    }
+
+    #[test]
+    fn weighs_fetched_tasks_rather_than_polls() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = PriorityQueue::new("lanes-ratio", client);
+        for &lane in &[Lane::High, Lane::Default, Lane::Low] {
+            let _: () = con.del(queue.lane(lane).queue()).unwrap();
+            let _: () = con.del(queue.lane(lane).backup_queue()).unwrap();
+        }
+
+        // Polls of the empty lanes don't use up turns
+        assert!(queue.next::<Job>(Duration::from_millis(120)).unwrap().is_none());
+        for _ in 0..10 {
+            queue.push_high(Job { id: 1 }).unwrap();
+            queue.push_default(Job { id: 2 }).unwrap();
+            queue.push_low(Job { id: 3 }).unwrap();
+        }
+
+        let ids: Vec<u64> = (0..10)
+            .map(|_| queue.next::<Job>(Duration::from_secs(1)).unwrap().unwrap().id)
+            .collect();
+        assert_eq!(vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 3], ids);
+    }
+
+    #[test]
+    fn manages_registered_queues() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let queue = Queue::new("managed".into(), client.clone());
+        delete_queue(&client, "managed").unwrap();
+
+        let meta = QueueMeta::new("managed").with_owner("billing").with_option("retention", "7d");
+        assert!(create_queue(&client, &meta).unwrap());
+        assert!(!create_queue(&client, &QueueMeta::new("managed")).unwrap());
+
+        let stored = queue_meta(&client, "managed").unwrap().unwrap();
+        assert_eq!(Some("billing".to_string()), stored.owner);
+        assert_eq!(Some(&"7d".to_string()), stored.options.get("retention"));
+        assert!(stored.created_at > 0);
+        assert!(list_queues(&client).unwrap().iter().any(|meta| meta.name == "managed"));
+
+        let info = discover(&client).unwrap().into_iter().find(|info| info.name == "managed").unwrap();
+        assert!(info.registered);
+        assert_eq!(0, info.pending);
+
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push_delayed(Job { id: 2 }, Duration::from_secs(60)).unwrap();
+        assert!(delete_queue(&client, "managed").unwrap());
+        assert_eq!(0, queue.size());
+        assert_eq!(0, queue.delayed_size());
+        assert_eq!(None, queue_meta(&client, "managed").unwrap());
+        assert!(!delete_queue(&client, "managed").unwrap());
+    }
+
+    #[test]
+    fn applies_central_config() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("configured".into(), client);
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(format!("{}:config", queue.queue())).unwrap();
+        assert_eq!(QueueConfig::default(), queue.config().unwrap());
+
+        let config = QueueConfig {
+            rate_limit: Some(10),
+            max_attempts: Some(3),
+            retention: Some(Retention { max_age: Duration::from_secs(60), max_jobs: 10 }),
+            ..QueueConfig::default()
+        };
+        queue.set_config(&config).unwrap();
+        queue.pause().unwrap();
+        assert!(queue.config().unwrap().paused);
+        assert_eq!(Some(3), queue.config().unwrap().max_attempts);
+
+        queue.push(Job { id: 1 }).unwrap();
+        let worker = Worker::new(queue.clone());
+        let handle = worker.clone();
+        let runner = thread::spawn(move || worker.run(|_job: Job, _token| -> Result<(), String> { Ok(()) }));
+
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(1, queue.size());
+        queue.resume().unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        handle.stop();
+        runner.join().unwrap();
+
+        assert_eq!(0, queue.size());
+        assert_eq!(config, queue.config().unwrap());
+    }
+
+    #[test]
+    fn reports_config_changes() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("reloaded".into(), client);
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(format!("{}:config", queue.queue())).unwrap();
+
+        let changes = Arc::new(Mutex::new(vec![]));
+        let seen = changes.clone();
+        let worker = Worker::new(queue.clone())
+            .config_reload(Duration::from_millis(100))
+            .on_config_change(move |old, new| seen.lock().unwrap().push((*old, *new)));
+        let handle = worker.clone();
+        let runner = thread::spawn(move || worker.run(|_job: Job, _token| -> Result<(), String> { Ok(()) }));
+
+        thread::sleep(Duration::from_millis(300));
+        queue.pause().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        queue.push(Job { id: 1 }).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(1, queue.size());
+
+        queue.set_config(&QueueConfig { concurrency: Some(1), ..QueueConfig::default() }).unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        handle.stop();
+        runner.join().unwrap();
+
+        assert_eq!(0, queue.size());
+        let changes = changes.lock().unwrap();
+        assert_eq!(2, changes.len());
+        assert!(!changes[0].0.paused && changes[0].1.paused);
+        assert!(changes[1].0.paused && !changes[1].1.paused);
+        assert_eq!(Some(1), changes[1].1.concurrency);
+    }
+
+    #[test]
+    fn retries_dead_jobs_throttled() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("retry-dead-throttled".into(), client);
 
-        let worker = Queue::new("enqueue".into(), client);
         let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
 
-        assert_eq!(0, worker.size());
+        for id in 0..6 {
+            worker.push(Job { id: id }).unwrap();
+            worker.next::<Job>(0).unwrap().unwrap().dead_letter("downstream unavailable");
+        }
+        assert_eq!(6, worker.dead_size());
 
-        worker.push(Job { id: 53 }).unwrap();
+        let started = Instant::now();
+        assert_eq!(6, worker.retry_all_dead_throttled(3).unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(1500));
+        assert_eq!(0, worker.dead_size());
+        assert_eq!(6, worker.size());
+    }
 
-        assert_eq!(1, worker.size());
+    #[test]
+    fn suggests_worker_count() {
+        let hint = ScalingHint::new(600, 0.0, 5.0, Some(Duration::from_millis(2000)), Duration::from_secs(60));
+        // 5 jobs per second coming in and 10 per second to drain the backlog, 2 seconds each
+        assert_eq!(30, hint.workers);
+        assert_eq!(1, ScalingHint::new(10, 0.0, 0.0, None, Duration::from_secs(60)).workers);
+        assert_eq!(0, ScalingHint::new(0, -1.0, 0.0, Some(Duration::from_secs(1)), Duration::from_secs(60)).workers);
 
-        let j = worker.next::<Job>(0).unwrap().unwrap();
-        assert_eq!(53, j.id);
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("scaling".into(), client);
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(format!("{}:depth", queue.queue())).unwrap();
+
+        assert_eq!(0, queue.scaling_hint(Duration::from_secs(60)).unwrap().workers);
+        for id in 0..5 {
+            queue.push(Job { id: id }).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        let hint = queue.scaling_hint(Duration::from_secs(60)).unwrap();
+        assert_eq!(5, hint.depth);
+        assert!(hint.trend > 0.0);
+        assert!(hint.workers >= 1);
     }
 
     #[test]
-    fn does_not_drop_failed() {
+    fn steals_from_overflow_queues() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
         let con = client.get_connection().unwrap();
-        let worker = Queue::new("failure".into(), client);
+        let overflow = Queue::new("stealing-overflow".into(), client.clone());
+        let _: () = con.del(overflow.queue()).unwrap();
+        overflow.push(Job { id: 2 }).unwrap();
+
+        let queue = PriorityQueue::new("stealing", client).with_overflow(vec![overflow.clone()], 3);
+        for &lane in &[Lane::High, Lane::Default, Lane::Low] {
+            let _: () = con.del(queue.lane(lane).queue()).unwrap();
+        }
+        queue.push_low(Job { id: 1 }).unwrap();
+
+        let task = queue.next::<Job>(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(1, task.id);
+        drop(task);
+
+        let started = Instant::now();
+        let task = queue.next::<Job>(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(2, task.id);
+        assert_eq!(overflow.queue(), task.queue().queue());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn announces_stalled_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let stalled = Arc::new(Mutex::new(vec![]));
+        let seen = stalled.clone();
+        let worker = Queue::new("stalled".into(), client)
+            .with_stall_handler(move |job: &StalledJob| seen.lock().unwrap().push(job.clone()));
 
         let _: () = con.del(worker.queue()).unwrap();
         let _: () = con.del(worker.backup_queue()).unwrap();
-        let _: () = con.lpush(worker.queue(), "{\"id\":1}").unwrap();
+        let _: () = con.del(format!("{}:processing", worker.queue())).unwrap();
+        worker.push(Job { id: 1 }).unwrap();
 
-        {
-            let task: TaskGuard<Job> = worker.next(0).unwrap().unwrap();
-            task.fail();
+        let mut subscription = worker.subscribe_stalled().unwrap();
+        subscription.set_timeout(Some(Duration::from_secs(1))).unwrap();
+        let task = worker.next::<Job>(1).unwrap().unwrap();
+        let jid = task.jid().unwrap().to_string();
+        ::std::mem::forget(task);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(1, worker.reap_stuck(Duration::from_millis(10)).unwrap());
+
+        let stalled = stalled.lock().unwrap();
+        assert_eq!(1, stalled.len());
+        assert_eq!(jid, stalled[0].jid);
+        assert_eq!(worker.worker_id(), stalled[0].worker);
+        assert!(stalled[0].held_for >= 50);
+        let announced = subscription.next().unwrap().unwrap();
+        assert_eq!(stalled[0], announced);
+    }
+
+    #[test]
+    fn reports_task_timings() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("timings".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+
+        worker.push(Job { id: 1 }).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let task = worker.next::<Job>(1).unwrap().unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        assert!(task.started_at() >= task.enqueued_at().unwrap());
+        assert!(task.wait_time().unwrap() >= Duration::from_millis(100));
+        assert!(task.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn skips_jobs_past_their_deadline() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("deadlines".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
+
+        // Due by the clock of the server, not the one of the worker
+        let (secs, _): (u64, u64) = redis::cmd("TIME").query(&con).unwrap();
+        let server = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let past = server - Duration::from_secs(1);
+        let future = server + Duration::from_secs(60);
+        let deadlines = [Deadline::dead_letter(past), Deadline::discard(past), Deadline::discard(future)];
+        for (id, &deadline) in (1..).zip(deadlines.iter()) {
+            worker.push_with_options(Job { id: id }, JobOptions {
+                deadline: Some(deadline),
+                ..JobOptions::default()
+            }).unwrap();
         }
 
-        let len: u32 = con.llen(worker.backup_queue()).unwrap();
-        assert_eq!(1, len);
+        // Expired jobs are finished on the way to the next one
+        assert_eq!(3, worker.next::<Job>(1).unwrap().unwrap().id);
+        assert_eq!(1, worker.dead_size());
+        assert_eq!(0, worker.size());
+    }
+
+    #[test]
+    fn rejects_duplicates_of_unique_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("unique".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+        for key in &["window", "started", "completed"] {
+            let _: () = con.del(format!("oppgave:lock:unique:{}", key)).unwrap();
+        }
+        let push = |unique: Unique| {
+            worker.push_with_options(Job { id: 1 }, JobOptions {
+                unique: Some(unique),
+                ..JobOptions::default()
+            })
+        };
+        let window = || Unique::for_duration("window", Duration::from_millis(200));
+
+        push(window()).unwrap();
+        assert!(matches!(push(window()), Err(PushError::Duplicate)));
+        drop(worker.try_next::<Job>().unwrap());
+        assert!(matches!(push(window()), Err(PushError::Duplicate)));
+        thread::sleep(Duration::from_millis(300));
+        push(window()).unwrap();
+        drop(worker.try_next::<Job>().unwrap());
+
+        push(Unique::until_started("started")).unwrap();
+        assert!(matches!(push(Unique::until_started("started")), Err(PushError::Duplicate)));
+        let running = worker.try_next::<Job>().unwrap().unwrap();
+        push(Unique::until_started("started")).unwrap();
+        drop(running);
+        drop(worker.try_next::<Job>().unwrap());
+
+        push(Unique::until_completed("completed")).unwrap();
+        let running = worker.try_next::<Job>().unwrap().unwrap();
+        assert!(matches!(push(Unique::until_completed("completed")), Err(PushError::Duplicate)));
+        drop(running);
+        push(Unique::until_completed("completed")).unwrap();
+    }
+
+    #[test]
+    fn pushes_items_in_chunks() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("chunked".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+
+        assert_eq!(0, worker.push_chunked(Vec::<u64>::new(), 10).unwrap());
+        assert_eq!(251, worker.push_chunked(0..2503u64, 10).unwrap());
+        assert_eq!(251, worker.size());
+
+        let first = worker.try_next::<ChunkTask<u64>>().unwrap().unwrap();
+        assert_eq!((0..10).collect::<Vec<_>>(), first.items);
+        let sizes: u64 = (1..251)
+            .map(|_| worker.try_next::<ChunkTask<u64>>().unwrap().unwrap().items.len() as u64)
+            .sum();
+        assert_eq!(2493, sizes);
+    }
+
+    #[test]
+    fn reduces_partial_results_of_chunks() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("map".into(), client.clone());
+        let reducer = Queue::new("reduce".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(reducer.queue()).unwrap();
+
+        let bid = worker.push_map_reduce(1..26u64, 10, "reduce").unwrap();
+        assert_eq!(3, worker.size());
+        assert_eq!(0, reducer.size());
+
+        let plain = ChunkTask { items: vec![1u64], partial: None };
+        assert!(worker.store_partial(&plain, &1u64).is_err());
+
+        for _ in 0..3 {
+            let chunk = worker.try_next::<ChunkTask<u64>>().unwrap().unwrap();
+            if chunk.items.len() < 10 {
+                // The last chunk fails, leaving a gap in the partial results
+                chunk.dead_letter("no sum for you");
+                continue;
+            }
+            let sum: u64 = chunk.items.iter().sum();
+            worker.store_partial(&chunk, &sum).unwrap();
+        }
+        assert_eq!(None, worker.batch_status(&bid).unwrap());
+
+        let reduce = reducer.try_next::<ReduceTask>().unwrap().unwrap();
+        assert_eq!(bid, reduce.batch);
+        let partials = reducer.partials::<u64>(&reduce).unwrap();
+        assert_eq!(vec![Some(55), Some(155), None], partials);
+
+        reducer.delete_partials(&reduce).unwrap();
+        assert_eq!(vec![None, None, None], reducer.partials::<u64>(&reduce).unwrap());
+    }
+
+    #[test]
+    fn runs_commands() {
+        let token = CancellationToken::new();
+        let echo = CommandTask::new("sh").with_args(&["-c", "echo \"$GREETING\"; echo oops >&2"]).with_env("GREETING", "hi");
+        let output = execute(echo, token.clone()).unwrap();
+        assert_eq!(Some(0), output.status);
+        assert_eq!("hi\n", output.stdout);
+        assert_eq!("oops\n", output.stderr);
+
+        let failing = CommandTask::new("sh").with_args(&["-c", "echo half done; echo broken >&2; exit 3"]);
+        match execute(failing, token.clone()) {
+            Err(e @ CommandError::Failed(_)) => {
+                assert_eq!("Command exited with status 3: broken", e.to_string());
+                assert_eq!(TaskOutcome::Retry, e.classify());
+                assert_eq!(
+                    "Command exited with status 3: broken\n--- stdout ---\nhalf done\n--- stderr ---\nbroken",
+                    ::command::Failure(e).to_string()
+                );
+            }
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+
+        let missing = execute(CommandTask::new("/nonexistent/oppgave"), token.clone()).unwrap_err();
+        assert_eq!(TaskOutcome::Fail, missing.classify());
+
+        token.cancel();
+        let started = Instant::now();
+        let sleeping = CommandTask::new("sleep").with_arg("10");
+        assert_eq!(Err(CommandError::Cancelled), execute(sleeping, token));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The background sleep would keep the output open until it ends
+        let started = Instant::now();
+        let detached = CommandTask::new("sh").with_args(&["-c", "sleep 60 & echo done"]);
+        let output = execute(detached, CancellationToken::new()).unwrap();
+        assert_eq!("done\n", output.stdout);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn kills_commands_on_timeout() {
+        // The background sleep keeps the output open unless the whole group is killed
+        let started = Instant::now();
+        let command = CommandTask::new("sh")
+            .with_args(&["-c", "echo started; sleep 10 & wait"])
+            .with_timeout(Duration::from_millis(300));
+        match execute(command, CancellationToken::new()) {
+            Err(e @ CommandError::TimedOut { .. }) => {
+                assert_eq!("Command timed out after 300 ms", e.to_string());
+                assert_eq!(TaskOutcome::Retry, e.classify());
+                if let CommandError::TimedOut { output, .. } = e {
+                    assert_eq!("started\n", output.stdout);
+                    assert_eq!(Some(9), output.signal);
+                }
+            }
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn isolates_handlers_in_child_processes() {
+        use isolate::{self, Report};
+
+        let retry = |e: &String| if e == "fatal" { TaskOutcome::Fail } else { TaskOutcome::Retry };
+        let token = CancellationToken::new();
+        let handler = |job: Job, _| -> Result<u64, String> {
+            match job.id {
+                0 => Err("fatal".into()),
+                1 => ::std::process::abort(),
+                2 => {
+                    thread::sleep(Duration::from_secs(10));
+                    Ok(2)
+                }
+                id => Ok(id * 2),
+            }
+        };
+        let run = |id| isolate::run(&handler, retry, Job { id: id }, &token, Some(Duration::from_millis(500)));
+
+        match run(21) {
+            Report::Completed(result) => assert_eq!("42", result.get()),
+            _ => panic!("handler should complete"),
+        }
+        match run(0) {
+            Report::Failed { outcome, message } => {
+                assert_eq!(TaskOutcome::Fail, isolate::outcome(&outcome));
+                assert_eq!("fatal", message);
+            }
+            _ => panic!("handler should fail"),
+        }
+        match run(1) {
+            Report::Failed { message, .. } => assert_eq!("Child process was killed by signal 6", message),
+            _ => panic!("child should crash"),
+        }
+
+        let started = Instant::now();
+        match run(2) {
+            Report::Failed { outcome, message } => {
+                assert_eq!(TaskOutcome::Retry, isolate::outcome(&outcome));
+                assert_eq!("Timed out after 500 ms", message);
+            }
+            _ => panic!("child should time out"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn gates_on_resource_pressure() {
+        let usage = Arc::new(Mutex::new(ResourceUsage { load: Some(0.5), memory: Some(0.5) }));
+        let changes = Arc::new(Mutex::new(vec![]));
+        let gate = {
+            let usage = usage.clone();
+            let changes = changes.clone();
+            ResourceGate::new()
+                .with_probe(move || Ok(*usage.lock().unwrap()))
+                .max_load(0.8)
+                .max_memory(0.9)
+                .sample_interval(Duration::from_millis(0))
+                .on_change(move |pressure| changes.lock().unwrap().push(pressure))
+        };
+        let set = |load, memory| *usage.lock().unwrap() = ResourceUsage { load: Some(load), memory: Some(memory) };
+
+        assert!(gate.allow());
+        set(0.5, 0.95);
+        assert!(!gate.allow());
+        assert!(gate.is_under_pressure());
+
+        // Just below the threshold is not enough to resume
+        set(0.5, 0.85);
+        assert!(!gate.allow());
+        set(0.5, 0.7);
+        assert!(gate.allow());
+        set(1.5, 0.7);
+        assert!(!gate.allow());
+        assert_eq!(vec![true, false, true], *changes.lock().unwrap());
+
+        let broken = ResourceGate::new()
+            .with_probe(|| Err(::std::io::Error::other("no /proc")))
+            .max_load(0.1);
+        assert!(broken.allow());
+    }
+
+    #[test]
+    fn fails_tasks_of_panicking_handlers() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("panicking".into(), client);
+        let running = on_spawned_thread(&queue);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(running.backup_queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+
+        let worker = Worker::new(queue.clone());
+        let handle = worker.clone();
+        let runner = thread::spawn(move || {
+            worker.run(|_job: Job, _token| -> Result<(), String> { panic!("handler bug") })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        handle.stop();
+        runner.join().unwrap();
+
+        // The task is kept for a retry instead of being acknowledged
+        let failed: u64 = con.llen(running.backup_queue()).unwrap();
+        assert_eq!(1, failed);
+    }
+
+    #[test]
+    fn finishes_cancelled_batch_members() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("cancelled-members".into(), client.clone());
+        let completed = Queue::new("cancelled-complete".into(), client);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        let _: () = con.del(completed.queue()).unwrap();
+        let batch = Batch::new()
+            .push(Job { id: 1 })
+            .on_complete("cancelled-complete", Job { id: 10 });
+        let bid = queue.push_batch(batch).unwrap();
+
+        let entries: Vec<String> = con.lrange(queue.queue(), 0, -1).unwrap();
+        let jid = serde_json::from_str::<serde_json::Value>(&entries[0]).unwrap()["jid"].as_str().unwrap().to_string();
+        assert_eq!(Cancellation::Removed, queue.handle(&jid).cancel().unwrap());
+
+        let status = queue.batch_status(&bid).unwrap().unwrap();
+        assert_eq!(0, status.pending);
+        assert_eq!(1, status.failed);
+        assert_eq!(1, completed.size());
+    }
+
+    #[test]
+    fn releases_unique_keys_of_buried_and_cancelled_jobs() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let worker = Queue::new("unique-released".into(), client);
+        let _: () = con.del(worker.queue()).unwrap();
+        let _: () = con.del(worker.backup_queue()).unwrap();
+        let _: () = con.del(worker.dead_queue()).unwrap();
+        let _: () = con.del("oppgave:lock:unique:released").unwrap();
+        let push = || {
+            worker.push_tracked_with_options(Job { id: 1 }, JobOptions {
+                unique: Some(Unique::until_completed("released")),
+                max_attempts: Some(1),
+                ..JobOptions::default()
+            })
+        };
+
+        push().unwrap();
+        worker.try_next::<Job>().unwrap().unwrap().fail();
+        assert_eq!(1, worker.dead_size());
+
+        let job = push().unwrap();
+        assert!(matches!(push(), Err(PushError::Duplicate)));
+        assert_eq!(Cancellation::Removed, job.cancel().unwrap());
+        push().unwrap();
+    }
+
+    #[test]
+    fn leaves_other_kinds_behind_in_lifo_queues() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let con = client.get_connection().unwrap();
+        let queue = Queue::new("kinds-lifo".into(), client).with_order(Order::Lifo);
+
+        let _: () = con.del(queue.queue()).unwrap();
+        queue.push(Job { id: 1 }).unwrap();
+        queue.push(serde_json::json!({ "type": "transcode", "id": 2 })).unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let worker = Worker::new(queue.clone()).skip_kinds(vec!["transcode"]);
+        let handle = worker.clone();
+        let handled = seen.clone();
+        let runner = thread::spawn(move || {
+            worker.run(move |job: serde_json::Value, _token| -> Result<(), String> {
+                handled.lock().unwrap().push(job["id"].as_u64().unwrap());
+                Ok(())
+            })
+        });
+
+        thread::sleep(Duration::from_millis(500));
+        handle.stop();
+        runner.join().unwrap();
+
+        // The skipped task went to the end fetched last instead of being fetched again
+        assert_eq!(vec![1], *seen.lock().unwrap());
+        assert_eq!(1, queue.size());
     }
 }
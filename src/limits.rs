@@ -0,0 +1,79 @@
+//! Limits on the number of tasks of a type running at once within a process.
+
+use std::any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Caps how many tasks of each type the workers of a process run concurrently.
+///
+/// Tasks are grouped by the type the worker decodes them into. A worker whose type is at its
+/// limit doesn't fetch new tasks until another worker of the type finished, so heavy tasks
+/// can't monopolize the threads of a process. Types without a limit are not restricted.
+///
+/// Clones share their state, pass a clone to every worker of the process.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let limits = ConcurrencyLimits::new().limit::<VideoTranscode>(2).limit::<SendEmail>(20);
+///
+/// for _ in 0..20 {
+///     let worker = Worker::new(videos.clone()).concurrency_limits(limits.clone());
+///     thread::spawn(move || worker.run(transcode));
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConcurrencyLimits {
+    // Maximum and current number of running tasks per type
+    inner: Arc<Mutex<HashMap<String, (usize, usize)>>>,
+}
+
+impl ConcurrencyLimits {
+    /// Create limits not restricting any type
+    pub fn new() -> ConcurrencyLimits {
+        ConcurrencyLimits::default()
+    }
+
+    /// Run at most `max` tasks of type `T` at once
+    pub fn limit<T>(self, max: usize) -> ConcurrencyLimits {
+        self.limit_named(any::type_name::<T>(), max)
+    }
+
+    /// Run at most `max` tasks of the type named `name` at once
+    ///
+    /// Types are named by `std::any::type_name`.
+    pub fn limit_named(self, name: &str, max: usize) -> ConcurrencyLimits {
+        self.inner.lock().unwrap().entry(name.into()).or_insert((0, 0)).0 = max;
+        self
+    }
+
+    /// Get the number of tasks of type `T` running right now
+    ///
+    /// Only types with a limit are counted.
+    pub fn running<T>(&self) -> usize {
+        self.inner.lock().unwrap().get(any::type_name::<T>()).map_or(0, |&(_, running)| running)
+    }
+
+    /// Take a slot for a task of the type `name`.
+    ///
+    /// Returns `false` if the type is at its limit.
+    pub(crate) fn acquire(&self, name: &str) -> bool {
+        match self.inner.lock().unwrap().get_mut(name) {
+            Some(&mut (max, ref mut running)) => {
+                if *running >= max {
+                    return false;
+                }
+                *running += 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Give back a slot taken with `acquire`.
+    pub(crate) fn release(&self, name: &str) {
+        if let Some(&mut (_, ref mut running)) = self.inner.lock().unwrap().get_mut(name) {
+            *running = running.saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Distributed locks with an expiry, released only by their owner.
+//!
+//! A lock is a single Redis key holding a random token of its owner, set with `SET NX PX`.
+//! Only the owner can extend or release the lock, and a lock of a crashed owner expires on its
+//! own.
+//!
+//! ## Example
+//!
+//! ```rust,ignore
+//! use oppgave::lock;
+//!
+//! if let Some(guard) = lock::acquire(&client, "invoices", Duration::from_secs(30))? {
+//!     // Only one process gets here at a time
+//!     guard.release()?;
+//! }
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+use redis::{self, Pipeline, RedisResult};
+use functions;
+use envelope::new_jid;
+
+/// Deletes a lock if it's still held by the given owner.
+///
+/// KEYS[1]: the lock
+/// ARGV[1]: token of the owner
+pub(crate) const RELEASE: &'static str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Resets the expiry of a lock if it's still held by the given owner.
+///
+/// KEYS[1]: the lock
+/// ARGV[1]: token of the owner
+/// ARGV[2]: the new expiry in milliseconds
+pub(crate) const EXTEND: &'static str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+";
+
+/// Get the key the lock `name` is stored in.
+pub(crate) fn lock_key(name: &str) -> String {
+    format!("oppgave:lock:{}", name)
+}
+
+/// Try to take the lock `name` for `token`, expiring after `ttl`.
+///
+/// Returns `false` if the lock is held by someone else.
+pub(crate) fn try_lock<C: redis::ConnectionLike>(con: &C, name: &str, token: &str, ttl: Duration) -> RedisResult<bool> {
+    let reply: Option<String> = redis::cmd("SET")
+        .arg(lock_key(name))
+        .arg(token)
+        .arg("NX")
+        .arg("PX")
+        .arg(::cmp::max(1, ::duration_millis(ttl)))
+        .query(con)?;
+    Ok(reply.is_some())
+}
+
+/// Add the command releasing the lock `name` held by `token` to the pipeline.
+pub(crate) fn unlock(pipe: &mut Pipeline, name: &str, token: &str) {
+    functions::eval(pipe, RELEASE, 1).arg(lock_key(name)).arg(token).ignore();
+}
+
+/// A held lock, released when dropped.
+///
+/// Release it explicitly with `release` to handle errors.
+pub struct LockGuard {
+    client: redis::Client,
+    name: String,
+    token: String,
+    released: bool,
+}
+
+impl LockGuard {
+    /// Get the name of the lock
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reset the expiry of the lock to `ttl` from now
+    ///
+    /// Returns `false` if the lock expired and was lost in the meantime.
+    pub fn extend(&self, ttl: Duration) -> RedisResult<bool> {
+        let extended: u64 = functions::script(EXTEND)
+            .key(lock_key(&self.name))
+            .arg(&self.token[..])
+            .arg(::cmp::max(1, ::duration_millis(ttl)))
+            .invoke(&self.client.get_connection()?)?;
+        Ok(extended > 0)
+    }
+
+    /// Release the lock
+    ///
+    /// Returns `false` if the lock expired and was lost in the meantime.
+    pub fn release(mut self) -> RedisResult<bool> {
+        self.released = true;
+        let released: u64 = functions::script(RELEASE)
+            .key(lock_key(&self.name))
+            .arg(&self.token[..])
+            .invoke(&self.client.get_connection()?)?;
+        Ok(released > 0)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let mut pipe = redis::pipe();
+        unlock(&mut pipe, &self.name, &self.token);
+        let _ = pipe.query::<()>(&self.client);
+    }
+}
+
+/// Try to take the lock `name`, expiring after `ttl` unless extended
+///
+/// Returns `None` right away if the lock is held by someone else.
+pub fn acquire(client: &redis::Client, name: &str, ttl: Duration) -> RedisResult<Option<LockGuard>> {
+    let token = new_jid();
+    if !try_lock(&client.get_connection()?, name, &token, ttl)? {
+        return Ok(None);
+    }
+
+    Ok(Some(LockGuard {
+        client: client.clone(),
+        name: name.into(),
+        token: token,
+        released: false,
+    }))
+}
+
+/// Take the lock `name`, waiting for up to `wait` while it's held by someone else
+///
+/// Returns `None` if the lock could not be taken in time.
+pub fn acquire_wait(client: &redis::Client, name: &str, ttl: Duration, wait: Duration) -> RedisResult<Option<LockGuard>> {
+    let deadline = Instant::now() + wait;
+    let mut idle = ::Idle::new(::IdleStrategy::Exponential {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(200),
+    });
+
+    loop {
+        if let Some(guard) = acquire(client, name, ttl)? {
+            return Ok(Some(guard));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        if now + idle.delay() > deadline {
+            thread::sleep(deadline - now);
+        } else {
+            idle.idle();
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Management of queues registered in a central index.
+
+use std::collections::BTreeMap;
+use redis::{self, RedisResult};
+use serde_json;
+use envelope::Envelope;
+
+/// Hash of all registered queues, mapping the name of a queue to its metadata
+pub(crate) const QUEUES_KEY: &'static str = "oppgave:queues";
+
+/// Metadata of a queue registered with `create_queue`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueMeta {
+    /// Name of the queue, as passed to `Queue::new`
+    pub name: String,
+    /// Time the queue was registered, in milliseconds since the Unix epoch
+    pub created_at: u64,
+    /// The team or service owning the queue, if any
+    pub owner: Option<String>,
+    /// Free-form settings of the queue, for tooling to apply
+    pub options: BTreeMap<String, String>,
+}
+
+impl QueueMeta {
+    /// Create the metadata of the queue `name`, without owner or options
+    pub fn new(name: &str) -> QueueMeta {
+        QueueMeta {
+            name: name.into(),
+            ..QueueMeta::default()
+        }
+    }
+
+    /// Set the owner of the queue
+    pub fn with_owner(mut self, owner: &str) -> QueueMeta {
+        self.owner = Some(owner.into());
+        self
+    }
+
+    /// Set the option `key` to `value`
+    pub fn with_option(mut self, key: &str, value: &str) -> QueueMeta {
+        self.options.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// Register a queue in the Redis instance of `client`
+///
+/// `created_at` is set to the time of the Redis server.
+/// Returns `false` if the queue is registered already, its metadata is left untouched then.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// create_queue(&client, &QueueMeta::new("emails").with_owner("growth"))?;
+/// let queue = Queue::new("emails".into(), client);
+/// ```
+pub fn create_queue(client: &redis::Client, meta: &QueueMeta) -> RedisResult<bool> {
+    let con = client.get_connection()?;
+    let meta = QueueMeta {
+        created_at: ::server_millis(&con)?,
+        ..meta.clone()
+    };
+    let record = serde_json::to_string(&meta).expect("Encoding queue metadata can't fail");
+
+    redis::cmd("HSETNX").arg(QUEUES_KEY).arg(&meta.name[..]).arg(record).query(&con)
+}
+
+/// Get the metadata of the queue `name`, `None` if it is not registered
+pub fn queue_meta(client: &redis::Client, name: &str) -> RedisResult<Option<QueueMeta>> {
+    let con = client.get_connection()?;
+    let record: Option<String> = redis::cmd("HGET").arg(QUEUES_KEY).arg(name).query(&con)?;
+    Ok(record.and_then(|record| serde_json::from_str(&record).ok()))
+}
+
+/// List all queues registered in the Redis instance of `client`, sorted by name
+pub fn list_queues(client: &redis::Client) -> RedisResult<Vec<QueueMeta>> {
+    let con = client.get_connection()?;
+    let records: Vec<String> = redis::cmd("HVALS").arg(QUEUES_KEY).query(&con)?;
+
+    let mut queues = records
+        .iter()
+        .filter_map(|record| serde_json::from_str::<QueueMeta>(record).ok())
+        .collect::<Vec<_>>();
+    queues.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(queues)
+}
+
+/// Get the names of all registered queues.
+pub(crate) fn registered<C: redis::ConnectionLike>(con: &C) -> RedisResult<Vec<String>> {
+    redis::cmd("HKEYS").arg(QUEUES_KEY).query(con)
+}
+
+/// Delete the queue `name` from the Redis instance of `client`
+///
+/// Removes the registration together with all keys of the queue: pending, reserved, delayed
+/// and dead jobs, failure records, archives and recurring jobs.
+/// Jobs still processed by workers are finished as usual, but their results are not kept.
+/// Keys of queues named `<name>:...` are removed as well. Unique keys held by the removed jobs
+/// are released.
+///
+/// Returns `false` if the queue was not registered, its keys are deleted anyway.
+pub fn delete_queue(client: &redis::Client, name: &str) -> RedisResult<bool> {
+    let con = client.get_connection()?;
+    let queue = format!("oppgave:{}", name);
+    let mut keys: Vec<String> = {
+        let iter = redis::cmd("SCAN").cursor_arg(0).arg("MATCH").arg(format!("{}:*", queue)).iter(&con)?;
+        iter.collect()
+    };
+    keys.push(queue);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic().cmd("HDEL").arg(QUEUES_KEY).arg(name);
+    for key in &keys {
+        // Unique keys are shared by all queues, so they're not among the deleted keys
+        for data in stored_jobs(&con, key)? {
+            let job = match Envelope::parse(&data) {
+                Some(job) => job,
+                None => continue,
+            };
+            if let Some(unique) = job.options.as_ref().and_then(|options| options.unique.as_ref()) {
+                unique.release(&mut pipe, &job.jid, None);
+            }
+        }
+    }
+    for chunk in keys.chunks(1000) {
+        pipe.cmd("DEL").arg(chunk).ignore();
+    }
+    let (removed,): (bool,) = pipe.query(&con)?;
+    Ok(removed)
+}
+
+/// Get the jobs stored in `key`: the entries of lists and sorted sets and the job of failure
+/// records.
+fn stored_jobs<C: redis::ConnectionLike>(con: &C, key: &str) -> RedisResult<Vec<Vec<u8>>> {
+    let kind: String = redis::cmd("TYPE").arg(key).query(con)?;
+    match &kind[..] {
+        "list" => redis::cmd("LRANGE").arg(key).arg(0).arg(-1).query(con),
+        "zset" => redis::cmd("ZRANGE").arg(key).arg(0).arg(-1).query(con),
+        "hash" => {
+            let job: Option<Vec<u8>> = redis::cmd("HGET").arg(key).arg("job").query(con)?;
+            Ok(job.into_iter().collect())
+        }
+        _ => Ok(vec![]),
+    }
+}
@@ -0,0 +1,72 @@
+//! Approximate memory consumed by a queue, as reported by Redis.
+
+use std::slice;
+use redis::{self, RedisResult};
+use failure;
+
+/// Bytes of Redis memory used by the structures of a queue.
+///
+/// The sizes are estimated by `MEMORY USAGE`, which samples large lists and sets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes used by the pending jobs, summed over all shards
+    pub pending: u64,
+    /// Bytes used by the delayed jobs
+    pub delayed: u64,
+    /// Bytes used by the dead letter queue and the failure records of its jobs
+    pub dead: u64,
+}
+
+impl MemoryUsage {
+    /// Get the total number of bytes
+    pub fn total(&self) -> u64 {
+        self.pending + self.delayed + self.dead
+    }
+}
+
+/// Get the memory used by the given keys, skipping missing ones.
+fn usage<C: redis::ConnectionLike>(con: &C, keys: &[String]) -> RedisResult<u64> {
+    if keys.is_empty() {
+        return Ok(0);
+    }
+
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.cmd("MEMORY").arg("USAGE").arg(&key[..]);
+    }
+    let sizes: Vec<Option<u64>> = pipe.query(con)?;
+    Ok(sizes.into_iter().flatten().sum())
+}
+
+/// Get the memory used by the lists `sources`, the delayed set `delayed` and the dead jobs of `queue`.
+pub(crate) fn measure<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    sources: &[String],
+    delayed: &str,
+) -> RedisResult<MemoryUsage> {
+    const CHUNK: usize = 500;
+    let dead_key = failure::dead_key(queue);
+    let mut dead = usage(con, slice::from_ref(&dead_key))?;
+
+    let mut offset = 0;
+    loop {
+        let jids: Vec<String> = redis::cmd("ZRANGE")
+            .arg(&dead_key[..])
+            .arg(offset)
+            .arg(offset + CHUNK - 1)
+            .query(con)?;
+        let records = jids.iter().map(|jid| failure::failure_key(queue, jid)).collect::<Vec<_>>();
+        dead += usage(con, &records)?;
+        if jids.len() < CHUNK {
+            break;
+        }
+        offset += CHUNK;
+    }
+
+    Ok(MemoryUsage {
+        pending: usage(con, sources)?,
+        delayed: usage(con, &[delayed.into()])?,
+        dead: dead,
+    })
+}
@@ -0,0 +1,103 @@
+//! Upgrades of queued tasks whose type changed shape since they were pushed.
+
+use std::any;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use serde_json::{self, Value as Json};
+use redis::{ErrorKind, RedisError, RedisResult, Value};
+
+type Upgrade = Arc<dyn Fn(Json) -> Result<Json, String> + Send + Sync>;
+
+/// The upgrades of a single task type.
+#[derive(Clone, Default)]
+struct Versions {
+    current: u32,
+    upgrades: BTreeMap<u32, Upgrade>,
+}
+
+/// Upgrade functions turning older versions of a task into the current one.
+///
+/// Every job records the version its task type had when it was pushed by a queue with these
+/// migrations. Jobs pushed without a version count as version 1. When such a job is fetched,
+/// its task is passed through the upgrades from its version up to the current one before it's
+/// decoded, so jobs queued before a deploy still decode after the struct changed shape.
+///
+/// Task types are identified by `std::any::type_name`, so migrations are registered for the
+/// type passed to `push` and `next`, or taken by the handler of a `Worker`. The current version of a type is the one after its last
+/// upgrade, unless declared with `version`.
+/// Only fetching a task migrates it, inspecting jobs shows them as they were pushed.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// // Version 2 of `Email` replaced `to: String` with `to: Vec<String>`
+/// let migrations = Migrations::new().register::<Email, _>(1, |mut task| {
+///     task["to"] = json!([task["to"].take()]);
+///     Ok(task)
+/// });
+///
+/// let queue = Queue::new("emails".into(), client).with_migrations(migrations);
+/// ```
+#[derive(Clone, Default)]
+pub struct Migrations {
+    types: HashMap<String, Versions>,
+}
+
+impl Migrations {
+    /// Create migrations without any upgrades
+    pub fn new() -> Migrations {
+        Migrations::default()
+    }
+
+    /// Register the upgrade of the task type `T` from version `from` to `from + 1`
+    pub fn register<T, F>(mut self, from: u32, upgrade: F) -> Migrations
+    where
+        F: Fn(Json) -> Result<Json, String> + Send + Sync + 'static,
+    {
+        let versions = self.types.entry(any::type_name::<T>().into()).or_default();
+        versions.current = versions.current.max(from + 1);
+        versions.upgrades.insert(from, Arc::new(upgrade));
+        self
+    }
+
+    /// Declare the current version of the task type `T`
+    ///
+    /// Needed for types whose version was bumped without an upgrade, e.g. after adding an
+    /// optional field.
+    pub fn version<T>(mut self, version: u32) -> Migrations {
+        let versions = self.types.entry(any::type_name::<T>().into()).or_default();
+        versions.current = versions.current.max(version);
+        self
+    }
+
+    /// Get the current version of the task type `T`, if it has one
+    pub fn current<T>(&self) -> Option<u32> {
+        self.types.get(any::type_name::<T>()).map(|versions| versions.current)
+    }
+
+    /// Bring the encoded task `data` of type `T` from `version` up to the current version.
+    ///
+    /// Returns `None` if the task is up to date.
+    pub(crate) fn migrate<T>(&self, version: Option<u32>, data: &[u8]) -> RedisResult<Option<Value>> {
+        let versions = match self.types.get(any::type_name::<T>()) {
+            Some(versions) => versions,
+            None => return Ok(None),
+        };
+        let version = version.unwrap_or(1);
+        if version >= versions.current {
+            return Ok(None);
+        }
+
+        let mut task: Json = serde_json::from_slice(data)
+            .map_err(|_| RedisError::from((ErrorKind::TypeError, "JSON decode failed")))?;
+        for from in version..versions.current {
+            if let Some(upgrade) = versions.upgrades.get(&from) {
+                task = upgrade(task).map_err(|e| RedisError::from((ErrorKind::TypeError, "Migrating task failed", e)))?;
+            }
+        }
+
+        let data = serde_json::to_vec(&task)
+            .map_err(|_| RedisError::from((ErrorKind::TypeError, "JSON encode failed")))?;
+        Ok(Some(Value::Data(data)))
+    }
+}
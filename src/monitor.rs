@@ -0,0 +1,247 @@
+//! Alerts on the health of queues, e.g. posted to a chat webhook.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use redis::{self, RedisResult};
+use {registry, Queue};
+
+type Sink = Arc<dyn Fn(&Alert) + Send + Sync>;
+
+/// A problem found by a `Monitor`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "alert", rename_all = "snake_case")]
+pub enum Alert {
+    /// Jobs were moved to the dead letter queue since the last check.
+    DeadJobs {
+        /// Name of the queue
+        queue: String,
+        /// Number of jobs in the dead letter queue
+        dead: u64,
+        /// Number of jobs added since the last check
+        added: u64,
+    },
+    /// More jobs are waiting in the queue than its threshold allows.
+    QueueDepth {
+        /// Name of the queue
+        queue: String,
+        /// Number of pending jobs
+        pending: u64,
+        /// The configured threshold
+        threshold: u64,
+    },
+    /// No worker sent a heartbeat recently.
+    NoWorkers {
+        /// Time of the last heartbeat of any worker in milliseconds since the Unix epoch, if any
+        last_heartbeat_at: Option<u64>,
+    },
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Alert::DeadJobs { ref queue, dead, added } => {
+                write!(f, "{} new dead jobs in queue {} ({} in total)", added, queue, dead)
+            }
+            Alert::QueueDepth { ref queue, pending, threshold } => {
+                write!(f, "Queue {} has {} pending jobs, more than {}", queue, pending, threshold)
+            }
+            Alert::NoWorkers { .. } => write!(f, "No worker sent a heartbeat recently"),
+        }
+    }
+}
+
+/// What the monitor saw on its last check, to only alert on changes.
+#[derive(Default)]
+struct State {
+    dead: HashMap<String, u64>,
+    deep: HashSet<String>,
+    no_workers: bool,
+}
+
+/// Watches queues and workers, raising alerts when something looks wrong.
+///
+/// A monitor alerts when
+///
+/// * the dead letter queue of a watched queue grew since the last check,
+/// * a watched queue holds more pending jobs than `max_depth`, not counting its shards,
+/// * no worker sent a heartbeat within `worker_timeout`.
+///
+/// Depth and worker alerts are raised once when the problem appears, and again only after it
+/// was resolved in between.
+/// Alerts are handed to every callback registered with `on_alert`. With the `webhook` feature,
+/// `webhook` posts them to a Slack-compatible incoming webhook.
+///
+/// Clones share their state, so a clone can be used to stop a running monitor.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let monitor = Monitor::new(client)
+///     .queue("emails")
+///     .max_depth(10_000)
+///     .worker_timeout(Duration::from_secs(60))
+///     .webhook("https://hooks.slack.com/services/...");
+///
+/// thread::spawn(move || monitor.run());
+/// ```
+#[derive(Clone)]
+pub struct Monitor {
+    client: redis::Client,
+    queues: Vec<String>,
+    max_depth: Option<u64>,
+    worker_timeout: Option<Duration>,
+    interval: Duration,
+    sinks: Vec<Sink>,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    /// Create a new monitor without any queues
+    pub fn new(client: redis::Client) -> Monitor {
+        Monitor {
+            client: client,
+            queues: vec![],
+            max_depth: None,
+            worker_timeout: None,
+            interval: Duration::from_secs(30),
+            sinks: vec![],
+            state: Arc::new(Mutex::new(State::default())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Add the queue `name` to the watched queues
+    pub fn queue(mut self, name: &str) -> Monitor {
+        self.queues.push(name.into());
+        self
+    }
+
+    /// Alert when a watched queue holds more than `threshold` pending jobs
+    pub fn max_depth(mut self, threshold: u64) -> Monitor {
+        self.max_depth = Some(threshold);
+        self
+    }
+
+    /// Alert when no worker sent a heartbeat for `timeout`
+    pub fn worker_timeout(mut self, timeout: Duration) -> Monitor {
+        self.worker_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long to wait between checks. Defaults to 30 seconds.
+    pub fn interval(mut self, interval: Duration) -> Monitor {
+        self.interval = interval;
+        self
+    }
+
+    /// Call `callback` for every alert
+    pub fn on_alert<F: Fn(&Alert) + Send + Sync + 'static>(mut self, callback: F) -> Monitor {
+        self.sinks.push(Arc::new(callback));
+        self
+    }
+
+    /// Post every alert as JSON to the webhook at `url`
+    ///
+    /// The alert is described in the `text` field, as expected by Slack and compatible services,
+    /// and included as it is in the `alert` field.
+    /// Delivery is best-effort, failed posts are not retried.
+    /// Requires the `webhook` feature.
+    #[cfg(feature = "webhook")]
+    pub fn webhook(self, url: &str) -> Monitor {
+        let url = url.to_string();
+        self.on_alert(move |alert| {
+            let body = ::serde_json::json!({ "text": alert.to_string(), "alert": alert });
+            let _ = ::ureq::post(&url)
+                .set("Content-Type", "application/json")
+                .timeout(Duration::from_secs(10))
+                .send_string(&body.to_string());
+        })
+    }
+
+    /// Stop the monitor
+    ///
+    /// A running monitor returns after its current check.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the monitor is stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Check all queues and workers once, raising alerts for new problems
+    ///
+    /// Returns the raised alerts.
+    pub fn check(&self) -> RedisResult<Vec<Alert>> {
+        let con = self.client.get_connection()?;
+        let mut alerts = vec![];
+        let mut state = self.state.lock().unwrap();
+
+        for name in &self.queues {
+            let queue = Queue::new(name.clone(), self.client.clone());
+            let (pending, dead): (u64, u64) = redis::pipe()
+                .cmd("LLEN")
+                .arg(queue.queue())
+                .cmd("ZCARD")
+                .arg(queue.dead_queue())
+                .query(&con)?;
+            if let Some(&seen) = state.dead.get(name) {
+                if dead > seen {
+                    alerts.push(Alert::DeadJobs {
+                        queue: name.clone(),
+                        dead: dead,
+                        added: dead - seen,
+                    });
+                }
+            }
+            state.dead.insert(name.clone(), dead);
+
+            if let Some(threshold) = self.max_depth {
+                if pending <= threshold {
+                    state.deep.remove(name);
+                } else if state.deep.insert(name.clone()) {
+                    alerts.push(Alert::QueueDepth {
+                        queue: name.clone(),
+                        pending: pending,
+                        threshold: threshold,
+                    });
+                }
+            }
+        }
+
+        if let Some(timeout) = self.worker_timeout {
+            let last = registry::list_workers(&self.client)?.iter().map(|worker| worker.heartbeat_at).max();
+            let now = ::server_millis(&con)?;
+            let stale = last.is_none_or(|last| now.saturating_sub(last) > ::duration_millis(timeout));
+            if stale && !state.no_workers {
+                alerts.push(Alert::NoWorkers { last_heartbeat_at: last });
+            }
+            state.no_workers = stale;
+        }
+        drop(state);
+
+        for alert in &alerts {
+            for sink in &self.sinks {
+                sink(alert);
+            }
+        }
+        Ok(alerts)
+    }
+
+    /// Check queues and workers until stopped
+    ///
+    /// Errors are returned right away, it is up to the caller to restart the monitor.
+    pub fn run(&self) -> RedisResult<()> {
+        while !self.is_stopped() {
+            self.check()?;
+            thread::sleep(self.interval);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+//! Per-job settings, carried in the job itself.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+use redis::Pipeline;
+use functions;
+use Queue;
+
+/// Retries or buries a failed job with a limited number of attempts.
+///
+/// KEYS[1]: the failure record
+/// KEYS[2]: the backup queue
+/// KEYS[3]: the delayed set
+/// KEYS[4]: the dead letter queue
+/// KEYS[5]: the queue
+/// KEYS[6]: prefix of the counters of the retry budget
+/// ARGV[1]: the maximum number of attempts
+/// ARGV[2]: the backoff before the first retry in milliseconds
+/// ARGV[3]: id of the job
+/// ARGV[4]: the stored job
+/// ARGV[5]: the number of retries per window of the budget, 0 for no budget
+/// ARGV[6]: the window of the budget in milliseconds
+pub(crate) const RETRY_OR_BURY: &'static str = r"
+if redis.call('ZSCORE', KEYS[4], ARGV[3]) then
+  return 0
+end
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local attempts = tonumber(redis.call('HGET', KEYS[1], 'attempts') or '0')
+redis.call('LREM', KEYS[2], -1, ARGV[4])
+if attempts >= tonumber(ARGV[1]) then
+  redis.call('ZADD', KEYS[4], now, ARGV[3])
+  return 2
+end
+local delay = tonumber(ARGV[2]) * 2 ^ (attempts - 1)
+local budget = tonumber(ARGV[5])
+if budget > 0 then
+  local window = math.max(1, tonumber(ARGV[6]))
+  local key = KEYS[6] .. ':' .. math.floor(now / window)
+  local count = redis.call('INCR', key)
+  redis.call('PEXPIRE', key, window * 2)
+  delay = delay + math.floor((count - 1) / budget) * window
+end
+if delay > 0 then
+  redis.call('ZADD', KEYS[3], now + delay, ARGV[4])
+else
+  redis.call('RPUSH', KEYS[5], ARGV[4])
+end
+return 1
+";
+
+/// Takes a job overlapping with a running singleton job out of the backup queue, delaying it
+/// if requested.
+///
+/// KEYS[1]: the backup queue
+/// KEYS[2]: the delayed set
+/// ARGV[1]: the stored job
+/// ARGV[2]: the delay in milliseconds, negative to drop the job
+pub(crate) const OVERLAP: &'static str = r"
+redis.call('LREM', KEYS[1], -1, ARGV[1])
+local delay = tonumber(ARGV[2])
+if delay >= 0 then
+  local time = redis.call('TIME')
+  local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+  redis.call('ZADD', KEYS[2], now + delay, ARGV[1])
+end
+";
+
+/// Releases the key of a unique job once the job is in the dead letter queue.
+///
+/// KEYS[1]: the lock of the key
+/// KEYS[2]: the dead letter queue
+/// ARGV[1]: id of the job
+pub(crate) const RELEASE_IF_DEAD: &'static str = r"
+if redis.call('ZSCORE', KEYS[2], ARGV[1]) and redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// How long the key of a unique job is held at most, in seconds, unless released earlier.
+const UNIQUE_TTL: u64 = 24 * 60 * 60;
+
+/// How long the lock of a singleton job is held if the job has no timeout.
+const SINGLETON_TTL: u64 = 60 * 60;
+
+/// How long a job waits if another job of its resource is running, in milliseconds.
+const RESOURCE_RETRY: u64 = 1000;
+
+/// What happens to a singleton job fetched while another instance is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Overlap {
+    /// Drop the job
+    Skip,
+    /// Try again after the given time
+    Delay(Duration),
+}
+
+/// Limits a job to a single running instance, cluster-wide.
+///
+/// All jobs with the same key share a lock, taken when a worker fetches one of them.
+/// The lock is released when the job finishes, or after the timeout of the job (an hour by
+/// default) if its worker dies.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Singleton {
+    /// Name of the lock shared by all instances
+    pub key: String,
+    /// What happens to instances fetched while another one is running
+    pub overlap: Overlap,
+}
+
+impl Singleton {
+    /// Drop instances fetched while another one is running
+    pub fn skip(key: &str) -> Singleton {
+        Singleton {
+            key: key.into(),
+            overlap: Overlap::Skip,
+        }
+    }
+
+    /// Delay instances fetched while another one is running by `delay`
+    ///
+    /// Delayed instances need a running `Promoter`.
+    pub fn delay(key: &str, delay: Duration) -> Singleton {
+        Singleton {
+            key: key.into(),
+            overlap: Overlap::Delay(delay),
+        }
+    }
+
+    /// Get the name of the lock.
+    pub(crate) fn lock_name(&self) -> String {
+        format!("singleton:{}", self.key)
+    }
+}
+
+/// How long a unique job blocks other jobs with the same key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Uniqueness {
+    /// For a fixed time after the push, no matter what happens to the job
+    For(Duration),
+    /// Until a worker fetched the job, so another one can be queued while it runs
+    UntilStarted,
+    /// Until the job completed, was dead-lettered or discarded
+    ///
+    /// Failed jobs waiting for a retry keep blocking their key, cancelled jobs release it.
+    UntilCompleted,
+}
+
+/// Rejects pushing a job while another job with the same key is around.
+///
+/// Pushing a duplicate fails with `PushError::Duplicate`. Keys are shared by all queues.
+/// Keys not released by their job, e.g. of jobs failing without `max_attempts`, expire after
+/// a day.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// // Reindex a user at most every ten minutes
+/// queue.push_with_options(Reindex { user: 42 }, JobOptions {
+///     unique: Some(Unique::for_duration("reindex:42", Duration::from_secs(600))),
+///     ..JobOptions::default()
+/// });
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unique {
+    /// The key identifying duplicates
+    pub key: String,
+    /// How long the key is held
+    pub uniqueness: Uniqueness,
+}
+
+impl Unique {
+    /// Reject duplicates for `window` after the push
+    pub fn for_duration(key: &str, window: Duration) -> Unique {
+        Unique {
+            key: key.into(),
+            uniqueness: Uniqueness::For(window),
+        }
+    }
+
+    /// Reject duplicates until a worker fetched the job
+    pub fn until_started(key: &str) -> Unique {
+        Unique {
+            key: key.into(),
+            uniqueness: Uniqueness::UntilStarted,
+        }
+    }
+
+    /// Reject duplicates until the job finished
+    pub fn until_completed(key: &str) -> Unique {
+        Unique {
+            key: key.into(),
+            uniqueness: Uniqueness::UntilCompleted,
+        }
+    }
+
+    /// Get the name of the lock held for the key.
+    pub(crate) fn lock_name(&self) -> String {
+        format!("unique:{}", self.key)
+    }
+
+    /// Get how long the lock is held at most.
+    pub(crate) fn lock_ttl(&self) -> Duration {
+        match self.uniqueness {
+            Uniqueness::For(window) => window,
+            Uniqueness::UntilStarted | Uniqueness::UntilCompleted => Duration::from_secs(UNIQUE_TTL),
+        }
+    }
+
+    /// Add the command releasing the key held by the finished job `jid` to the pipeline.
+    ///
+    /// A failed job that may still be retried keeps the key until it's dead, pass its dead
+    /// letter queue as `if_dead` then. Keys held for a fixed time expire on their own.
+    pub(crate) fn release(&self, pipe: &mut Pipeline, jid: &str, if_dead: Option<&str>) {
+        if let Uniqueness::For(_) = self.uniqueness {
+            return;
+        }
+        match if_dead {
+            Some(dead) => {
+                functions::eval(pipe, RELEASE_IF_DEAD, 2)
+                    .arg(::lock::lock_key(&self.lock_name()))
+                    .arg(dead)
+                    .arg(jid)
+                    .ignore();
+            }
+            None => ::lock::unlock(pipe, &self.lock_name(), jid),
+        }
+    }
+}
+
+/// What happens to a job fetched after its deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Expiry {
+    /// Move the job to the dead letter queue
+    DeadLetter,
+    /// Drop the job
+    Discard,
+}
+
+/// A point in time after which a job is not started anymore.
+///
+/// Jobs fetched after their deadline are never handed out, e.g. a reminder that only makes
+/// sense before the meeting starts. Jobs already running when the deadline passes are not
+/// interrupted, give them a `timeout` for that.
+/// Deadlines are checked against the time of the Redis server, so clock skew of the workers
+/// doesn't matter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deadline {
+    /// The deadline, in milliseconds since the Unix epoch
+    pub at: u64,
+    /// What happens to the job if it's fetched after the deadline
+    pub expiry: Expiry,
+}
+
+impl Deadline {
+    /// Move the job to the dead letter queue if it's fetched after `at`
+    pub fn dead_letter(at: SystemTime) -> Deadline {
+        Deadline {
+            at: ::unix_millis(at),
+            expiry: Expiry::DeadLetter,
+        }
+    }
+
+    /// Drop the job if it's fetched after `at`
+    pub fn discard(at: SystemTime) -> Deadline {
+        Deadline {
+            at: ::unix_millis(at),
+            expiry: Expiry::Discard,
+        }
+    }
+}
+
+/// Settings of a single job, overriding the defaults of the queue and worker.
+///
+/// The options are stored with the job, so every worker honors them.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// queue.push_with_options(Job { id: 42 }, JobOptions {
+///     timeout: Some(Duration::from_secs(10)),
+///     max_attempts: Some(5),
+///     backoff: Some(Duration::from_secs(1)),
+///     ..JobOptions::default()
+/// });
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobOptions {
+    /// Maximum processing time, honored by `Worker`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    /// Number of attempts before the job is moved to the dead letter queue
+    ///
+    /// Without a limit, failed jobs stay in the backup queue.
+    /// With a limit, failed jobs are retried automatically until it is reached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Time to wait before the first retry, doubled for every further retry
+    ///
+    /// Retries wait in the set of delayed tasks, so they need a running `Promoter`.
+    /// Without a backoff, failed jobs are retried right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<Duration>,
+    /// Jobs with a positive priority are put at the front of the queue
+    ///
+    /// Delayed jobs and retries keep their priority when they are promoted into the queue.
+    /// The front is the end the queue is consumed from, see `Order`. For queues consumed
+    /// newest-first, promote with `Promoter::queue_with_order`.
+    #[serde(default)]
+    pub priority: i32,
+    /// Time to wait before the job is processed the first time
+    ///
+    /// The job waits in the set of delayed tasks, so it needs a running `Promoter`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<Duration>,
+    /// Free-form labels, e.g. `customer:1234`, to find the job by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The type of the task, for workers picking only some types of a shared queue
+    ///
+    /// Defaults to the `type` field of the task, as written by enums with `#[serde(tag = "type")]`.
+    /// See `Worker::only_kinds`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// Free-form metadata for handlers, e.g. a trace id, not interpreted by oppgave
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Allow only one instance of the job to run at a time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub singleton: Option<Singleton>,
+    /// The entity the job works on, e.g. `user:42`
+    ///
+    /// At most one job per resource runs at a time, jobs of different resources run in
+    /// parallel. A job fetched while another job of its resource is running is delayed by a
+    /// second, so it needs a running `Promoter`. The lock expires like the one of a `Singleton`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+    /// Run the job only after all earlier jobs with the same key finished, e.g. `account:42`
+    ///
+    /// Jobs with an ordering key are pushed to the shard picked by the key, see `push_keyed`.
+    /// Only one job per key is in the queue at a time, the next one is released once it
+    /// completed or was moved to the dead letter queue. A job failing without `max_attempts`
+    /// holds up its key until it's retried and completes.
+    /// Ordered jobs can't be delayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ordering_key: Option<String>,
+    /// Don't start the job after this deadline, see `Deadline`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<Deadline>,
+    /// Reject pushing duplicates of the job, see `Unique`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unique: Option<Unique>,
+}
+
+impl JobOptions {
+    /// Get how long the locks of the job are held at most.
+    pub(crate) fn lock_ttl(&self) -> Duration {
+        self.timeout.unwrap_or_else(|| Duration::from_secs(SINGLETON_TTL))
+    }
+
+    /// Get the names of the locks the job needs, together with what happens if one is taken.
+    pub(crate) fn locks(&self) -> Vec<(String, Overlap)> {
+        let mut locks = vec![];
+        if let Some(ref singleton) = self.singleton {
+            locks.push((singleton.lock_name(), singleton.overlap));
+        }
+        if let Some(ref resource) = self.resource {
+            locks.push((format!("resource:{}", resource), Overlap::Delay(Duration::from_millis(RESOURCE_RETRY))));
+        }
+        locks
+    }
+}
+
+/// Add the command retrying the failed job `jid`, or burying it once out of attempts, to the
+/// pipeline.
+pub(crate) fn retry_or_bury(
+    pipe: &mut Pipeline,
+    queue: &Queue,
+    jid: &str,
+    job: &[u8],
+    options: &JobOptions,
+    max_attempts: u32,
+) {
+    let budget = queue.retry_budget;
+    let (queue, backup) = (queue.queue(), queue.backup_queue());
+    functions::eval(pipe, RETRY_OR_BURY, 6)
+        .arg(::failure::failure_key(queue, jid))
+        .arg(backup)
+        .arg(format!("{}:delayed", queue))
+        .arg(::failure::dead_key(queue))
+        .arg(queue)
+        .arg(budget.map_or_else(|| format!("{}:retries", queue), |budget| budget.key(queue)))
+        .arg(max_attempts)
+        .arg(options.backoff.map_or(0, ::duration_millis))
+        .arg(jid)
+        .arg(job)
+        .arg(budget.map_or(0, |budget| budget.max()))
+        .arg(budget.map_or(0, |budget| ::duration_millis(budget.per())))
+        .ignore();
+}
+
+/// Add the command taking the job `data`, overlapping with a running instance, out of `backup`
+/// to the pipeline.
+pub(crate) fn resolve_overlap(pipe: &mut Pipeline, backup: &str, delayed: &str, data: &[u8], overlap: Overlap) {
+    let delay = match overlap {
+        Overlap::Skip => -1,
+        Overlap::Delay(delay) => ::duration_millis(delay) as i64,
+    };
+    functions::eval(pipe, OVERLAP, 2).arg(backup).arg(delayed).arg(data).arg(delay).ignore();
+}
@@ -0,0 +1,107 @@
+//! Strict ordering of jobs sharing an ordering key.
+//!
+//! Only one job per key is released into the queue at a time. The others wait in a list per
+//! key until the released job finished, so they run in the order they were pushed, no matter
+//! how many workers consume the queue.
+
+use redis::{self, Pipeline, RedisResult};
+use functions;
+
+/// Pushes a job with an ordering key, holding it back while an earlier job of the key is
+/// released.
+///
+/// KEYS[1]: the jobs waiting for their key
+/// KEYS[2]: the released jobs of the queue, by key
+/// KEYS[3]: the list to release the job to
+/// ARGV[1]: the ordering key
+/// ARGV[2]: id of the job
+/// ARGV[3]: the stored job
+/// ARGV[4]: the command pushing the job, `LPUSH` or `RPUSH`
+pub(crate) const ENQUEUE: &'static str = r"
+if redis.call('HSETNX', KEYS[2], ARGV[1], ARGV[2]) == 0 then
+  redis.call('RPUSH', KEYS[1], ARGV[3])
+  return 0
+end
+redis.call(ARGV[4], KEYS[3], ARGV[3])
+return 1
+";
+
+/// Releases the next job of a key once the released job finished.
+///
+/// KEYS[1]: the jobs waiting for their key
+/// KEYS[2]: the released jobs of the queue, by key
+/// KEYS[3]: the list to release the next job to
+/// KEYS[4]: the dead letter queue
+/// ARGV[1]: the ordering key
+/// ARGV[2]: id of the finished job
+/// ARGV[3]: `1` to only release the next job if the finished one is dead
+pub(crate) const RELEASE: &'static str = r"
+if redis.call('HGET', KEYS[2], ARGV[1]) ~= ARGV[2] then
+  return 0
+end
+if ARGV[3] == '1' and not redis.call('ZSCORE', KEYS[4], ARGV[2]) then
+  return 0
+end
+local job = redis.call('LPOP', KEYS[1])
+if not job then
+  redis.call('HDEL', KEYS[2], ARGV[1])
+  return 1
+end
+redis.call('HSET', KEYS[2], ARGV[1], cjson.decode(job)['jid'])
+redis.call('LPUSH', KEYS[3], job)
+return 1
+";
+
+/// Get the name of `queue` without the common prefix.
+fn name(queue: &str) -> &str {
+    queue.trim_start_matches("oppgave:")
+}
+
+/// Get the key of the hash holding the released job per ordering key of `queue`.
+fn released_key(queue: &str) -> String {
+    format!("oppgave:ordered:{}", name(queue))
+}
+
+/// Get the key of the list of jobs of `queue` waiting for `key`.
+fn waiting_key(queue: &str, key: &str) -> String {
+    format!("oppgave:ordered:{}:{}", name(queue), key)
+}
+
+/// Push the job `data` of `queue` with the ordering key `key` to `target`, or hold it back.
+///
+/// Returns `false` if the job waits for an earlier job of the key.
+pub(crate) fn enqueue<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    key: &str,
+    target: &str,
+    push: &str,
+    jid: &str,
+    data: &[u8],
+) -> RedisResult<bool> {
+    functions::script(ENQUEUE)
+        .key(waiting_key(queue, key))
+        .key(released_key(queue))
+        .key(target)
+        .arg(key)
+        .arg(jid)
+        .arg(data)
+        .arg(push)
+        .invoke(con)
+}
+
+/// Add the command releasing the next job of `key` to `target` to the pipeline.
+///
+/// With `if_dead`, the next job is only released once the job `jid` was moved to the dead
+/// letter queue, e.g. after running out of attempts.
+pub(crate) fn release(pipe: &mut Pipeline, queue: &str, key: &str, target: &str, jid: &str, if_dead: bool) {
+    functions::eval(pipe, RELEASE, 4)
+        .arg(waiting_key(queue, key))
+        .arg(released_key(queue))
+        .arg(target)
+        .arg(::failure::dead_key(queue))
+        .arg(key)
+        .arg(jid)
+        .arg(if if_dead { 1 } else { 0 })
+        .ignore();
+}
@@ -0,0 +1,205 @@
+//! Relaying jobs written to an application's own storage into their queues.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{cmp, thread};
+use std::time::Duration;
+use redis::{self, RedisResult};
+use envelope::Envelope;
+use functions;
+use {Queue, TaskEncodable};
+
+/// Pushes a relayed job unless it was pushed before.
+///
+/// KEYS[1]: the marker of the relayed job
+/// KEYS[2]: the queue
+/// ARGV[1]: the stored job
+/// ARGV[2]: how long to remember relayed jobs in seconds
+pub(crate) const RELAY: &'static str = r"
+if redis.call('SET', KEYS[1], 1, 'NX', 'EX', ARGV[2]) then
+  redis.call('LPUSH', KEYS[2], ARGV[1])
+  return 1
+end
+return 0
+";
+
+/// How long relayed jobs are remembered to skip them if relayed again, in seconds.
+const RELAYED_TTL: u64 = 24 * 60 * 60;
+
+/// Get the key marking the job `jid` as relayed.
+fn relayed_key(jid: &str) -> String {
+    format!("oppgave:outbox:{}", jid)
+}
+
+/// A job stored in an outbox, waiting to be relayed to its queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboxEntry {
+    /// Id of the job, used as the id of the entry as well
+    pub jid: String,
+    /// Full name of the list the job is pushed to
+    pub queue: String,
+    /// The stored job
+    pub job: Vec<u8>,
+}
+
+impl OutboxEntry {
+    /// Create the entry for pushing `task` to `queue`, to be stored in the outbox
+    ///
+    /// The task needs to be encoded as JSON. Nothing is written to Redis.
+    pub fn new<T: TaskEncodable>(queue: &Queue, task: T) -> RedisResult<OutboxEntry> {
+        let mut job = Envelope::new(task.try_encode_task()?)?;
+        job.version = queue.version::<T>();
+        Ok(OutboxEntry {
+            jid: job.jid.clone(),
+            queue: queue.target(None),
+            job: job.encode(),
+        })
+    }
+}
+
+/// The storage of an application holding jobs until they are relayed, e.g. a table of its
+/// SQL database.
+///
+/// Entries are written in the same transaction as the state they belong to, so a job is
+/// enqueued if and only if its state was committed.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// impl Outbox for PgOutbox {
+///     fn pending(&self, limit: usize) -> io::Result<Vec<OutboxEntry>> {
+///         let rows = self.db.query("SELECT jid, queue, job FROM outbox ORDER BY id LIMIT $1", &[&(limit as i64)])
+///             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+///         Ok(rows.iter().map(|row| OutboxEntry { jid: row.get(0), queue: row.get(1), job: row.get(2) }).collect())
+///     }
+///
+///     fn relayed(&self, jids: &[String]) -> io::Result<()> {
+///         self.db.execute("DELETE FROM outbox WHERE jid = ANY($1)", &[&jids])
+///             .map(|_| ())
+///             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+///     }
+/// }
+/// ```
+pub trait Outbox {
+    /// Get up to `limit` entries not relayed yet, oldest first
+    fn pending(&self, limit: usize) -> io::Result<Vec<OutboxEntry>>;
+
+    /// Mark the entries `jids` as relayed, e.g. by deleting them
+    fn relayed(&self, jids: &[String]) -> io::Result<()>;
+}
+
+/// Tails an `Outbox` and pushes its entries to their queues.
+///
+/// Every entry is pushed once, even if the relay stops between pushing it and marking it as
+/// relayed: pushed jobs are remembered for a day, so a retried entry is only marked.
+/// Several relays can tail the same outbox.
+///
+/// Clones share their stop flag, so a clone can be used to stop a running relay.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// // Within the transaction of the application
+/// let entry = OutboxEntry::new(&queue, SendReceipt { order: 42 })?;
+/// tx.execute("INSERT INTO outbox (jid, queue, job) VALUES ($1, $2, $3)", &[&entry.jid, &entry.queue, &entry.job])?;
+///
+/// // In the background
+/// let relay = Relay::new(PgOutbox::new(pool), client);
+/// thread::spawn(move || relay.run());
+/// ```
+pub struct Relay<O> {
+    outbox: Arc<O>,
+    client: redis::Client,
+    batch_size: usize,
+    interval: Duration,
+    stopped: Arc<AtomicBool>,
+}
+
+// Not derived, which would require `O: Clone` although the outbox is shared
+impl<O> Clone for Relay<O> {
+    fn clone(&self) -> Relay<O> {
+        Relay {
+            outbox: self.outbox.clone(),
+            client: self.client.clone(),
+            batch_size: self.batch_size,
+            interval: self.interval,
+            stopped: self.stopped.clone(),
+        }
+    }
+}
+
+impl<O: Outbox> Relay<O> {
+    /// Create a new relay of the entries of `outbox`
+    pub fn new(outbox: O, client: redis::Client) -> Relay<O> {
+        Relay {
+            outbox: Arc::new(outbox),
+            client: client,
+            batch_size: 100,
+            interval: Duration::from_secs(1),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set the maximum number of entries relayed in one step. Defaults to 100.
+    pub fn batch_size(mut self, batch_size: usize) -> Relay<O> {
+        self.batch_size = cmp::max(1, batch_size);
+        self
+    }
+
+    /// Set how long to wait for new entries once the outbox is empty. Defaults to 1 second.
+    pub fn interval(mut self, interval: Duration) -> Relay<O> {
+        self.interval = interval;
+        self
+    }
+
+    /// Stop the relay
+    ///
+    /// A running relay returns after its current step.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the relay is stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Relay up to one batch of pending entries
+    ///
+    /// Returns the number of relayed entries, including entries which were pushed before.
+    pub fn relay(&self) -> RedisResult<usize> {
+        let entries = self.outbox.pending(self.batch_size)?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pipe = redis::pipe();
+        for entry in &entries {
+            functions::eval(&mut pipe, RELAY, 2)
+                .arg(relayed_key(&entry.jid))
+                .arg(&entry.queue[..])
+                .arg(&entry.job[..])
+                .arg(RELAYED_TTL)
+                .ignore();
+        }
+        pipe.query::<()>(&self.client.get_connection()?)?;
+
+        let jids: Vec<String> = entries.into_iter().map(|entry| entry.jid).collect();
+        self.outbox.relayed(&jids)?;
+        Ok(jids.len())
+    }
+
+    /// Relay entries until stopped
+    ///
+    /// Errors are returned right away, it is up to the caller to restart the relay.
+    pub fn run(&self) -> RedisResult<()> {
+        while !self.is_stopped() {
+            if self.relay()? < self.batch_size {
+                thread::sleep(self.interval);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+//! Classification of handler errors into what happens to the failed task.
+
+use std::fmt;
+
+/// What happens to a task whose handler failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskOutcome {
+    /// Keep the task to retry it. This is what happens to all errors of plain handlers.
+    Retry,
+    /// Give up on the task right away, moving it to the dead letter queue.
+    Fail,
+    /// Drop the task without retrying it or keeping it as dead, e.g. when it became obsolete.
+    Discard,
+}
+
+impl fmt::Display for TaskOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TaskOutcome::Retry => write!(f, "retry"),
+            TaskOutcome::Fail => write!(f, "fail"),
+            TaskOutcome::Discard => write!(f, "discard"),
+        }
+    }
+}
+
+/// Errors knowing whether the failed task is worth retrying.
+///
+/// Handlers run with `Worker::run_classified` return such errors, so transient errors (e.g. a
+/// timeout of a downstream service) are retried, while fatal ones (e.g. a malformed payload)
+/// are dead-lettered or dropped right away.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// impl Classify for MailError {
+///     fn classify(&self) -> TaskOutcome {
+///         match *self {
+///             MailError::Unavailable => TaskOutcome::Retry,
+///             MailError::InvalidAddress(_) => TaskOutcome::Fail,
+///             MailError::Unsubscribed => TaskOutcome::Discard,
+///         }
+///     }
+/// }
+/// ```
+pub trait Classify {
+    /// Decide what happens to the failed task
+    fn classify(&self) -> TaskOutcome;
+}
+
+/// Handlers can return the outcome itself as their error.
+impl Classify for TaskOutcome {
+    fn classify(&self) -> TaskOutcome {
+        *self
+    }
+}
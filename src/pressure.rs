@@ -0,0 +1,195 @@
+//! Gate pausing consumption while the host is short on CPU or memory.
+
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How busy the host is, as seen by a `ResourceProbe`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    /// The load average of the last minute per CPU, 1.0 means all CPUs are busy
+    pub load: Option<f64>,
+    /// The share of memory in use, from 0.0 to 1.0
+    pub memory: Option<f64>,
+}
+
+/// Source of the resource usage a `ResourceGate` looks at.
+///
+/// Closures returning the usage implement it as well, e.g. to look at the limits of a container
+/// instead of the whole host.
+pub trait ResourceProbe: Send + Sync {
+    /// Measure the current resource usage
+    fn usage(&self) -> io::Result<ResourceUsage>;
+}
+
+impl<F: Fn() -> io::Result<ResourceUsage> + Send + Sync> ResourceProbe for F {
+    fn usage(&self) -> io::Result<ResourceUsage> {
+        self()
+    }
+}
+
+/// Reads the load average and memory usage of the host from `/proc`.
+///
+/// Only available on Linux, on other systems it fails, which keeps the gate open.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemProbe;
+
+impl ResourceProbe for SystemProbe {
+    fn usage(&self) -> io::Result<ResourceUsage> {
+        let cpus = thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(1);
+        let load = fs::read_to_string("/proc/loadavg")?
+            .split_whitespace()
+            .next()
+            .and_then(|load| load.parse::<f64>().ok())
+            .map(|load| load / cpus as f64);
+
+        let meminfo = fs::read_to_string("/proc/meminfo")?;
+        let field = |name: &str| {
+            meminfo.lines()
+                .find(|line| line.starts_with(name))
+                .and_then(|line| line[name.len()..].split_whitespace().next())
+                .and_then(|kb| kb.parse::<f64>().ok())
+        };
+        let memory = match (field("MemTotal:"), field("MemAvailable:")) {
+            (Some(total), Some(available)) if total > 0.0 => Some(1.0 - available / total),
+            _ => None,
+        };
+
+        Ok(ResourceUsage {
+            load: load,
+            memory: memory,
+        })
+    }
+}
+
+struct Inner {
+    sampled_at: Option<Instant>,
+    pressure: bool,
+}
+
+/// Pauses consumption while the load or memory usage of the host is above a threshold.
+///
+/// The usage is sampled at most once per `sample_interval`. Once it's above one of the
+/// thresholds, no tasks are fetched until it dropped below `resume_below` of all thresholds
+/// again, so workers don't flap around the threshold. Tasks already running are not affected.
+///
+/// Errors of the probe keep the gate as it is until the next sample.
+///
+/// Clones share their state.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let gate = ResourceGate::new()
+///     .max_load(0.8)
+///     .max_memory(0.9)
+///     .on_change(|pressure| println!("Resource pressure: {}", pressure));
+///
+/// Worker::new(queue).resource_gate(gate).run(handler);
+/// ```
+#[derive(Clone)]
+pub struct ResourceGate {
+    probe: Arc<dyn ResourceProbe>,
+    max_load: Option<f64>,
+    max_memory: Option<f64>,
+    resume_below: f64,
+    sample_interval: Duration,
+    on_change: Option<Arc<dyn Fn(bool) + Send + Sync>>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ResourceGate {
+    /// Create a new gate looking at the host with `SystemProbe`, without any thresholds
+    pub fn new() -> ResourceGate {
+        ResourceGate {
+            probe: Arc::new(SystemProbe),
+            max_load: None,
+            max_memory: None,
+            resume_below: 0.9,
+            sample_interval: Duration::from_secs(1),
+            on_change: None,
+            inner: Arc::new(Mutex::new(Inner {
+                sampled_at: None,
+                pressure: false,
+            })),
+        }
+    }
+
+    /// Measure the resource usage with `probe` instead
+    pub fn with_probe<P: ResourceProbe + 'static>(mut self, probe: P) -> ResourceGate {
+        self.probe = Arc::new(probe);
+        self
+    }
+
+    /// Pause above this load average per CPU, e.g. 0.8
+    pub fn max_load(mut self, load: f64) -> ResourceGate {
+        self.max_load = Some(load);
+        self
+    }
+
+    /// Pause above this share of memory in use, e.g. 0.9
+    pub fn max_memory(mut self, memory: f64) -> ResourceGate {
+        self.max_memory = Some(memory);
+        self
+    }
+
+    /// Set the share of the thresholds the usage needs to drop below to resume. Defaults to 0.9.
+    pub fn resume_below(mut self, share: f64) -> ResourceGate {
+        self.resume_below = share;
+        self
+    }
+
+    /// Set how often the usage is sampled. Defaults to 1 second.
+    pub fn sample_interval(mut self, interval: Duration) -> ResourceGate {
+        self.sample_interval = interval;
+        self
+    }
+
+    /// Call `callback` with `true` when consumption pauses and with `false` when it resumes
+    pub fn on_change<F: Fn(bool) + Send + Sync + 'static>(mut self, callback: F) -> ResourceGate {
+        self.on_change = Some(Arc::new(callback));
+        self
+    }
+
+    /// Check if consumption is paused because of resource pressure
+    pub fn is_under_pressure(&self) -> bool {
+        self.inner.lock().unwrap().pressure
+    }
+
+    /// Check if a task may be fetched, sampling the usage when due.
+    pub(crate) fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.sampled_at.is_some_and(|at| at.elapsed() < self.sample_interval) {
+            return !inner.pressure;
+        }
+        inner.sampled_at = Some(Instant::now());
+
+        let usage = match self.probe.usage() {
+            Ok(usage) => usage,
+            Err(_) => return !inner.pressure,
+        };
+        // Resuming needs some headroom below the thresholds
+        let share = if inner.pressure { self.resume_below } else { 1.0 };
+        let above = |value: Option<f64>, max: Option<f64>| match (value, max) {
+            (Some(value), Some(max)) => value > max * share,
+            _ => false,
+        };
+        let pressure = above(usage.load, self.max_load) || above(usage.memory, self.max_memory);
+
+        if pressure != inner.pressure {
+            inner.pressure = pressure;
+            if let Some(ref callback) = self.on_change {
+                callback(pressure);
+            }
+        }
+        !pressure
+    }
+}
+
+impl Default for ResourceGate {
+    fn default() -> ResourceGate {
+        ResourceGate::new()
+    }
+}
@@ -0,0 +1,196 @@
+//! Queues with high, default and low priority lanes.
+
+use std::cell::Cell;
+use std::{cmp, thread};
+use std::time::{Duration, Instant};
+use redis::{self, RedisResult};
+use {PushError, Queue, TaskDecodable, TaskEncodable, TaskGuard};
+
+/// A lane of a `PriorityQueue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lane {
+    /// Urgent tasks, e.g. triggered by a waiting user
+    High,
+    /// Regular tasks
+    Default,
+    /// Tasks which can wait, e.g. backfills
+    Low,
+}
+
+/// Three queues of the same tasks, consumed by weight.
+///
+/// Tasks are pushed to the lane of their priority, which are the queues `<name>-high`, `<name>`
+/// and `<name>-low`. Out of every ten tasks fetched, six are taken from the high lane, three
+/// from the default lane and one from the low lane by default, see `with_weights`. Lanes without
+/// tasks yield their turn to the others, so no lane idles while another one has tasks, and low
+/// priority tasks still progress under a steady load of urgent ones.
+///
+/// Consumers idling on empty lanes can help out other queues, see `with_overflow`.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let queue = PriorityQueue::new("thumbnails", client);
+/// queue.push_high(Thumbnail { id: 1 })?;
+/// queue.push_low(Thumbnail { id: 2 })?;
+///
+/// loop {
+///     let task = match queue.next::<Thumbnail>(Duration::from_secs(1))? {
+///         Some(task) => task,
+///         None => continue,
+///     };
+///     render(&task);
+/// }
+/// ```
+pub struct PriorityQueue {
+    high: Queue,
+    default: Queue,
+    low: Queue,
+    weights: (u32, u32, u32),
+    turn: Cell<u32>,
+    overflow: Vec<Queue>,
+    steal_after: u32,
+    empty_polls: Cell<u32>,
+}
+
+impl PriorityQueue {
+    /// Create a new priority queue for the given name
+    pub fn new(name: &str, client: redis::Client) -> PriorityQueue {
+        PriorityQueue {
+            high: Queue::new(format!("{}-high", name), client.clone()),
+            default: Queue::new(name.into(), client.clone()),
+            low: Queue::new(format!("{}-low", name), client),
+            weights: (6, 3, 1),
+            turn: Cell::new(0),
+            overflow: vec![],
+            steal_after: 0,
+            empty_polls: Cell::new(0),
+        }
+    }
+
+    /// Set the share of fetched tasks taken from each lane. Defaults to 6, 3 and 1.
+    pub fn with_weights(mut self, high: u32, default: u32, low: u32) -> PriorityQueue {
+        self.weights = (high, default, low);
+        self
+    }
+
+    /// Take tasks from the `overflow` queues after `after` consecutive polls found all lanes empty
+    ///
+    /// Lanes are polled every 50 milliseconds while empty, so with `after` set to 20 a consumer
+    /// starts helping out after idling for a second. Overflow queues are tried in order and only
+    /// while all lanes stay empty, the own lanes are checked first for every task.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let reports = Queue::new("reports".into(), client.clone());
+    /// let queue = PriorityQueue::new("exports", client).with_overflow(vec![reports], 20);
+    /// ```
+    pub fn with_overflow(mut self, overflow: Vec<Queue>, after: u32) -> PriorityQueue {
+        self.overflow = overflow;
+        self.steal_after = after;
+        self
+    }
+
+    /// Apply `configure` to the queues of all lanes, e.g. to set an order or archive
+    pub fn configure<F: Fn(Queue) -> Queue>(self, configure: F) -> PriorityQueue {
+        PriorityQueue {
+            high: configure(self.high),
+            default: configure(self.default),
+            low: configure(self.low),
+            ..self
+        }
+    }
+
+    /// Get the queue of `lane`
+    pub fn lane(&self, lane: Lane) -> &Queue {
+        match lane {
+            Lane::High => &self.high,
+            Lane::Default => &self.default,
+            Lane::Low => &self.low,
+        }
+    }
+
+    /// Push a task to `lane`
+    pub fn push_to<T: TaskEncodable>(&self, lane: Lane, task: T) -> Result<(), PushError> {
+        self.lane(lane).push(task)
+    }
+
+    /// Push an urgent task
+    pub fn push_high<T: TaskEncodable>(&self, task: T) -> Result<(), PushError> {
+        self.high.push(task)
+    }
+
+    /// Push a regular task
+    pub fn push_default<T: TaskEncodable>(&self, task: T) -> Result<(), PushError> {
+        self.default.push(task)
+    }
+
+    /// Push a task which can wait
+    pub fn push_low<T: TaskEncodable>(&self, task: T) -> Result<(), PushError> {
+        self.low.push(task)
+    }
+
+    /// Get the number of pending tasks of all lanes
+    pub fn size(&self) -> u64 {
+        self.high.size() + self.default.size() + self.low.size()
+    }
+
+    /// Stop processing all lanes
+    pub fn stop(&self) {
+        self.high.stop();
+        self.default.stop();
+        self.low.stop();
+    }
+
+    /// Get the lanes in the order they are tried for the next task.
+    ///
+    /// The turn only moves on once a task was taken, so the weights apply to fetched tasks
+    /// rather than to polls of empty lanes.
+    fn order(&self) -> [Lane; 3] {
+        let (high, default, low) = self.weights;
+        let total = cmp::max(1, high + default + low);
+        let slot = self.turn.get() % total;
+
+        if slot < high {
+            [Lane::High, Lane::Default, Lane::Low]
+        } else if slot < high + default {
+            [Lane::Default, Lane::High, Lane::Low]
+        } else {
+            [Lane::Low, Lane::High, Lane::Default]
+        }
+    }
+
+    /// Grab the next task, waiting for up to `timeout` while all lanes are empty
+    ///
+    /// Returns `Ok(None)` if no task arrived in time. Lanes are polled, so new tasks are seen
+    /// within 50 milliseconds.
+    /// The task may come from an overflow queue, see `TaskGuard::queue`.
+    pub fn next<T: TaskDecodable>(&self, timeout: Duration) -> RedisResult<Option<TaskGuard<T>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            for &lane in &self.order() {
+                if let Some(task) = self.lane(lane).try_next()? {
+                    self.turn.set(self.turn.get().wrapping_add(1));
+                    self.empty_polls.set(0);
+                    return Ok(Some(task));
+                }
+            }
+
+            self.empty_polls.set(self.empty_polls.get().saturating_add(1));
+            if self.empty_polls.get() >= self.steal_after {
+                for queue in &self.overflow {
+                    if let Some(task) = queue.try_next()? {
+                        return Ok(Some(task));
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline || self.high.is_stopped() {
+                return Ok(None);
+            }
+            thread::sleep(cmp::min(Duration::from_millis(50), deadline - now));
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Liveness and readiness of a running worker, for container probes.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The lifecycle stage of a worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerState {
+    /// The worker was not run yet, or has not polled the queue yet.
+    Starting,
+    /// The worker is polling the queue and processing tasks.
+    Running,
+    /// The worker returned from `run`.
+    Stopped,
+}
+
+struct Inner {
+    state: WorkerState,
+    connected: bool,
+    last_poll: Option<Instant>,
+    last_tick: Option<Instant>,
+    in_flight: usize,
+}
+
+/// A cheap handle on the state of a running worker.
+///
+/// Get one with `Worker::probe` before running the worker, and check it from another thread,
+/// e.g. the handler of a health endpoint. Updating and reading it doesn't touch Redis.
+///
+/// * A worker is ready once it polled the queue successfully and Redis is reachable.
+/// * A worker is live while it keeps polling, or holds off polling on purpose, e.g. because its
+///   queue is paused or its circuit breaker is open. A worker which didn't do either for longer
+///   than the longest expected task is likely wedged.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let worker = Worker::new(queue);
+/// let probe = worker.probe();
+/// thread::spawn(move || worker.run(handler));
+///
+/// // In the health endpoint
+/// if !probe.is_live(Duration::from_secs(300)) {
+///     return 503;
+/// }
+/// ```
+#[derive(Clone)]
+pub struct WorkerProbe {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl WorkerProbe {
+    pub(crate) fn new() -> WorkerProbe {
+        WorkerProbe {
+            inner: Arc::new(Mutex::new(Inner {
+                state: WorkerState::Starting,
+                connected: false,
+                last_poll: None,
+                last_tick: None,
+                in_flight: 0,
+            })),
+        }
+    }
+
+    /// Get the lifecycle stage of the worker
+    pub fn state(&self) -> WorkerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Check if the last poll of the queue reached Redis
+    pub fn is_connected(&self) -> bool {
+        self.inner.lock().unwrap().connected
+    }
+
+    /// Get the time of the last successful poll of the queue
+    pub fn last_poll(&self) -> Option<Instant> {
+        self.inner.lock().unwrap().last_poll
+    }
+
+    /// Get the number of tasks the worker is processing right now
+    pub fn in_flight(&self) -> usize {
+        self.inner.lock().unwrap().in_flight
+    }
+
+    /// Check if the worker is running and connected to Redis
+    pub fn is_ready(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.state == WorkerState::Running && inner.connected
+    }
+
+    /// Check if the worker polled the queue or held off polling on purpose within `max_stall`
+    ///
+    /// Starting workers are live, stopped ones are not.
+    pub fn is_live(&self, max_stall: Duration) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            WorkerState::Starting => true,
+            WorkerState::Running => inner.last_tick.is_some_and(|at| at.elapsed() <= max_stall),
+            WorkerState::Stopped => false,
+        }
+    }
+
+    /// Record a poll of the queue, successful if it reached Redis.
+    pub(crate) fn polled(&self, connected: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connected = connected;
+        if connected {
+            inner.state = WorkerState::Running;
+            inner.last_poll = Some(Instant::now());
+            inner.last_tick = inner.last_poll;
+        }
+    }
+
+    /// Record that the worker holds off polling on purpose, e.g. while its queue is paused.
+    pub(crate) fn waiting(&self) {
+        self.inner.lock().unwrap().last_tick = Some(Instant::now());
+    }
+
+    /// Record the start or end of processing a task.
+    pub(crate) fn processing(&self, running: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if running {
+            inner.in_flight += 1;
+        } else {
+            inner.in_flight = inner.in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Record that the worker stopped.
+    pub(crate) fn stopped(&self) {
+        self.inner.lock().unwrap().state = WorkerState::Stopped;
+    }
+}
@@ -0,0 +1,192 @@
+//! Entries for every reserved task, kept while the task is processed.
+
+use std::collections::HashMap;
+use redis::{self, Pipeline, RedisResult};
+use functions;
+
+/// Records a reservation, stamped with the server time.
+///
+/// KEYS[1]: the index of reservations, scored by their start
+/// ARGV[1]: id of the job
+/// ARGV[2]: the stored job
+/// ARGV[3]: the worker
+/// ARGV[4]: the backup queue
+pub(crate) const RESERVE: &'static str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+redis.call('HMSET', KEYS[1] .. ':' .. ARGV[1], 'job', ARGV[2], 'worker', ARGV[3], 'backup', ARGV[4], 'started_at', now)
+redis.call('ZADD', KEYS[1], now, ARGV[1])
+";
+
+/// Moves reserved tasks which are processed for too long back into the queue.
+///
+/// Returns the id, worker and reservation age in milliseconds of every moved task.
+///
+/// KEYS[1]: the index of reservations, scored by their start
+/// KEYS[2]: the queue
+/// ARGV[1]: the maximum age of a reservation in milliseconds, by server time
+pub(crate) const REAP: &'static str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local stuck = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', now - tonumber(ARGV[1]), 'WITHSCORES')
+local moved = {}
+for i = 1, #stuck, 2 do
+  local rid = stuck[i]
+  local key = KEYS[1] .. ':' .. rid
+  local entry = redis.call('HMGET', key, 'job', 'backup', 'worker')
+  if entry[1] then
+    if entry[2] then
+      redis.call('LREM', entry[2], -1, entry[1])
+    end
+    redis.call('LPUSH', KEYS[2], entry[1])
+    table.insert(moved, {rid, entry[3] or '', now - tonumber(stuck[i + 1])})
+  end
+  redis.call('DEL', key)
+  redis.call('ZREM', KEYS[1], rid)
+end
+return moved
+";
+
+/// Get the key of the index of reservations of `queue`.
+fn index_key(queue: &str) -> String {
+    format!("{}:processing", queue)
+}
+
+/// Get the key the reservation `rid` of `queue` is stored in.
+fn entry_key(queue: &str, rid: &str) -> String {
+    format!("{}:processing:{}", queue, rid)
+}
+
+/// A task reserved by a worker and not finished yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    /// Id of the job
+    pub jid: String,
+    /// The stored job
+    pub job: String,
+    /// The worker processing the job
+    pub worker: String,
+    /// Full name of the backup queue the job is kept in
+    pub backup: String,
+    /// Time the worker fetched the job, in milliseconds since the Unix epoch, by server time
+    pub started_at: u64,
+}
+
+/// A task moved back into the queue by `Queue::reap_stuck`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StalledJob {
+    /// Full name of the queue the job was moved back into
+    pub queue: String,
+    /// Id of the job
+    pub jid: String,
+    /// The worker which held the job
+    pub worker: String,
+    /// How long the worker held the job, in milliseconds by server time
+    pub held_for: u64,
+}
+
+/// Hook called whenever a stuck task is moved back into its queue, e.g. to spot handlers which
+/// keep crashing their workers.
+///
+/// Set it with `Queue::with_stall_handler`. It's called by `Queue::reap_stuck` once the task is
+/// back in the queue. Closures taking the same arguments implement it as well.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let queue = Queue::new("videos".into(), client).with_stall_handler(|job: &StalledJob| {
+///     warn!("{} stalled on {} after {} ms", job.jid, job.worker, job.held_for);
+/// });
+/// ```
+pub trait StallHandler: Send + Sync {
+    /// Handle the stalled `job`
+    fn on_stalled(&self, job: &StalledJob);
+}
+
+impl<F: Fn(&StalledJob) + Send + Sync> StallHandler for F {
+    fn on_stalled(&self, job: &StalledJob) {
+        self(job)
+    }
+}
+
+/// Add the command recording the reservation of `job` by `worker` to the pipeline.
+///
+/// The reservation starts at the current server time.
+pub(crate) fn reserve(pipe: &mut Pipeline, queue: &str, rid: &str, job: &[u8], worker: &str, backup: &str) {
+    functions::eval(pipe, RESERVE, 1)
+        .arg(index_key(queue))
+        .arg(rid)
+        .arg(job)
+        .arg(worker)
+        .arg(backup)
+        .ignore();
+}
+
+/// Add the commands dropping the reservation `rid` to the pipeline.
+pub(crate) fn release(pipe: &mut Pipeline, queue: &str, rid: &str) {
+    pipe.cmd("DEL").arg(entry_key(queue, rid)).ignore();
+    pipe.cmd("ZREM").arg(index_key(queue)).arg(rid).ignore();
+}
+
+/// Check if the job `rid` of `queue` is reserved by a worker.
+pub(crate) fn is_reserved<C: redis::ConnectionLike>(con: &C, queue: &str, rid: &str) -> RedisResult<bool> {
+    redis::cmd("EXISTS").arg(entry_key(queue, rid)).query(con)
+}
+
+/// Get the number of reserved tasks of `queue`.
+pub(crate) fn count<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<u64> {
+    redis::cmd("ZCARD").arg(index_key(queue)).query(con)
+}
+
+/// List all reservations of `queue`, oldest first.
+pub(crate) fn list<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<Vec<Reservation>> {
+    let rids: Vec<String> = redis::cmd("ZRANGE").arg(index_key(queue)).arg(0).arg(-1).query(con)?;
+    if rids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for rid in &rids {
+        pipe.cmd("HGETALL").arg(entry_key(queue, rid));
+    }
+    let entries: Vec<HashMap<String, String>> = pipe.query(con)?;
+
+    Ok(
+        rids.into_iter()
+            .zip(entries)
+            .filter_map(|(rid, mut entry)| {
+                Some(Reservation {
+                    jid: rid,
+                    job: entry.remove("job")?,
+                    worker: entry.remove("worker").unwrap_or_default(),
+                    backup: entry.remove("backup").unwrap_or_default(),
+                    started_at: entry.get("started_at").and_then(|at| at.parse().ok()).unwrap_or(0),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Move all tasks of `queue` reserved more than `max_age` milliseconds ago back into the queue.
+///
+/// Returns the moved tasks.
+pub(crate) fn reap<C: redis::ConnectionLike>(con: &C, queue: &str, max_age: u64) -> RedisResult<Vec<StalledJob>> {
+    let moved: Vec<(String, String, u64)> = functions::script(REAP)
+        .key(index_key(queue))
+        .key(queue)
+        .arg(max_age)
+        .invoke(con)?;
+
+    Ok(
+        moved.into_iter()
+            .map(|(jid, worker, held_for)| {
+                StalledJob {
+                    queue: queue.into(),
+                    jid: jid,
+                    worker: worker,
+                    held_for: held_for,
+                }
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,182 @@
+//! Promotion of delayed and recurring jobs into their queues once they are due.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{cmp, thread};
+use std::time::Duration;
+use redis::{self, RedisResult};
+use functions;
+use recurring;
+use Order;
+
+/// Moves due jobs from the delayed set to the queue.
+///
+/// Jobs with a positive priority are moved to the front of the queue, as they are when pushed
+/// to the end it is consumed from: the tail for `Order::Fifo`, the head for `Order::Lifo`.
+///
+/// KEYS[1]: the delayed set
+/// KEYS[2]: the queue
+/// ARGV[1]: the current time in milliseconds
+/// ARGV[2]: the maximum number of jobs to move
+/// ARGV[3]: the command pushing jobs with a positive priority, `RPUSH` or `LPUSH`
+pub(crate) const PROMOTE: &'static str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, job in ipairs(due) do
+  redis.call('ZREM', KEYS[1], job)
+  local ok, decoded = pcall(cjson.decode, job)
+  local priority = 0
+  if ok and type(decoded) == 'table' and type(decoded.options) == 'table' then
+    priority = tonumber(decoded.options.priority) or 0
+  end
+  if priority > 0 then
+    redis.call(ARGV[3], KEYS[2], job)
+  else
+    redis.call('LPUSH', KEYS[2], job)
+  end
+end
+return #due
+";
+
+/// Move all jobs due at `now` from the delayed set of `queue`, consumed in `order`, into the queue.
+///
+/// Jobs are moved in batches of at most `batch_size` jobs.
+/// Returns the number of moved jobs.
+pub(crate) fn promote_due<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    order: Order,
+    now: u64,
+    batch_size: usize,
+) -> RedisResult<usize> {
+    let delayed = format!("{}:delayed", queue);
+    let urgent = match order {
+        Order::Fifo => "RPUSH",
+        Order::Lifo => "LPUSH",
+    };
+    let mut promoted = 0;
+
+    loop {
+        let moved: usize = functions::script(PROMOTE)
+            .key(&delayed[..])
+            .key(queue)
+            .arg(now)
+            .arg(batch_size)
+            .arg(urgent)
+            .invoke(con)?;
+        promoted += moved;
+        if moved < batch_size {
+            return Ok(promoted);
+        }
+    }
+}
+
+/// Promotes delayed jobs into their queues once they are due.
+///
+/// Jobs pushed with `Queue::push_delayed` or `Queue::push_at` wait in a sorted set until a
+/// promoter moves them to the queue.
+/// Recurring jobs registered with `Queue::register_recurring` are pushed by the promoter as well.
+/// Due jobs are determined by the time of the Redis server, not the clock of the promoter.
+/// Jobs are moved atomically in batches, so any number of promoters can run side by side,
+/// each job is promoted exactly once.
+///
+/// A promoter can be run in a background thread of a worker or as a standalone process.
+/// Clones share their state, so a clone can be used to stop a running promoter.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let promoter = Promoter::new(client).queue("default").queue("emails");
+///
+/// let handle = promoter.clone();
+/// thread::spawn(move || promoter.run());
+///
+/// // ...
+/// handle.stop();
+/// ```
+#[derive(Clone)]
+pub struct Promoter {
+    client: redis::Client,
+    queues: Vec<(String, Order)>,
+    batch_size: usize,
+    interval: Duration,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Promoter {
+    /// Create a new promoter without any queues
+    pub fn new(client: redis::Client) -> Promoter {
+        Promoter {
+            client: client,
+            queues: vec![],
+            batch_size: 100,
+            interval: Duration::from_secs(1),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Add the queue `name` to the queues to promote jobs for
+    pub fn queue(mut self, name: &str) -> Promoter {
+        self.queues.push((format!("oppgave:{}", name), Order::Fifo));
+        self
+    }
+
+    /// Add the queue `name` consumed in `order`, see `Queue::with_order`
+    ///
+    /// Jobs with a positive priority are promoted to the end the queue is consumed from.
+    pub fn queue_with_order(mut self, name: &str, order: Order) -> Promoter {
+        self.queues.push((format!("oppgave:{}", name), order));
+        self
+    }
+
+    /// Set the maximum number of jobs moved in one step. Defaults to 100.
+    pub fn batch_size(mut self, batch_size: usize) -> Promoter {
+        self.batch_size = cmp::max(1, batch_size);
+        self
+    }
+
+    /// Set how long to wait between checks for due jobs. Defaults to 1 second.
+    pub fn interval(mut self, interval: Duration) -> Promoter {
+        self.interval = interval;
+        self
+    }
+
+    /// Stop the promoter
+    ///
+    /// A running promoter returns after its current round.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the promoter is stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Promote all delayed and recurring jobs currently due
+    ///
+    /// Returns the number of promoted jobs.
+    pub fn promote(&self) -> RedisResult<usize> {
+        let con = self.client.get_connection()?;
+        let now = ::server_millis(&con)?;
+        let mut promoted = 0;
+
+        for &(ref queue, order) in &self.queues {
+            promoted += promote_due(&con, queue, order, now, self.batch_size)?;
+            promoted += recurring::enqueue_due(&con, queue, now, self.batch_size)?;
+        }
+
+        Ok(promoted)
+    }
+
+    /// Promote due jobs until stopped
+    ///
+    /// Errors are returned right away, it is up to the caller to restart the promoter.
+    pub fn run(&self) -> RedisResult<()> {
+        while !self.is_stopped() {
+            self.promote()?;
+            thread::sleep(self.interval);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Jobs enqueued repeatedly on a fixed schedule.
+
+use std::cmp;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use redis::{self, RedisResult};
+use functions;
+use envelope::Envelope;
+
+/// Stores a recurring job, keeping its next run unless the interval or jitter changed.
+///
+/// KEYS[1]: the schedule of the queue
+/// KEYS[2]: the definition of the job
+/// ARGV[1]: name of the job
+/// ARGV[2]: the queue
+/// ARGV[3]: the interval in milliseconds
+/// ARGV[4]: the encoded task
+/// ARGV[5]: the current time in milliseconds
+/// ARGV[6]: the jitter window in milliseconds
+pub(crate) const REGISTER: &'static str = r"
+local function offset(name, base, jitter)
+  if jitter <= 0 then
+    return 0
+  end
+  local s = name .. ':' .. string.format('%d', base)
+  local h = 0
+  for i = 1, #s do
+    h = (h * 31 + string.byte(s, i)) % 2147483647
+  end
+  return h % jitter
+end
+
+local old = redis.call('HMGET', KEYS[2], 'interval', 'jitter')
+redis.call('HMSET', KEYS[2], 'queue', ARGV[2], 'interval', ARGV[3], 'task', ARGV[4], 'jitter', ARGV[6])
+if old[1] ~= ARGV[3] or (old[2] or '0') ~= ARGV[6] or not redis.call('ZSCORE', KEYS[1], ARGV[1]) then
+  local base = tonumber(ARGV[5]) + tonumber(ARGV[3])
+  redis.call('HSET', KEYS[2], 'base', base)
+  redis.call('ZADD', KEYS[1], base + offset(ARGV[1], base, tonumber(ARGV[6])), ARGV[1])
+  return 1
+end
+return 0
+";
+
+/// Enqueues all due recurring jobs and schedules their next run.
+///
+/// Runs missed while no promoter was running are skipped.
+/// Every run is shifted by an offset within the jitter window of the job, derived from the name
+/// of the job and the scheduled time, so runs don't drift.
+///
+/// KEYS[1]: the schedule of the queue
+/// ARGV[1]: the current time in milliseconds
+/// ARGV[2]: the maximum number of jobs to enqueue
+pub(crate) const ENQUEUE_DUE: &'static str = r#"
+local function offset(name, base, jitter)
+  if jitter <= 0 then
+    return 0
+  end
+  local s = name .. ':' .. string.format('%d', base)
+  local h = 0
+  for i = 1, #s do
+    h = (h * 31 + string.byte(s, i)) % 2147483647
+  end
+  return h % jitter
+end
+
+local now = tonumber(ARGV[1])
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', now, 'WITHSCORES', 'LIMIT', 0, ARGV[2])
+local count = 0
+for i = 1, #due, 2 do
+  local name, at = due[i], tonumber(due[i + 1])
+  local key = KEYS[1] .. ':' .. name
+  local job = redis.call('HMGET', key, 'queue', 'interval', 'task', 'jitter', 'base')
+  if job[1] then
+    local interval = tonumber(job[2])
+    local base = tonumber(job[5]) or at
+    local next = base + interval
+    if next <= now then
+      next = now + interval
+    end
+    redis.call('HSET', key, 'base', next)
+    redis.call('ZADD', KEYS[1], next + offset(name, next, tonumber(job[4]) or 0), name)
+    local jid = cjson.encode(name .. ':' .. string.format('%d', base))
+    redis.call('LPUSH', job[1], '{"jid":' .. jid .. ',"task":' .. job[3] .. '}')
+    count = count + 1
+  else
+    redis.call('ZREM', KEYS[1], name)
+  end
+end
+return count
+"#;
+
+/// Get the key of the schedule of recurring jobs for `queue`.
+fn schedule_key(queue: &str) -> String {
+    format!("{}:recurring", queue)
+}
+
+/// Get the key the definition of the recurring job `name` is stored in.
+fn job_key(queue: &str, name: &str) -> String {
+    format!("{}:recurring:{}", queue, name)
+}
+
+/// A job registered to be enqueued repeatedly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecurringJob {
+    /// Unique name of the job within its queue
+    pub name: String,
+    /// Full name of the queue the job is pushed to
+    pub queue: String,
+    /// Time between two runs
+    pub interval: Duration,
+    /// Window every run is shifted by a random offset within
+    pub jitter: Duration,
+    /// The encoded task
+    pub task: String,
+    /// Time of the next run
+    pub next_run: SystemTime,
+}
+
+/// Register the recurring job `name`, replacing any job of the same name.
+pub(crate) fn register<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    name: &str,
+    interval: Duration,
+    jitter: Duration,
+    task: Vec<u8>,
+) -> RedisResult<()> {
+    let job = Envelope::new(task)?;
+    // A zero interval would make the job due again right away
+    let interval = cmp::max(1, ::duration_millis(interval));
+
+    functions::script(REGISTER)
+        .key(schedule_key(queue))
+        .key(job_key(queue, name))
+        .arg(name)
+        .arg(queue)
+        .arg(interval)
+        .arg(job.task.get())
+        .arg(::server_millis(con)?)
+        .arg(::duration_millis(jitter))
+        .invoke(con)
+}
+
+/// Remove the recurring job `name`.
+///
+/// Returns `true` if the job existed.
+pub(crate) fn unregister<C: redis::ConnectionLike>(con: &C, queue: &str, name: &str) -> RedisResult<bool> {
+    let (removed, _): (u64, u64) = redis::pipe()
+        .atomic()
+        .cmd("ZREM")
+        .arg(schedule_key(queue))
+        .arg(name)
+        .cmd("DEL")
+        .arg(job_key(queue, name))
+        .query(con)?;
+
+    Ok(removed > 0)
+}
+
+/// List all recurring jobs of `queue`, ordered by their next run.
+pub(crate) fn list<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<Vec<RecurringJob>> {
+    let schedule: Vec<(String, u64)> = redis::cmd("ZRANGE")
+        .arg(schedule_key(queue))
+        .arg(0)
+        .arg(-1)
+        .arg("WITHSCORES")
+        .query(con)?;
+    if schedule.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for entry in &schedule {
+        pipe.cmd("HMGET")
+            .arg(job_key(queue, &entry.0))
+            .arg("interval")
+            .arg("task")
+            .arg("jitter");
+    }
+    let jobs: Vec<(Option<u64>, Option<String>, Option<u64>)> = pipe.query(con)?;
+
+    Ok(
+        schedule
+            .into_iter()
+            .zip(jobs)
+            .filter_map(|((name, next_run), job)| match job {
+                (Some(interval), Some(task), jitter) => Some(RecurringJob {
+                    name: name,
+                    queue: queue.into(),
+                    interval: Duration::from_millis(interval),
+                    jitter: Duration::from_millis(jitter.unwrap_or(0)),
+                    task: task,
+                    next_run: UNIX_EPOCH + Duration::from_millis(next_run),
+                }),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Enqueue all recurring jobs of `queue` due at `now`.
+///
+/// Returns the number of enqueued jobs.
+pub(crate) fn enqueue_due<C: redis::ConnectionLike>(
+    con: &C,
+    queue: &str,
+    now: u64,
+    batch_size: usize,
+) -> RedisResult<usize> {
+    let schedule = schedule_key(queue);
+    let mut enqueued = 0;
+
+    loop {
+        let count: usize = functions::script(ENQUEUE_DUE).key(&schedule[..]).arg(now).arg(batch_size).invoke(con)?;
+        enqueued += count;
+        if count < batch_size {
+            return Ok(enqueued);
+        }
+    }
+}
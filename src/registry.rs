@@ -0,0 +1,103 @@
+//! Registry of live workers, kept up to date with heartbeats.
+
+use redis::{self, RedisResult};
+use serde_json;
+
+/// Index of all registered workers, scored by their last heartbeat
+const WORKERS_KEY: &'static str = "oppgave:workers";
+
+/// Get the key the details of worker `id` are stored in.
+fn worker_key(id: &str) -> String {
+    format!("oppgave:worker:{}", id)
+}
+
+/// A worker registered in Redis.
+///
+/// All times are in milliseconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerInfo {
+    /// Identity of the worker: the host name, PID and thread name
+    pub id: String,
+    /// Host the worker runs on
+    pub hostname: String,
+    /// Process id of the worker
+    pub pid: i32,
+    /// Full names of the queues the worker consumes
+    pub queues: Vec<String>,
+    /// Number of tasks the worker processes at the same time
+    pub concurrency: usize,
+    /// Time the worker started
+    pub started_at: u64,
+    /// Time of the last heartbeat
+    pub heartbeat_at: u64,
+    /// Ids of the jobs the worker currently processes
+    pub current: Vec<String>,
+    /// Whether the worker stopped fetching new tasks
+    #[serde(default)]
+    pub quiet: bool,
+}
+
+/// Register `worker` or refresh its registration.
+///
+/// The registration expires after `ttl` seconds without another heartbeat.
+pub(crate) fn beat<C: redis::ConnectionLike>(con: &C, worker: &WorkerInfo, ttl: usize) -> RedisResult<()> {
+    let record = serde_json::to_string(worker).expect("Encoding a worker can't fail");
+
+    redis::pipe()
+        .atomic()
+        .cmd("SETEX")
+        .arg(worker_key(&worker.id))
+        .arg(ttl)
+        .arg(record)
+        .ignore()
+        .cmd("ZADD")
+        .arg(WORKERS_KEY)
+        .arg(worker.heartbeat_at)
+        .arg(&worker.id[..])
+        .ignore()
+        .query(con)
+}
+
+/// Remove the registration of worker `id`.
+pub(crate) fn remove<C: redis::ConnectionLike>(con: &C, id: &str) -> RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("ZREM")
+        .arg(WORKERS_KEY)
+        .arg(id)
+        .ignore()
+        .cmd("DEL")
+        .arg(worker_key(id))
+        .ignore()
+        .query(con)
+}
+
+/// List all live workers registered in the Redis instance of `client`
+///
+/// Workers are ordered by their last heartbeat, oldest first.
+/// Registrations of workers which stopped sending heartbeats are cleaned up on the way.
+pub fn list_workers(client: &redis::Client) -> RedisResult<Vec<WorkerInfo>> {
+    let con = client.get_connection()?;
+    let ids: Vec<String> = redis::cmd("ZRANGE").arg(WORKERS_KEY).arg(0).arg(-1).query(&con)?;
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let keys = ids.iter().map(|id| worker_key(id)).collect::<Vec<_>>();
+    let records: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query(&con)?;
+
+    let mut workers = vec![];
+    let mut expired = vec![];
+    for (id, record) in ids.into_iter().zip(records) {
+        match record.and_then(|record| serde_json::from_str(&record).ok()) {
+            Some(worker) => workers.push(worker),
+            None => expired.push(id),
+        }
+    }
+
+    if !expired.is_empty() {
+        redis::cmd("ZREM").arg(WORKERS_KEY).arg(expired).query::<()>(&con)?;
+    }
+
+    Ok(workers)
+}
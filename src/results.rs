@@ -0,0 +1,183 @@
+//! Results of finished jobs, kept for producers waiting on them.
+
+use std::time::{Duration, Instant};
+use redis::{self, ErrorKind, Pipeline, RedisError, RedisResult};
+use functions;
+use serde_json::{self, value::RawValue};
+use TaskDecodable;
+use job::JobStatus;
+
+/// Stores the result of a finished job, unless the job waits for a retry.
+///
+/// The result is kept in a list holding a single entry, so waiting producers can block on it
+/// with `BLMOVE` (or `BRPOPLPUSH` on older servers) without taking it away.
+///
+/// KEYS[1]: the result of the job
+/// KEYS[2]: the dead letter queue
+/// ARGV[1]: the stored result
+/// ARGV[2]: how long to keep the result in seconds
+/// ARGV[3]: `1` to only store the result if the job is dead
+/// ARGV[4]: id of the job
+pub(crate) const STORE: &'static str = r"
+if ARGV[3] == '1' and not redis.call('ZSCORE', KEYS[2], ARGV[4]) then
+  return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('RPUSH', KEYS[1], ARGV[1])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return 1
+";
+
+/// Get the key the result of `jid` is stored in.
+fn result_key(jid: &str) -> String {
+    format!("oppgave:job:{}:result", jid)
+}
+
+/// The outcome of a finished job as stored in Redis.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Stored {
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Box<RawValue>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Stored {
+    /// A completed job, with the encoded result of its handler if it set one.
+    pub(crate) fn completed(result: Option<&[u8]>) -> Stored {
+        Stored {
+            status: "completed".into(),
+            result: result.and_then(|result| serde_json::from_slice(result).ok()),
+            error: None,
+        }
+    }
+
+    /// A job which was given up on.
+    pub(crate) fn failed(error: Option<&str>) -> Stored {
+        Stored {
+            status: "failed".into(),
+            result: None,
+            error: error.map(String::from),
+        }
+    }
+
+    /// A job which was dropped without running to completion.
+    pub(crate) fn discarded() -> Stored {
+        Stored {
+            status: "discarded".into(),
+            result: None,
+            error: None,
+        }
+    }
+
+    /// Get the status of the job, discarded jobs count as failed.
+    pub(crate) fn status(&self) -> JobStatus {
+        match &self.status[..] {
+            "completed" => JobStatus::Completed,
+            _ => JobStatus::Failed,
+        }
+    }
+
+    /// Decode the outcome, with the result as `R`.
+    fn decode<R: TaskDecodable>(self) -> RedisResult<JobResult<R>> {
+        Ok(match &self.status[..] {
+            "completed" => {
+                let raw = self.result.map_or_else(|| "null".to_string(), |result| result.get().to_string());
+                JobResult::Completed(R::decode_task(&redis::Value::Data(raw.into_bytes()))?)
+            }
+            "discarded" => JobResult::Discarded,
+            _ => JobResult::Failed { error: self.error },
+        })
+    }
+}
+
+/// The outcome of a job, as seen by a producer waiting for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobResult<R> {
+    /// The job completed, with the result set by its handler.
+    ///
+    /// Jobs completing without a result decode `null`, e.g. as `()` or `Option<R>`.
+    Completed(R),
+    /// The job failed for good and was moved to the dead letter queue.
+    Failed {
+        /// The error of the last failure, if one was given
+        error: Option<String>,
+    },
+    /// The job was dropped, see `TaskGuard::discard`.
+    Discarded,
+    /// The job didn't finish in time.
+    TimedOut,
+}
+
+/// Add the command storing the outcome of `jid` in `queue` for `ttl` to the pipeline.
+///
+/// With `if_dead`, the outcome is only stored if the job was moved to the dead letter queue,
+/// e.g. after running out of attempts.
+pub(crate) fn store(pipe: &mut Pipeline, queue: &str, jid: &str, stored: &Stored, ttl: Duration, if_dead: bool) {
+    functions::eval(pipe, STORE, 2)
+        .arg(result_key(jid))
+        .arg(::failure::dead_key(queue))
+        .arg(serde_json::to_string(stored).expect("Encoding a result can't fail"))
+        .arg(::cmp::max(1, ttl.as_secs()))
+        .arg(if if_dead { 1 } else { 0 })
+        .arg(jid)
+        .ignore();
+}
+
+/// Wait for the outcome of `jid` until `deadline`, with `BLMOVE` if `lmove` is set.
+///
+/// Returns `None` if the job didn't finish in time.
+pub(crate) fn wait<C: redis::ConnectionLike>(con: &C, jid: &str, deadline: Instant, lmove: bool) -> RedisResult<Option<Stored>> {
+    let key = result_key(jid);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        // Blocking timeouts are whole seconds, 0 would block forever
+        let timeout = ::cmp::max(1, ::duration_millis(left).div_ceil(1000));
+        let stored: Option<String> = if lmove {
+            redis::cmd("BLMOVE").arg(&key[..]).arg(&key[..]).arg("RIGHT").arg("LEFT").arg(timeout).query(con)?
+        } else {
+            redis::cmd("BRPOPLPUSH").arg(&key[..]).arg(&key[..]).arg(timeout).query(con)?
+        };
+        match stored {
+            Some(stored) => {
+                return serde_json::from_str(&stored)
+                    .map(Some)
+                    .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid job result")))
+            }
+            None if Instant::now() >= deadline => return Ok(None),
+            None => {}
+        }
+    }
+}
+
+/// Wait for the outcome of `jid` until `deadline` and decode its result as `R`.
+pub(crate) fn await_result<C, R>(con: &C, jid: &str, deadline: Instant, lmove: bool) -> RedisResult<JobResult<R>>
+where
+    C: redis::ConnectionLike,
+    R: TaskDecodable,
+{
+    match wait(con, jid, deadline, lmove)? {
+        Some(stored) => stored.decode(),
+        None => Ok(JobResult::TimedOut),
+    }
+}
+
+/// Wait for the outcomes of all `jids` until `deadline`, in the order of `jids`.
+///
+/// Jobs not finished in time are `JobResult::TimedOut`.
+pub(crate) fn await_all<C, R, S>(con: &C, jids: &[S], deadline: Instant, lmove: bool) -> RedisResult<Vec<JobResult<R>>>
+where
+    C: redis::ConnectionLike,
+    R: TaskDecodable,
+    S: AsRef<str>,
+{
+    // Jobs finishing while waiting for an earlier one are picked up right away
+    jids.iter().map(|jid| await_result(con, jid.as_ref(), deadline, lmove)).collect()
+}
+
+/// Get the stored outcome of `jid` without waiting for it.
+pub(crate) fn peek<C: redis::ConnectionLike>(con: &C, jid: &str) -> RedisResult<Option<Stored>> {
+    let stored: Option<String> = redis::cmd("LINDEX").arg(result_key(jid)).arg(0).query(con)?;
+    Ok(stored.and_then(|stored| serde_json::from_str(&stored).ok()))
+}
@@ -0,0 +1,55 @@
+//! Routing of tasks to queues based on their type.
+
+use redis;
+use {PushError, Queue, TaskEncodable};
+
+/// Task types with a default queue.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// #[derive(Deserialize, Serialize)]
+/// struct Email { to: String }
+///
+/// impl Route for Email {
+///     const QUEUE: &'static str = "emails";
+/// }
+/// ```
+pub trait Route {
+    /// Name of the queue tasks of this type are pushed to, as passed to `Queue::new`
+    const QUEUE: &'static str;
+}
+
+/// Pushes tasks to the queue declared by their type.
+///
+/// This keeps queue names out of call sites: tasks are routed by their `Route` implementation.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let router = Router::new(client);
+///
+/// router.push(Email { to: "jane@example.com".into() }).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Router {
+    client: redis::Client,
+}
+
+impl Router {
+    /// Create a new router pushing to queues on the given Redis server
+    pub fn new(client: redis::Client) -> Router {
+        Router { client: client }
+    }
+
+    /// Get the queue tasks of type `T` are routed to
+    pub fn queue<T: Route>(&self) -> Queue {
+        Queue::new(T::QUEUE.into(), self.client.clone())
+    }
+
+    /// Push a task to the queue declared by its type
+    pub fn push<T: Route + TaskEncodable>(&self, task: T) -> Result<(), PushError> {
+        self.queue::<T>().push(task)
+    }
+}
@@ -0,0 +1,114 @@
+//! Suggested number of workers for a queue, e.g. for autoscalers.
+
+use std::cmp;
+use std::time::Duration;
+use redis::{self, RedisResult};
+
+/// Number of depth samples kept per queue.
+const SAMPLES: isize = 120;
+
+/// The depth is compared to the one sampled at least this long ago, in milliseconds.
+const TREND_WINDOW: u64 = 60 * 1000;
+
+/// How long depth samples are kept without new ones, in seconds.
+const SAMPLES_TTL: usize = 60 * 60;
+
+/// Get the key the depth samples of `queue` are stored in.
+fn samples_key(queue: &str) -> String {
+    format!("{}:depth", queue)
+}
+
+/// The number of workers a queue needs, together with the numbers it is based on.
+///
+/// See `Queue::scaling_hint`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScalingHint {
+    /// Suggested number of workers
+    pub workers: u64,
+    /// Number of pending jobs
+    pub depth: u64,
+    /// Change of the depth in jobs per second, positive while the queue grows
+    pub trend: f64,
+    /// Number of jobs finished per second
+    pub rate: f64,
+    /// Average processing time of a job, `None` if no jobs finished lately
+    pub processing_time: Option<Duration>,
+}
+
+impl ScalingHint {
+    /// Suggest the number of workers to keep up with the incoming jobs and to work off the
+    /// backlog within `drain_within`.
+    pub(crate) fn new(
+        depth: u64,
+        trend: f64,
+        rate: f64,
+        processing_time: Option<Duration>,
+        drain_within: Duration,
+    ) -> ScalingHint {
+        let workers = match processing_time {
+            Some(processing_time) => {
+                // Jobs arrive as fast as they finish, plus the growth of the queue
+                let inflow = (rate + trend).max(0.0);
+                let needed = inflow + depth as f64 / drain_within.as_secs_f64().max(1.0);
+                (needed * processing_time.as_secs_f64()).ceil() as u64
+            }
+            // Without jobs finishing there is nothing to base an estimate on
+            None => 0,
+        };
+
+        ScalingHint {
+            workers: if depth > 0 { cmp::max(1, workers) } else { workers },
+            depth: depth,
+            trend: trend,
+            rate: rate,
+            processing_time: processing_time,
+        }
+    }
+}
+
+/// Record `depth` of `queue` at `now` and get the change of the depth in jobs per second.
+///
+/// Returns 0 until another sample was recorded before.
+pub(crate) fn sample<C: redis::ConnectionLike>(con: &C, queue: &str, now: u64, depth: u64) -> RedisResult<f64> {
+    let key = samples_key(queue);
+    let (samples,): (Vec<String>,) = redis::pipe()
+        .atomic()
+        .cmd("LRANGE")
+        .arg(&key[..])
+        .arg(0)
+        .arg(-1)
+        .cmd("LPUSH")
+        .arg(&key[..])
+        .arg(format!("{}:{}", now, depth))
+        .ignore()
+        .cmd("LTRIM")
+        .arg(&key[..])
+        .arg(0)
+        .arg(SAMPLES - 1)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&key[..])
+        .arg(SAMPLES_TTL)
+        .ignore()
+        .query(con)?;
+
+    let parsed = samples.iter().filter_map(|sample| {
+        let mut parts = sample.splitn(2, ':');
+        let at = parts.next()?.parse::<u64>().ok()?;
+        let depth = parts.next()?.parse::<u64>().ok()?;
+        Some((at, depth))
+    });
+    // The newest sample old enough, or the oldest one while all are younger
+    let mut base = None;
+    for (at, old) in parsed {
+        base = Some((at, old));
+        if now.saturating_sub(at) >= TREND_WINDOW {
+            break;
+        }
+    }
+
+    Ok(match base {
+        Some((at, old)) if now > at => (depth as f64 - old as f64) / ((now - at) as f64 / 1000.0),
+        _ => 0.0,
+    })
+}
@@ -0,0 +1,65 @@
+//! Reporting of failed jobs to Sentry.
+
+use std::borrow::Cow;
+use serde_json;
+use sentry_core::{self, protocol::{Event, Level, Value}};
+use {FailedJob, FailureHandler};
+
+/// Reports failed jobs to Sentry, next to the errors of the application.
+///
+/// Events are sent through the current Sentry hub, so the application needs to initialize the
+/// `sentry` crate as usual. Every event carries the queue and job id as tags, and the task and
+/// number of attempts as extra data. Events are grouped by queue and error.
+///
+/// By default, only jobs moved to the dead letter queue are reported.
+/// Requires the `sentry` feature.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let _guard = sentry::init("https://key@sentry.example.com/1");
+/// let queue = Queue::new("emails".into(), client).with_failure_handler(SentryReporter::new());
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SentryReporter {
+    retries: bool,
+}
+
+impl SentryReporter {
+    /// Create a reporter for dead-lettered jobs
+    pub fn new() -> SentryReporter {
+        SentryReporter::default()
+    }
+
+    /// Report every failure, including those which are retried, as warnings
+    pub fn with_retries(mut self) -> SentryReporter {
+        self.retries = true;
+        self
+    }
+}
+
+impl FailureHandler for SentryReporter {
+    fn on_failure(&self, queue: &str, job: &FailedJob, dead: bool) {
+        if !dead && !self.retries {
+            return;
+        }
+
+        let error = job.error.clone().unwrap_or_else(|| "Job failed".into());
+        let mut event = Event {
+            message: Some(format!("{} in {}: {}", if dead { "Dead job" } else { "Failed job" }, queue, error)),
+            level: if dead { Level::Error } else { Level::Warning },
+            fingerprint: Cow::Owned(vec![queue.to_string().into(), error.into()]),
+            ..Event::default()
+        };
+        event.tags.insert("queue".into(), queue.into());
+        event.tags.insert("jid".into(), job.jid.clone());
+        event.tags.insert("dead".into(), dead.to_string());
+        event.extra.insert("attempts".into(), job.attempts.into());
+        event.extra.insert("worker".into(), job.worker.clone().into());
+        event.extra.insert("poison".into(), job.poison.into());
+        let task = serde_json::from_str(&job.task).unwrap_or_else(|_| Value::String(job.task.clone()));
+        event.extra.insert("task".into(), task);
+
+        sentry_core::capture_event(event);
+    }
+}
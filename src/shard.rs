@@ -0,0 +1,28 @@
+//! Splitting of a queue into several shards.
+
+/// Get the key of shard `index` of `queue`.
+pub(crate) fn shard_key(queue: &str, index: usize) -> String {
+    format!("{}:shard:{}", queue, index)
+}
+
+/// Pick one of `shards` shards for `key`.
+///
+/// This uses FNV-1a, so the same key maps to the same shard across processes and releases.
+pub(crate) fn pick(key: &str, shards: usize) -> usize {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+
+    (hash % shards as u64) as usize
+}
+
+/// Strip the shard suffix from `key`, if it is the key of a shard.
+pub(crate) fn logical_queue(key: &str) -> Option<&str> {
+    let at = key.rfind(":shard:")?;
+    let index = &key[at + ":shard:".len()..];
+    if !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()) {
+        Some(&key[..at])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,24 @@
+//! Scoping of queues to tenants.
+
+/// Prefix of the names of tenant scoped queues
+const PREFIX: &'static str = "tenant:";
+
+/// Get the name of the queue `name` scoped to `tenant`
+///
+/// The result can be used wherever a queue name is expected, e.g. for `Queue::new`,
+/// `Chain::then` or `Promoter::queue`.
+/// Tenant ids must not contain a `:`.
+pub fn tenant_queue(tenant: &str, name: &str) -> String {
+    format!("{}{}:{}", PREFIX, tenant, name)
+}
+
+/// Split a queue name into the tenant and the name within the tenant.
+pub(crate) fn split(name: &str) -> (Option<&str>, &str) {
+    if let Some(scoped) = name.strip_prefix(PREFIX) {
+        if let Some(at) = scoped.find(':') {
+            return (Some(&scoped[..at]), &scoped[at + 1..]);
+        }
+    }
+
+    (None, name)
+}
@@ -0,0 +1,70 @@
+//! Counters of finished jobs, to estimate how fast a queue is processed.
+
+use std::time::Duration;
+use redis::{self, Pipeline, RedisResult};
+use functions;
+
+/// Number of minutes the processing rate is averaged over.
+const WINDOW: u64 = 5;
+
+/// Counts a finished job and its processing time in the buckets of the current minute.
+///
+/// KEYS[1]: prefix of the per-minute buckets
+/// ARGV[1]: seconds to keep a bucket
+/// ARGV[2]: processing time of the job in milliseconds
+pub(crate) const RECORD: &'static str = r"
+local minute = math.floor(tonumber(redis.call('TIME')[1]) / 60)
+local key = KEYS[1] .. ':' .. minute
+redis.call('INCR', key)
+redis.call('EXPIRE', key, ARGV[1])
+redis.call('INCRBY', key .. ':ms', ARGV[2])
+redis.call('EXPIRE', key .. ':ms', ARGV[1])
+";
+
+/// Sums up the finished jobs of the last minutes.
+///
+/// Returns the number of jobs, the number of seconds they finished in and their total
+/// processing time in milliseconds.
+///
+/// KEYS[1]: prefix of the per-minute buckets
+/// ARGV[1]: number of full minutes to look at
+pub(crate) const RATE: &'static str = r"
+local now = tonumber(redis.call('TIME')[1])
+local minute = math.floor(now / 60)
+local jobs = 0
+local millis = 0
+for i = 0, tonumber(ARGV[1]) do
+  local key = KEYS[1] .. ':' .. (minute - i)
+  jobs = jobs + tonumber(redis.call('GET', key) or '0')
+  millis = millis + tonumber(redis.call('GET', key .. ':ms') or '0')
+end
+return {jobs, tonumber(ARGV[1]) * 60 + now % 60, millis}
+";
+
+/// Get the prefix of the per-minute buckets of `queue`.
+fn key(queue: &str) -> String {
+    format!("{}:throughput", queue)
+}
+
+/// Add the command counting a finished job of `queue`, processed for `millis`, to the pipeline.
+pub(crate) fn record(pipe: &mut Pipeline, queue: &str, millis: u64) {
+    functions::eval(pipe, RECORD, 1)
+        .arg(key(queue))
+        .arg((WINDOW + 2) * 60)
+        .arg(millis)
+        .ignore();
+}
+
+/// Get the number of jobs of `queue` finished per second over the last minutes.
+pub(crate) fn rate<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<f64> {
+    stats(con, queue).map(|(rate, _)| rate)
+}
+
+/// Get the rate of `queue` together with the average processing time of its jobs over the
+/// last minutes, `None` if no jobs finished.
+pub(crate) fn stats<C: redis::ConnectionLike>(con: &C, queue: &str) -> RedisResult<(f64, Option<Duration>)> {
+    let (jobs, seconds, millis): (u64, u64, u64) =
+        functions::script(RATE).key(key(queue)).arg(WINDOW).invoke(con)?;
+    let average = millis.checked_div(jobs).map(Duration::from_millis);
+    Ok((jobs as f64 / seconds as f64, average))
+}
@@ -0,0 +1,152 @@
+//! Export and import of queue contents as newline-delimited JSON.
+
+use std::io::{BufRead, Write};
+use serde_json;
+use redis::{self, RedisResult, ErrorKind};
+
+/// Number of entries read or written per round trip.
+const CHUNK: usize = 500;
+
+/// A single exported entry, written as one line.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    /// The entry as stored in Redis
+    #[serde(default)]
+    job: String,
+    /// The bytes of an entry which is not valid UTF-8, instead of `job`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw: Option<Vec<u8>>,
+    /// Time a delayed entry is due, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due_at: Option<u64>,
+}
+
+impl Line {
+    /// Get the entry as stored in Redis.
+    fn into_data(self) -> Vec<u8> {
+        match self.raw {
+            Some(raw) => raw,
+            None => self.job.into_bytes(),
+        }
+    }
+}
+
+fn write_line<W: Write>(writer: &mut W, job: Vec<u8>, due_at: Option<u64>) -> RedisResult<()> {
+    let (job, raw) = match String::from_utf8(job) {
+        Ok(job) => (job, None),
+        Err(e) => (String::new(), Some(e.into_bytes())),
+    };
+    let line = Line {
+        job: job,
+        raw: raw,
+        due_at: due_at,
+    };
+    writeln!(writer, "{}", serde_json::to_string(&line).expect("Encoding an entry can't fail"))?;
+    Ok(())
+}
+
+/// Write all entries of the lists `sources` and the delayed set `delayed` to `writer`.
+///
+/// Lists are written from head to tail. Returns the number of written entries.
+pub(crate) fn export<C, W>(con: &C, sources: &[String], delayed: &str, writer: &mut W) -> RedisResult<usize>
+where
+    C: redis::ConnectionLike,
+    W: Write,
+{
+    let mut count = 0;
+
+    for source in sources {
+        let mut offset = 0;
+        loop {
+            let chunk: Vec<Vec<u8>> = redis::cmd("LRANGE")
+                .arg(&source[..])
+                .arg(offset)
+                .arg(offset + CHUNK - 1)
+                .query(con)?;
+            let done = chunk.len() < CHUNK;
+            for job in chunk {
+                write_line(writer, job, None)?;
+                count += 1;
+            }
+            if done {
+                break;
+            }
+            offset += CHUNK;
+        }
+    }
+
+    let mut offset = 0;
+    loop {
+        let chunk: Vec<(Vec<u8>, u64)> = redis::cmd("ZRANGE")
+            .arg(delayed)
+            .arg(offset)
+            .arg(offset + CHUNK - 1)
+            .arg("WITHSCORES")
+            .query(con)?;
+        let done = chunk.len() < CHUNK;
+        for (job, due_at) in chunk {
+            write_line(writer, job, Some(due_at))?;
+            count += 1;
+        }
+        if done {
+            break;
+        }
+        offset += CHUNK;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Append all entries read from `reader` to the list `queue` or the delayed set `delayed`.
+///
+/// Entries of the list are pushed once all were read, so they end up behind the entries
+/// already in `queue` in the order they were exported.
+/// Returns the number of imported entries.
+pub(crate) fn import<C, R>(con: &C, queue: &str, delayed: &str, reader: R) -> RedisResult<usize>
+where
+    C: redis::ConnectionLike,
+    R: BufRead,
+{
+    let mut count = 0;
+    let mut pipe = redis::pipe();
+    let mut pending = 0;
+    let mut jobs = vec![];
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: Line = serde_json::from_str(&line).map_err(|_| {
+            redis::RedisError::from((ErrorKind::TypeError, "Invalid exported entry"))
+        })?;
+
+        count += 1;
+        match line.due_at {
+            Some(due_at) => {
+                pipe.cmd("ZADD").arg(delayed).arg(due_at).arg(line.into_data()).ignore();
+                pending += 1;
+            }
+            None => jobs.push(line.into_data()),
+        }
+
+        if pending == CHUNK {
+            pipe.query::<()>(con)?;
+            pipe = redis::pipe();
+            pending = 0;
+        }
+    }
+
+    if pending > 0 {
+        pipe.query::<()>(con)?;
+    }
+
+    // Jobs are exported from head to tail, pushing the last one first to the head keeps them
+    // in order behind the jobs already in the queue
+    for chunk in jobs.rchunks(CHUNK) {
+        let chunk: Vec<&[u8]> = chunk.iter().rev().map(|job| &job[..]).collect();
+        redis::cmd("LPUSH").arg(queue).arg(chunk).query::<()>(con)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,55 @@
+//! Retries of commands failing for transient reasons.
+
+use std::thread;
+use std::time::Duration;
+use redis::{ErrorKind, RedisError};
+use instrument;
+
+/// How often commands failing for transient reasons are retried, see `Queue::with_transient_retries`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Retries {
+    /// Number of retries after the first attempt
+    pub(crate) retries: u32,
+    /// Time to wait before the first retry, doubled for every further retry
+    pub(crate) backoff: Duration,
+}
+
+/// Check if `error` is likely to go away on its own, e.g. a dropped connection or a server
+/// still loading its data.
+pub(crate) fn is_transient(error: &RedisError) -> bool {
+    match error.kind() {
+        ErrorKind::IoError | ErrorKind::BusyLoadingError => true,
+        // Cluster redirections and failovers
+        ErrorKind::ExtensionError => {
+            matches!(error.extension_error_code(), Some("MOVED") | Some("ASK") | Some("TRYAGAIN") | Some("CLUSTERDOWN"))
+        }
+        _ => false,
+    }
+}
+
+/// Run `f` and retry it according to `retries` while `transient` tells its errors are transient.
+///
+/// Retries are recorded as retries of `command`.
+pub(crate) fn retry<T, E, F, P>(retries: Option<Retries>, command: &str, transient: P, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    P: Fn(&E) -> bool,
+{
+    let retries = match retries {
+        Some(retries) => retries,
+        None => return f(),
+    };
+
+    let mut backoff = retries.backoff;
+    for _ in 0..retries.retries {
+        match f() {
+            Err(ref e) if transient(e) => {
+                instrument::record_retry(command);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    f()
+}
@@ -0,0 +1,643 @@
+//! A worker loop running a handler for every task of a queue.
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::{any, cmp, thread};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, value::RawValue};
+use redis::RedisResult;
+use registry::{self, WorkerInfo};
+use control::{self, Control};
+use {command, config, isolate, job, processing};
+use {CancellationToken, CircuitBreaker, Classify, ConcurrencyLimits, Idle, IdleStrategy, Queue, QueueConfig,
+     ResourceGate, TaskOutcome, WorkerProbe};
+
+/// The task types a worker processes.
+#[derive(Clone, Debug)]
+pub(crate) enum KindFilter {
+    /// Only the given types. Tasks without a type are skipped.
+    Only(Vec<String>),
+    /// All but the given types.
+    Skip(Vec<String>),
+}
+
+impl KindFilter {
+    /// Check if a task of type `kind` is processed.
+    pub(crate) fn accepts(&self, kind: Option<&str>) -> bool {
+        match *self {
+            KindFilter::Only(ref kinds) => kind.is_some_and(|kind| kinds.iter().any(|k| k == kind)),
+            KindFilter::Skip(ref kinds) => !kind.is_some_and(|kind| kinds.iter().any(|k| k == kind)),
+        }
+    }
+}
+
+/// Called with the previous and the new settings of the queue, see `Worker::on_config_change`.
+type ConfigCallback = Arc<dyn Fn(&QueueConfig, &QueueConfig) + Send + Sync>;
+
+/// Why a handler failed and what happens to its task.
+struct HandlerError {
+    message: String,
+    outcome: TaskOutcome,
+}
+
+impl HandlerError {
+    /// A failure to retry, e.g. a timeout.
+    fn retry(message: String) -> HandlerError {
+        HandlerError {
+            message: message,
+            outcome: TaskOutcome::Retry,
+        }
+    }
+}
+
+/// Runs a handler for every task fetched from a queue.
+///
+/// Every handler gets a `CancellationToken`, which is cancelled when the worker is stopped, the
+/// handler runs over its timeout or the job is cancelled with `JobHandle::cancel`.
+///
+/// If the handler returns an error, the task is failed with that error and stays in the backup
+/// queue. See `TaskGuard::fail_with`. With `run_classified`, the error decides whether the task
+/// is retried, dead-lettered or dropped.
+///
+/// ## Timeouts
+///
+/// The timeout can be set for all tasks of the worker, or per job with `JobOptions`.
+/// With a timeout set, every task is handled in its own thread.
+/// If the handler does not finish in time, the task is failed with a timeout error and the
+/// worker moves on to the next task.
+/// Threads can't be aborted, so the overrunning handler keeps running in the background and its
+/// result is ignored.
+///
+/// ## Isolation
+///
+/// With `isolated`, every task is handled in a forked child process instead, so a handler
+/// leaking memory, crashing in foreign code or getting killed for using too much memory only
+/// fails its own task. Overrunning children are killed.
+///
+/// While running, the worker is listed by `list_workers` and refreshes its registration with
+/// regular heartbeats.
+///
+/// Workers can be controlled remotely with `send_control`.
+/// They follow the settings stored with `Queue::set_config`, e.g. to pause or rate limit a queue
+/// at runtime, see `QueueConfig`.
+///
+/// Clones share their stop flag, so a clone can be used to stop a running worker.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let worker = Worker::new(Queue::new("default".into(), client))
+///     .timeout(Duration::from_secs(30));
+///
+/// worker.run(|job: Job, token: CancellationToken| -> Result<(), String> {
+///     for step in 0..job.steps {
+///         if token.is_cancelled() {
+///             return Err("cancelled".into());
+///         }
+///         // ...
+///     }
+///     Ok(())
+/// });
+/// ```
+#[derive(Clone)]
+pub struct Worker {
+    queue: Queue,
+    timeout: Option<Duration>,
+    isolated: bool,
+    heartbeat: Duration,
+    breaker: Option<CircuitBreaker>,
+    gate: Option<ResourceGate>,
+    idle: Option<IdleStrategy>,
+    limits: Option<ConcurrencyLimits>,
+    config_reload: Duration,
+    on_config_change: Option<ConfigCallback>,
+    probe: WorkerProbe,
+    stopped: Arc<AtomicBool>,
+    quiet: Arc<AtomicBool>,
+    current: Arc<Mutex<Option<CancellationToken>>>,
+}
+
+impl Worker {
+    /// Create a new worker processing tasks of the given queue
+    pub fn new(queue: Queue) -> Worker {
+        Worker {
+            queue: queue,
+            timeout: None,
+            isolated: false,
+            heartbeat: Duration::from_secs(5),
+            breaker: None,
+            gate: None,
+            idle: None,
+            limits: None,
+            config_reload: Duration::from_secs(1),
+            on_config_change: None,
+            probe: WorkerProbe::new(),
+            stopped: Arc::new(AtomicBool::new(false)),
+            quiet: Arc::new(AtomicBool::new(false)),
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Set the maximum time a handler may take for a single task
+    pub fn timeout(mut self, timeout: Duration) -> Worker {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run every task in a child process of its own
+    ///
+    /// The worker forks before running the handler and waits for the child to exit. The outcome
+    /// reported by the child decides what happens to the task, just like for handlers running in
+    /// the worker. Tasks whose child crashed, e.g. from a segfault or the OOM killer, are retried.
+    /// Cancelling the task terminates the child with `SIGTERM`, running over the timeout kills it.
+    ///
+    /// The child is a copy of the worker process with only the forking thread, so handlers must
+    /// not rely on other threads or locks they hold. Open their own connections to Redis instead
+    /// of sharing ones created before the fork.
+    pub fn isolated(mut self) -> Worker {
+        self.isolated = true;
+        self
+    }
+
+    /// Set how often the worker refreshes its registration. Defaults to 5 seconds.
+    ///
+    /// A worker missing three heartbeats is considered dead.
+    /// Requests to cancel the running job, see `JobHandle::cancel`, are picked up with every
+    /// heartbeat.
+    pub fn heartbeat(mut self, interval: Duration) -> Worker {
+        self.heartbeat = interval;
+        self
+    }
+
+    /// Pause fetching tasks while too many of them fail, see `CircuitBreaker`
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Worker {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Pause fetching tasks while the host is short on CPU or memory, see `ResourceGate`
+    pub fn resource_gate(mut self, gate: ResourceGate) -> Worker {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// Poll the queue without blocking, waiting according to `strategy` while it's empty
+    ///
+    /// By default, the worker blocks on the queue for up to a second at a time.
+    /// The strategy also applies while Redis is unavailable.
+    pub fn idle_strategy(mut self, strategy: IdleStrategy) -> Worker {
+        self.idle = Some(strategy);
+        self
+    }
+
+    /// Share per-type limits on concurrently running tasks with other workers of the process
+    ///
+    /// While the task type of the worker is at its limit, no tasks are fetched.
+    /// See `ConcurrencyLimits`.
+    pub fn concurrency_limits(mut self, limits: ConcurrencyLimits) -> Worker {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Set how often the settings of the queue are reloaded from Redis. Defaults to 1 second.
+    ///
+    /// Changes stored with `Queue::set_config`, `Queue::pause` or `Queue::resume` are applied
+    /// before fetching the next task after the reload.
+    pub fn config_reload(mut self, interval: Duration) -> Worker {
+        self.config_reload = interval;
+        self
+    }
+
+    /// Call `callback` with the previous and the new settings whenever a reload changed them
+    ///
+    /// The callback runs on the worker thread before the next task is fetched, e.g. to log
+    /// transitions. It also runs once the settings are first loaded, if they differ from the
+    /// defaults.
+    ///
+    /// ## Example
+    ///
+    /// ```rust,ignore
+    /// let worker = Worker::new(queue).on_config_change(|old, new| {
+    ///     if old.paused != new.paused {
+    ///         println!("queue {}", if new.paused { "paused" } else { "resumed" });
+    ///     }
+    /// });
+    /// ```
+    pub fn on_config_change<F>(mut self, callback: F) -> Worker
+    where
+        F: Fn(&QueueConfig, &QueueConfig) + Send + Sync + 'static,
+    {
+        self.on_config_change = Some(Arc::new(callback));
+        self
+    }
+
+    /// Only process tasks of the given types, putting others back into the queue
+    ///
+    /// Lets specialized workers, e.g. on GPU machines, share a queue with general workers.
+    /// Skipped tasks are pushed back to the end of the queue for other workers.
+    /// Make sure every type is processed by some worker, or its tasks circle forever.
+    /// See `JobOptions::kind` for how the type of a task is determined.
+    pub fn only_kinds<S: Into<String>>(mut self, kinds: Vec<S>) -> Worker {
+        self.queue.kinds = Some(KindFilter::Only(kinds.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Put tasks of the given types back into the queue instead of processing them
+    ///
+    /// See `only_kinds`.
+    pub fn skip_kinds<S: Into<String>>(mut self, kinds: Vec<S>) -> Worker {
+        self.queue.kinds = Some(KindFilter::Skip(kinds.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Get a handle on the state of the worker, e.g. for liveness and readiness probes
+    ///
+    /// The handle is shared by all clones of the worker. See `WorkerProbe`.
+    pub fn probe(&self) -> WorkerProbe {
+        self.probe.clone()
+    }
+
+    /// Get the queue tasks are fetched from
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    /// Stop the worker
+    ///
+    /// The token of the current task is cancelled and the worker returns after the task.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(ref token) = *self.current.lock().unwrap() {
+            token.cancel();
+        }
+    }
+
+    /// Check if the worker is stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Stop fetching new tasks, without stopping the worker
+    pub fn quiet(&self) {
+        self.quiet.store(true, Ordering::SeqCst);
+    }
+
+    /// Check if the worker stopped fetching new tasks
+    pub fn is_quiet(&self) -> bool {
+        self.quiet.load(Ordering::SeqCst)
+    }
+
+    /// Run `handler` for every task until the worker or its queue is stopped
+    pub fn run<T, F, E>(&self, handler: F)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<(), E> + Send + Sync + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        self.run_with(handler, |_: &E| TaskOutcome::Retry)
+    }
+
+    /// Run `handler` for every task like `run`, storing what it returns as the result of the task
+    ///
+    /// Results are kept if the queue was set up with `Queue::with_results`, so producers can wait
+    /// for them with `Queue::await_result`.
+    pub fn run_returning<T, F, R, E>(&self, handler: F)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<R, E> + Send + Sync + 'static,
+        R: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        self.run_with(handler, |_: &E| TaskOutcome::Retry)
+    }
+
+    /// Run `handler` for every task, deciding what happens to failed tasks with `classify`.
+    fn run_with<T, F, R, E>(&self, handler: F, classify: fn(&E) -> TaskOutcome)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<R, E> + Send + Sync + 'static,
+        R: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        // Workers are usually set up on one thread and run on another
+        let worker = Worker {
+            queue: self.queue.on_current_thread(),
+            ..self.clone()
+        };
+        worker.serve(handler, classify)
+    }
+
+    /// Run `handler` like `run_with`, with the queue bound to the current thread.
+    fn serve<T, F, R, E>(&self, handler: F, classify: fn(&E) -> TaskOutcome)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<R, E> + Send + Sync + 'static,
+        R: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        let info = Arc::new(Mutex::new(WorkerInfo {
+            id: self.queue.worker_id().into(),
+            hostname: ::hostname(),
+            pid: ::getpid(),
+            queues: vec![self.queue.queue().into()],
+            concurrency: 1,
+            started_at: ::now_millis(),
+            heartbeat_at: 0,
+            current: vec![],
+            quiet: false,
+        }));
+        let done = Arc::new(AtomicBool::new(false));
+        let heartbeat = self.spawn_heartbeat(info.clone(), done.clone());
+
+        self.process(Arc::new(handler), classify, &info);
+        self.probe.stopped();
+
+        done.store(true, Ordering::SeqCst);
+        let _ = heartbeat.join();
+        if let Ok(con) = self.queue.connection() {
+            let _ = registry::remove(&con, self.queue.worker_id());
+        }
+    }
+
+    /// Run `handler` for every task like `run`, letting its errors decide what happens to the task
+    ///
+    /// Errors classified as `TaskOutcome::Fail` are moved to the dead letter queue and those
+    /// classified as `TaskOutcome::Discard` are dropped, see `Classify`.
+    /// Timeouts and tasks failing to decode are retried.
+    pub fn run_classified<T, F, E>(&self, handler: F)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<(), E> + Send + Sync + 'static,
+        E: Classify + fmt::Display + Send + 'static,
+    {
+        self.run_with(handler, E::classify)
+    }
+
+    /// Run every task as an external command, see `CommandTask`
+    ///
+    /// The exit status and output of every command are stored as the result of its task, if the
+    /// queue was set up with `Queue::with_results`. Commands exiting with a non-zero status are
+    /// retried, with their exit status and output as the error of the failure record. Commands
+    /// which can't be started are moved to the dead letter queue. Cancelled commands and commands running over
+    /// their `CommandTask::timeout` are killed, together with all processes they started.
+    pub fn run_commands(&self) {
+        let execute = |task, token| command::execute(task, token).map_err(command::Failure);
+        self.run_with(execute, command::Failure::classify)
+    }
+
+    /// Refresh the registration of the worker and handle remote commands in the background until
+    /// `done` is set.
+    fn spawn_heartbeat(&self, info: Arc<Mutex<WorkerInfo>>, done: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        let worker = self.clone();
+        let interval = self.heartbeat;
+        let ttl = cmp::max(1, 3 * interval.as_secs() as usize);
+
+        thread::spawn(move || {
+            let tick = Duration::from_millis(::duration_millis(interval).clamp(1, 100));
+            let mut next_beat = Instant::now();
+            while !done.load(Ordering::SeqCst) {
+                if Instant::now() >= next_beat {
+                    // A missed heartbeat is retried on the next tick
+                    if worker.beat(&info, ttl).is_ok() {
+                        next_beat = Instant::now() + interval;
+                    }
+                }
+                thread::sleep(tick);
+            }
+        })
+    }
+
+    /// Refresh the registration of the worker and apply pending remote commands.
+    fn beat(&self, info: &Mutex<WorkerInfo>, ttl: usize) -> RedisResult<()> {
+        let con = self.queue.connection()?;
+        let mut info = info.lock().unwrap().clone();
+        info.heartbeat_at = ::server_millis(&con)?;
+        info.quiet = self.is_quiet();
+        registry::beat(&con, &info, ttl)?;
+
+        if let Some(jid) = info.current.first() {
+            if job::is_cancel_requested(&con, jid)? {
+                if let Some(ref token) = *self.current.lock().unwrap() {
+                    token.cancel();
+                }
+            }
+        }
+
+        for command in control::take(&con, &info.id)? {
+            match command {
+                Control::Quiet => self.quiet(),
+                // Let the current task finish instead of cancelling it
+                Control::Terminate => self.stopped.store(true, Ordering::SeqCst),
+                Control::Dump => {
+                    info.quiet = self.is_quiet();
+                    control::dump(&con, &info)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `handler` for every task until the worker or its queue is stopped.
+    fn process<T, F, R, E>(&self, handler: Arc<F>, classify: fn(&E) -> TaskOutcome, info: &Mutex<WorkerInfo>)
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<R, E> + Send + Sync + 'static,
+        R: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        let mut idle = Idle::new(self.idle.unwrap_or(IdleStrategy::Fixed(Duration::from_millis(100))));
+        let kind = any::type_name::<T>();
+        while !self.is_stopped() {
+            let config = self.reload_config();
+            if config.paused || !self.within_limits(&config) || self.gate.as_ref().is_some_and(|gate| !gate.allow()) {
+                self.probe.waiting();
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            if self.is_quiet() || self.breaker.as_ref().is_some_and(|breaker| !breaker.allow()) {
+                self.probe.waiting();
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            if self.limits.as_ref().is_some_and(|limits| !limits.acquire(kind)) {
+                if let Some(ref breaker) = self.breaker {
+                    breaker.release();
+                }
+                self.probe.waiting();
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+
+            let timeout = if self.idle.is_some() { None } else { Some(1) };
+            let next = self.queue.poll_as::<T, serde_json::Value>(timeout);
+            if let Some(ref next) = next {
+                self.probe.polled(next.is_ok());
+            }
+            let guard = match next {
+                Some(Ok(Some(guard))) => guard,
+                next => {
+                    if let Some(ref breaker) = self.breaker {
+                        breaker.release();
+                    }
+                    if let Some(ref limits) = self.limits {
+                        limits.release(kind);
+                    }
+                    match next {
+                        None if self.queue.is_stopped() => return,
+                        // Nothing to do yet or Redis is unavailable
+                        _ => {
+                            idle.idle();
+                            continue;
+                        }
+                    }
+                }
+            };
+            idle.reset();
+            self.probe.processing(true);
+            if config.rate_limit.is_some() {
+                // Counting is best-effort, the limit is not exact anyway
+                let _ = self.queue.connection().and_then(|con| config::count(&con, self.queue.queue()));
+            }
+
+            let result = match serde_json::from_value::<T>(guard.inner().clone()) {
+                Ok(task) => {
+                    info.lock().unwrap().current = guard.jid().map(String::from).into_iter().collect();
+                    let timeout = guard.options().and_then(|options| options.timeout).or(self.timeout);
+                    let result = self.handle(&handler, classify, task, timeout);
+                    info.lock().unwrap().current.clear();
+                    result
+                }
+                Err(e) => Err(HandlerError::retry(format!("Invalid task: {}", e))),
+            };
+
+            if let Some(ref breaker) = self.breaker {
+                breaker.record(result.is_ok());
+            }
+            match result {
+                Ok(Ok(result)) => {
+                    if let Err(e) = guard.set_result(&result) {
+                        guard.fail_with(format!("Invalid result: {}", e));
+                    }
+                }
+                Ok(Err(e)) => guard.fail_with(format!("Invalid result: {}", e)),
+                Err(e) => match e.outcome {
+                    TaskOutcome::Retry => guard.fail_with(e.message),
+                    TaskOutcome::Fail => guard.dead_letter(e.message),
+                    TaskOutcome::Discard => guard.discard(),
+                },
+            }
+            drop(guard);
+            self.probe.processing(false);
+            if let Some(ref limits) = self.limits {
+                limits.release(kind);
+            }
+        }
+    }
+
+    /// Reload the settings of the queue when due, reporting changes to the callback.
+    fn reload_config(&self) -> QueueConfig {
+        let previous = self.queue.applied_config();
+        let config = self.queue.refresh_config(self.config_reload);
+        if config != previous {
+            if let Some(ref callback) = self.on_config_change {
+                callback(&previous, &config);
+            }
+        }
+        config
+    }
+
+    /// Check if the rate limit and concurrency of the queue allow fetching another task.
+    ///
+    /// Fetching goes on while Redis is unavailable, so the error surfaces when polling.
+    fn within_limits(&self, config: &QueueConfig) -> bool {
+        if config.rate_limit.is_none() && config.concurrency.is_none() {
+            return true;
+        }
+        let queue = self.queue.queue();
+        self.queue.connection().and_then(|con| {
+            if let Some(limit) = config.rate_limit {
+                if !config::rate_allows(&con, queue, limit)? {
+                    return Ok(false);
+                }
+            }
+            match config.concurrency {
+                Some(max) => Ok(processing::count(&con, queue)? < u64::from(max)),
+                None => Ok(true),
+            }
+        }).unwrap_or(true)
+    }
+
+    /// Run `handler` for a single task, enforcing the timeout.
+    ///
+    /// Returns the encoded result of the handler.
+    fn handle<T, F, R, E>(
+        &self,
+        handler: &Arc<F>,
+        classify: fn(&E) -> TaskOutcome,
+        task: T,
+        timeout: Option<Duration>,
+    ) -> Result<Result<Box<RawValue>, String>, HandlerError>
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T, CancellationToken) -> Result<R, E> + Send + Sync + 'static,
+        R: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        let token = CancellationToken::new();
+        *self.current.lock().unwrap() = Some(token.clone());
+        // The worker might have been stopped while fetching the task
+        if self.is_stopped() {
+            token.cancel();
+        }
+
+        if self.isolated {
+            let report = isolate::run(&**handler, classify, task, &token, timeout);
+            *self.current.lock().unwrap() = None;
+            return match report {
+                isolate::Report::Completed(result) => Ok(Ok(result)),
+                isolate::Report::InvalidResult(e) => Ok(Err(e)),
+                isolate::Report::Failed { outcome, message } => Err(HandlerError {
+                    outcome: isolate::outcome(&outcome),
+                    message: message,
+                }),
+            };
+        }
+
+        let failed = move |e: E| HandlerError {
+            outcome: classify(&e),
+            message: e.to_string(),
+        };
+        let result = match timeout {
+            None => match panic::catch_unwind(AssertUnwindSafe(|| handler(task, token))) {
+                Ok(result) => result.map_err(failed),
+                Err(_) => Err(HandlerError::retry("Handler panicked".into())),
+            },
+            Some(timeout) => {
+                let (tx, rx) = mpsc::channel();
+                let handler = handler.clone();
+                let handler_token = token.clone();
+                thread::spawn(move || {
+                    let _ = tx.send(handler(task, handler_token).map_err(failed));
+                });
+
+                match rx.recv_timeout(timeout) {
+                    Ok(result) => result,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        token.cancel();
+                        Err(HandlerError::retry(format!("Timed out after {} ms", ::duration_millis(timeout))))
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Err(HandlerError::retry("Handler panicked".into())),
+                }
+            }
+        };
+
+        *self.current.lock().unwrap() = None;
+        result.map(|result| serde_json::value::to_raw_value(&result).map_err(|e| e.to_string()))
+    }
+}
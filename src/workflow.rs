@@ -0,0 +1,245 @@
+//! Workflows of tasks depending on each other.
+
+use redis::{self, Pipeline, RedisResult};
+use functions;
+use envelope::{Envelope, WorkflowNode};
+use TaskEncodable;
+
+/// How long workflow state is kept in Redis if a workflow never finishes, in seconds.
+const WORKFLOW_TTL: usize = 30 * 24 * 60 * 60;
+
+/// Marks one node of a workflow as finished and releases all children without unfinished parents.
+///
+/// KEYS[1]: the workflow key
+/// KEYS[2]: the dead letter queue of the node
+/// ARGV[1]: the finished node
+/// ARGV[2]: "1" if the node failed
+/// ARGV[3]: id of the job to only finish the node if it's dead, empty to finish it right away
+pub(crate) const FINISH_NODE: &'static str = r"
+local key = KEYS[1]
+if ARGV[3] ~= '' and not redis.call('ZSCORE', KEYS[2], ARGV[3]) then
+  return 0
+end
+if redis.call('EXISTS', key) == 0 then
+  return 0
+end
+if ARGV[2] == '1' then
+  redis.call('HINCRBY', key, 'failed', 1)
+  return 0
+end
+local children = redis.call('HGET', key, ARGV[1] .. ':children')
+if children then
+  for child in string.gmatch(children, '[^,]+') do
+    if redis.call('HINCRBY', key, child .. ':parents', -1) == 0 then
+      local next = redis.call('HMGET', key, child .. ':queue', child .. ':job')
+      redis.call('LPUSH', next[1], next[2])
+    end
+  end
+end
+if redis.call('HINCRBY', key, 'pending', -1) == 0 then
+  redis.call('DEL', key)
+end
+return 1
+";
+
+/// Get the key the state of a workflow is stored in.
+fn workflow_key(wid: &str) -> String {
+    format!("oppgave:workflow:{}", wid)
+}
+
+/// Add the commands marking `node` of the workflow `wid` as finished to the pipeline.
+///
+/// With `if_dead`, the dead letter queue and id of the job, the node only counts as finished if
+/// the job is dead by then.
+pub(crate) fn finish_node(pipe: &mut Pipeline, wid: &str, node: usize, failed: bool, if_dead: Option<(&str, &str)>) {
+    let key = workflow_key(wid);
+    let (dead, jid) = if_dead.unwrap_or((&key, ""));
+    functions::eval(pipe, FINISH_NODE, 2)
+        .arg(&key[..])
+        .arg(dead)
+        .arg(node)
+        .arg(if failed { "1" } else { "0" })
+        .arg(jid)
+        .ignore();
+}
+
+/// A handle to a task added to a `Workflow`, used to declare dependencies on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node(usize);
+
+struct NodeSpec {
+    queue: Option<String>,
+    task: RedisResult<Vec<u8>>,
+    parents: Vec<usize>,
+}
+
+/// A small graph of tasks, where each task only runs once all the tasks it depends on completed.
+///
+/// Tasks without dependencies are pushed right away with `Queue::push_workflow`.
+/// All other tasks are stored in Redis and released by the worker finishing their last
+/// unfinished dependency.
+/// If a task fails, the tasks depending on it are never released.
+///
+/// Dependencies can only be declared on tasks already added to the workflow, so the graph is
+/// guaranteed to be free of cycles.
+///
+/// Tasks in a workflow need to be encoded as JSON, which is the default for all `Serialize` types.
+///
+/// ## Example
+///
+/// ```rust,ignore
+/// let mut workflow = Workflow::new();
+/// let fetch = workflow.add(Fetch { id: 1 }, &[]);
+/// let resize = workflow.add_to("images", Resize { id: 1 }, &[fetch]);
+/// let index = workflow.add_to("search", Index { id: 1 }, &[fetch]);
+/// workflow.add(Publish { id: 1 }, &[resize, index]);
+///
+/// let wid = queue.push_workflow(workflow).unwrap();
+/// ```
+pub struct Workflow {
+    wid: String,
+    nodes: Vec<NodeSpec>,
+}
+
+/// The progress of a workflow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkflowStatus {
+    /// Number of tasks in the workflow
+    pub total: u64,
+    /// Number of tasks not completed yet
+    pub pending: u64,
+    /// Number of tasks that failed
+    pub failed: u64,
+}
+
+impl Workflow {
+    /// Create a new, empty workflow
+    pub fn new() -> Workflow {
+        Workflow {
+            wid: ::envelope::new_jid(),
+            nodes: vec![],
+        }
+    }
+
+    /// Get the id of this workflow
+    pub fn id(&self) -> &str {
+        &self.wid
+    }
+
+    /// Add a task for the queue the workflow is pushed to, running after all of `parents`
+    pub fn add<T: TaskEncodable>(&mut self, task: T, parents: &[Node]) -> Node {
+        self.add_node(None, task.try_encode_task(), parents)
+    }
+
+    /// Add a task for the queue `name`, running after all of `parents`
+    pub fn add_to<T: TaskEncodable>(&mut self, name: &str, task: T, parents: &[Node]) -> Node {
+        self.add_node(Some(format!("oppgave:{}", name)), task.try_encode_task(), parents)
+    }
+
+    fn add_node(&mut self, queue: Option<String>, task: RedisResult<Vec<u8>>, parents: &[Node]) -> Node {
+        let idx = self.nodes.len();
+        let mut deps = vec![];
+        for &Node(parent) in parents {
+            assert!(parent < idx, "Parent node does not belong to this workflow");
+            if !deps.contains(&parent) {
+                deps.push(parent);
+            }
+        }
+
+        self.nodes.push(NodeSpec {
+            queue: queue,
+            task: task,
+            parents: deps,
+        });
+        Node(idx)
+    }
+
+    /// Build a pipeline atomically storing the workflow state and pushing all initial tasks.
+    pub(crate) fn into_pipeline(self, queue: &str) -> RedisResult<(String, Pipeline)> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        if self.nodes.is_empty() {
+            return Ok((self.wid, pipe));
+        }
+
+        let key = workflow_key(&self.wid);
+        pipe.cmd("HMSET")
+            .arg(&key[..])
+            .arg("total")
+            .arg(self.nodes.len())
+            .arg("pending")
+            .arg(self.nodes.len())
+            .arg("failed")
+            .arg(0)
+            .ignore();
+
+        let mut children = vec![vec![]; self.nodes.len()];
+        for (idx, node) in self.nodes.iter().enumerate() {
+            for &parent in &node.parents {
+                children[parent].push(idx.to_string());
+            }
+        }
+
+        for (idx, node) in self.nodes.into_iter().enumerate() {
+            let mut job = Envelope::new(node.task?)?;
+            job.workflow = Some(WorkflowNode {
+                id: self.wid.clone(),
+                node: idx,
+            });
+            let target = node.queue.unwrap_or_else(|| queue.into());
+
+            if !children[idx].is_empty() {
+                pipe.cmd("HSET")
+                    .arg(&key[..])
+                    .arg(format!("{}:children", idx))
+                    .arg(children[idx].join(","))
+                    .ignore();
+            }
+
+            if node.parents.is_empty() {
+                pipe.cmd("LPUSH").arg(&target[..]).arg(job.encode()).ignore();
+            } else {
+                pipe.cmd("HMSET")
+                    .arg(&key[..])
+                    .arg(format!("{}:parents", idx))
+                    .arg(node.parents.len())
+                    .arg(format!("{}:queue", idx))
+                    .arg(&target[..])
+                    .arg(format!("{}:job", idx))
+                    .arg(job.encode())
+                    .ignore();
+            }
+        }
+        pipe.cmd("EXPIRE").arg(&key[..]).arg(WORKFLOW_TTL).ignore();
+
+        Ok((self.wid, pipe))
+    }
+}
+
+impl Default for Workflow {
+    fn default() -> Workflow {
+        Workflow::new()
+    }
+}
+
+/// Get the progress of the workflow `wid`.
+///
+/// Returns `None` if the workflow is unknown or already completed.
+pub(crate) fn status<C: redis::ConnectionLike>(con: &C, wid: &str) -> RedisResult<Option<WorkflowStatus>> {
+    let (total, pending, failed): (Option<u64>, Option<u64>, Option<u64>) = redis::cmd("HMGET")
+        .arg(workflow_key(wid))
+        .arg("total")
+        .arg("pending")
+        .arg("failed")
+        .query(con)?;
+
+    Ok(match (total, pending, failed) {
+        (Some(total), Some(pending), Some(failed)) => Some(WorkflowStatus {
+            total: total,
+            pending: pending,
+            failed: failed,
+        }),
+        _ => None,
+    })
+}